@@ -0,0 +1,47 @@
+//! Compares the throughput of taking and returning tokens directly through a semaphore and
+//! through the per-thread caches of a `CachedSemaphore`.
+//!
+//! The semaphore stays in userspace when there's no contention, so the difference shows with the
+//! threads running on multiple cores, fighting over the counter.
+//!
+//! Run with `cargo run --release --example cached`.
+
+extern crate unix_semaphore;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use unix_semaphore::{CachedSemaphore, Semaphore};
+
+const THREADS: u32 = 4;
+const ROUNDS: u32 = 1_000_000;
+const TOKENS: u32 = 256;
+const BATCH: u32 = 16;
+
+fn measure<A, R>(acquire: A, release: R) -> Duration
+where
+    A: Fn() + Sync,
+    R: Fn() + Sync,
+{
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    acquire();
+                    release();
+                }
+            });
+        }
+    });
+    start.elapsed() / (THREADS * ROUNDS)
+}
+
+fn main() {
+    let sem = Semaphore::anonymous(TOKENS).unwrap();
+    let direct = measure(|| sem.wait(), || sem.post().unwrap());
+    println!("Semaphore:       {:?} per acquire + release", direct);
+    let cached = CachedSemaphore::new(TOKENS, BATCH).unwrap();
+    let through_cache = measure(|| cached.acquire(), || cached.release().unwrap());
+    println!("CachedSemaphore: {:?} per acquire + release", through_cache);
+}
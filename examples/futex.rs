@@ -0,0 +1,65 @@
+//! Compares the futex backend with the POSIX one, uncontended and contended.
+//!
+//! Run with `cargo run --release --features futex --example futex`.
+
+extern crate unix_semaphore;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use unix_semaphore::{FutexSemaphore, Semaphore};
+
+const ROUNDS: u32 = 1_000_000;
+const THREADS: u32 = 4;
+
+/// A post and a wait with no other thread around, per pair.
+fn uncontended<S, W, P>(sem: &S, wait: W, post: P) -> Duration
+where
+    W: Fn(&S),
+    P: Fn(&S),
+{
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        post(sem);
+        wait(sem);
+    }
+    start.elapsed() / ROUNDS
+}
+
+/// Threads fighting over a single token, per wait and post pair of one thread.
+fn contended<S, W, P>(sem: &S, wait: W, post: P) -> Duration
+where
+    S: Sync,
+    W: Fn(&S) + Sync,
+    P: Fn(&S) + Sync,
+{
+    let rounds = ROUNDS / 10;
+    let start = Instant::now();
+    thread::scope(|s| {
+        for _ in 0..THREADS {
+            s.spawn(|| {
+                for _ in 0..rounds {
+                    wait(sem);
+                    post(sem);
+                }
+            });
+        }
+    });
+    start.elapsed() / (rounds * THREADS)
+}
+
+fn main() {
+    let posix = Semaphore::anonymous(0).unwrap();
+    let futex = FutexSemaphore::new(0).unwrap();
+    let time = uncontended(&posix, |s| s.wait(), |s| s.post().unwrap());
+    println!("uncontended POSIX: {:?}", time);
+    let time = uncontended(&futex, |s| s.wait(), |s| s.post().unwrap());
+    println!("uncontended futex: {:?}", time);
+
+    posix.post().unwrap();
+    futex.post().unwrap();
+    let time = contended(&posix, |s| s.wait(), |s| s.post().unwrap());
+    println!("contended POSIX:   {:?}", time);
+    let time = contended(&futex, |s| s.wait(), |s| s.post().unwrap());
+    println!("contended futex:   {:?}", time);
+}
@@ -0,0 +1,64 @@
+//! Compares ping-pong latency of a separately allocated and an inline semaphore, and of
+//! blocking right away versus spinning first.
+//!
+//! Run with `cargo run --release --example ping_pong`.
+
+extern crate unix_semaphore;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use unix_semaphore::{InlineSemaphore, Semaphore, SpinConfig};
+
+const ROUNDS: u32 = 100_000;
+
+fn measure<S, W, P>(ping: S, pong: S, wait: W, post: P) -> Duration
+where
+    S: Clone + Send + 'static,
+    W: Fn(&S) + Copy + Send + 'static,
+    P: Fn(&S) + Copy + Send + 'static,
+{
+    let other = thread::spawn({
+        let ping = ping.clone();
+        let pong = pong.clone();
+        move || {
+            for _ in 0..ROUNDS {
+                wait(&ping);
+                post(&pong);
+            }
+        }
+    });
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        post(&ping);
+        wait(&pong);
+    }
+    let elapsed = start.elapsed();
+    other.join().unwrap();
+    elapsed / ROUNDS
+}
+
+fn main() {
+    let boxed = measure(
+        Arc::new(Semaphore::anonymous(0).unwrap()),
+        Arc::new(Semaphore::anonymous(0).unwrap()),
+        |s| s.wait(),
+        |s| s.post().unwrap(),
+    );
+    println!("Arc<Semaphore>:       {:?} per round trip", boxed);
+    let inline = measure(
+        InlineSemaphore::arc(0).unwrap(),
+        InlineSemaphore::arc(0).unwrap(),
+        |s| s.semaphore().wait(),
+        |s| s.semaphore().post().unwrap(),
+    );
+    println!("InlineSemaphore::arc: {:?} per round trip", inline);
+    let spin = measure(
+        Arc::new(Semaphore::anonymous(0).unwrap()),
+        Arc::new(Semaphore::anonymous(0).unwrap()),
+        |s| s.wait_spin(SpinConfig::new().attempts(1000)),
+        |s| s.post().unwrap(),
+    );
+    println!("wait_spin:            {:?} per round trip", spin);
+}
@@ -0,0 +1,36 @@
+//! Two processes exchanging tokens through a semaphore placed in a `shared_memory` segment.
+//!
+//! Run it without arguments. It starts a copy of itself that attaches to the segment.
+
+extern crate shared_memory;
+extern crate unix_semaphore;
+
+use std::env;
+use std::process::Command;
+
+use shared_memory::ShmemConf;
+use unix_semaphore::Semaphore;
+
+const OFFSET: usize = 64;
+
+fn main() {
+    match env::args().nth(1) {
+        Some(os_id) => {
+            let shmem = ShmemConf::new().os_id(os_id).open().unwrap();
+            let sem = Semaphore::attach_in_shmem(&shmem, OFFSET).unwrap();
+            sem.wait();
+            println!("Child got the token");
+        },
+        None => {
+            let shmem = ShmemConf::new().size(4096).create().unwrap();
+            let sem = Semaphore::create_in_shmem(&shmem, OFFSET, 0).unwrap();
+            let mut child = Command::new(env::current_exe().unwrap())
+                .arg(shmem.get_os_id())
+                .spawn()
+                .unwrap();
+            sem.post().unwrap();
+            assert!(child.wait().unwrap().success());
+            println!("Parent is done");
+        },
+    }
+}
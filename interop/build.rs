@@ -0,0 +1,6 @@
+extern crate cc;
+
+fn main() {
+    cc::Build::new().file("c/sem_helper.c").compile("sem_helper");
+    println!("cargo:rerun-if-changed=c/sem_helper.c");
+}
@@ -0,0 +1,47 @@
+/* Helpers for tests/c_interop.rs, checking the Rust side agrees with C about sem_t. */
+
+#include <pthread.h>
+#include <semaphore.h>
+#include <stddef.h>
+#include <sys/types.h>
+#include <sys/wait.h>
+#include <unistd.h>
+
+size_t helper_sem_size(void) {
+    return sizeof(sem_t);
+}
+
+size_t helper_sem_align(void) {
+    return _Alignof(sem_t);
+}
+
+static void *post_thread(void *sem) {
+    usleep(10000);
+    sem_post(sem);
+    return NULL;
+}
+
+/* Posts the semaphore from a new thread, after a short while. */
+int helper_post_later(sem_t *sem) {
+    pthread_t thread;
+    if (pthread_create(&thread, NULL, post_thread, sem) != 0) {
+        return -1;
+    }
+    return pthread_detach(thread);
+}
+
+/* Posts the semaphore from a forked child, returns the child's exit status. */
+int helper_fork_post(sem_t *sem) {
+    int status;
+    pid_t pid = fork();
+    if (pid == -1) {
+        return -1;
+    }
+    if (pid == 0) {
+        _exit(sem_post(sem) == 0 ? 0 : 1);
+    }
+    if (waitpid(pid, &status, 0) == -1) {
+        return -1;
+    }
+    return status;
+}
@@ -0,0 +1,4 @@
+//! Tests of interoperability with C code.
+//!
+//! This lives in its own crate so the C helper (and the C compiler it needs) is built only for
+//! these tests, not for every user of `unix-semaphore`. The tests themselves are in `tests/`.
@@ -0,0 +1,50 @@
+//! The semaphores are usable by C code, with the layout it expects.
+
+extern crate libc;
+extern crate unix_semaphore;
+
+use std::mem::MaybeUninit;
+
+use libc::{c_int, sem_t, size_t};
+use unix_semaphore::Semaphore;
+
+#[link(name = "sem_helper", kind = "static")]
+extern "C" {
+    fn helper_sem_size() -> size_t;
+    fn helper_sem_align() -> size_t;
+    fn helper_post_later(sem: *mut sem_t) -> c_int;
+    fn helper_fork_post(sem: *mut sem_t) -> c_int;
+}
+
+#[test]
+fn layout() {
+    unsafe {
+        assert_eq!(helper_sem_size(), Semaphore::LAYOUT.size());
+        assert_eq!(helper_sem_align(), Semaphore::LAYOUT.align());
+    }
+}
+
+#[test]
+fn c_thread_posts() {
+    // Not anonymous, that one may be backed by GCD and have no sem_t
+    let sem = Semaphore::anonymous_shared(0).unwrap();
+    assert_eq!(0, unsafe { helper_post_later(sem.as_raw()) });
+    sem.wait();
+    assert_eq!(0, sem.value());
+}
+
+#[test]
+fn c_thread_posts_placed() {
+    let mut slot = MaybeUninit::uninit();
+    let sem = Semaphore::init_in(&mut slot, false, 0).unwrap();
+    assert_eq!(0, unsafe { helper_post_later(sem.as_raw()) });
+    sem.wait();
+}
+
+#[test]
+fn c_child_posts_shared() {
+    let sem = Semaphore::anonymous_shared(0).unwrap();
+    assert_eq!(0, unsafe { helper_fork_post(sem.as_raw()) });
+    sem.wait();
+    assert_eq!(0, sem.value());
+}
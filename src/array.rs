@@ -0,0 +1,183 @@
+//! Many process-shared semaphores in a single shared mapping.
+
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::ops::Index;
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use libc::{self, sem_t};
+
+use {init, SemaphoreSlot};
+
+/// A fixed number of process-shared semaphores laid out next to each other in one anonymous
+/// shared mapping.
+///
+/// Like with [`Semaphore::anonymous_shared`][::Semaphore::anonymous_shared], children created
+/// by `fork` share the whole array with the parent.
+pub struct SemaphoreArray {
+    slots: NonNull<SemaphoreSlot>,
+    len: usize,
+}
+
+impl SemaphoreArray {
+    /// Creates `count` semaphores, each with the same initial value.
+    pub fn anonymous_shared(count: usize, value: u32) -> Result<Self, Error> {
+        if count == 0 {
+            return Ok(SemaphoreArray {
+                slots: NonNull::dangling(),
+                len: 0,
+            });
+        }
+        let size = count
+            .checked_mul(mem::size_of::<sem_t>())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Too many semaphores"))?;
+        unsafe {
+            let mem = libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if mem == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
+            }
+            let first = mem as *mut sem_t;
+            for i in 0..count {
+                if let Err(e) = init(first.add(i), true, value) {
+                    for j in 0..i {
+                        libc::sem_destroy(first.add(j));
+                    }
+                    libc::munmap(mem, size);
+                    return Err(e);
+                }
+            }
+            Ok(SemaphoreArray {
+                slots: NonNull::new(mem as *mut SemaphoreSlot).expect("mmap returned NULL"),
+                len: count,
+            })
+        }
+    }
+
+    /// All the semaphores.
+    pub fn as_slice(&self) -> &[SemaphoreSlot] {
+        unsafe { slice::from_raw_parts(self.slots.as_ptr(), self.len) }
+    }
+
+    /// The semaphore at the given index.
+    ///
+    /// # Panics
+    ///
+    /// If the index is out of bounds.
+    pub fn get(&self, idx: usize) -> &SemaphoreSlot {
+        &self.as_slice()[idx]
+    }
+
+    /// The semaphore at the given index, or `None` if out of bounds.
+    pub fn try_get(&self, idx: usize) -> Option<&SemaphoreSlot> {
+        self.as_slice().get(idx)
+    }
+
+    /// Number of semaphores in the array.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Is the array empty?
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Index<usize> for SemaphoreArray {
+    type Output = SemaphoreSlot;
+    fn index(&self, idx: usize) -> &SemaphoreSlot {
+        self.get(idx)
+    }
+}
+
+impl Drop for SemaphoreArray {
+    fn drop(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        unsafe {
+            for slot in self.as_slice() {
+                // Best effort, like with Semaphore
+                libc::sem_destroy(slot.as_ptr());
+            }
+            let size = self.len * mem::size_of::<sem_t>();
+            libc::munmap(self.slots.as_ptr() as *mut _, size);
+        }
+    }
+}
+
+unsafe impl Send for SemaphoreArray {}
+unsafe impl Sync for SemaphoreArray {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+
+    const WORKERS: usize = 4;
+
+    #[test]
+    fn workers() {
+        // Slot 0 is for the replies, the rest one for each worker
+        let array = SemaphoreArray::anonymous_shared(WORKERS + 1, 0).unwrap();
+        assert_eq!(WORKERS + 1, array.len());
+        thread::scope(|s| {
+            for i in 1..=WORKERS {
+                let array = &array;
+                s.spawn(move || {
+                    for _ in 0..10 {
+                        array[i].wait();
+                        array[0].post().unwrap();
+                    }
+                });
+            }
+            for _ in 0..10 {
+                for i in 1..=WORKERS {
+                    array.get(i).post().unwrap();
+                }
+                for _ in 1..=WORKERS {
+                    array[0].wait();
+                }
+            }
+        });
+        assert!(array.as_slice().iter().all(|slot| slot.value() == 0));
+    }
+
+    #[test]
+    fn bounds() {
+        let array = SemaphoreArray::anonymous_shared(2, 1).unwrap();
+        assert!(array.try_get(1).is_some());
+        assert!(array.try_get(2).is_none());
+        assert!(SemaphoreArray::anonymous_shared(0, 1).unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_bounds() {
+        SemaphoreArray::anonymous_shared(2, 1).unwrap().get(2);
+    }
+
+    #[test]
+    fn fork_shared() {
+        let array = SemaphoreArray::anonymous_shared(3, 0).unwrap();
+        let child = fork(|| {
+            for (i, slot) in array.as_slice().iter().enumerate() {
+                for _ in 0..=i {
+                    slot.post().unwrap();
+                }
+            }
+        });
+        child.join();
+        assert_eq!(vec![1, 2, 3], array.as_slice().iter().map(|s| s.value()).collect::<Vec<_>>());
+    }
+}
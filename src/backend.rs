@@ -0,0 +1,102 @@
+//! The few calls all the semaphore operations are built of.
+//!
+//! Mostly, these are the libc `sem_*` functions on a `sem_t`. But on Darwin with the `dispatch`
+//! feature, a private [`Semaphore`][::Semaphore] is a GCD semaphore that emulates them. The
+//! operations of [`SemaphoreSlot`] are written against the calls here, so they work the same
+//! on either. The errors are errno values, as the libc would leave them.
+
+use std::io::Error;
+
+use libc::{self, c_int, clockid_t, sem_t, timespec};
+
+use clock::{self, ClockWait};
+#[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+use dispatch::DispatchSemaphore;
+use SemaphoreSlot;
+
+/// The semaphore the operations run on.
+#[derive(Copy, Clone)]
+pub(crate) enum Backend<'a> {
+    Posix(&'a SemaphoreSlot),
+    #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+    Dispatch(&'a DispatchSemaphore),
+}
+
+/// Turns the return value of a libc call into the errno it left.
+fn errno(result: c_int) -> Result<(), c_int> {
+    match result {
+        0 => Ok(()),
+        _ => Err(Error::last_os_error().raw_os_error().unwrap_or(0)),
+    }
+}
+
+impl<'a> Backend<'a> {
+    /// Identifies the semaphore, eg. for counting its waiters.
+    pub(crate) fn address(self) -> *const sem_t {
+        match self {
+            Backend::Posix(slot) => slot.as_ptr(),
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(sem) => sem as *const DispatchSemaphore as *const sem_t,
+        }
+    }
+
+    pub(crate) fn sem_wait(self) -> Result<(), c_int> {
+        match self {
+            Backend::Posix(slot) => errno(unsafe { libc::sem_wait(slot.as_ptr()) }),
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(sem) => sem.sem_wait(),
+        }
+    }
+
+    pub(crate) fn sem_trywait(self) -> Result<(), c_int> {
+        match self {
+            Backend::Posix(slot) => errno(unsafe { libc::sem_trywait(slot.as_ptr()) }),
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(sem) => sem.sem_trywait(),
+        }
+    }
+
+    /// Waits until the absolute real time, like `sem_timedwait`.
+    pub(crate) fn sem_timedwait(self, abstime: &timespec) -> Result<(), c_int> {
+        match self {
+            Backend::Posix(slot) => errno(unsafe { clock::sem_timedwait(slot.as_ptr(), abstime) }),
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(sem) => sem.sem_timedwait(abstime),
+        }
+    }
+
+    /// Waits until the absolute time of the clock, with the `sem_clockwait` of the libc.
+    ///
+    /// GCD can wait only for the real time, the other clocks are refused with `EINVAL`, like
+    /// `sem_clockwait` does with the clocks it doesn't support.
+    pub(crate) fn sem_clockwait(self, clockwait: ClockWait, clock: clockid_t, abstime: &timespec)
+        -> Result<(), c_int>
+    {
+        match self {
+            Backend::Posix(slot) => errno(unsafe { clockwait(slot.as_ptr(), clock, abstime) }),
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(sem) if clock == libc::CLOCK_REALTIME => sem.sem_timedwait(abstime),
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(_) => Err(libc::EINVAL),
+        }
+    }
+
+    pub(crate) fn sem_post(self) -> Result<(), c_int> {
+        match self {
+            Backend::Posix(slot) => errno(unsafe { libc::sem_post(slot.as_ptr()) }),
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(sem) => sem.sem_post(),
+        }
+    }
+
+    pub(crate) fn sem_getvalue(self) -> Result<c_int, c_int> {
+        match self {
+            Backend::Posix(slot) => {
+                let mut val = 0;
+                errno(unsafe { libc::sem_getvalue(slot.as_ptr(), &mut val) }).map(|()| val)
+            },
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Backend::Dispatch(sem) => Ok(sem.sem_getvalue()),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+//! A semaphore that is either signalled or not.
+
+use std::error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use {NoToken, Semaphore, WaitError};
+
+/// The [`BinarySemaphore`] was already signalled.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct AlreadySignalled;
+
+impl Display for AlreadySignalled {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Semaphore already signalled")
+    }
+}
+
+impl error::Error for AlreadySignalled {}
+
+impl From<AlreadySignalled> for Error {
+    fn from(_: AlreadySignalled) -> Error {
+        Error::other(AlreadySignalled)
+    }
+}
+
+/// A semaphore holding at most one token.
+///
+/// A plain semaphore used as a signal silently turns into a counter if signalled twice. This one
+/// refuses the second [`signal`][BinarySemaphore::signal] instead. The refusal doesn't change
+/// anything, so ignoring the error turns repeated signals into no-ops.
+///
+/// Signals are serialized by a lock inside the value, so concurrent ones can't sneak in a second
+/// token. The check is against the current value, so a signal racing with a wait may succeed or
+/// be refused, depending on which one comes first.
+pub struct BinarySemaphore {
+    sem: Semaphore,
+    signal_lock: Mutex<()>,
+}
+
+impl BinarySemaphore {
+    fn new(value: bool) -> Result<Self, Error> {
+        Ok(BinarySemaphore {
+            sem: Semaphore::anonymous(u32::from(value))?,
+            signal_lock: Mutex::new(()),
+        })
+    }
+
+    /// Creates the semaphore with its token.
+    pub fn new_signalled() -> Result<Self, Error> {
+        Self::new(true)
+    }
+
+    /// Creates the semaphore without a token.
+    pub fn new_unsignalled() -> Result<Self, Error> {
+        Self::new(false)
+    }
+
+    /// Puts the token in, unless it's already there.
+    pub fn signal(&self) -> Result<(), AlreadySignalled> {
+        // Only this posts and waits only take the value down, so it can't become 1 under our
+        // hands.
+        let _lock = self.signal_lock.lock().unwrap_or_else(|e| e.into_inner());
+        if self.is_signalled() {
+            return Err(AlreadySignalled);
+        }
+        self.sem.post().expect("Overflow of a semaphore with no token");
+        Ok(())
+    }
+
+    /// Is the token there right now?
+    pub fn is_signalled(&self) -> bool {
+        self.sem.value() > 0
+    }
+
+    /// Waits for the token and takes it.
+    pub fn wait(&self) {
+        self.sem.wait()
+    }
+
+    /// Takes the token if it's there.
+    pub fn trywait(&self) -> Result<(), NoToken> {
+        self.sem.trywait()
+    }
+
+    /// Waits for the token until the given time.
+    pub fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+        self.sem.timedwait(until)
+    }
+
+    /// Waits for the token, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.sem.wait_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn at_most_one() {
+        let sem = BinarySemaphore::new_unsignalled().unwrap();
+        assert!(!sem.is_signalled());
+        sem.signal().unwrap();
+        assert_eq!(Err(AlreadySignalled), sem.signal());
+        assert!(sem.is_signalled());
+        sem.trywait().unwrap();
+        sem.trywait().unwrap_err();
+        let timeout = Duration::from_millis(1);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(timeout));
+
+        let sem = BinarySemaphore::new_signalled().unwrap();
+        assert_eq!(Err(AlreadySignalled), sem.signal());
+        sem.wait();
+        assert!(!sem.is_signalled());
+    }
+
+    #[test]
+    fn concurrent_signals() {
+        let sem = BinarySemaphore::new_unsignalled().unwrap();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let _ = sem.signal();
+                        assert!(sem.sem.value() <= 1);
+                    }
+                });
+            }
+        });
+        sem.trywait().unwrap();
+        sem.trywait().unwrap_err();
+    }
+}
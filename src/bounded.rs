@@ -0,0 +1,185 @@
+//! A semaphore refusing to count over its maximum.
+
+use std::error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+
+use many::value_max;
+use {NoToken, Semaphore, WaitError};
+
+/// A post to a [`BoundedSemaphore`] would take it over its maximum.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct BoundExceeded;
+
+impl Display for BoundExceeded {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Semaphore already at its maximum")
+    }
+}
+
+impl error::Error for BoundExceeded {}
+
+impl From<BoundExceeded> for Error {
+    fn from(_: BoundExceeded) -> Error {
+        Error::other(BoundExceeded)
+    }
+}
+
+/// A semaphore with a maximum value.
+///
+/// Releasing something twice with a plain semaphore raises its value over the intended capacity
+/// and lets too many through later on. This one refuses the [`post`][BoundedSemaphore::post] that
+/// would take it over the maximum instead, so the bug shows up right where it happens.
+///
+/// The value is tracked by a counter next to the semaphore. A post reserves its place in the
+/// counter before posting and a wait gives it back only after taking the token, so the
+/// semaphore never holds more than the maximum, even with concurrent posts and waits. A post may
+/// still be refused while a concurrent wait is finishing.
+pub struct BoundedSemaphore {
+    sem: Semaphore,
+    value: AtomicU32,
+    max: u32,
+}
+
+impl BoundedSemaphore {
+    /// Creates the semaphore with `initial` tokens, allowing at most `max` of them.
+    ///
+    /// Fails if the `max` is over what the system supports.
+    ///
+    /// # Panics
+    ///
+    /// If `initial` is larger than `max`.
+    pub fn new(initial: u32, max: u32) -> Result<Self, Error> {
+        assert!(initial <= max, "Initial value {} over the maximum {}", initial, max);
+        if max > value_max() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Maximum too large for a semaphore"));
+        }
+        Ok(BoundedSemaphore {
+            sem: Semaphore::anonymous(initial)?,
+            value: AtomicU32::new(initial),
+            max,
+        })
+    }
+
+    /// Returns a token, unless it would exceed the maximum.
+    ///
+    /// Nothing is posted if this fails.
+    pub fn post(&self) -> Result<(), BoundExceeded> {
+        let max = self.max;
+        self.value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                if v < max {
+                    Some(v + 1)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| BoundExceeded)?;
+        self.sem.post().expect("Semaphore over its checked maximum");
+        Ok(())
+    }
+
+    fn taken(&self) {
+        self.value.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Waits for a token and takes it.
+    pub fn wait(&self) {
+        self.sem.wait();
+        self.taken();
+    }
+
+    /// Takes a token if one is available.
+    pub fn trywait(&self) -> Result<(), NoToken> {
+        self.sem.trywait()?;
+        self.taken();
+        Ok(())
+    }
+
+    /// Waits for a token until the given time.
+    pub fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+        self.sem.timedwait(until)?;
+        self.taken();
+        Ok(())
+    }
+
+    /// Waits for a token, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.sem.wait_timeout(timeout)?;
+        self.taken();
+        Ok(())
+    }
+
+    /// The number of available tokens.
+    ///
+    /// Tokens in the middle of being posted or taken count as available.
+    pub fn available(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// The number of tokens taken out and not returned yet.
+    pub fn in_use(&self) -> u32 {
+        self.max - self.available()
+    }
+
+    /// The maximum number of tokens.
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn refuses_over_max() {
+        let sem = BoundedSemaphore::new(1, 2).unwrap();
+        assert_eq!(1, sem.available());
+        assert_eq!(1, sem.in_use());
+        sem.post().unwrap();
+        assert_eq!(Err(BoundExceeded), sem.post());
+        assert_eq!(2, sem.sem.value());
+        assert_eq!(0, sem.in_use());
+        sem.wait();
+        sem.trywait().unwrap();
+        sem.trywait().unwrap_err();
+        let timeout = Duration::from_millis(1);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(timeout));
+        assert_eq!(2, sem.in_use());
+    }
+
+    #[test]
+    #[should_panic]
+    fn initial_over_max() {
+        let _ = BoundedSemaphore::new(3, 2);
+    }
+
+    #[test]
+    fn hammer() {
+        const MAX: u32 = 3;
+        let sem = BoundedSemaphore::new(MAX, MAX).unwrap();
+        thread::scope(|s| {
+            for i in 0..6 {
+                let sem = &sem;
+                s.spawn(move || {
+                    for j in 0..10_000 {
+                        // Mismatched on purpose, some threads post more than they take
+                        if (i + j) % 3 == 0 {
+                            let _ = sem.trywait();
+                        } else {
+                            let _ = sem.post();
+                        }
+                        assert!(sem.available() <= MAX);
+                        assert!(sem.sem.value() <= MAX as _);
+                    }
+                });
+            }
+        });
+        assert_eq!(sem.available(), sem.sem.value() as u32);
+    }
+}
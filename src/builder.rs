@@ -0,0 +1,145 @@
+//! Creating anonymous semaphores with explicit sharing.
+
+use std::io::{Error, ErrorKind};
+
+use {capabilities, Semaphore};
+
+/// Options for creating an anonymous [`Semaphore`], with the process sharing spelled out.
+///
+/// The resulting semaphore can be checked by its [`kind`][Semaphore::kind].
+#[derive(Clone, Debug, Default)]
+pub struct Builder {
+    process_shared: bool,
+    value: u32,
+    track_value: bool,
+}
+
+impl Builder {
+    /// Creates the default options, a private semaphore with no tokens.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether child processes forked afterwards share the semaphore.
+    ///
+    /// A process-shared semaphore gets its own shared mapping, see
+    /// [`anonymous_shared`][Semaphore::anonymous_shared].
+    pub fn process_shared(&mut self, process_shared: bool) -> &mut Self {
+        self.process_shared = process_shared;
+        self
+    }
+
+    /// The initial value.
+    ///
+    /// Defaults to 0.
+    pub fn value(&mut self, value: u32) -> &mut Self {
+        self.value = value;
+        self
+    }
+
+    /// Whether to count the value in this process, for [`tracked_value`][Semaphore::tracked_value].
+    ///
+    /// Meant for platforms where the OS can't tell the value. Counting a process-shared semaphore
+    /// is pointless, as the posts of the other processes can't be seen.
+    ///
+    /// Defaults to false.
+    pub fn track_value(&mut self, track_value: bool) -> &mut Self {
+        self.track_value = track_value;
+        self
+    }
+
+    /// Creates the semaphore.
+    ///
+    /// Fails with [`ErrorKind::Unsupported`] for a process-shared semaphore on platforms without
+    /// them, and with [`ErrorKind::InvalidInput`] if the value is over
+    /// [`max_value`][Semaphore::max_value].
+    pub fn build(&self) -> Result<Semaphore, Error> {
+        if !self.process_shared {
+            let mut sem = Semaphore::anonymous(self.value)?;
+            if self.track_value {
+                sem.track_value(self.value);
+            }
+            return Ok(sem);
+        }
+        if !capabilities().process_shared {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Process-shared semaphores not supported on this platform",
+            ));
+        }
+        Semaphore::anonymous_shared(self.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::MaybeUninit;
+
+    use super::*;
+    use named::NamedSemaphore;
+    use shadow;
+    use test_util::unique_name;
+    use {Kind, ShmSemaphore};
+
+    #[test]
+    fn built() {
+        let private = Builder::new().value(2).build().unwrap();
+        assert_eq!(Kind::Anonymous, private.kind());
+        assert_eq!(Some(false), private.kind().is_process_shared());
+        assert_eq!(2, private.value());
+        let shared = Builder::new().process_shared(true).build().unwrap();
+        assert_eq!(Kind::AnonymousShared, shared.kind());
+        assert_eq!(Some(true), shared.kind().is_process_shared());
+        let e = Builder::new().value(Semaphore::max_value() + 1).build().map(|_| ()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn tracked() {
+        let sem = Builder::new().value(3).track_value(true).build().unwrap();
+        sem.wait();
+        sem.trywait().unwrap();
+        sem.post().unwrap();
+        drop(sem.access());
+        sem.acquire_many(2);
+        sem.post_many(3).unwrap();
+        sem.drain_up_to(1);
+        {
+            let _guard = sem.try_access().unwrap();
+            assert_eq!(1, sem.tracked_value().unwrap());
+        }
+        sem.set_value(4).unwrap();
+        assert_eq!(4, sem.tracked_value().unwrap());
+        assert_eq!(sem.value() as u32, sem.tracked_value().unwrap());
+
+        let untracked = Builder::new().build().unwrap();
+        if !shadow::GETVALUE_BROKEN {
+            let e = untracked.tracked_value().unwrap_err();
+            assert_eq!(ErrorKind::Unsupported, e.kind());
+        }
+        let shared = Builder::new().process_shared(true).track_value(true).build().unwrap();
+        let e = shared.tracked_value().unwrap_err();
+        assert_eq!(ErrorKind::Unsupported, e.kind());
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(Kind::Anonymous, Semaphore::anonymous(0).unwrap().kind());
+        assert_eq!(Kind::AnonymousShared, Semaphore::anonymous_shared(0).unwrap().kind());
+        assert_eq!(Kind::Named, NamedSemaphore::temporary(0).unwrap().kind());
+        let name = unique_name("kind");
+        let shm = ShmSemaphore::create(name.as_str(), 0o600, 0).unwrap();
+        ShmSemaphore::unlink(name.as_str()).unwrap();
+        assert_eq!(Kind::Mapped, shm.kind());
+        let mut slot = MaybeUninit::uninit();
+        let placed = Semaphore::init_in(&mut slot, true, 0).unwrap();
+        assert_eq!(Kind::Placed { process_shared: true }, placed.kind());
+        drop(placed);
+        let placed = Semaphore::init_in(&mut slot, false, 0).unwrap();
+        assert_eq!(Some(false), placed.kind().is_process_shared());
+        let raw = Semaphore::anonymous_posix(0).unwrap().into_raw();
+        let adopted = unsafe { Semaphore::from_raw(raw, true) };
+        assert_eq!(Kind::Foreign, adopted.kind());
+        assert_eq!(None, adopted.kind().is_process_shared());
+    }
+}
@@ -0,0 +1,300 @@
+//! A semaphore with per-thread caches of tokens.
+
+use std::cell::RefCell;
+use std::io::Error;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use {NoToken, Overflow, PartialPost, Semaphore};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct Inner {
+    id: usize,
+    sem: Semaphore,
+    batch: u32,
+    // The caches of all the threads, for draining them
+    caches: Mutex<Vec<Weak<AtomicU32>>>,
+}
+
+impl Inner {
+    fn caches(&self) -> MutexGuard<'_, Vec<Weak<AtomicU32>>> {
+        self.caches.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Moves all the tokens from the cache to the semaphore.
+    fn flush(&self, cache: &AtomicU32) -> Result<(), Overflow> {
+        let tokens = cache.swap(0, Ordering::Acquire);
+        self.sem.post_many(tokens).map_err(|PartialPost { posted }| {
+            // Better keep them than lose them
+            cache.fetch_add(tokens - posted, Ordering::Release);
+            Overflow::new()
+        })
+    }
+}
+
+/// A cache of one thread for one semaphore.
+struct Local {
+    inner: Weak<Inner>,
+    id: usize,
+    tokens: Arc<AtomicU32>,
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        // The thread is going away, don't leave the tokens stranded with it
+        if let Some(inner) = self.inner.upgrade() {
+            let _ = inner.flush(&self.tokens);
+        }
+    }
+}
+
+thread_local! {
+    static CACHES: RefCell<Vec<Local>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A semaphore where each thread keeps a few tokens around for itself.
+///
+/// A thread that needs a token takes it from its own cache, which is only an uncontended atomic
+/// operation. When the cache is empty, it goes to the real semaphore and takes up to `batch`
+/// tokens at once. Released tokens go back to the cache and only when there are more than
+/// `batch` of them, they are all returned to the semaphore. A thread that terminates returns its
+/// whole cache.
+///
+/// The price is that tokens sitting in one thread's cache are not available to the others. A
+/// thread may block while another one has idle tokens cached. Call
+/// [`drain_caches`][CachedSemaphore::drain_caches] (for example periodically, or when waiting
+/// for too long) to return them all to the semaphore. This makes sense only for semaphores
+/// with many tokens compared to the number of threads.
+pub struct CachedSemaphore {
+    inner: Arc<Inner>,
+}
+
+impl CachedSemaphore {
+    /// Creates the semaphore with `value` tokens, with the caches holding up to `batch` each.
+    ///
+    /// # Panics
+    ///
+    /// If the `batch` is zero.
+    pub fn new(value: u32, batch: u32) -> Result<Self, Error> {
+        assert!(batch > 0, "Caches need room for at least one token");
+        Ok(CachedSemaphore {
+            inner: Arc::new(Inner {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                sem: Semaphore::anonymous(value)?,
+                batch,
+                caches: Mutex::new(Vec::new()),
+            }),
+        })
+    }
+
+    /// Runs the closure with the cache of the current thread.
+    fn with_local<R, F: FnOnce(&AtomicU32) -> R>(&self, f: F) -> R {
+        CACHES.with(|caches| {
+            let mut caches = caches.borrow_mut();
+            let pos = match caches.iter().position(|local| local.id == self.inner.id) {
+                Some(pos) => pos,
+                None => {
+                    // Forget the caches of semaphores that are gone
+                    caches.retain(|local| local.inner.strong_count() > 0);
+                    let tokens = Arc::new(AtomicU32::new(0));
+                    self.inner.caches().push(Arc::downgrade(&tokens));
+                    caches.push(Local {
+                        inner: Arc::downgrade(&self.inner),
+                        id: self.inner.id,
+                        tokens,
+                    });
+                    caches.len() - 1
+                },
+            };
+            f(&caches[pos].tokens)
+        })
+    }
+
+    fn from_cache(cache: &AtomicU32) -> bool {
+        cache
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |t| t.checked_sub(1))
+            .is_ok()
+    }
+
+    /// Takes more tokens into the cache, after getting one for the caller.
+    fn refill(&self, cache: &AtomicU32) {
+        let mut got = 0;
+        while got + 1 < self.inner.batch && self.inner.sem.trywait().is_ok() {
+            got += 1;
+        }
+        cache.fetch_add(got, Ordering::Release);
+    }
+
+    /// Takes a token, from the cache if possible, waiting for the semaphore if not.
+    pub fn acquire(&self) {
+        self.with_local(|cache| {
+            if !Self::from_cache(cache) {
+                self.inner.sem.wait();
+                self.refill(cache);
+            }
+        })
+    }
+
+    /// Takes a token from the cache or the semaphore, if there's one.
+    ///
+    /// This doesn't look into the caches of other threads.
+    pub fn try_acquire(&self) -> Result<(), NoToken> {
+        self.with_local(|cache| {
+            if !Self::from_cache(cache) {
+                self.inner.sem.trywait()?;
+                self.refill(cache);
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns a token to the cache, moving the cache to the semaphore if it's full.
+    pub fn release(&self) -> Result<(), Overflow> {
+        self.with_local(|cache| {
+            if cache.fetch_add(1, Ordering::Release) + 1 > self.inner.batch {
+                self.inner.flush(cache)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Returns the tokens cached by the current thread to the semaphore.
+    pub fn flush(&self) -> Result<(), Overflow> {
+        self.with_local(|cache| self.inner.flush(cache))
+    }
+
+    /// Returns the tokens cached by all the threads to the semaphore.
+    pub fn drain_caches(&self) -> Result<(), Overflow> {
+        let mut caches = self.inner.caches();
+        // Threads that terminated already returned theirs
+        caches.retain(|cache| cache.strong_count() > 0);
+        let mut result = Ok(());
+        for cache in caches.iter().filter_map(Weak::upgrade) {
+            result = result.and(self.inner.flush(&cache));
+        }
+        result
+    }
+
+    /// The number of tokens in the semaphore itself, not counting the caches.
+    pub fn value(&self) -> u32 {
+        self.inner.sem.value().max(0) as u32
+    }
+
+    /// The number of tokens sitting in the caches of all the threads.
+    pub fn cached(&self) -> u32 {
+        self.inner
+            .caches()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|cache| cache.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const TOKENS: u32 = 64;
+    const BATCH: u32 = 8;
+
+    #[test]
+    fn batches() {
+        let sem = CachedSemaphore::new(TOKENS, BATCH).unwrap();
+        sem.acquire();
+        assert_eq!(BATCH - 1, sem.cached());
+        assert_eq!(TOKENS - BATCH, sem.value());
+        for _ in 1..BATCH {
+            sem.try_acquire().unwrap();
+        }
+        assert_eq!(0, sem.cached());
+        // Empty cache, another batch
+        sem.acquire();
+        assert_eq!(BATCH - 1, sem.cached());
+        sem.release().unwrap();
+        assert_eq!(BATCH, sem.cached());
+        // Over the limit, everything goes back
+        sem.release().unwrap();
+        assert_eq!(0, sem.cached());
+        // Still holding the rest of the first batch
+        assert_eq!(TOKENS - BATCH + 1, sem.value());
+        for _ in 1..BATCH {
+            sem.release().unwrap();
+        }
+        sem.flush().unwrap();
+        assert_eq!(0, sem.cached());
+        assert_eq!(TOKENS, sem.value());
+    }
+
+    #[test]
+    fn thread_exit_returns_cache() {
+        let sem = CachedSemaphore::new(TOKENS, BATCH).unwrap();
+        thread::scope(|s| {
+            // Joining explicitly waits for the thread locals to be destroyed too
+            s.spawn(|| {
+                sem.acquire();
+                sem.release().unwrap();
+                assert_eq!(BATCH, sem.cached());
+            })
+            .join()
+            .unwrap();
+        });
+        assert_eq!(0, sem.cached());
+        assert_eq!(TOKENS, sem.value());
+    }
+
+    #[test]
+    fn drain() {
+        let sem = CachedSemaphore::new(BATCH, BATCH).unwrap();
+        let hoarded = Semaphore::anonymous(0).unwrap();
+        let drained = Semaphore::anonymous(0).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                sem.acquire();
+                sem.release().unwrap();
+                hoarded.post().unwrap();
+                // Keep the thread alive, with the cache in place
+                drained.wait();
+            });
+            hoarded.wait();
+            // Everything is in the other thread's cache
+            sem.try_acquire().unwrap_err();
+            sem.drain_caches().unwrap();
+            sem.try_acquire().unwrap();
+            drained.post().unwrap();
+        });
+    }
+
+    #[test]
+    fn conserves_tokens() {
+        let sem = CachedSemaphore::new(TOKENS, BATCH).unwrap();
+        for _ in 0..10 {
+            thread::scope(|s| {
+                let workers = (0..8).map(|i| {
+                    let sem = &sem;
+                    s.spawn(move || {
+                        for j in 0..1000 {
+                            // Hold a few at a time, in varying amounts
+                            let held = (i + j) % 4 + 1;
+                            for _ in 0..held {
+                                sem.acquire();
+                            }
+                            for _ in 0..held {
+                                sem.release().unwrap();
+                            }
+                        }
+                    })
+                });
+                for worker in workers.collect::<Vec<_>>() {
+                    worker.join().unwrap();
+                }
+            });
+        }
+        sem.flush().unwrap();
+        assert_eq!(0, sem.cached());
+        assert_eq!(TOKENS, sem.value());
+    }
+}
@@ -0,0 +1,94 @@
+//! Finding out what the platform supports, at runtime.
+
+use std::io::Error;
+use std::mem::MaybeUninit;
+use std::sync::OnceLock;
+
+use libc;
+
+use clock::{self, Clock, SystemClock};
+use {NamedSemaphore, Semaphore};
+
+/// What the platform (the libc and the kernel) supports.
+///
+/// Obtained by [`capabilities`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct Capabilities {
+    /// Waiting with a timeout (`sem_timedwait`) works.
+    pub timed_wait: bool,
+    /// Waiting against the monotonic clock (`sem_clockwait`) works.
+    ///
+    /// Without it, [`wait_deadline`][Semaphore::wait_deadline] emulates it.
+    pub monotonic_wait: bool,
+    /// Process-shared anonymous semaphores can be created.
+    pub process_shared: bool,
+    /// The current value of a semaphore can be read (`sem_getvalue`).
+    pub getvalue: bool,
+    /// Named semaphores can be created.
+    pub named: bool,
+}
+
+/// Probes for the capabilities of the platform.
+///
+/// The probing (creating scratch semaphores, looking up symbols) is done on the first call only,
+/// further calls return the cached result.
+pub fn capabilities() -> Capabilities {
+    static CAPABILITIES: OnceLock<Capabilities> = OnceLock::new();
+    *CAPABILITIES.get_or_init(probe)
+}
+
+fn probe() -> Capabilities {
+    // Not init_in, that one doesn't work everywhere anonymous does
+    let (timed_wait, getvalue) = match Semaphore::anonymous_posix(0) {
+        Ok(sem) => unsafe {
+            // A deadline in the past, so this must time out right away if supported at all
+            let past = clock::timespec(0, 0);
+            let timed_wait = clock::sem_timedwait(sem.as_raw(), &past) == -1
+                && Error::last_os_error().raw_os_error() == Some(libc::ETIMEDOUT);
+            let mut value = 0;
+            let getvalue = libc::sem_getvalue(sem.as_raw(), &mut value) == 0;
+            (timed_wait, getvalue)
+        },
+        Err(_) => (false, false),
+    };
+    let monotonic_wait = clock::sem_clockwait().is_some_and(|clockwait| unsafe {
+        let mut scratch = MaybeUninit::uninit();
+        match Semaphore::init_in(&mut scratch, false, 1) {
+            Ok(sem) => {
+                let deadline = SystemClock.now_monotonic();
+                clockwait(sem.as_raw(), libc::CLOCK_MONOTONIC, &deadline) == 0
+            },
+            Err(_) => false,
+        }
+    });
+    Capabilities {
+        timed_wait,
+        monotonic_wait,
+        process_shared: Semaphore::anonymous_shared(0).is_ok(),
+        getvalue,
+        named: NamedSemaphore::temporary(0).is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn linux() {
+        let caps = capabilities();
+        assert!(caps.timed_wait);
+        assert!(caps.monotonic_wait);
+        assert!(caps.process_shared);
+        assert!(caps.getvalue);
+        assert!(caps.named);
+    }
+
+    #[test]
+    fn cached() {
+        assert_eq!(capabilities(), capabilities());
+        assert_eq!(capabilities(), probe());
+    }
+}
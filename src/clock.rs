@@ -0,0 +1,366 @@
+//! Clocks and absolute deadlines for the timed waits.
+
+use std::convert::TryFrom;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::Mutex;
+#[cfg(target_vendor = "apple")]
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(target_vendor = "apple")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use libc::{self, c_int, clockid_t, sem_t, time_t, timespec};
+
+/// How long to wait at once when emulating a monotonic wait with the realtime clock.
+pub const FALLBACK_SLICE: Duration = Duration::from_millis(10);
+
+const NSEC_PER_SEC: u32 = 1_000_000_000;
+
+/// A clock to measure deadlines of timed waits against.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ClockId {
+    /// The system time (`CLOCK_REALTIME`), subject to changes by the administrator or NTP.
+    Realtime,
+    /// The monotonic clock (`CLOCK_MONOTONIC`), which doesn't jump.
+    Monotonic,
+    /// Like the monotonic clock, but also ticking while the system is suspended
+    /// (`CLOCK_BOOTTIME`).
+    ///
+    /// Usually not supported for waiting on semaphores.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    Boottime,
+}
+
+/// A source of the current time the deadlines of the waits are computed from.
+///
+/// The default is [`SystemClock`]. Tests may use a `MockClock` instead to check the deadline
+/// arithmetic without waiting for the real time to pass.
+pub trait Clock {
+    /// The current time of `CLOCK_REALTIME`.
+    fn now_realtime(&self) -> timespec;
+
+    /// The current time of `CLOCK_MONOTONIC`.
+    fn now_monotonic(&self) -> timespec;
+
+    /// The current time as an `Instant`, to measure the deadlines given as one against.
+    fn now(&self) -> Instant;
+
+    /// A wait timed out after waiting for the given time by this clock.
+    ///
+    /// The default does nothing, the time has already passed. `MockClock` moves forward by it.
+    fn timed_out(&self, _waited: Duration) {}
+
+    /// The realtime clock after the timeout, saturating instead of overflowing.
+    fn realtime_after(&self, timeout: Duration) -> timespec {
+        add(self.now_realtime(), timeout)
+    }
+
+    /// The monotonic clock after the timeout, saturating instead of overflowing.
+    fn monotonic_after(&self, timeout: Duration) -> timespec {
+        add(self.now_monotonic(), timeout)
+    }
+}
+
+/// The real clocks of the system.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_realtime(&self) -> timespec {
+        now(libc::CLOCK_REALTIME)
+    }
+
+    fn now_monotonic(&self) -> timespec {
+        now(libc::CLOCK_MONOTONIC)
+    }
+
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that moves only when told to or when a wait on it times out.
+///
+/// Both the clocks start at zero, which lies in the past of the real clocks, so the waits
+/// computed from it time out right away and move it forward by their timeout instead. Its
+/// `Instant`s start at its creation and follow the monotonic clock. Available with the
+/// `test-util` feature.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug)]
+pub struct MockClock {
+    origin: Instant,
+    // realtime, monotonic
+    time: Mutex<(Duration, Duration)>,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl MockClock {
+    /// Creates the clock, with both clocks at zero.
+    pub fn new() -> Self {
+        MockClock {
+            origin: Instant::now(),
+            time: Mutex::new((Duration::from_secs(0), Duration::from_secs(0))),
+        }
+    }
+
+    /// Sets the clocks to the given times.
+    pub fn set(&self, realtime: Duration, monotonic: Duration) {
+        *self.time.lock().unwrap() = (realtime, monotonic);
+    }
+
+    /// Moves both the clocks forward.
+    pub fn advance(&self, by: Duration) {
+        let mut time = self.time.lock().unwrap();
+        time.0 = time.0.saturating_add(by);
+        time.1 = time.1.saturating_add(by);
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for MockClock {
+    fn now_realtime(&self) -> timespec {
+        from_epoch(self.time.lock().unwrap().0)
+    }
+
+    fn now_monotonic(&self) -> timespec {
+        from_epoch(self.time.lock().unwrap().1)
+    }
+
+    /// # Panics
+    ///
+    /// If the monotonic clock was moved too far to fit into an `Instant`.
+    fn now(&self) -> Instant {
+        self.origin + self.time.lock().unwrap().1
+    }
+
+    fn timed_out(&self, waited: Duration) {
+        self.advance(waited);
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockId {
+    /// The libc identifier of the clock.
+    pub fn raw(self) -> clockid_t {
+        match self {
+            ClockId::Realtime => libc::CLOCK_REALTIME,
+            ClockId::Monotonic => libc::CLOCK_MONOTONIC,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClockId::Boottime => libc::CLOCK_BOOTTIME,
+        }
+    }
+
+    /// The current time of the clock.
+    pub fn now(self) -> timespec {
+        now(self.raw())
+    }
+}
+
+pub type ClockWait = unsafe extern "C" fn(*mut sem_t, clockid_t, *const timespec) -> c_int;
+
+const NOT_LOOKED_UP: usize = 1;
+static CLOCKWAIT: AtomicUsize = AtomicUsize::new(NOT_LOOKED_UP);
+
+/// The `sem_clockwait` function, if the libc we run with has one.
+///
+/// It is fairly new (glibc 2.30, musl 1.2.2), so it is looked up at runtime instead of linking
+/// to it.
+pub fn sem_clockwait() -> Option<ClockWait> {
+    let mut addr = CLOCKWAIT.load(Ordering::Relaxed);
+    if addr == NOT_LOOKED_UP {
+        addr = unsafe {
+            libc::dlsym(libc::RTLD_DEFAULT, b"sem_clockwait\0".as_ptr() as *const _) as usize
+        };
+        CLOCKWAIT.store(addr, Ordering::Relaxed);
+    }
+    if addr == 0 {
+        None
+    } else {
+        Some(unsafe { mem::transmute::<usize, ClockWait>(addr) })
+    }
+}
+
+/// The shortest and longest sleep between the attempts of the emulated `sem_timedwait`.
+#[cfg(target_vendor = "apple")]
+const POLL_MIN: Duration = Duration::from_micros(50);
+#[cfg(target_vendor = "apple")]
+const POLL_MAX: Duration = Duration::from_millis(5);
+
+/// Waits for a token until the absolute real time, like `sem_timedwait`.
+#[cfg(not(target_vendor = "apple"))]
+pub unsafe fn sem_timedwait(sem: *mut sem_t, abstime: *const timespec) -> c_int {
+    libc::sem_timedwait(sem, abstime)
+}
+
+/// Emulates `sem_timedwait`, which Darwin doesn't have.
+///
+/// Polls with `sem_trywait`, sleeping in between for twice as long each time, from
+/// [`POLL_MIN`] up to [`POLL_MAX`]. A token posted meanwhile is noticed that late and the
+/// deadline is overshot by at most that much (and the scheduling latency), so the granularity
+/// of the timed waits there is about 5 ms. Signals don't interrupt the sleeps.
+#[cfg(target_vendor = "apple")]
+pub unsafe fn sem_timedwait(sem: *mut sem_t, abstime: *const timespec) -> c_int {
+    let abstime = &*abstime;
+    let deadline = UNIX_EPOCH + Duration::new(abstime.tv_sec as u64, abstime.tv_nsec as u32);
+    let mut sleep = POLL_MIN;
+    loop {
+        if libc::sem_trywait(sem) == 0 {
+            return 0;
+        }
+        if *libc::__error() != libc::EAGAIN {
+            return -1;
+        }
+        let remaining = match deadline.duration_since(SystemTime::now()) {
+            Ok(remaining) if remaining > Duration::from_secs(0) => remaining,
+            _ => {
+                *libc::__error() = libc::ETIMEDOUT;
+                return -1;
+            },
+        };
+        thread::sleep(sleep.min(remaining));
+        sleep = (sleep * 2).min(POLL_MAX);
+    }
+}
+
+/// Builds a timespec.
+///
+/// The types of the fields differ between targets (`tv_nsec` is not always 64 bits and some
+/// targets have padding fields), this hides it from the rest of the code. The nanoseconds are
+/// always below a second, so they fit into any of them.
+pub fn timespec(secs: time_t, nsec: u32) -> timespec {
+    debug_assert!(nsec < NSEC_PER_SEC);
+    let mut ts: timespec = unsafe { mem::zeroed() };
+    ts.tv_sec = secs;
+    ts.tv_nsec = nsec as _;
+    ts
+}
+
+/// The latest representable time.
+fn max_timespec() -> timespec {
+    timespec(time_t::MAX, NSEC_PER_SEC - 1)
+}
+
+/// The current time of the clock.
+pub fn now(clock: clockid_t) -> timespec {
+    let mut now = timespec(0, 0);
+    assert_eq!(0, unsafe { libc::clock_gettime(clock, &mut now) });
+    now
+}
+
+/// Converts the seconds to the type of `tv_sec`.
+///
+/// Saturates to `max` if they don't fit. Generic so both the 32 and 64 bit `time_t` can be
+/// tested everywhere.
+fn saturating_secs<T: TryFrom<u64>>(secs: u64, max: T) -> T {
+    T::try_from(secs).unwrap_or(max)
+}
+
+/// The time since the epoch as a timespec, saturating to the maximum representable time.
+pub fn from_epoch(since_epoch: Duration) -> timespec {
+    match time_t::try_from(since_epoch.as_secs()) {
+        Ok(secs) => timespec(secs, since_epoch.subsec_nanos()),
+        Err(_) => max_timespec(),
+    }
+}
+
+/// Adds the duration to the time, saturating instead of overflowing.
+pub fn add(time: timespec, dur: Duration) -> timespec {
+    let secs = saturating_secs(dur.as_secs(), time_t::MAX);
+    // Both are below a second, the sum fits
+    let mut nsec = time.tv_nsec as u32 + dur.subsec_nanos();
+    let mut carry = 0;
+    if nsec >= NSEC_PER_SEC {
+        nsec -= NSEC_PER_SEC;
+        carry = 1;
+    }
+    match time.tv_sec.checked_add(secs).and_then(|secs| secs.checked_add(carry)) {
+        Some(secs) => timespec(secs, nsec),
+        None => max_timespec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn clockwait_present() {
+        // Our CI runs a new enough glibc, make sure the monotonic path is the one tested
+        assert!(sem_clockwait().is_some());
+    }
+
+    #[test]
+    fn clock_ids() {
+        assert_eq!(libc::CLOCK_MONOTONIC, ClockId::Monotonic.raw());
+        assert!(ClockId::Realtime.now().tv_sec > 0);
+    }
+
+    #[test]
+    fn secs_saturate() {
+        assert_eq!(42i32, saturating_secs(42, i32::MAX));
+        assert_eq!(i32::MAX, saturating_secs(u64::from(u32::MAX), i32::MAX));
+        assert_eq!(i32::MAX, saturating_secs(u64::MAX / 2, i32::MAX));
+        assert_eq!(i64::from(u32::MAX), saturating_secs(u64::from(u32::MAX), i64::MAX));
+        assert_eq!(i64::MAX, saturating_secs(u64::MAX / 2 + 1, i64::MAX));
+        assert_eq!(i64::MAX, saturating_secs(u64::MAX, i64::MAX));
+    }
+
+    #[test]
+    fn from_epoch_saturates() {
+        let ts = from_epoch(Duration::new(10, 5));
+        assert_eq!((10, 5), (ts.tv_sec, ts.tv_nsec));
+        let ts = from_epoch(Duration::new(u64::MAX / 2 + 1, 5));
+        assert_eq!((libc::time_t::MAX, 999_999_999), (ts.tv_sec, ts.tv_nsec));
+    }
+
+    #[test]
+    fn build_timespec() {
+        let ts = timespec(-1, 999_999_999);
+        assert_eq!((-1, 999_999_999), (ts.tv_sec, ts.tv_nsec));
+    }
+
+    #[test]
+    fn mock_arithmetic() {
+        let clock = MockClock::new();
+        clock.set(Duration::new(100, 900_000_000), Duration::from_secs(5));
+        let ts = clock.realtime_after(Duration::from_millis(200));
+        assert_eq!((101, 100_000_000), (ts.tv_sec, ts.tv_nsec));
+        clock.advance(Duration::from_secs(1));
+        let ts = clock.monotonic_after(Duration::from_secs(1));
+        assert_eq!((7, 0), (ts.tv_sec, ts.tv_nsec));
+        let ts = clock.realtime_after(Duration::MAX);
+        assert_eq!(time_t::MAX, ts.tv_sec);
+        clock.advance(Duration::MAX);
+        assert_eq!(time_t::MAX, clock.now_realtime().tv_sec);
+    }
+
+    #[test]
+    fn mock_now() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Duration::from_secs(10), clock.now() - start);
+        clock.timed_out(Duration::from_millis(5));
+        assert_eq!(Duration::from_millis(10_005), clock.now() - start);
+        let ts = clock.now_monotonic();
+        assert_eq!((10, 5_000_000), (ts.tv_sec, ts.tv_nsec));
+    }
+
+    #[test]
+    fn add_saturates() {
+        let time = timespec(10, 999_999_999);
+        let sum = add(time, Duration::new(1, 1));
+        assert_eq!((12, 0), (sum.tv_sec, sum.tv_nsec));
+        let sum = add(time, Duration::MAX);
+        assert_eq!(libc::time_t::MAX, sum.tv_sec);
+    }
+}
@@ -0,0 +1,213 @@
+//! Semaphores backed by Grand Central Dispatch on Darwin.
+//!
+//! Darwin has no `sem_init`, so without the `dispatch` feature a private
+//! [`Semaphore`][::Semaphore] is an unlinked named one and its timed waits poll (see
+//! [`clock::sem_timedwait`]). With the feature, [`Semaphore::anonymous`][::Semaphore::anonymous]
+//! creates a `dispatch_semaphore_t` instead and the waits, including the timed ones, block in the
+//! kernel.
+//!
+//! The semaphore is only ever used through a [`Semaphore`][::Semaphore], which routes its
+//! operations to the `sem_*` lookalikes here (see the backend module). Everything built on top of
+//! the basic calls works the same, but there's no `sem_t` to hand out.
+
+use std::io::Error;
+use std::os::raw::c_void;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use libc::{self, c_int, timespec};
+
+use many::value_max;
+use metrics::Metrics;
+use shadow::Shadow;
+use {check_value, Kind, Mode, Semaphore};
+
+#[repr(C)]
+struct DispatchSemaphoreS {
+    _private: [u8; 0],
+}
+
+type DispatchTime = u64;
+
+const DISPATCH_TIME_NOW: DispatchTime = 0;
+const DISPATCH_TIME_FOREVER: DispatchTime = !0;
+
+extern "C" {
+    fn dispatch_semaphore_create(value: isize) -> *mut DispatchSemaphoreS;
+    fn dispatch_semaphore_wait(dsema: *mut DispatchSemaphoreS, timeout: DispatchTime) -> isize;
+    fn dispatch_semaphore_signal(dsema: *mut DispatchSemaphoreS) -> isize;
+    fn dispatch_walltime(when: *const timespec, delta: i64) -> DispatchTime;
+    fn dispatch_release(object: *mut c_void);
+}
+
+/// A `dispatch_semaphore_t` with the `sem_*` calls emulated on top of it.
+///
+/// GCD neither exposes the value nor has a maximum. So the value is kept in a shadow counter next
+/// to it, updated around each operation: it may be off for a moment while an operation is in
+/// progress, but never for long. The same counter enforces
+/// [`max_value`][Semaphore::max_value], so the posts overflow just like with `sem_post`.
+///
+/// The waits are never interrupted by signals.
+pub(crate) struct DispatchSemaphore {
+    inner: *mut DispatchSemaphoreS,
+    value: AtomicI64,
+}
+
+impl DispatchSemaphore {
+    fn new(value: u32) -> Result<Self, Error> {
+        check_value(value)?;
+        // GCD aborts when releasing a semaphore with a lower value than it was created with, so
+        // it starts empty and gets the tokens posted.
+        let inner = unsafe { dispatch_semaphore_create(0) };
+        if inner.is_null() {
+            return Err(Error::other("dispatch_semaphore_create failed"));
+        }
+        for _ in 0..value {
+            unsafe { dispatch_semaphore_signal(inner) };
+        }
+        Ok(DispatchSemaphore {
+            inner,
+            value: AtomicI64::new(i64::from(value)),
+        })
+    }
+
+    /// Waits until the GCD timeout, keeping the shadow counter up to date.
+    fn wait_until(&self, timeout: DispatchTime) -> bool {
+        let acquired = unsafe { dispatch_semaphore_wait(self.inner, timeout) } == 0;
+        if acquired {
+            self.value.fetch_sub(1, Ordering::Relaxed);
+        }
+        acquired
+    }
+
+    pub(crate) fn sem_wait(&self) -> Result<(), c_int> {
+        let acquired = self.wait_until(DISPATCH_TIME_FOREVER);
+        debug_assert!(acquired, "Waiting forever timed out");
+        Ok(())
+    }
+
+    pub(crate) fn sem_trywait(&self) -> Result<(), c_int> {
+        if self.wait_until(DISPATCH_TIME_NOW) {
+            Ok(())
+        } else {
+            Err(libc::EAGAIN)
+        }
+    }
+
+    /// Waits until the absolute real time.
+    pub(crate) fn sem_timedwait(&self, abstime: &timespec) -> Result<(), c_int> {
+        // GCD counts the nanoseconds in 64 bits, anything further is as good as forever
+        let timeout = if i64::from(abstime.tv_sec) >= i64::MAX / 1_000_000_000 {
+            DISPATCH_TIME_FOREVER
+        } else {
+            unsafe { dispatch_walltime(abstime, 0) }
+        };
+        if self.wait_until(timeout) {
+            Ok(())
+        } else {
+            Err(libc::ETIMEDOUT)
+        }
+    }
+
+    pub(crate) fn sem_post(&self) -> Result<(), c_int> {
+        let max = i64::from(value_max());
+        self.value
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |value| {
+                if value < max {
+                    Some(value + 1)
+                } else {
+                    None
+                }
+            })
+            .map_err(|_| libc::EOVERFLOW)?;
+        unsafe { dispatch_semaphore_signal(self.inner) };
+        Ok(())
+    }
+
+    /// The value by the shadow counter.
+    pub(crate) fn sem_getvalue(&self) -> c_int {
+        self.value.load(Ordering::Relaxed).max(0) as c_int
+    }
+}
+
+impl Drop for DispatchSemaphore {
+    fn drop(&mut self) {
+        // Take the tokens back, see new
+        while unsafe { dispatch_semaphore_wait(self.inner, DISPATCH_TIME_NOW) } == 0 {}
+        unsafe { dispatch_release(self.inner as *mut c_void) };
+    }
+}
+
+unsafe impl Send for DispatchSemaphore {}
+unsafe impl Sync for DispatchSemaphore {}
+
+impl Semaphore {
+    /// Creates the private semaphore on top of GCD.
+    pub(crate) fn dispatch(value: u32) -> Result<Self, Error> {
+        let sem = Box::new(DispatchSemaphore::new(value)?);
+        Ok(Semaphore {
+            // Not a sem_t, Mode::Dispatch tells
+            inner: NonNull::from(Box::leak(sem)).cast(),
+            mode: Mode::Dispatch,
+            kind: Kind::Anonymous,
+            metrics: Metrics::new(),
+            slow_wait: None,
+            // Like the named stand-in, so the value is read the same on macOS
+            shadow: Shadow::automatic(value),
+        })
+    }
+
+    /// The GCD semaphore behind a semaphore in [`Mode::Dispatch`].
+    ///
+    /// # Safety
+    ///
+    /// The mode must be `Dispatch`.
+    pub(crate) unsafe fn dispatch_semaphore(&self) -> &DispatchSemaphore {
+        &*(self.inner.as_ptr() as *const DispatchSemaphore)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use {Kind, WaitError};
+
+    #[test]
+    fn backs_anonymous() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        assert!(matches!(sem.mode, Mode::Dispatch));
+        assert_eq!(Kind::Anonymous, sem.kind());
+        assert_eq!(1, sem.try_value().unwrap());
+        sem.wait();
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(Duration::from_millis(10)));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                sem.post().unwrap();
+            });
+            sem.wait_timeout(Duration::from_secs(10)).unwrap();
+        });
+        // The borrowed view goes through GCD too
+        sem.as_ref().post().unwrap();
+        sem.as_ref().trywait().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "no sem_t")]
+    fn no_raw() {
+        Semaphore::anonymous(0).unwrap().as_raw();
+    }
+
+    #[test]
+    fn overflow() {
+        let max = value_max();
+        let sem = DispatchSemaphore::new(max).unwrap();
+        assert_eq!(Err(libc::EOVERFLOW), sem.sem_post());
+        assert_eq!(max as c_int, sem.sem_getvalue());
+        sem.sem_wait().unwrap();
+        sem.sem_post().unwrap();
+    }
+}
@@ -0,0 +1,482 @@
+//! A semaphore serving its waiters in order.
+
+use std::io::Error;
+use std::marker::PhantomPinned;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use libc::{self, sem_t};
+
+use ipc::Shared;
+use many::value_max;
+use {check_value, init, NoToken, Overflow, SemaphoreSlot, WaitError};
+
+/// How many waiters can queue up at once, each with its own place to sleep in.
+const PLACES: u32 = 32;
+
+/// Where the waiter holding a ticket sleeps.
+#[repr(C)]
+struct Place {
+    wakeup: SemaphoreSlot,
+    // The waiter timed out before its turn came
+    abandoned: AtomicBool,
+}
+
+/// A semaphore handing out its tokens first come, first served.
+///
+/// POSIX doesn't specify the order in which waiters of a semaphore wake up, and in practice a
+/// thread that keeps posting and waiting in a loop may get the token back again and again,
+/// starving everyone else. This one gives every waiter a ticket and only the oldest ticket may
+/// take a token, even if a newer waiter or a [`trywait`][FifoSemaphore::trywait] comes just at
+/// the right time.
+///
+/// The ticket counter and the now-serving counter live in the structure itself, next to a place
+/// to sleep for each of the queued tickets, so the whole semaphore can be put into memory shared
+/// with other processes (see [`anonymous_shared`][FifoSemaphore::anonymous_shared] and
+/// [`init_at`][FifoSemaphore::init_at]). Up to 32 waiters queue in order; the ones coming when
+/// the queue is full wait for a free place first and the order among them is not guaranteed.
+///
+/// Each wakeup is handed from one waiter to the next. The fairness has its price: when a token
+/// is released, it goes to a sleeping thread that has to wake up first instead of to a running
+/// one that could take it right away, so there are more context switches and the throughput
+/// under contention is considerably lower than with a plain [`Semaphore`][::Semaphore].
+/// Everything happens under an internal lock too.
+#[repr(C)]
+pub struct FifoSemaphore {
+    // A binary semaphore guarding all the counters below
+    lock: SemaphoreSlot,
+    // Wakes the waiters that found the queue full
+    room: SemaphoreSlot,
+    room_waiters: AtomicU32,
+    tokens: AtomicU32,
+    // The next ticket to hand out
+    next: AtomicU32,
+    // The ticket at the head of the queue, the only one allowed to take a token
+    serving: AtomicU32,
+    // Did the head get a wakeup it didn't pick up yet?
+    woken: AtomicBool,
+    places: [Place; PLACES as usize],
+    _pin: PhantomPinned,
+}
+
+/// Waits for a token on the semaphore, until the deadline if there's one.
+fn wait_on(sem: &SemaphoreSlot, deadline: Option<Instant>) -> Result<(), WaitError> {
+    match deadline {
+        Some(deadline) => sem.wait_deadline(deadline),
+        None => {
+            sem.wait();
+            Ok(())
+        },
+    }
+}
+
+impl FifoSemaphore {
+    /// Initializes all the parts, destroying the already initialized ones on failure.
+    unsafe fn init_in(place: *mut Self, pshared: bool, value: u32) -> Result<(), Error> {
+        check_value(value)?;
+        let places = ptr::addr_of_mut!((*place).places).cast::<Place>();
+        let mut sems: Vec<*mut sem_t> = (0..PLACES as usize)
+            .map(|i| ptr::addr_of_mut!((*places.add(i)).wakeup).cast())
+            .collect();
+        sems.push(ptr::addr_of_mut!((*place).room).cast());
+        sems.push(ptr::addr_of_mut!((*place).lock).cast());
+        for (i, &sem) in sems.iter().enumerate() {
+            // The lock starts unlocked, all the rest empty
+            let initial = if i + 1 == sems.len() { 1 } else { 0 };
+            if let Err(e) = init(sem, pshared, initial) {
+                for &sem in &sems[..i] {
+                    libc::sem_destroy(sem);
+                }
+                return Err(e);
+            }
+        }
+        for i in 0..PLACES as usize {
+            ptr::addr_of_mut!((*places.add(i)).abandoned).write(AtomicBool::new(false));
+        }
+        ptr::addr_of_mut!((*place).room_waiters).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).tokens).write(AtomicU32::new(value));
+        ptr::addr_of_mut!((*place).next).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).serving).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).woken).write(AtomicBool::new(false));
+        Ok(())
+    }
+
+    /// Creates the semaphore with the given number of tokens, private to this process.
+    ///
+    /// As the semaphores inside must not move, it is pinned in a box.
+    pub fn new(value: u32) -> Result<Pin<Box<Self>>, Error> {
+        let mut me = Box::new(MaybeUninit::<Self>::uninit());
+        unsafe {
+            Self::init_in(me.as_mut_ptr(), false, value)?;
+            Ok(Box::into_pin(Box::from_raw(Box::into_raw(me).cast::<Self>())))
+        }
+    }
+
+    /// Creates the semaphore in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(value: u32) -> Result<Shared<Self>, Error> {
+        unsafe { Shared::new(|place| Self::init_in(place.as_ptr(), true, value)) }
+    }
+
+    /// Initializes the semaphore in the provided memory, for use by multiple processes.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][::ipc::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, value: u32) -> Result<&'a Self, Error> {
+        Self::init_in(place.as_ptr(), true, value)?;
+        Ok(&*place.as_ptr())
+    }
+
+    /// Views a semaphore initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a semaphore initialized by [`init_at`][FifoSemaphore::init_at]
+    /// that stays valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// Runs the closure with the internal lock held.
+    fn locked<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        self.lock.wait();
+        let result = f();
+        let _ = self.lock.post();
+        result
+    }
+
+    fn place(&self, ticket: u32) -> &Place {
+        &self.places[(ticket % PLACES) as usize]
+    }
+
+    /// The number of tickets handed out and not yet served.
+    fn queued(&self) -> u32 {
+        let next = self.next.load(Ordering::Relaxed);
+        next.wrapping_sub(self.serving.load(Ordering::Relaxed))
+    }
+
+    /// Wakes up the head of the queue, if it has a reason to wake up.
+    fn wake_head(&self) {
+        let ready = self.tokens.load(Ordering::Relaxed) > 0 && self.queued() > 0;
+        if ready && !self.woken.load(Ordering::Relaxed) {
+            self.woken.store(true, Ordering::Relaxed);
+            // At most one wakeup is in flight at any time, so this can't overflow
+            let _ = self.place(self.serving.load(Ordering::Relaxed)).wakeup.post();
+        }
+    }
+
+    /// Moves on to the next ticket in the queue, skipping the abandoned ones.
+    fn advance(&self) {
+        let mut freed = 0;
+        loop {
+            self.serving.fetch_add(1, Ordering::Relaxed);
+            freed += 1;
+            if self.queued() == 0 {
+                break;
+            }
+            let head = self.place(self.serving.load(Ordering::Relaxed));
+            if !head.abandoned.swap(false, Ordering::Relaxed) {
+                break;
+            }
+        }
+        let room = freed.min(self.room_waiters.load(Ordering::Relaxed));
+        self.room_waiters.fetch_sub(room, Ordering::Relaxed);
+        for _ in 0..room {
+            let _ = self.room.post();
+        }
+        self.wake_head();
+    }
+
+    /// Takes a token without waiting, if nobody is queued before us.
+    fn take_uncontended(&self) -> bool {
+        let free = self.queued() == 0 && self.tokens.load(Ordering::Relaxed) > 0;
+        if free {
+            self.tokens.fetch_sub(1, Ordering::Relaxed);
+        }
+        free
+    }
+
+    /// Takes a ticket, or a token if there's no queue.
+    ///
+    /// Returns `None` in the latter case.
+    fn enqueue(&self, deadline: Option<Instant>) -> Result<Option<u32>, WaitError> {
+        loop {
+            let full = self.locked(|| {
+                if self.take_uncontended() {
+                    Ok(None)
+                } else if self.queued() < PLACES {
+                    Ok(Some(self.next.fetch_add(1, Ordering::Relaxed)))
+                } else {
+                    self.room_waiters.fetch_add(1, Ordering::Relaxed);
+                    Err(())
+                }
+            });
+            if let Ok(ticket) = full {
+                return Ok(ticket);
+            }
+            if let Err(e) = wait_on(&self.room, deadline) {
+                self.locked(|| {
+                    // A place got freed for us just now, we already have the wakeup for it
+                    if self.room.trywait().is_ok() {
+                        return Ok(());
+                    }
+                    self.room_waiters.fetch_sub(1, Ordering::Relaxed);
+                    Err(e)
+                })?;
+            }
+        }
+    }
+
+    fn wait_until(&self, deadline: Option<Instant>) -> Result<(), WaitError> {
+        let ticket = match self.enqueue(deadline)? {
+            Some(ticket) => ticket,
+            None => return Ok(()),
+        };
+        let place = self.place(ticket);
+        let mut deadline = deadline;
+        loop {
+            let result = wait_on(&place.wakeup, deadline);
+            let done = self.locked(|| {
+                let head = self.serving.load(Ordering::Relaxed) == ticket;
+                match result {
+                    // Only the head gets woken up and nobody else takes its tokens
+                    Ok(()) => {
+                        debug_assert!(head);
+                        self.woken.store(false, Ordering::Relaxed);
+                        self.tokens.fetch_sub(1, Ordering::Relaxed);
+                        self.advance();
+                        Some(Ok(()))
+                    },
+                    // A wakeup is on its way to us, so we have to pick it up
+                    Err(_) if head && self.woken.load(Ordering::Relaxed) => None,
+                    Err(e) if head => {
+                        self.advance();
+                        Some(Err(e))
+                    },
+                    Err(e) => {
+                        place.abandoned.store(true, Ordering::Relaxed);
+                        Some(Err(e))
+                    },
+                }
+            });
+            match done {
+                Some(result) => return result,
+                None => deadline = None,
+            }
+        }
+    }
+
+    /// Waits for a token and takes it.
+    pub fn wait(&self) {
+        self.wait_until(None).expect("Wait without a deadline failed");
+    }
+
+    /// Takes a token if one is available and nobody waits for it.
+    pub fn trywait(&self) -> Result<(), NoToken> {
+        if self.locked(|| self.take_uncontended()) {
+            Ok(())
+        } else {
+            Err(NoToken::new())
+        }
+    }
+
+    /// Waits for a token until the given time.
+    pub fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+        let timeout = until.duration_since(SystemTime::now()).unwrap_or_default();
+        self.wait_timeout(timeout)
+    }
+
+    /// Waits for a token, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.wait_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Returns a token, for the longest waiting thread if there's any.
+    pub fn post(&self) -> Result<(), Overflow> {
+        self.locked(|| {
+            if self.tokens.load(Ordering::Relaxed) >= value_max() {
+                return Err(Overflow::new());
+            }
+            self.tokens.fetch_add(1, Ordering::Relaxed);
+            self.wake_head();
+            Ok(())
+        })
+    }
+
+    /// The number of tokens not yet taken by anyone.
+    pub fn value(&self) -> u32 {
+        self.tokens.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for FifoSemaphore {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.lock.as_ptr());
+            libc::sem_destroy(self.room.as_ptr());
+            for place in &self.places {
+                libc::sem_destroy(place.wakeup.as_ptr());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+    use Semaphore;
+
+    fn queued(sem: &FifoSemaphore) -> u32 {
+        sem.locked(|| sem.queued())
+    }
+
+    #[test]
+    fn in_order() {
+        const WAITERS: u32 = PLACES;
+        let sem = FifoSemaphore::new(0).unwrap();
+        let order = Mutex::new(Vec::new());
+        thread::scope(|s| {
+            for i in 0..WAITERS {
+                let (sem, order) = (&*sem, &order);
+                s.spawn(move || {
+                    sem.wait();
+                    order.lock().unwrap().push(i);
+                    sem.post().unwrap();
+                });
+                // Make sure they queue in the order they are spawned
+                while queued(sem) <= i {
+                    thread::yield_now();
+                }
+            }
+            sem.trywait().unwrap_err();
+            sem.post().unwrap();
+        });
+        assert_eq!((0..WAITERS).collect::<Vec<_>>(), order.into_inner().unwrap());
+        assert_eq!(1, sem.value());
+    }
+
+    /// The waiters over the capacity of the queue wait for a place and get served too.
+    #[test]
+    fn full_queue() {
+        const WAITERS: u32 = PLACES + 8;
+        let sem = FifoSemaphore::new(0).unwrap();
+        thread::scope(|s| {
+            let waiters = (0..WAITERS)
+                .map(|i| {
+                    let sem = &*sem;
+                    // Some of the ones waiting for a place give up
+                    s.spawn(move || match i % 4 {
+                        0 => sem.wait_timeout(Duration::from_millis(10)).is_ok(),
+                        _ => sem.wait_timeout(Duration::from_secs(10)).is_ok(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            while queued(&sem) < PLACES {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(20));
+            for _ in 0..WAITERS {
+                sem.post().unwrap();
+            }
+            let results = waiters.into_iter().map(|w| w.join().unwrap());
+            let served = results.filter(|&ok| ok).count() as u32;
+            assert!(served >= WAITERS * 3 / 4);
+            assert_eq!(WAITERS - served, sem.value());
+        });
+        assert_eq!(0, queued(&sem));
+        assert_eq!(0, sem.room_waiters.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn processes() {
+        let sem = FifoSemaphore::anonymous_shared(0).unwrap();
+        let done = Semaphore::anonymous_shared(0).unwrap();
+        let children = (0..4)
+            .map(|_| {
+                fork(|| {
+                    sem.wait();
+                    done.post().unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        while queued(&sem) < 4 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        for _ in 0..4 {
+            sem.post().unwrap();
+        }
+        for _ in 0..4 {
+            done.wait();
+        }
+        for child in children {
+            child.join();
+        }
+        assert_eq!(0, sem.value());
+        assert_eq!(0, queued(&sem));
+    }
+
+    #[test]
+    fn timeouts_leave_queue() {
+        let sem = FifoSemaphore::new(0).unwrap();
+        let timeout = Duration::from_millis(5);
+        thread::scope(|s| {
+            let waiters = (0..4)
+                .map(|i| {
+                    let sem = &*sem;
+                    s.spawn(move || sem.wait_timeout(Duration::from_millis(5 * i + 5)))
+                })
+                .collect::<Vec<_>>();
+            let patient = s.spawn(|| sem.wait_timeout(Duration::from_secs(10)));
+            for waiter in waiters {
+                assert_eq!(Err(WaitError::TimedOut), waiter.join().unwrap());
+            }
+            sem.post().unwrap();
+            assert_eq!(Ok(()), patient.join().unwrap());
+        });
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(timeout));
+        sem.post().unwrap();
+        sem.trywait().unwrap();
+        assert_eq!(0, queued(&sem));
+    }
+
+    #[test]
+    fn no_starvation() {
+        let sem = FifoSemaphore::new(1).unwrap();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                // Greedy, takes the token back as soon as it returns it
+                while !done.load(Ordering::Relaxed) {
+                    sem.wait();
+                    sem.post().unwrap();
+                }
+            });
+            let patient = (0..4)
+                .map(|_| {
+                    s.spawn(|| {
+                        for _ in 0..20 {
+                            let start = Instant::now();
+                            sem.wait();
+                            let waited = start.elapsed();
+                            sem.post().unwrap();
+                            assert!(waited < Duration::from_secs(1), "Starved {:?}", waited);
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for p in patient {
+                p.join().unwrap();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+    }
+}
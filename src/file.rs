@@ -0,0 +1,125 @@
+//! Process-shared semaphores living in a file at an arbitrary path.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind};
+use std::ops::Deref;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Instant;
+
+use libc::{self, mode_t};
+
+use mapped::{self, INIT_TIMEOUT, REGION_SIZE};
+use Semaphore;
+
+/// A process-shared semaphore inside a file of the caller's choosing.
+///
+/// This is like [`ShmSemaphore`][::ShmSemaphore], but the semaphore lives at a path instead of
+/// in the `/dev/shm` namespace. The file should be on a memory-backed filesystem (eg. `tmpfs`),
+/// otherwise the kernel might keep writing it to disk. It dereferences to [`Semaphore`] for all
+/// the waiting and posting.
+#[derive(Debug)]
+pub struct FileSemaphore {
+    sem: Semaphore,
+    _file: File,
+}
+
+fn map_error(e: Error) -> Error {
+    if e.raw_os_error() == Some(libc::ENODEV) {
+        Error::new(ErrorKind::Unsupported, "The filesystem doesn't support shared mappings")
+    } else {
+        e
+    }
+}
+
+impl FileSemaphore {
+    /// Creates the file and a semaphore inside.
+    ///
+    /// Fails with [`ErrorKind::AlreadyExists`] if the file exists.
+    pub fn create<P: AsRef<Path>>(path: P, mode: mode_t, value: u32) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .mode(mode as _)
+            .open(path)?;
+        match mapped::create(file.as_raw_fd(), value) {
+            Ok(region) => Ok(FileSemaphore {
+                sem: unsafe { mapped::semaphore(region) },
+                _file: file,
+            }),
+            Err(e) => {
+                let _ = fs::remove_file(path);
+                Err(map_error(e))
+            },
+        }
+    }
+
+    /// Opens a semaphore created by [`create`][FileSemaphore::create], possibly in another
+    /// process.
+    ///
+    /// Files of a size that doesn't fit a semaphore are refused with
+    /// [`ErrorKind::InvalidData`]. If the creator hasn't finished initializing the semaphore
+    /// yet, this waits for it (but gives up with [`ErrorKind::TimedOut`] after a few seconds).
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        // Empty might be a file the creator hasn't resized yet
+        let size = mapped::size(file.as_raw_fd())?;
+        if size != 0 && size != REGION_SIZE as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, "File doesn't contain a semaphore"));
+        }
+        let region = mapped::attach(file.as_raw_fd(), Instant::now() + INIT_TIMEOUT)
+            .map_err(map_error)?;
+        Ok(FileSemaphore {
+            sem: unsafe { mapped::semaphore(region) },
+            _file: file,
+        })
+    }
+}
+
+impl Deref for FileSemaphore {
+    type Target = Semaphore;
+    fn deref(&self) -> &Semaphore {
+        &self.sem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use test_util::{child_arg, run_child};
+
+    #[test]
+    fn child_post() {
+        if let Some(path) = child_arg() {
+            let sem = FileSemaphore::open(path).unwrap();
+            sem.wait();
+            sem.post().unwrap();
+            sem.post().unwrap();
+        }
+    }
+
+    #[test]
+    fn two_processes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("sem");
+        let sem = FileSemaphore::create(&path, 0o600, 1).unwrap();
+        run_child("file::tests::child_post", path.to_str().unwrap());
+        assert_eq!(2, sem.value());
+        let err = FileSemaphore::create(&path, 0o600, 1).err().unwrap();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+    }
+
+    #[test]
+    fn wrong_size() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("garbage");
+        fs::write(&path, b"not a semaphore").unwrap();
+        let err = FileSemaphore::open(&path).err().unwrap();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+    }
+}
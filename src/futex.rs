@@ -0,0 +1,582 @@
+//! Semaphores built directly on Linux futexes.
+//!
+//! A futex is a 32-bit word the kernel can put threads to sleep on until it changes. Here that
+//! word is the number of tokens itself and a count of the sleepers sits next to it, so a wait or a
+//! post nobody contends on is a single atomic operation. The kernel is asked only to block a
+//! waiter when the value is zero and to wake the blocked ones when a poster sees any.
+//!
+//! The timeouts are measured on the monotonic clock (`FUTEX_WAIT_BITSET` takes an absolute
+//! deadline on it), so setting the system time doesn't stretch or cut short a wait the way it can
+//! with `sem_timedwait`.
+
+use std::io::{Error, ErrorKind};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, SystemTime};
+
+use libc::{self, c_int, c_long, timespec};
+
+use clock::{Clock, SystemClock};
+use {NoToken, Overflow, Semaphore, WaitError};
+
+/// A semaphore made of an atomic counter and a futex to sleep on.
+///
+/// Available on Linux with the `futex` feature. Taking and returning a token with no other thread
+/// around is a single atomic operation, with no call into the libc. The system is called only to
+/// block and to wake the ones blocked.
+///
+/// Unlike the POSIX API, [`post_many`][FutexSemaphore::post_many] adds any number of tokens at
+/// once and wakes that many waiters with one call.
+///
+/// The value is limited to [`Semaphore::MAX_VALUE`], the posts over it fail with [`Overflow`]
+/// just like with the other semaphores.
+///
+/// The semaphore has no destructor, it's just the two numbers. It can be created in a `static`
+/// by [`const_new`][FutexSemaphore::const_new].
+#[derive(Debug)]
+pub struct FutexSemaphore {
+    value: AtomicU32,
+    waiters: AtomicU32,
+    process_shared: bool,
+}
+
+impl FutexSemaphore {
+    fn with_sharing(value: u32, process_shared: bool) -> Result<Self, Error> {
+        if value > Semaphore::MAX_VALUE {
+            return Err(Error::new(ErrorKind::InvalidInput, "Initial value over SEM_VALUE_MAX"));
+        }
+        Ok(FutexSemaphore {
+            process_shared,
+            ..Self::const_new(value)
+        })
+    }
+
+    /// Creates a private semaphore with `initial` tokens at compile time, eg. in a `static`.
+    ///
+    /// There's nothing to initialize lazily and nothing to destroy, so the operations work on
+    /// the static right away, without locking or allocating. All of them only touch the atomics
+    /// and call the futex, so [`post`][FutexSemaphore::post] may be used from signal handlers.
+    ///
+    /// # Panics
+    ///
+    /// If the value is over [`Semaphore::MAX_VALUE`], at compile time when used in a `static`.
+    pub const fn const_new(initial: u32) -> Self {
+        assert!(initial <= Semaphore::MAX_VALUE, "Initial value over SEM_VALUE_MAX");
+        FutexSemaphore {
+            value: AtomicU32::new(initial),
+            waiters: AtomicU32::new(0),
+            process_shared: false,
+        }
+    }
+
+    /// Creates a semaphore private to this process with `value` tokens.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the value is over [`Semaphore::MAX_VALUE`].
+    pub fn new(value: u32) -> Result<Self, Error> {
+        Self::with_sharing(value, false)
+    }
+
+    /// Creates a semaphore that may be shared with other processes.
+    ///
+    /// For that, it needs to be moved into memory shared with them, like a `MAP_SHARED` mapping
+    /// inherited over `fork`. The private one is a bit cheaper to wait on.
+    pub fn new_shared(value: u32) -> Result<Self, Error> {
+        Self::with_sharing(value, true)
+    }
+
+    /// If the semaphore was created to be shared with other processes.
+    pub fn is_process_shared(&self) -> bool {
+        self.process_shared
+    }
+
+    fn futex(&self, op: c_int, val: u32, timeout: *const timespec, val3: u32) -> c_long {
+        let op = if self.process_shared { op } else { op | libc::FUTEX_PRIVATE_FLAG };
+        let addr = &self.value as *const AtomicU32;
+        unsafe {
+            libc::syscall(libc::SYS_futex, addr, op, val, timeout, ptr::null::<u32>(), val3)
+        }
+    }
+
+    /// Blocks until the value is no longer zero, the deadline passes or a signal comes.
+    fn sleep(&self, abstime: Option<&timespec>) -> Result<(), WaitError> {
+        let timeout = abstime.map_or(ptr::null(), |abstime| abstime as *const timespec);
+        // Pairs with the load in wake: either the poster sees us counted, or the kernel sees the
+        // value already posted and doesn't put us to sleep.
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        let any = libc::FUTEX_BITSET_MATCH_ANY as u32;
+        let result = self.futex(libc::FUTEX_WAIT_BITSET, 0, timeout, any);
+        self.waiters.fetch_sub(1, Ordering::Relaxed);
+        if result == 0 {
+            return Ok(());
+        }
+        match Error::last_os_error().raw_os_error() {
+            Some(libc::ETIMEDOUT) => Err(WaitError::TimedOut),
+            // The value changed before we got to sleep, or a signal; both just try again
+            Some(libc::EAGAIN) | Some(libc::EINTR) => Ok(()),
+            _ => panic!("Futex wait failed: {}", Error::last_os_error()),
+        }
+    }
+
+    /// Waits for a token until the absolute monotonic deadline, or forever.
+    fn wait_until(&self, abstime: Option<&timespec>) -> Result<(), WaitError> {
+        loop {
+            if self.trywait().is_ok() {
+                return Ok(());
+            }
+            if let Err(e) = self.sleep(abstime) {
+                // A last chance, a token may have come together with the timeout
+                return self.trywait().map_err(|_| e);
+            }
+        }
+    }
+
+    /// Waits for a token.
+    ///
+    /// Signals don't interrupt the wait.
+    pub fn wait(&self) {
+        self.wait_until(None).expect("Waiting forever timed out");
+    }
+
+    /// Takes a token if one is available right away.
+    pub fn trywait(&self) -> Result<(), NoToken> {
+        self.value
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |value| value.checked_sub(1))
+            .map(|_| ())
+            .map_err(|_| NoToken::new())
+    }
+
+    /// Waits for a token until the absolute time.
+    ///
+    /// The deadline is converted to the monotonic clock when the wait starts, so changes to the
+    /// system time made meanwhile don't make the wait shorter or longer.
+    pub fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+        match until.duration_since(SystemTime::now()) {
+            Ok(timeout) => self.wait_timeout(timeout),
+            Err(_) => self.trywait().map_err(|_| WaitError::TimedOut),
+        }
+    }
+
+    /// Waits for a token until the absolute time of the `CLOCK_MONOTONIC`.
+    ///
+    /// # Panics
+    ///
+    /// If the nanoseconds are out of range.
+    pub fn timedwait_monotonic(&self, abstime: timespec) -> Result<(), WaitError> {
+        assert!(abstime.tv_nsec >= 0 && abstime.tv_nsec < 1_000_000_000, "Invalid timespec");
+        self.wait_until(Some(&abstime))
+    }
+
+    /// Waits for a token, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        if timeout == Duration::from_secs(0) {
+            return self.trywait().map_err(|_| WaitError::TimedOut);
+        }
+        self.timedwait_monotonic(SystemClock.monotonic_after(timeout))
+    }
+
+    /// Wakes up to `n` of the blocked threads, if there are any.
+    fn wake(&self, n: u32) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let n = n.min(i32::MAX as u32);
+            self.futex(libc::FUTEX_WAKE, n, ptr::null(), 0);
+        }
+    }
+
+    /// Returns a token.
+    ///
+    /// Fails if the semaphore would go over [`Semaphore::MAX_VALUE`].
+    pub fn post(&self) -> Result<(), Overflow> {
+        self.post_many(1)
+    }
+
+    /// Returns `n` tokens at once, waking up to `n` waiters.
+    ///
+    /// Either all the tokens are added or, if that would go over [`Semaphore::MAX_VALUE`], none.
+    pub fn post_many(&self, n: u32) -> Result<(), Overflow> {
+        if n == 0 {
+            return Ok(());
+        }
+        self.value
+            .fetch_update(Ordering::SeqCst, Ordering::Relaxed, |value| {
+                value.checked_add(n).filter(|&value| value <= Semaphore::MAX_VALUE)
+            })
+            .map_err(|_| Overflow::new())?;
+        self.wake(n);
+        Ok(())
+    }
+
+    /// The number of tokens available right now.
+    pub fn value(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// The number of threads blocked on the semaphore right now.
+    ///
+    /// For a process-shared one, this includes the threads of the other processes.
+    pub fn waiters(&self) -> u32 {
+        self.waiters.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+    use test_util::{fork, interrupt};
+
+    /// What the scenarios below need, so they run against both backends.
+    ///
+    /// These are the behaviours the two share: values, timeouts, signals and overflows. The other
+    /// [`Semaphore`] tests are about the `sem_t` itself (its errno, layout, naming, destroying and
+    /// reinitializing), which the futex semaphore doesn't have.
+    trait Backend: Sized + Sync {
+        fn create(value: u32) -> Result<Self, Error>;
+        fn wait(&self);
+        fn trywait(&self) -> Result<(), NoToken>;
+        fn timedwait(&self, until: SystemTime) -> Result<(), WaitError>;
+        fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError>;
+        fn post(&self) -> Result<(), Overflow>;
+        fn value(&self) -> u32;
+    }
+
+    impl Backend for Semaphore {
+        fn create(value: u32) -> Result<Self, Error> {
+            Semaphore::anonymous(value)
+        }
+        fn wait(&self) {
+            Semaphore::wait(self)
+        }
+        fn trywait(&self) -> Result<(), NoToken> {
+            Semaphore::trywait(self)
+        }
+        fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+            Semaphore::timedwait(self, until)
+        }
+        fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+            Semaphore::wait_timeout(self, timeout)
+        }
+        fn post(&self) -> Result<(), Overflow> {
+            Semaphore::post(self)
+        }
+        fn value(&self) -> u32 {
+            Semaphore::value(self) as u32
+        }
+    }
+
+    impl Backend for FutexSemaphore {
+        fn create(value: u32) -> Result<Self, Error> {
+            FutexSemaphore::new(value)
+        }
+        fn wait(&self) {
+            FutexSemaphore::wait(self)
+        }
+        fn trywait(&self) -> Result<(), NoToken> {
+            FutexSemaphore::trywait(self)
+        }
+        fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+            FutexSemaphore::timedwait(self, until)
+        }
+        fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+            FutexSemaphore::wait_timeout(self, timeout)
+        }
+        fn post(&self) -> Result<(), Overflow> {
+            FutexSemaphore::post(self)
+        }
+        fn value(&self) -> u32 {
+            FutexSemaphore::value(self)
+        }
+    }
+
+    fn tokens<S: Backend>() {
+        let sem = S::create(2).unwrap();
+        sem.wait();
+        sem.trywait().unwrap();
+        sem.trywait().unwrap_err();
+        assert_eq!(0, sem.value());
+        let timeout = Duration::from_millis(10);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(timeout));
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(Duration::from_secs(0)));
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait(SystemTime::now() + timeout));
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait(SystemTime::UNIX_EPOCH));
+        sem.post().unwrap();
+        assert_eq!(1, sem.value());
+        sem.timedwait(SystemTime::now() + timeout).unwrap();
+        sem.post().unwrap();
+        sem.wait_timeout(Duration::from_secs(0)).unwrap();
+    }
+
+    #[test]
+    fn tokens_posix() {
+        tokens::<Semaphore>();
+    }
+
+    #[test]
+    fn tokens_futex() {
+        tokens::<FutexSemaphore>();
+    }
+
+    fn overflow<S: Backend>() {
+        let max = Semaphore::MAX_VALUE;
+        let e = S::create(max + 1).map(|_| ()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+        let sem = S::create(max).unwrap();
+        sem.post().unwrap_err();
+        assert_eq!(max, sem.value());
+        sem.wait();
+        sem.post().unwrap();
+    }
+
+    #[test]
+    fn overflow_posix() {
+        overflow::<Semaphore>();
+    }
+
+    #[test]
+    fn overflow_futex() {
+        overflow::<FutexSemaphore>();
+    }
+
+    fn timeout_elapses<S: Backend>() {
+        let sem = S::create(0).unwrap();
+        let start = Instant::now();
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(Duration::from_millis(100)));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(2), "Took too long: {:?}", elapsed);
+        let start = Instant::now();
+        let until = SystemTime::now() + Duration::from_millis(100);
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait(until));
+        assert!(start.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn timeout_elapses_posix() {
+        timeout_elapses::<Semaphore>();
+    }
+
+    #[test]
+    fn timeout_elapses_futex() {
+        timeout_elapses::<FutexSemaphore>();
+    }
+
+    /// Deadlines too far to represent wait forever instead of overflowing.
+    fn timeout_huge<S: Backend>() {
+        let sem = S::create(0).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                sem.post().unwrap();
+                thread::sleep(Duration::from_millis(20));
+                sem.post().unwrap();
+            });
+            sem.wait_timeout(Duration::MAX).unwrap();
+            let far = SystemTime::now() + Duration::from_secs(100 * 365 * 24 * 3600);
+            sem.timedwait(far).unwrap();
+        });
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn timeout_huge_posix() {
+        timeout_huge::<Semaphore>();
+    }
+
+    #[test]
+    fn timeout_huge_futex() {
+        timeout_huge::<FutexSemaphore>();
+    }
+
+    fn cross_thread<S: Backend>() {
+        let sem = S::create(0).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                sem.post().unwrap();
+            });
+            sem.wait_timeout(Duration::from_secs(10)).unwrap();
+        });
+    }
+
+    #[test]
+    fn cross_thread_posix() {
+        cross_thread::<Semaphore>();
+    }
+
+    #[test]
+    fn cross_thread_futex() {
+        cross_thread::<FutexSemaphore>();
+    }
+
+    /// Many threads passing tokens around, no token may get lost or duplicated.
+    fn contended<S: Backend>() {
+        const THREADS: u32 = 8;
+        const ROUNDS: u32 = 10_000;
+        let sem = S::create(2).unwrap();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        sem.wait();
+                        sem.post().unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(2, sem.value());
+    }
+
+    #[test]
+    fn contended_posix() {
+        contended::<Semaphore>();
+    }
+
+    #[test]
+    fn contended_futex() {
+        contended::<FutexSemaphore>();
+    }
+
+    fn interrupted<S: Backend>() {
+        let sem = S::create(0).unwrap();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(50));
+                done.store(true, Ordering::Release);
+                sem.post().unwrap();
+            });
+            // Signals don't end the wait early
+            interrupt(|| sem.wait());
+            assert!(done.load(Ordering::Acquire));
+            sem.post().unwrap();
+            interrupt(|| sem.wait_timeout(Duration::from_secs(10))).unwrap();
+        });
+    }
+
+    #[test]
+    fn interrupted_posix() {
+        interrupted::<Semaphore>();
+    }
+
+    #[test]
+    fn interrupted_futex() {
+        interrupted::<FutexSemaphore>();
+    }
+
+    #[test]
+    fn post_many() {
+        let sem = FutexSemaphore::new(0).unwrap();
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| sem.wait());
+            }
+            while sem.waiters() < 3 {
+                thread::sleep(Duration::from_millis(1));
+            }
+            sem.post_many(4).unwrap();
+        });
+        assert_eq!(1, sem.value());
+        assert_eq!(0, sem.waiters());
+        sem.post_many(Semaphore::MAX_VALUE).unwrap_err();
+        assert_eq!(1, sem.value());
+        sem.post_many(0).unwrap();
+    }
+
+    #[test]
+    fn monotonic() {
+        let sem = FutexSemaphore::new(0).unwrap();
+        let past = timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait_monotonic(past));
+        let soon = SystemClock.monotonic_after(Duration::from_millis(10));
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait_monotonic(soon));
+    }
+
+    static WORK_AVAILABLE: FutexSemaphore = FutexSemaphore::const_new(0);
+    static STARTED_FULL: FutexSemaphore = FutexSemaphore::const_new(2);
+    static SIGNALLED: FutexSemaphore = FutexSemaphore::const_new(0);
+
+    #[test]
+    fn static_threads() {
+        STARTED_FULL.trywait().unwrap();
+        STARTED_FULL.wait();
+        STARTED_FULL.trywait().unwrap_err();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        WORK_AVAILABLE.wait();
+                    }
+                });
+            }
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        WORK_AVAILABLE.post().unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(0, WORK_AVAILABLE.value());
+    }
+
+    extern "C" fn post_signalled(_: c_int) {
+        // Don't let a failing futex call change the errno of the interrupted code
+        let errno = unsafe { *libc::__errno_location() };
+        let _ = SIGNALLED.post();
+        unsafe { *libc::__errno_location() = errno };
+    }
+
+    #[test]
+    fn static_signal_handler() {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = post_signalled as *const () as libc::sighandler_t;
+            assert_eq!(0, libc::sigaction(libc::SIGUSR2, &action, ptr::null_mut()));
+        }
+        thread::scope(|s| {
+            let waiter = s.spawn(|| SIGNALLED.wait_timeout(Duration::from_secs(10)));
+            while SIGNALLED.waiters() == 0 && !waiter.is_finished() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(0, unsafe { libc::raise(libc::SIGUSR2) });
+            waiter.join().unwrap().unwrap();
+        });
+        assert_eq!(0, SIGNALLED.value());
+    }
+
+    #[test]
+    fn shared_fork() {
+        let size = mem::size_of::<FutexSemaphore>();
+        unsafe {
+            let mem = libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(libc::MAP_FAILED, mem);
+            let place = mem as *mut FutexSemaphore;
+            ptr::write(place, FutexSemaphore::new_shared(0).unwrap());
+            let sem = &*place;
+            assert!(sem.is_process_shared());
+            let child = fork(|| {
+                thread::sleep(Duration::from_millis(10));
+                sem.post().unwrap();
+                sem.post().unwrap();
+            });
+            sem.wait();
+            sem.wait_timeout(Duration::from_secs(10)).unwrap();
+            child.join();
+            assert_eq!(0, sem.value());
+            libc::munmap(mem, size);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Flow control between producers and consumers.
+
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use many::value_max;
+use {NoToken, Semaphore, WaitError};
+
+/// A counter of items that blocks both when empty and when full.
+///
+/// [`release`][Gate::release] adds an item, waiting while there are already `capacity` of them.
+/// [`acquire`][Gate::acquire] takes one out, waiting while there are none. This is the classic
+/// pair of semaphores, one counting the items and the other the free space.
+///
+/// The two semaphores always add up to the capacity (except for the moment inside one of the
+/// operations). Once an operation took its token from one of them, nothing can panic or fail
+/// before it posts the other one, so the pair doesn't get out of sync.
+pub struct Gate {
+    filled: Semaphore,
+    empty: Semaphore,
+    capacity: u32,
+}
+
+impl Gate {
+    /// Creates an empty gate for up to `capacity` items.
+    pub fn new(capacity: u32) -> Result<Self, Error> {
+        if capacity > value_max() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Capacity too large for a semaphore"));
+        }
+        Ok(Gate {
+            filled: Semaphore::anonymous(0)?,
+            empty: Semaphore::anonymous(capacity)?,
+            capacity,
+        })
+    }
+
+    /// Finishes an operation by posting the other semaphore, after the token was taken.
+    fn pass(taken: &Semaphore, to: &Semaphore) {
+        // Both of them together never hold more than the capacity, which fits. But if someone
+        // manages to overflow anyway, put the token back instead of losing it.
+        if to.post().is_err() {
+            let _ = taken.post();
+        }
+    }
+
+    /// Takes an item out, waiting for one to be there.
+    pub fn acquire(&self) {
+        self.filled.wait();
+        Self::pass(&self.filled, &self.empty);
+    }
+
+    /// Takes an item out, if there's one.
+    pub fn try_acquire(&self) -> Result<(), NoToken> {
+        self.filled.trywait()?;
+        Self::pass(&self.filled, &self.empty);
+        Ok(())
+    }
+
+    /// Takes an item out, waiting for one at most for the given time.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.filled.wait_timeout(timeout)?;
+        Self::pass(&self.filled, &self.empty);
+        Ok(())
+    }
+
+    /// Adds an item, waiting for space.
+    pub fn release(&self) {
+        self.empty.wait();
+        Self::pass(&self.empty, &self.filled);
+    }
+
+    /// Adds an item, if there's space for it.
+    pub fn try_release(&self) -> Result<(), NoToken> {
+        self.empty.trywait()?;
+        Self::pass(&self.empty, &self.filled);
+        Ok(())
+    }
+
+    /// Adds an item, waiting for space at most for the given time.
+    pub fn release_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.empty.wait_timeout(timeout)?;
+        Self::pass(&self.empty, &self.filled);
+        Ok(())
+    }
+
+    /// The number of items in the gate.
+    pub fn len(&self) -> u32 {
+        self.filled.value().max(0) as u32
+    }
+
+    /// Are there no items in the gate?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The maximum number of items.
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+
+    use super::*;
+
+    const CAPACITY: u32 = 4;
+
+    #[test]
+    fn both_ends() {
+        let gate = Gate::new(2).unwrap();
+        assert!(gate.is_empty());
+        let timeout = Duration::from_millis(1);
+        gate.try_acquire().unwrap_err();
+        assert_eq!(Err(WaitError::TimedOut), gate.acquire_timeout(timeout));
+        gate.release();
+        gate.try_release().unwrap();
+        gate.try_release().unwrap_err();
+        assert_eq!(Err(WaitError::TimedOut), gate.release_timeout(timeout));
+        assert_eq!(2, gate.len());
+        gate.acquire_timeout(timeout).unwrap();
+        gate.release_timeout(timeout).unwrap();
+        gate.try_acquire().unwrap();
+        gate.acquire();
+        assert!(gate.is_empty());
+        assert_eq!(2, gate.capacity());
+    }
+
+    #[test]
+    fn producer_blocks_at_capacity() {
+        const ITEMS: u32 = 20;
+        let gate = Gate::new(CAPACITY).unwrap();
+        let produced = AtomicU32::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..ITEMS {
+                    gate.release();
+                    produced.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+            for consumed in 0..ITEMS {
+                let full = CAPACITY.min(ITEMS - consumed);
+                while produced.load(Ordering::SeqCst) - consumed < full {
+                    thread::yield_now();
+                }
+                // A slow consumer, the producer could go on if it wasn't blocked
+                thread::sleep(Duration::from_millis(2));
+                assert_eq!(full, produced.load(Ordering::SeqCst) - consumed);
+                gate.acquire();
+            }
+        });
+        assert!(gate.is_empty());
+        assert_eq!(CAPACITY as libc::c_int, gate.empty.value());
+    }
+}
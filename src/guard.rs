@@ -0,0 +1,253 @@
+//! Scoped access to a token, returned when done.
+
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+use backend::Backend;
+use shadow::{Shadow, UNTRACKED};
+use {Overflow, SemaphoreSlot, WaitError};
+
+/// A token taken from a semaphore, posted back on drop.
+///
+/// Returned by [`access`][SemaphoreSlot::access] and friends. The token goes back exactly once,
+/// even if the code holding the guard panics or returns early.
+///
+/// Unlike a mutex guard, it may be sent to and dropped in another thread. A semaphore doesn't
+/// care which thread posts.
+#[must_use = "The token is returned right away if the guard is dropped"]
+pub struct SemaphoreGuard<'a> {
+    backend: Backend<'a>,
+    shadow: &'a Shadow,
+}
+
+impl<'a> SemaphoreGuard<'a> {
+    /// Wraps a token already taken.
+    pub(crate) fn held(backend: Backend<'a>, shadow: &'a Shadow) -> Self {
+        SemaphoreGuard { backend, shadow }
+    }
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        // We took the token, so there should be room for it, unless someone posts more than they
+        // took. Don't panic in drop because of that in production, and never while unwinding
+        // already, as that would abort.
+        let result = self.backend.post();
+        if result.is_ok() {
+            self.shadow.posted(1);
+        }
+        debug_assert!(result.is_ok() || thread::panicking(), "Overflow returning a token");
+    }
+}
+
+/// A token taken from a semaphore, with the choice to keep or return it made later.
+///
+/// Dropping it posts the token back, like [`SemaphoreGuard`]. But it's possible to consume it
+/// for good with [`forget`][Token::forget], or return it early and see if that worked with
+/// [`repost`][Token::repost].
+#[must_use = "The token is returned right away if dropped"]
+pub struct Token<'a> {
+    backend: Backend<'a>,
+    shadow: &'a Shadow,
+}
+
+impl<'a> Token<'a> {
+    /// Wraps a token already taken.
+    pub(crate) fn held(backend: Backend<'a>, shadow: &'a Shadow) -> Self {
+        Token { backend, shadow }
+    }
+
+    /// Consumes the token, it never gets posted back.
+    pub fn forget(self) {
+        mem::forget(self);
+    }
+
+    /// Posts the token back now.
+    pub fn repost(self) -> Result<(), Overflow> {
+        let (backend, shadow) = (self.backend, self.shadow);
+        mem::forget(self);
+        backend.post()?;
+        shadow.posted(1);
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Token<'a> {
+    fn drop(&mut self) {
+        let result = self.backend.post();
+        if result.is_ok() {
+            self.shadow.posted(1);
+        }
+        debug_assert!(result.is_ok() || thread::panicking(), "Overflow returning a token");
+    }
+}
+
+impl SemaphoreSlot {
+    /// Waits for a token and returns a guard that posts it back when dropped.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        self.backend().access()
+    }
+
+    /// Waits for a token, leaving the decision to keep or return it for later.
+    pub fn take(&self) -> Token<'_> {
+        self.backend().take()
+    }
+
+    /// Takes a token if available, returning a guard that posts it back when dropped.
+    pub fn try_access(&self) -> Option<SemaphoreGuard<'_>> {
+        self.backend().try_access()
+    }
+
+    /// Waits for a token at most for the given time, returning a guard that posts it back when
+    /// dropped.
+    pub fn access_timeout(&self, timeout: Duration) -> Result<SemaphoreGuard<'_>, WaitError> {
+        self.backend().access_timeout(timeout)
+    }
+
+    /// Runs the closure while holding a token.
+    ///
+    /// The token is posted back afterwards, even if the closure panics (the panic then
+    /// continues).
+    pub fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        self.backend().with(f)
+    }
+
+    /// Runs the closure while holding a token, if one is available right away.
+    pub fn try_with<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        self.backend().try_with(f)
+    }
+
+    /// Runs the closure while holding a token, waiting for it at most for the given time.
+    pub fn with_timeout<R, F: FnOnce() -> R>(&self, timeout: Duration, f: F)
+        -> Result<R, WaitError>
+    {
+        self.backend().with_timeout(timeout, f)
+    }
+}
+
+impl<'a> Backend<'a> {
+    pub(crate) fn access(self) -> SemaphoreGuard<'a> {
+        self.wait();
+        SemaphoreGuard::held(self, &UNTRACKED)
+    }
+
+    pub(crate) fn take(self) -> Token<'a> {
+        self.wait();
+        Token::held(self, &UNTRACKED)
+    }
+
+    pub(crate) fn try_access(self) -> Option<SemaphoreGuard<'a>> {
+        self.trywait().ok().map(|()| SemaphoreGuard::held(self, &UNTRACKED))
+    }
+
+    pub(crate) fn access_timeout(self, timeout: Duration) -> Result<SemaphoreGuard<'a>, WaitError> {
+        self.wait_timeout(timeout)?;
+        Ok(SemaphoreGuard::held(self, &UNTRACKED))
+    }
+
+    pub(crate) fn with<R, F: FnOnce() -> R>(self, f: F) -> R {
+        let _guard = self.access();
+        f()
+    }
+
+    pub(crate) fn try_with<R, F: FnOnce() -> R>(self, f: F) -> Option<R> {
+        let _guard = self.try_access()?;
+        Some(f())
+    }
+
+    pub(crate) fn with_timeout<R, F: FnOnce() -> R>(self, timeout: Duration, f: F)
+        -> Result<R, WaitError>
+    {
+        let _guard = self.access_timeout(timeout)?;
+        Ok(f())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+    use many::value_max;
+    use Semaphore;
+
+    #[test]
+    fn returned() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        {
+            let _guard = sem.access();
+            assert_eq!(0, sem.value());
+            assert!(sem.try_access().is_none());
+            let timeout = Duration::from_millis(10);
+            assert_eq!(WaitError::TimedOut, sem.access_timeout(timeout).err().unwrap());
+        }
+        assert_eq!(1, sem.value());
+        drop(sem.try_access().unwrap());
+        drop(sem.access_timeout(Duration::from_secs(1)).unwrap());
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn returned_on_panic() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let result = panic::catch_unwind(|| {
+            let _guard = sem.access();
+            panic!("Inside the guarded section");
+        });
+        assert!(result.is_err());
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn with() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        assert_eq!(0, sem.with(|| sem.value()));
+        assert_eq!(Some(0), sem.try_with(|| sem.value()));
+        let nested = sem.with(|| sem.with_timeout(Duration::from_millis(10), || ()));
+        assert_eq!(Err(WaitError::TimedOut), nested);
+        assert_eq!(None, sem.with(|| sem.try_with(|| ())));
+        assert_eq!(Ok(1), sem.with_timeout(Duration::from_secs(1), || 1));
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn with_panic() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let result = panic::catch_unwind(|| sem.with(|| panic!("In the closure")));
+        assert!(result.is_err());
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn overflow_while_unwinding() {
+        let sem = Semaphore::anonymous(value_max()).unwrap();
+        let result = panic::catch_unwind(|| {
+            sem.with(|| {
+                // Someone misbehaves and fills the semaphore, returning the token fails
+                sem.post().unwrap();
+                panic!("In the closure");
+            })
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn token_exits() {
+        let sem = Semaphore::anonymous(2).unwrap();
+        drop(sem.take());
+        assert_eq!(2, sem.value());
+        let token = sem.take();
+        assert_eq!(1, sem.value());
+        token.repost().unwrap();
+        assert_eq!(2, sem.value());
+        sem.take().forget();
+        assert_eq!(1, sem.value());
+        let result = panic::catch_unwind(|| {
+            let _token = sem.take();
+            panic!("Inspecting the work");
+        });
+        assert!(result.is_err());
+        assert_eq!(1, sem.value());
+    }
+}
@@ -0,0 +1,197 @@
+//! A lock-free histogram of the wait latencies.
+//!
+//! The buckets are log-linear, like in HDR histograms: each power of two of microseconds is split
+//! into [`SUB_BUCKETS`] equal buckets. That keeps the error of any percentile under 1/8 of the
+//! value, with a fixed array of atomic counters the waiters just increment.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const SUB_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BITS;
+const BUCKETS: usize = ((64 - SUB_BITS as usize) + 1) * SUB_BUCKETS as usize;
+
+fn index(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exp = 63 - micros.leading_zeros();
+    let sub = (micros >> (exp - SUB_BITS)) & (SUB_BUCKETS - 1);
+    ((exp - SUB_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+/// The largest value falling into the bucket.
+fn upper_bound(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let exp = index / SUB_BUCKETS + u64::from(SUB_BITS) - 1;
+    let sub = index % SUB_BUCKETS;
+    let width = 1u64 << (exp - u64::from(SUB_BITS));
+    // Summed in this order so the very last bucket doesn't overflow
+    (1u64 << exp) - 1 + (sub + 1) * width
+}
+
+pub(crate) struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Histogram {
+    pub(crate) fn new() -> Self {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u128::from(u64::MAX)) as u64;
+        self.buckets[index(micros)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> HistogramSnapshot {
+        let counts = self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).collect();
+        HistogramSnapshot {
+            counts,
+            max: self.max.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Zeroes the histogram, each bucket on its own.
+    pub(crate) fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The latencies of the blocking waits of a [`Semaphore`][::Semaphore] at one moment.
+///
+/// Only the waits that actually blocked and got a token are recorded, the ones finding a token
+/// right away and the ones timing out are not (see [`MetricsSnapshot`][::MetricsSnapshot] for
+/// counting those). The resolution is a microsecond and the percentiles are within 1/8 of the
+/// real value, rounded up.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct HistogramSnapshot {
+    counts: Box<[u64]>,
+    max: u64,
+}
+
+impl HistogramSnapshot {
+    /// How many waits were recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The latency not exceeded by the given fraction of the waits.
+    ///
+    /// Zero if nothing was recorded.
+    ///
+    /// # Panics
+    ///
+    /// If the fraction is not between 0 and 1.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        assert!((0.0..=1.0).contains(&fraction), "Percentile {} out of range", fraction);
+        let count = self.count();
+        if count == 0 {
+            return Duration::from_micros(0);
+        }
+        let rank = ((fraction * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, &bucket) in self.counts.iter().enumerate() {
+            seen += bucket;
+            if seen >= rank {
+                return Duration::from_micros(upper_bound(index).min(self.max));
+            }
+        }
+        self.max()
+    }
+
+    /// The median latency.
+    pub fn p50(&self) -> Duration {
+        self.percentile(0.5)
+    }
+
+    /// The latency 95% of the waits fit in.
+    pub fn p95(&self) -> Duration {
+        self.percentile(0.95)
+    }
+
+    /// The latency 99% of the waits fit in.
+    pub fn p99(&self) -> Duration {
+        self.percentile(0.99)
+    }
+
+    /// The longest latency, exact.
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use Semaphore;
+
+    #[test]
+    fn buckets() {
+        let mut last = None;
+        for micros in (0..10_000).chain([u64::MAX / 2, u64::MAX - 1, u64::MAX].iter().cloned()) {
+            let index = index(micros);
+            assert!(index < BUCKETS);
+            assert!(micros <= upper_bound(index), "{} in {}", micros, index);
+            // Within 1/8, rounding up
+            assert!(upper_bound(index) - micros <= micros / SUB_BUCKETS, "{}", micros);
+            assert!(last.is_none_or(|last| last <= index));
+            last = Some(index);
+        }
+    }
+
+    #[test]
+    fn empty() {
+        let snapshot = Histogram::new().snapshot();
+        assert_eq!(0, snapshot.count());
+        assert_eq!(Duration::from_micros(0), snapshot.p99());
+        assert_eq!(Duration::from_micros(0), snapshot.max());
+    }
+
+    #[test]
+    fn injected_waits() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        // Not blocking, not recorded
+        sem.wait();
+        let delays = [10, 10, 10, 10, 10, 10, 10, 10, 10, 100];
+        for &delay in &delays {
+            thread::scope(|s| {
+                s.spawn(|| {
+                    thread::sleep(Duration::from_millis(delay));
+                    sem.post().unwrap();
+                });
+                sem.wait();
+            });
+        }
+        let histogram = sem.latency_histogram();
+        assert_eq!(10, histogram.count());
+        let p50 = histogram.p50();
+        // The sleep starts a bit before the wait
+        assert!(p50 >= Duration::from_millis(5) && p50 < Duration::from_millis(50), "{:?}", p50);
+        let p95 = histogram.p95();
+        assert!(p95 >= Duration::from_millis(90) && p95 < Duration::from_secs(1), "{:?}", p95);
+        assert_eq!(histogram.max(), histogram.p99());
+        assert!(histogram.max() >= Duration::from_millis(90));
+        sem.reset_latency_histogram();
+        assert_eq!(0, sem.latency_histogram().count());
+    }
+}
@@ -0,0 +1,144 @@
+//! Semaphores stored inline in their handle's allocation.
+
+use std::io::Error;
+use std::marker::PhantomPinned;
+#[cfg(not(target_vendor = "apple"))]
+use std::mem;
+use std::pin::Pin;
+#[cfg(not(target_vendor = "apple"))]
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+#[cfg(not(target_vendor = "apple"))]
+use libc;
+
+#[cfg(not(target_vendor = "apple"))]
+use {init, SemaphoreSlot};
+#[cfg(target_vendor = "apple")]
+use Semaphore;
+use SemaphoreRef;
+
+/// A private semaphore living directly inside the structure, with no extra allocation.
+///
+/// [`Semaphore::anonymous`][::Semaphore::anonymous] allocates the `sem_t` separately, so an
+/// `Arc<Semaphore>` means two allocations and a pointer chase on each operation. This one
+/// lives wherever it is placed. As a `sem_t` must not move once initialized, it is only ever
+/// handed out pinned, either in a [`Box`] or an [`Arc`].
+///
+/// Darwin has no `sem_init`, so there this holds a [`Semaphore`][::Semaphore] created by
+/// `anonymous` and saves nothing.
+///
+/// All the operations are available through [`semaphore`][InlineSemaphore::semaphore].
+pub struct InlineSemaphore {
+    #[cfg(not(target_vendor = "apple"))]
+    slot: SemaphoreSlot,
+    #[cfg(not(target_vendor = "apple"))]
+    initialized: bool,
+    #[cfg(target_vendor = "apple")]
+    fallback: Option<Semaphore>,
+    _pin: PhantomPinned,
+}
+
+impl InlineSemaphore {
+    fn uninitialized() -> Self {
+        InlineSemaphore {
+            #[cfg(not(target_vendor = "apple"))]
+            slot: unsafe { mem::zeroed() },
+            #[cfg(not(target_vendor = "apple"))]
+            initialized: false,
+            #[cfg(target_vendor = "apple")]
+            fallback: None,
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Initializes the semaphore in its final place.
+    #[cfg(not(target_vendor = "apple"))]
+    fn init(&mut self, value: u32) -> Result<(), Error> {
+        unsafe { init(self.slot.as_ptr(), false, value)? };
+        self.initialized = true;
+        Ok(())
+    }
+
+    /// Creates the semaphore it stands for.
+    #[cfg(target_vendor = "apple")]
+    fn init(&mut self, value: u32) -> Result<(), Error> {
+        self.fallback = Some(Semaphore::anonymous(value)?);
+        Ok(())
+    }
+
+    /// Creates the semaphore in its own box.
+    pub fn boxed(value: u32) -> Result<Pin<Box<Self>>, Error> {
+        let mut me = Box::new(Self::uninitialized());
+        me.init(value)?;
+        Ok(Box::into_pin(me))
+    }
+
+    /// Creates the semaphore inside an [`Arc`], ready for sharing between threads.
+    pub fn arc(value: u32) -> Result<Pin<Arc<Self>>, Error> {
+        let mut me = Arc::new(Self::uninitialized());
+        Arc::get_mut(&mut me).expect("Fresh Arc is unique").init(value)?;
+        Ok(unsafe { Pin::new_unchecked(me) })
+    }
+
+    /// A borrowed view of the semaphore.
+    ///
+    /// Not called `as_ref`, as that of the [`Pin`] around would shadow it.
+    #[cfg(not(target_vendor = "apple"))]
+    pub fn semaphore(&self) -> SemaphoreRef<'_> {
+        unsafe { SemaphoreRef::from_ptr(NonNull::new_unchecked(self.slot.as_ptr())) }
+    }
+
+    /// A borrowed view of the semaphore.
+    #[cfg(target_vendor = "apple")]
+    pub fn semaphore(&self) -> SemaphoreRef<'_> {
+        // Only handed out after init
+        self.fallback.as_ref().expect("Uninitialized semaphore").as_ref()
+    }
+}
+
+#[cfg(not(target_vendor = "apple"))]
+impl Drop for InlineSemaphore {
+    fn drop(&mut self) {
+        if self.initialized {
+            // Best effort, like with Semaphore
+            unsafe { libc::sem_destroy(self.slot.as_ptr()) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn arc_threads() {
+        let sem = InlineSemaphore::arc(0).unwrap();
+        let poster = thread::spawn({
+            let sem = Pin::clone(&sem);
+            move || {
+                sem.semaphore().post().unwrap();
+                sem.semaphore().post().unwrap();
+            }
+        });
+        sem.semaphore().wait();
+        sem.semaphore().wait();
+        poster.join().unwrap();
+        assert_eq!(0, sem.semaphore().value());
+    }
+
+    #[test]
+    fn boxed() {
+        let sem = InlineSemaphore::boxed(1).unwrap();
+        let sem = sem.semaphore();
+        sem.trywait().unwrap();
+        sem.trywait().unwrap_err();
+    }
+
+    #[test]
+    fn too_large() {
+        assert!(InlineSemaphore::boxed(u32::MAX).is_err());
+    }
+}
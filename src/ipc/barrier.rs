@@ -0,0 +1,252 @@
+//! The cross-process barrier.
+
+use std::io::Error;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use super::Shared;
+use {init, SemaphoreSlot, WaitError};
+
+/// Tells if this waiter was the one to complete the generation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BarrierWaitResult(bool);
+
+impl BarrierWaitResult {
+    /// Was this the last one to arrive?
+    ///
+    /// Exactly one of the waiters of each generation is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+/// A barrier usable by multiple processes (or threads).
+///
+/// The waiters block until `n` of them arrive, then all get released together and the barrier
+/// can be used again. It uses the two-phase turnstile construction, so nobody can get to the
+/// next generation while someone is still leaving the previous one.
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes.
+#[repr(C)]
+pub struct Barrier {
+    lock: SemaphoreSlot,
+    arrive: SemaphoreSlot,
+    leave: SemaphoreSlot,
+    n: u32,
+    count: AtomicU32,
+    generation: AtomicU32,
+}
+
+impl Barrier {
+    /// Creates the barrier in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is zero.
+    pub fn anonymous_shared(n: u32) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, n)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the barrier in the provided memory.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is zero.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, n: u32) -> Result<&'a Self, Error> {
+        assert!(n > 0, "Barrier for nobody");
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).lock).cast(), true, 1)?;
+        if let Err(e) = init(ptr::addr_of_mut!((*place).arrive).cast(), true, 0) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).lock).cast());
+            return Err(e);
+        }
+        if let Err(e) = init(ptr::addr_of_mut!((*place).leave).cast(), true, 0) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).lock).cast());
+            libc::sem_destroy(ptr::addr_of_mut!((*place).arrive).cast());
+            return Err(e);
+        }
+        ptr::addr_of_mut!((*place).n).write(n);
+        ptr::addr_of_mut!((*place).count).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).generation).write(AtomicU32::new(0));
+        Ok(&*place)
+    }
+
+    /// Views a barrier initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a barrier initialized by [`init_at`][Barrier::init_at] that
+    /// stays valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// Registers the arrival, releasing everyone if this is the last one.
+    ///
+    /// Returns the generation we arrived at and if we are the leader.
+    fn arrive(&self) -> (u32, bool) {
+        self.lock.wait();
+        let generation = self.generation.load(Ordering::Relaxed);
+        let count = self.count.load(Ordering::Relaxed) + 1;
+        self.count.store(count, Ordering::Relaxed);
+        let leader = count == self.n;
+        if leader {
+            self.generation.store(generation.wrapping_add(1), Ordering::Relaxed);
+            self.arrive.post_many(self.n).expect("Overflow releasing a barrier");
+        }
+        let _ = self.lock.post();
+        (generation, leader)
+    }
+
+    /// Waits for everyone to get through the arrival phase, so the barrier is ready for reuse.
+    fn leave(&self, leader: bool) -> BarrierWaitResult {
+        self.lock.wait();
+        let count = self.count.load(Ordering::Relaxed) - 1;
+        self.count.store(count, Ordering::Relaxed);
+        if count == 0 {
+            self.leave.post_many(self.n).expect("Overflow releasing a barrier");
+        }
+        let _ = self.lock.post();
+        self.leave.wait();
+        BarrierWaitResult(leader)
+    }
+
+    /// Waits until `n` waiters arrive.
+    pub fn wait(&self) -> BarrierWaitResult {
+        let (_, leader) = self.arrive();
+        self.arrive.wait();
+        self.leave(leader)
+    }
+
+    /// Waits until `n` waiters arrive, but at most for the given time.
+    ///
+    /// On timeout, this waiter withdraws its arrival, as if it never came. The others keep
+    /// waiting and the barrier needs one more waiter (possibly this one again) to release them.
+    /// If the barrier got released just as the time ran out, this succeeds instead.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<BarrierWaitResult, WaitError> {
+        let deadline = Instant::now().checked_add(timeout);
+        let (generation, leader) = self.arrive();
+        let result = match deadline {
+            Some(deadline) => self.arrive.wait_deadline(deadline),
+            None => {
+                self.arrive.wait();
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            self.lock.wait();
+            if self.generation.load(Ordering::Relaxed) == generation {
+                // Not released yet, withdraw. There are fewer than n waiters, so no token for
+                // us got posted.
+                let count = self.count.load(Ordering::Relaxed) - 1;
+                self.count.store(count, Ordering::Relaxed);
+                let _ = self.lock.post();
+                return Err(e);
+            }
+            let _ = self.lock.post();
+            // Released meanwhile, our token is waiting for us
+            self.arrive.wait();
+        }
+        Ok(self.leave(leader))
+    }
+}
+
+impl Drop for Barrier {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.lock.as_ptr());
+            libc::sem_destroy(self.arrive.as_ptr());
+            libc::sem_destroy(self.leave.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    use super::*;
+    use ipc::Mutex;
+    use test_util::fork;
+
+    const GENERATIONS: u64 = 100;
+
+    #[test]
+    fn threads() {
+        const THREADS: usize = 4;
+        let barrier = Barrier::anonymous_shared(THREADS as u32).unwrap();
+        let arrived = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for generation in 0..GENERATIONS as usize {
+                        let before = arrived.fetch_add(1, Ordering::Relaxed) + 1;
+                        assert!(before > generation * THREADS);
+                        assert!(before <= (generation + 1) * THREADS);
+                        if barrier.wait().is_leader() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                        assert!(arrived.load(Ordering::Relaxed) >= (generation + 1) * THREADS);
+                    }
+                });
+            }
+        });
+        assert_eq!(GENERATIONS as usize, leaders.into_inner());
+    }
+
+    #[test]
+    fn processes() {
+        const PROCS: u64 = 3;
+        let barrier = Barrier::anonymous_shared(PROCS as u32).unwrap();
+        let arrived = Mutex::anonymous_shared(0u64).unwrap();
+        let run = || {
+            for generation in 0..GENERATIONS {
+                let before = {
+                    let mut arrived = arrived.lock();
+                    *arrived += 1;
+                    *arrived
+                };
+                assert!(before > generation * PROCS);
+                assert!(before <= (generation + 1) * PROCS);
+                barrier.wait();
+                assert!(*arrived.lock() >= (generation + 1) * PROCS);
+            }
+        };
+        let children = (1..PROCS).map(|_| fork(run)).collect::<Vec<_>>();
+        run();
+        for child in children {
+            child.join();
+        }
+        assert_eq!(GENERATIONS * PROCS, *arrived.lock());
+    }
+
+    #[test]
+    fn timeout_withdraws() {
+        let barrier = Barrier::anonymous_shared(2).unwrap();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(WaitError::TimedOut, barrier.wait_timeout(timeout).unwrap_err());
+        assert_eq!(0, barrier.count.load(Ordering::Relaxed));
+        // Still usable with the full count
+        thread::scope(|s| {
+            let other = s.spawn(|| barrier.wait().is_leader());
+            let leader = barrier.wait_timeout(Duration::from_secs(10)).unwrap().is_leader();
+            assert!(leader != other.join().unwrap());
+        });
+    }
+}
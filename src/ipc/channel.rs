@@ -0,0 +1,396 @@
+//! A bounded channel between processes.
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use mapped::{self, INIT_TIMEOUT};
+use {init, NoToken, SemaphoreSlot, WaitError};
+
+// The mapping of a fresh file starts zeroed, not ready
+const READY: u32 = 1;
+
+/// The start of the mapping, followed by the ring of values.
+#[repr(C)]
+struct Header {
+    empty: SemaphoreSlot,
+    filled: SemaphoreSlot,
+    send_lock: SemaphoreSlot,
+    recv_lock: SemaphoreSlot,
+    head: AtomicU32,
+    tail: AtomicU32,
+    capacity: u32,
+    value_size: u32,
+    state: AtomicU32,
+}
+
+/// The mapping holding the channel, unmapped when the last handle in this process goes away.
+struct Ring<T> {
+    header: NonNull<Header>,
+    len: usize,
+    // Destroy the semaphores too, not only unmap
+    owned: bool,
+    _values: PhantomData<T>,
+}
+
+impl<T: Copy> Ring<T> {
+    fn values_offset() -> usize {
+        let align = mem::align_of::<T>();
+        mem::size_of::<Header>().div_ceil(align) * align
+    }
+
+    fn len(capacity: u32) -> usize {
+        Self::values_offset() + mem::size_of::<T>() * capacity as usize
+    }
+
+    fn map(fd: RawFd, len: usize) -> Result<NonNull<Header>, Error> {
+        let flags = if fd == -1 {
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS
+        } else {
+            libc::MAP_SHARED
+        };
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let mem = unsafe { libc::mmap(ptr::null_mut(), len, prot, flags, fd, 0) };
+        if mem == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(NonNull::new(mem as *mut Header).expect("mmap returned NULL"))
+    }
+
+    /// Maps and initializes a new channel.
+    fn create(fd: RawFd, capacity: u32) -> Result<Self, Error> {
+        assert!(capacity > 0, "Channel with no room");
+        assert!(mem::align_of::<T>() <= 4096, "Too large alignment");
+        let value_size = u32::try_from(mem::size_of::<T>())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Too large values"))?;
+        let len = Self::len(capacity);
+        if fd != -1 && unsafe { libc::ftruncate(fd, len as _) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        let header = Self::map(fd, len)?;
+        let mut ring = Ring {
+            header,
+            len,
+            // Nothing to destroy until it's initialized
+            owned: false,
+            _values: PhantomData,
+        };
+        unsafe {
+            let h = header.as_ptr();
+            let sems = [
+                (ptr::addr_of_mut!((*h).empty), capacity),
+                (ptr::addr_of_mut!((*h).filled), 0),
+                (ptr::addr_of_mut!((*h).send_lock), 1),
+                (ptr::addr_of_mut!((*h).recv_lock), 1),
+            ];
+            for (i, &(sem, value)) in sems.iter().enumerate() {
+                if let Err(e) = init(sem.cast(), true, value) {
+                    for &(sem, _) in &sems[..i] {
+                        libc::sem_destroy(sem.cast());
+                    }
+                    return Err(e);
+                }
+            }
+            ptr::addr_of_mut!((*h).head).write(AtomicU32::new(0));
+            ptr::addr_of_mut!((*h).tail).write(AtomicU32::new(0));
+            ptr::addr_of_mut!((*h).capacity).write(capacity);
+            ptr::addr_of_mut!((*h).value_size).write(value_size);
+            (*ptr::addr_of!((*h).state)).store(READY, Ordering::Release);
+        }
+        // Anonymous ones are ours, the ones in a file belong to everyone using the file
+        ring.owned = fd == -1;
+        Ok(ring)
+    }
+
+    /// Maps a channel created by someone else.
+    fn open(fd: RawFd) -> Result<Self, Error> {
+        let deadline = Instant::now() + INIT_TIMEOUT;
+        let min = Self::len(1);
+        mapped::wait_size(fd, min, deadline)?;
+        let len = mapped::size(fd)? as usize;
+        let header = Self::map(fd, len)?;
+        let ring = Ring {
+            header,
+            len,
+            owned: false,
+            _values: PhantomData,
+        };
+        let mut attempt = 0;
+        while ring.header().state.load(Ordering::Acquire) != READY {
+            let what = "Channel was never initialized";
+            mapped::backoff(&mut attempt, Some(deadline), what)?;
+        }
+        let header = ring.header();
+        let matches = header.value_size as usize == mem::size_of::<T>()
+            && header.capacity > 0
+            && Self::len(header.capacity) == len;
+        if !matches {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a channel of this type"));
+        }
+        Ok(ring)
+    }
+
+    fn header(&self) -> &Header {
+        unsafe { self.header.as_ref() }
+    }
+
+    fn value(&self, idx: u32) -> *mut T {
+        let values = unsafe { (self.header.as_ptr() as *mut u8).add(Self::values_offset()) };
+        unsafe { (values as *mut T).add(idx as usize) }
+    }
+
+    /// Moves one position forward on the ring.
+    fn advance(&self, pos: &AtomicU32) -> u32 {
+        let idx = pos.load(Ordering::Relaxed);
+        pos.store((idx + 1) % self.header().capacity, Ordering::Relaxed);
+        idx
+    }
+
+    /// Puts the value in, after getting a free place.
+    fn push(&self, value: T) {
+        let header = self.header();
+        header.send_lock.wait();
+        let idx = self.advance(&header.tail);
+        unsafe { self.value(idx).write_volatile(value) };
+        let _ = header.send_lock.post();
+        let _ = header.filled.post();
+    }
+
+    /// Takes a value out, after making sure one is there.
+    fn pop(&self) -> T {
+        let header = self.header();
+        header.recv_lock.wait();
+        let idx = self.advance(&header.head);
+        let value = unsafe { self.value(idx).read_volatile() };
+        let _ = header.recv_lock.post();
+        let _ = header.empty.post();
+        value
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.owned {
+                let header = self.header.as_ref();
+                for sem in &[&header.empty, &header.filled, &header.send_lock, &header.recv_lock] {
+                    libc::sem_destroy(sem.as_ptr());
+                }
+            }
+            libc::munmap(self.header.as_ptr() as *mut _, self.len);
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for Ring<T> {}
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+/// The sending side of a [`channel`].
+///
+/// It can be cloned, and it can be used by multiple processes at once (for example, by a
+/// forked child).
+pub struct Sender<T> {
+    ring: Arc<Ring<T>>,
+}
+
+// Manual impls, derive would require T: Clone
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            ring: Arc::clone(&self.ring),
+        }
+    }
+}
+
+impl<T: Copy> Sender<T> {
+    /// Sends the value, waiting for a free place in the channel.
+    pub fn send(&self, value: T) {
+        self.ring.header().empty.wait();
+        self.ring.push(value);
+    }
+
+    /// Sends the value if there's room in the channel.
+    pub fn try_send(&self, value: T) -> Result<(), NoToken> {
+        self.ring.header().empty.trywait()?;
+        self.ring.push(value);
+        Ok(())
+    }
+
+    /// Sends the value, waiting for a free place at most for the given time.
+    pub fn send_timeout(&self, value: T, timeout: Duration) -> Result<(), WaitError> {
+        self.ring.header().empty.wait_timeout(timeout)?;
+        self.ring.push(value);
+        Ok(())
+    }
+}
+
+/// The receiving side of a [`channel`].
+///
+/// Like the [`Sender`], it can be cloned and used by multiple processes at once.
+pub struct Receiver<T> {
+    ring: Arc<Ring<T>>,
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Receiver {
+            ring: Arc::clone(&self.ring),
+        }
+    }
+}
+
+impl<T: Copy> Receiver<T> {
+    /// Receives a value, waiting for one to arrive.
+    pub fn recv(&self) -> T {
+        self.ring.header().filled.wait();
+        self.ring.pop()
+    }
+
+    /// Receives a value if one is waiting in the channel.
+    pub fn try_recv(&self) -> Result<T, NoToken> {
+        self.ring.header().filled.trywait()?;
+        Ok(self.ring.pop())
+    }
+
+    /// Receives a value, waiting for it at most for the given time.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, WaitError> {
+        self.ring.header().filled.wait_timeout(timeout)?;
+        Ok(self.ring.pop())
+    }
+}
+
+fn split<T>(ring: Ring<T>) -> (Sender<T>, Receiver<T>) {
+    let ring = Arc::new(ring);
+    let sender = Sender {
+        ring: Arc::clone(&ring),
+    };
+    (sender, Receiver { ring })
+}
+
+/// Creates a channel for up to `capacity` values in a shared anonymous mapping.
+///
+/// The channel is shared with child processes forked after this call. The values are copied
+/// bit by bit into the shared memory, so they should be plain data, meaningful in the other
+/// process too (eg. no references or pointers). There's no tracking of disconnection, a receiver
+/// waits for new values even if all the senders are gone.
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn channel<T: Copy>(capacity: u32) -> Result<(Sender<T>, Receiver<T>), Error> {
+    Ring::create(-1, capacity).map(split)
+}
+
+/// Creates a channel in a freshly created file, like a shared memory object or a memfd.
+///
+/// The file is resized to fit the channel. Other processes open the channel with
+/// [`channel_open_fd`]. The file descriptor doesn't need to stay open.
+///
+/// # Panics
+///
+/// If `capacity` is zero.
+pub fn channel_create_fd<T: Copy>(fd: BorrowedFd<'_>, capacity: u32)
+    -> Result<(Sender<T>, Receiver<T>), Error>
+{
+    Ring::create(fd.as_raw_fd(), capacity).map(split)
+}
+
+/// Opens a channel created by [`channel_create_fd`], possibly in another process.
+///
+/// The type of the values must be the same as the creator used, but this is checked only
+/// approximately (by their size).
+pub fn channel_open_fd<T: Copy>(fd: BorrowedFd<'_>) -> Result<(Sender<T>, Receiver<T>), Error> {
+    Ring::open(fd.as_raw_fd()).map(split)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::os::unix::io::AsFd;
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+
+    #[test]
+    fn bounded() {
+        let (sender, receiver) = channel::<u16>(2).unwrap();
+        sender.send(1);
+        sender.try_send(2).unwrap();
+        sender.try_send(3).unwrap_err();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(Err(WaitError::TimedOut), sender.send_timeout(3, timeout));
+        assert_eq!(1, receiver.recv());
+        sender.send_timeout(3, timeout).unwrap();
+        assert_eq!(Ok(2), receiver.try_recv());
+        assert_eq!(Ok(3), receiver.recv_timeout(timeout));
+        receiver.try_recv().unwrap_err();
+        assert_eq!(Err(WaitError::TimedOut), receiver.recv_timeout(timeout));
+    }
+
+    #[test]
+    fn threads() {
+        let (sender, receiver) = channel::<(u8, u32)>(3).unwrap();
+        thread::scope(|s| {
+            for id in 0..4 {
+                let sender = sender.clone();
+                s.spawn(move || {
+                    for i in 0..1000 {
+                        sender.send((id, i));
+                    }
+                });
+            }
+            let mut last = [None; 4];
+            for _ in 0..4000 {
+                let (id, i) = receiver.recv();
+                // Each sender's values come in order
+                assert!(last[id as usize] < Some(i));
+                last[id as usize] = Some(i);
+            }
+        });
+    }
+
+    #[test]
+    fn processes() {
+        const COUNT: u64 = 1_000_000;
+        let (sender, receiver) = channel::<u64>(1024).unwrap();
+        let child = fork(|| {
+            for i in 0..COUNT {
+                sender.send(i);
+            }
+        });
+        let sum = (0..COUNT).map(|_| receiver.recv()).sum::<u64>();
+        child.join();
+        assert_eq!(COUNT * (COUNT - 1) / 2, sum);
+    }
+
+    #[test]
+    fn through_file() {
+        let dir = tempfile::tempdir_in("/dev/shm").unwrap();
+        let path = dir.path().join("channel");
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .unwrap()
+        };
+        let file = open();
+        let (sender, _) = channel_create_fd::<u32>(file.as_fd(), 4).unwrap();
+        drop(file);
+        let (_, receiver) = channel_open_fd::<u32>(open().as_fd()).unwrap();
+        sender.send(42);
+        assert_eq!(42, receiver.recv());
+        channel_open_fd::<u64>(open().as_fd()).err().unwrap();
+    }
+}
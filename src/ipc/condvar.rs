@@ -0,0 +1,260 @@
+//! The cross-process condition variable.
+
+use std::io::Error;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use super::{MutexGuard, Shared};
+use {init, SemaphoreSlot};
+
+/// Tells if a [`wait_timeout`][Condvar::wait_timeout] returned because the time ran out.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WaitTimeoutResult(bool);
+
+impl WaitTimeoutResult {
+    /// Did the wait time out (instead of being notified)?
+    pub fn timed_out(&self) -> bool {
+        self.0
+    }
+}
+
+/// A condition variable usable by multiple processes, together with an [`ipc::Mutex`].
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes. It's built
+/// from semaphores in the classic way: a count of the waiters and a handshake, so a notification
+/// wakes only the threads already waiting and the notifier waits until they have taken it (a
+/// notification with nobody waiting is lost, not saved for later).
+///
+/// A waiter may wake up even if not notified (for example, the notification was meant for
+/// another waiter that timed out at the same moment, or another thread changed the state before
+/// the waiter got the mutex back). Always check the condition in a loop.
+///
+/// [`ipc::Mutex`]: super::Mutex
+#[repr(C)]
+pub struct Condvar {
+    lock: SemaphoreSlot,
+    wakeup: SemaphoreSlot,
+    handshake: SemaphoreSlot,
+    waiters: AtomicU32,
+}
+
+impl Condvar {
+    /// Creates the condition variable in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared() -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the condition variable in the provided memory.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>) -> Result<&'a Self, Error> {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).lock).cast(), true, 1)?;
+        if let Err(e) = init(ptr::addr_of_mut!((*place).wakeup).cast(), true, 0) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).lock).cast());
+            return Err(e);
+        }
+        if let Err(e) = init(ptr::addr_of_mut!((*place).handshake).cast(), true, 0) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).lock).cast());
+            libc::sem_destroy(ptr::addr_of_mut!((*place).wakeup).cast());
+            return Err(e);
+        }
+        ptr::addr_of_mut!((*place).waiters).write(AtomicU32::new(0));
+        Ok(&*place)
+    }
+
+    /// Views a condition variable initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a condition variable initialized by
+    /// [`init_at`][Condvar::init_at] that stays valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    fn enqueue(&self) {
+        self.lock.wait();
+        self.waiters.fetch_add(1, Ordering::Relaxed);
+        let _ = self.lock.post();
+    }
+
+    /// Unlocks the mutex, waits for a notification and locks it again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        self.enqueue();
+        drop(guard);
+        self.wakeup.wait();
+        let _ = self.handshake.post();
+        mutex.lock()
+    }
+
+    /// Like [`wait`][Condvar::wait], but waits for the notification at most for the given time.
+    ///
+    /// Getting the mutex back may take longer.
+    pub fn wait_timeout<'a, T>(&self, guard: MutexGuard<'a, T>, timeout: Duration)
+        -> (MutexGuard<'a, T>, WaitTimeoutResult)
+    {
+        let mutex = guard.mutex;
+        self.enqueue();
+        drop(guard);
+        let woken = match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.wakeup.wait_deadline(deadline).is_ok(),
+            None => {
+                self.wakeup.wait();
+                true
+            },
+        };
+        let timed_out = !woken && !self.withdraw();
+        if !timed_out {
+            let _ = self.handshake.post();
+        }
+        (mutex.lock(), WaitTimeoutResult(timed_out))
+    }
+
+    /// Stops waiting after a timeout.
+    ///
+    /// A notifier may be in the middle of waking us (or someone else) up while holding the lock,
+    /// so we can't just wait for it. Either we get the lock (and nobody waits for a handshake) or
+    /// a wakeup token, whichever comes first. Returns if it was the token, which needs a
+    /// handshake.
+    fn withdraw(&self) -> bool {
+        loop {
+            if self.wakeup.trywait().is_ok() {
+                return true;
+            }
+            if self.lock.trywait().is_ok() {
+                self.waiters.fetch_sub(1, Ordering::Relaxed);
+                let _ = self.lock.post();
+                return false;
+            }
+            thread::yield_now();
+        }
+    }
+
+    /// Wakes up one of the waiting threads, if any.
+    pub fn notify_one(&self) {
+        self.lock.wait();
+        let waiters = self.waiters.load(Ordering::Relaxed);
+        if waiters > 0 {
+            self.waiters.store(waiters - 1, Ordering::Relaxed);
+            let _ = self.wakeup.post();
+            self.handshake.wait();
+        }
+        let _ = self.lock.post();
+    }
+
+    /// Wakes up all the threads waiting right now.
+    pub fn notify_all(&self) {
+        self.lock.wait();
+        let waiters = self.waiters.swap(0, Ordering::Relaxed);
+        self.wakeup.post_many(waiters).expect("Overflow waking waiters");
+        for _ in 0..waiters {
+            self.handshake.wait();
+        }
+        let _ = self.lock.post();
+    }
+}
+
+impl Drop for Condvar {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.lock.as_ptr());
+            libc::sem_destroy(self.wakeup.as_ptr());
+            libc::sem_destroy(self.handshake.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipc::Mutex;
+    use test_util::fork;
+
+    #[test]
+    fn nothing_banked() {
+        let mutex = Mutex::anonymous_shared(()).unwrap();
+        let condvar = Condvar::anonymous_shared().unwrap();
+        condvar.notify_one();
+        condvar.notify_all();
+        let guard = mutex.lock();
+        let (_guard, result) = condvar.wait_timeout(guard, Duration::from_millis(10));
+        assert!(result.timed_out());
+        assert_eq!(0, condvar.waiters.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn notify_all_threads() {
+        const THREADS: u32 = 4;
+        let mutex = Mutex::anonymous_shared((false, 0)).unwrap();
+        let condvar = Condvar::anonymous_shared().unwrap();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    let mut guard = mutex.lock();
+                    guard.1 += 1;
+                    while !guard.0 {
+                        guard = condvar.wait(guard);
+                    }
+                });
+            }
+            loop {
+                let mut guard = mutex.lock();
+                if guard.1 == THREADS {
+                    guard.0 = true;
+                    break;
+                }
+                drop(guard);
+                thread::yield_now();
+            }
+            condvar.notify_all();
+        });
+    }
+
+    #[test]
+    fn producer_consumer() {
+        const ITEMS: u32 = 2000;
+        // Produced, consumed
+        let mutex = Mutex::anonymous_shared((0u32, 0u32)).unwrap();
+        let available = Condvar::anonymous_shared().unwrap();
+        let room = Condvar::anonymous_shared().unwrap();
+        let producer = fork(|| {
+            for _ in 0..ITEMS {
+                let mut guard = mutex.lock();
+                // Keep at most a few items in the queue, so both sides wait a lot
+                while guard.0 - guard.1 >= 3 {
+                    guard = room.wait(guard);
+                }
+                guard.0 += 1;
+                drop(guard);
+                available.notify_one();
+            }
+        });
+        for _ in 0..ITEMS {
+            let mut guard = mutex.lock();
+            while guard.0 == guard.1 {
+                let (g, _) = available.wait_timeout(guard, Duration::from_millis(1));
+                guard = g;
+            }
+            guard.1 += 1;
+            drop(guard);
+            room.notify_one();
+        }
+        producer.join();
+        assert_eq!((ITEMS, ITEMS), *mutex.lock());
+    }
+}
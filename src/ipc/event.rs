@@ -0,0 +1,224 @@
+//! The cross-process manual-reset event.
+
+use std::io::Error;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use super::Shared;
+use {init, SemaphoreSlot, WaitError};
+
+/// An event that, once set, lets everyone through until reset.
+///
+/// [`set`][Event::set] wakes all the current waiters and the waits that come later return right
+/// away, until someone calls [`reset`][Event::reset].
+///
+/// A waiter released by `set` returns even if the event gets reset before it gets a chance to
+/// run (it was released while the event was set). Only waits starting after the reset block.
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes.
+#[repr(C)]
+pub struct Event {
+    lock: SemaphoreSlot,
+    gate: SemaphoreSlot,
+    waiters: AtomicU32,
+    // Bumped by each set, so a waiter that timed out can tell if it got released meanwhile
+    generation: AtomicU32,
+    set: AtomicBool,
+}
+
+impl Event {
+    /// Creates the event in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(initially_set: bool) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, initially_set)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the event in the provided memory.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, initially_set: bool)
+        -> Result<&'a Self, Error>
+    {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).lock).cast(), true, 1)?;
+        if let Err(e) = init(ptr::addr_of_mut!((*place).gate).cast(), true, 0) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).lock).cast());
+            return Err(e);
+        }
+        ptr::addr_of_mut!((*place).waiters).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).generation).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).set).write(AtomicBool::new(initially_set));
+        Ok(&*place)
+    }
+
+    /// Views an event initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to an event initialized by [`init_at`][Event::init_at] that stays
+    /// valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// Sets the event, releasing all the waiters.
+    pub fn set(&self) {
+        self.lock.wait();
+        self.set.store(true, Ordering::Release);
+        let waiters = self.waiters.swap(0, Ordering::Relaxed);
+        if waiters > 0 {
+            self.generation.fetch_add(1, Ordering::Relaxed);
+            self.gate.post_many(waiters).expect("Overflow setting an event");
+        }
+        let _ = self.lock.post();
+    }
+
+    /// Resets the event, so new waiters block again.
+    pub fn reset(&self) {
+        self.lock.wait();
+        self.set.store(false, Ordering::Release);
+        let _ = self.lock.post();
+    }
+
+    /// Is the event set right now?
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Registers as a waiter, unless the event is set.
+    ///
+    /// Returns the generation to wait for, if we need to wait.
+    fn enqueue(&self) -> Option<u32> {
+        if self.is_set() {
+            return None;
+        }
+        self.lock.wait();
+        let generation = if self.is_set() {
+            None
+        } else {
+            self.waiters.fetch_add(1, Ordering::Relaxed);
+            Some(self.generation.load(Ordering::Relaxed))
+        };
+        let _ = self.lock.post();
+        generation
+    }
+
+    /// Waits until the event is set.
+    pub fn wait(&self) {
+        if self.enqueue().is_some() {
+            self.gate.wait();
+        }
+    }
+
+    /// Waits until the event is set, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        let generation = match self.enqueue() {
+            Some(generation) => generation,
+            None => return Ok(()),
+        };
+        let result = match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.gate.wait_deadline(deadline),
+            None => {
+                self.gate.wait();
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            self.lock.wait();
+            let released = self.generation.load(Ordering::Relaxed) != generation;
+            if !released {
+                self.waiters.fetch_sub(1, Ordering::Relaxed);
+            }
+            let _ = self.lock.post();
+            if !released {
+                return Err(e);
+            }
+            // Set just now, there's a token for us
+            self.gate.wait();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.lock.as_ptr());
+            libc::sem_destroy(self.gate.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+
+    const WAITERS: u32 = 4;
+
+    fn wait_for_waiters(event: &Event, count: u32) {
+        while event.waiters.load(Ordering::Relaxed) != count {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn set_releases_all() {
+        let event = Event::anonymous_shared(false).unwrap();
+        thread::scope(|s| {
+            for _ in 0..WAITERS {
+                s.spawn(|| event.wait());
+            }
+            wait_for_waiters(&event, WAITERS);
+            event.set();
+        });
+        assert!(event.is_set());
+        event.wait();
+        event.wait_timeout(Duration::from_millis(1)).unwrap();
+
+        event.reset();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(Err(WaitError::TimedOut), event.wait_timeout(timeout));
+        assert_eq!(0, event.waiters.load(Ordering::Relaxed));
+        assert_eq!(0, event.gate.value());
+    }
+
+    #[test]
+    fn released_before_reset() {
+        let event = Event::anonymous_shared(false).unwrap();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| event.wait_timeout(Duration::from_secs(10)));
+            wait_for_waiters(&event, 1);
+            event.set();
+            event.reset();
+            assert_eq!(Ok(()), waiter.join().unwrap());
+        });
+        assert!(!event.is_set());
+    }
+
+    #[test]
+    fn processes() {
+        let event = Event::anonymous_shared(false).unwrap();
+        let children = (0..WAITERS).map(|_| fork(|| event.wait())).collect::<Vec<_>>();
+        wait_for_waiters(&event, WAITERS);
+        event.set();
+        for child in children {
+            child.join();
+        }
+        event.reset();
+        fork(|| assert!(event.wait_timeout(Duration::from_millis(10)).is_err())).join();
+    }
+}
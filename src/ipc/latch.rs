@@ -0,0 +1,228 @@
+//! The cross-process countdown latch.
+
+use std::io::Error;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use super::Shared;
+use {init, SemaphoreSlot, WaitError};
+
+/// A one-shot gate that opens after being counted down enough times.
+///
+/// Waiters block until the count reaches zero. From then on, the latch stays open and all the
+/// waits return right away. Counting down an open latch does nothing.
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes.
+#[repr(C)]
+pub struct Latch {
+    lock: SemaphoreSlot,
+    gate: SemaphoreSlot,
+    count: AtomicU32,
+    waiters: AtomicU32,
+    open: AtomicBool,
+}
+
+impl Latch {
+    /// Creates the latch in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(count: u32) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, count)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the latch in the provided memory.
+    ///
+    /// A latch with zero count starts open.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, count: u32) -> Result<&'a Self, Error> {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).lock).cast(), true, 1)?;
+        if let Err(e) = init(ptr::addr_of_mut!((*place).gate).cast(), true, 0) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).lock).cast());
+            return Err(e);
+        }
+        ptr::addr_of_mut!((*place).count).write(AtomicU32::new(count));
+        ptr::addr_of_mut!((*place).waiters).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).open).write(AtomicBool::new(count == 0));
+        Ok(&*place)
+    }
+
+    /// Views a latch initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a latch initialized by [`init_at`][Latch::init_at] that stays
+    /// valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// Decrements the count, opening the latch when it reaches zero.
+    ///
+    /// Does nothing if the latch is already open.
+    pub fn count_down(&self) {
+        self.lock.wait();
+        match self.count.load(Ordering::Relaxed) {
+            0 => (),
+            1 => {
+                self.count.store(0, Ordering::Relaxed);
+                self.open.store(true, Ordering::Release);
+                // Let through everyone already waiting, the new ones see the flag
+                let waiters = self.waiters.swap(0, Ordering::Relaxed);
+                self.gate.post_many(waiters).expect("Overflow opening a latch");
+            },
+            count => self.count.store(count - 1, Ordering::Relaxed),
+        }
+        let _ = self.lock.post();
+    }
+
+    /// The remaining count.
+    pub fn count(&self) -> u32 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Has the count reached zero?
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Acquire)
+    }
+
+    /// Registers as a waiter, unless already open.
+    ///
+    /// Returns if we need to wait.
+    fn enqueue(&self) -> bool {
+        if self.is_open() {
+            return false;
+        }
+        self.lock.wait();
+        let wait = !self.is_open();
+        if wait {
+            self.waiters.fetch_add(1, Ordering::Relaxed);
+        }
+        let _ = self.lock.post();
+        wait
+    }
+
+    /// Waits until the latch opens.
+    pub fn wait(&self) {
+        if self.enqueue() {
+            self.gate.wait();
+        }
+    }
+
+    /// Waits until the latch opens, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        if !self.enqueue() {
+            return Ok(());
+        }
+        let result = match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.gate.wait_deadline(deadline),
+            None => {
+                self.gate.wait();
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            self.lock.wait();
+            let open = self.is_open();
+            if !open {
+                self.waiters.fetch_sub(1, Ordering::Relaxed);
+            }
+            let _ = self.lock.post();
+            if !open {
+                return Err(e);
+            }
+            // Opened just now, there's a token for us
+            self.gate.wait();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Latch {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.lock.as_ptr());
+            libc::sem_destroy(self.gate.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+    use Semaphore;
+
+    const WORKERS: u32 = 3;
+    const WAITERS: usize = 4;
+
+    #[test]
+    fn threads() {
+        let latch = Latch::anonymous_shared(WORKERS).unwrap();
+        let released = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..WAITERS {
+                s.spawn(|| {
+                    latch.wait();
+                    released.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+            for _ in 0..WORKERS {
+                s.spawn(|| latch.count_down());
+            }
+        });
+        assert_eq!(WAITERS, released.into_inner());
+        assert_eq!(0, latch.gate.value());
+        // Stays open
+        latch.count_down();
+        latch.wait();
+        latch.wait_timeout(Duration::from_millis(1)).unwrap();
+        assert!(latch.is_open());
+    }
+
+    #[test]
+    fn processes() {
+        let latch = Latch::anonymous_shared(WORKERS).unwrap();
+        let released = Semaphore::anonymous_shared(0).unwrap();
+        let waiters = (0..WAITERS)
+            .map(|_| {
+                fork(|| {
+                    latch.wait();
+                    released.post().unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        let workers = (0..WORKERS).map(|_| fork(|| latch.count_down())).collect::<Vec<_>>();
+        for child in waiters.into_iter().chain(workers) {
+            child.join();
+        }
+        assert_eq!(WAITERS as libc::c_int, released.value());
+        assert_eq!(0, latch.gate.value());
+    }
+
+    #[test]
+    fn timeout() {
+        let latch = Latch::anonymous_shared(1).unwrap();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(Err(WaitError::TimedOut), latch.wait_timeout(timeout));
+        assert_eq!(0, latch.waiters.load(Ordering::Relaxed));
+        assert_eq!(1, latch.count());
+        latch.count_down();
+        latch.wait_timeout(timeout).unwrap();
+        assert_eq!(0, latch.gate.value());
+    }
+}
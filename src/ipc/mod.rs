@@ -0,0 +1,40 @@
+//! Locks and other synchronization primitives for multiple processes, built out of
+//! process-shared semaphores.
+//!
+//! They are plain structures that live wherever the processes can all see them: in a shared
+//! anonymous mapping inherited over `fork` (see [`Shared`]), or in memory the processes map some
+//! other way (like a file or a shared memory object). The data protected by the locks lives
+//! right next to the semaphores, so it must be something that makes sense in another process
+//! too (no pointers, no file descriptors and such).
+//!
+//! None of the locks are robust. If a process dies while holding one, it stays locked (only
+//! [`ReentrantMutex`] allows detecting and fixing that). Similarly, [`RobustSemaphore`] can get
+//! back the tokens of dead processes.
+
+mod barrier;
+mod channel;
+mod condvar;
+mod event;
+mod latch;
+mod mutex;
+mod once;
+mod poison;
+mod raw_mutex;
+mod reentrant;
+mod robust;
+mod rwlock;
+mod shared;
+
+pub use self::barrier::{Barrier, BarrierWaitResult};
+pub use self::channel::{channel, channel_create_fd, channel_open_fd, Receiver, Sender};
+pub use self::condvar::{Condvar, WaitTimeoutResult};
+pub use self::event::Event;
+pub use self::latch::Latch;
+pub use self::mutex::{Mutex, MutexGuard};
+pub use self::once::{Once, Poisoned};
+pub use self::poison::{PoisonGuard, PoisoningSemaphore};
+pub use self::raw_mutex::RawSemMutex;
+pub use self::reentrant::{ReentrantMutex, ReentrantMutexGuard};
+pub use self::robust::{RobustGuard, RobustSemaphore, MAX_HOLDERS};
+pub use self::rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use self::shared::Shared;
@@ -0,0 +1,204 @@
+//! The cross-process mutex.
+
+use std::cell::UnsafeCell;
+use std::io::Error;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::time::Duration;
+
+use libc;
+
+use super::Shared;
+use {init, SemaphoreSlot, WaitError};
+
+/// A mutex usable by multiple processes.
+///
+/// It's a process-shared semaphore with at most one token, followed by the protected data. It
+/// is placed into memory shared between the processes, either by
+/// [`anonymous_shared`][Mutex::anonymous_shared] or by [`init_at`][Mutex::init_at].
+///
+/// The guard returns the token even if the code holding it panics. But a process dying while
+/// holding the lock (crashing, getting killed) leaves it locked forever.
+#[repr(C)]
+pub struct Mutex<T = ()> {
+    sem: SemaphoreSlot,
+    data: UnsafeCell<T>,
+}
+
+impl<T> Mutex<T> {
+    /// Creates the mutex in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(value: T) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, value)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the mutex in the provided memory.
+    ///
+    /// Other processes mapping the same memory access it through [`from_ptr`][Mutex::from_ptr].
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be aligned and valid for writes of the mutex for the lifetime `'a`. The
+    /// memory must not move or be used in other ways during that time.
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, value: T) -> Result<&'a Self, Error> {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).sem).cast(), true, 1)?;
+        ptr::addr_of_mut!((*place).data).write(UnsafeCell::new(value));
+        Ok(&*place)
+    }
+
+    /// Views a mutex initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a mutex initialized by [`init_at`][Mutex::init_at] that stays
+    /// valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    fn guard(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            mutex: self,
+            _data: PhantomData,
+        }
+    }
+
+    /// Locks the mutex, waiting for it as long as needed.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.sem.wait();
+        self.guard()
+    }
+
+    /// Locks the mutex if it's not locked already.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.sem.trywait().ok().map(|()| self.guard())
+    }
+
+    /// Locks the mutex, waiting at most for the given time.
+    pub fn lock_timeout(&self, timeout: Duration) -> Result<MutexGuard<'_, T>, WaitError> {
+        self.sem.wait_timeout(timeout)?;
+        Ok(self.guard())
+    }
+
+    /// The protected data, no locking needed thanks to the exclusive borrow.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        unsafe { libc::sem_destroy(self.sem.as_ptr()) };
+    }
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// The lock of a [`Mutex`], unlocking it on drop.
+#[must_use = "The mutex is unlocked right away if the guard is dropped"]
+pub struct MutexGuard<'a, T: 'a> {
+    pub(super) mutex: &'a Mutex<T>,
+    // Sharing the guard shares the data
+    _data: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // We hold the only token, there's room for it
+        let _ = self.mutex.sem.post();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+
+    #[test]
+    fn exclusive() {
+        let mutex = Mutex::anonymous_shared(1).unwrap();
+        let mut guard = mutex.lock();
+        *guard += 1;
+        assert!(mutex.try_lock().is_none());
+        let timeout = Duration::from_millis(10);
+        assert_eq!(WaitError::TimedOut, mutex.lock_timeout(timeout).err().unwrap());
+        drop(guard);
+        assert_eq!(2, *mutex.try_lock().unwrap());
+        assert_eq!(2, *mutex.lock_timeout(Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn unlocked_on_panic() {
+        let mutex = Mutex::anonymous_shared(()).unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = mutex.lock();
+            panic!("Holding the lock");
+        }));
+        assert!(result.is_err());
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn threads() {
+        let mutex = Mutex::anonymous_shared(0u64).unwrap();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *mutex.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(4000, *mutex.lock());
+    }
+
+    #[test]
+    fn processes() {
+        const CHILDREN: u64 = 4;
+        const ROUNDS: u64 = 1000;
+        let mutex = Mutex::anonymous_shared(0u64).unwrap();
+        let children = (0..CHILDREN)
+            .map(|_| {
+                fork(|| {
+                    for _ in 0..ROUNDS {
+                        let mut guard = mutex.lock();
+                        // Read and write separately, to give a chance for lost updates
+                        let value = *guard;
+                        thread::yield_now();
+                        *guard = value + 1;
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for child in children {
+            child.join();
+        }
+        assert_eq!(CHILDREN * ROUNDS, *mutex.lock());
+    }
+}
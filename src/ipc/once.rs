@@ -0,0 +1,241 @@
+//! One-time initialization shared between processes.
+
+use std::error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::Error;
+use std::mem;
+use std::process;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::time::Duration;
+
+use libc;
+
+use super::Shared;
+use {init, SemaphoreSlot};
+
+const INCOMPLETE: u32 = 0;
+const RUNNING: u32 = 1;
+const COMPLETE: u32 = 2;
+const POISONED: u32 = 3;
+
+/// How often the waiters check if the process running the initialization is still alive.
+const CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The initialization of a [`Once`] didn't finish, because it panicked or its process died.
+///
+/// Also returned by a [`PoisoningSemaphore`][super::PoisoningSemaphore] after a token holder
+/// panicked.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Poisoned;
+
+impl Display for Poisoned {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Poisoned by an earlier failure")
+    }
+}
+
+impl error::Error for Poisoned {}
+
+impl From<Poisoned> for Error {
+    fn from(_: Poisoned) -> Error {
+        Error::other(Poisoned)
+    }
+}
+
+/// Runs an initialization exactly once, across all the processes sharing it.
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes. The first
+/// caller of [`call_once`][Once::call_once] runs its closure, the others block until it
+/// finishes.
+///
+/// If the closure panics, or its process dies in the middle, the `Once` becomes poisoned and
+/// all the current and future calls return [`Poisoned`]. The death of the process is detected by
+/// checking if its PID still exists every few tens of milliseconds. A zombie (a dead child its
+/// parent didn't reap yet) still counts as alive, and so does an unrelated process that got the
+/// same PID in the unlikely event of reuse.
+#[repr(C)]
+pub struct Once {
+    done: SemaphoreSlot,
+    state: AtomicU32,
+    runner: AtomicI32,
+}
+
+/// Poisons the once if the closure panics.
+struct Running<'a>(&'a Once);
+
+impl<'a> Drop for Running<'a> {
+    fn drop(&mut self) {
+        self.0.finish(POISONED);
+    }
+}
+
+impl Once {
+    /// Creates the once in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared() -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the once in the provided memory.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>) -> Result<&'a Self, Error> {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).done).cast(), true, 0)?;
+        ptr::addr_of_mut!((*place).state).write(AtomicU32::new(INCOMPLETE));
+        ptr::addr_of_mut!((*place).runner).write(AtomicI32::new(0));
+        Ok(&*place)
+    }
+
+    /// Views a once initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a once initialized by [`init_at`][Once::init_at] that stays
+    /// valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// Has the initialization finished successfully?
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Runs the closure, unless someone else already did or is doing so.
+    ///
+    /// In the latter case, this waits for them to finish.
+    pub fn call_once<F: FnOnce()>(&self, f: F) -> Result<(), Poisoned> {
+        let claim = self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire);
+        if claim.is_err() {
+            return self.wait();
+        }
+        self.runner.store(process::id() as _, Ordering::Relaxed);
+        let running = Running(self);
+        f();
+        mem::forget(running);
+        self.finish(COMPLETE);
+        Ok(())
+    }
+
+    fn finish(&self, state: u32) {
+        if self
+            .state
+            .compare_exchange(RUNNING, state, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            // Every waiter passes the token on, so one is enough for all of them
+            let _ = self.done.post();
+        }
+    }
+
+    /// Is the process running the initialization gone?
+    fn runner_dead(&self) -> bool {
+        let runner = self.runner.load(Ordering::Relaxed);
+        if runner == 0 || runner == process::id() as libc::pid_t {
+            // Not started yet, or it's us and we are obviously alive
+            return false;
+        }
+        let gone = unsafe { libc::kill(runner, 0) } == -1;
+        gone && Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+    }
+
+    /// Waits for someone else to finish the initialization, without running anything.
+    pub fn wait(&self) -> Result<(), Poisoned> {
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETE => return Ok(()),
+                POISONED => return Err(Poisoned),
+                RUNNING if self.runner_dead() => self.finish(POISONED),
+                _ => (),
+            }
+            if self.done.wait_timeout(CHECK_INTERVAL).is_ok() {
+                let _ = self.done.post();
+            }
+        }
+    }
+}
+
+impl Drop for Once {
+    fn drop(&mut self) {
+        unsafe { libc::sem_destroy(self.done.as_ptr()) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+    use Semaphore;
+
+    #[test]
+    fn race() {
+        let once = Once::anonymous_shared().unwrap();
+        let runs = Semaphore::anonymous_shared(0).unwrap();
+        let children = (0..5)
+            .map(|_| {
+                fork(|| {
+                    once.call_once(|| {
+                        thread::sleep(Duration::from_millis(20));
+                        runs.post().unwrap();
+                    })
+                    .unwrap();
+                    assert!(once.is_completed());
+                    assert_eq!(1, runs.value());
+                })
+            })
+            .collect::<Vec<_>>();
+        for child in children {
+            child.join();
+        }
+        assert_eq!(1, runs.value());
+        once.wait().unwrap();
+        once.call_once(|| panic!("Runs again")).unwrap();
+    }
+
+    #[test]
+    fn poisoned_by_panic() {
+        let once = Once::anonymous_shared().unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            once.call_once(|| panic!("Initialization failed"))
+        }));
+        assert!(result.is_err());
+        assert!(!once.is_completed());
+        assert_eq!(Err(Poisoned), once.call_once(|| ()));
+        assert_eq!(Err(Poisoned), once.wait());
+    }
+
+    #[test]
+    fn poisoned_by_death() {
+        let once = Once::anonymous_shared().unwrap();
+        let started = Semaphore::anonymous_shared(0).unwrap();
+        let child = fork(|| {
+            once.call_once(|| {
+                started.post().unwrap();
+                thread::sleep(Duration::from_millis(100));
+                unsafe { libc::_exit(0) };
+            })
+            .unwrap();
+        });
+        started.wait();
+        thread::scope(|s| {
+            // Reap it, or it stays around as a zombie
+            s.spawn(move || child.join());
+            assert_eq!(Err(Poisoned), once.call_once(|| ()));
+        });
+    }
+}
@@ -0,0 +1,202 @@
+//! The cross-process semaphore that gets poisoned by panicking token holders.
+
+use std::io::Error;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use libc;
+
+use super::{Poisoned, Shared};
+use {init, Overflow, SemaphoreSlot};
+
+/// A semaphore that remembers a token holder panicked.
+///
+/// Like with [`std::sync::Mutex`], a panic while holding a [`PoisonGuard`] may leave whatever
+/// the tokens protect in an inconsistent state. So the semaphore gets poisoned and the following
+/// [`access`][PoisoningSemaphore::access] and [`wait`][PoisoningSemaphore::wait] calls fail
+/// with [`Poisoned`], until someone fixes the state and calls
+/// [`clear_poison`][PoisoningSemaphore::clear_poison]. The token of the panicking holder is
+/// still returned.
+///
+/// The flag lives next to the semaphore, so all the processes sharing it see the poisoning.
+/// Only panics poison it, a process dying while holding a token doesn't (and the token is lost).
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes.
+#[repr(C)]
+pub struct PoisoningSemaphore {
+    sem: SemaphoreSlot,
+    poisoned: AtomicBool,
+}
+
+/// A token taken from a [`PoisoningSemaphore`], posted back on drop.
+///
+/// If dropped during a panic, the semaphore gets poisoned.
+#[must_use = "The token is returned right away if the guard is dropped"]
+pub struct PoisonGuard<'a> {
+    sem: &'a PoisoningSemaphore,
+}
+
+impl<'a> Drop for PoisonGuard<'a> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.sem.poisoned.store(true, Ordering::Release);
+        }
+        // Don't panic in drop, especially not while already unwinding, see SemaphoreGuard.
+        let result = self.sem.sem.post();
+        debug_assert!(result.is_ok() || thread::panicking(), "Overflow returning a token");
+    }
+}
+
+impl PoisoningSemaphore {
+    /// Creates the semaphore in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(value: u32) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, value)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the semaphore in the provided memory, not poisoned.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, value: u32) -> Result<&'a Self, Error> {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).sem).cast(), true, value)?;
+        ptr::addr_of_mut!((*place).poisoned).write(AtomicBool::new(false));
+        Ok(&*place)
+    }
+
+    /// Views a semaphore initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a semaphore initialized by
+    /// [`init_at`][PoisoningSemaphore::init_at] that stays valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// Has a token holder panicked since the poison was last cleared?
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Acquire)
+    }
+
+    /// Marks the semaphore as fine again.
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Release);
+    }
+
+    /// Waits for a token, failing if the semaphore is poisoned.
+    ///
+    /// The poisoning is checked both before and after waiting. If it happens while waiting, the
+    /// token is posted back.
+    pub fn wait(&self) -> Result<(), Poisoned> {
+        if self.is_poisoned() {
+            return Err(Poisoned);
+        }
+        self.sem.wait();
+        if self.is_poisoned() {
+            // We just took it, so there's room for it
+            let _ = self.sem.post();
+            return Err(Poisoned);
+        }
+        Ok(())
+    }
+
+    /// Returns a token taken by [`wait`][PoisoningSemaphore::wait].
+    ///
+    /// Posting doesn't poison the semaphore, only dropping a guard during a panic does.
+    pub fn post(&self) -> Result<(), Overflow> {
+        self.sem.post()
+    }
+
+    /// Waits for a token and returns a guard that posts it back when dropped.
+    ///
+    /// Fails if the semaphore is poisoned, see [`wait`][PoisoningSemaphore::wait].
+    pub fn access(&self) -> Result<PoisonGuard<'_>, Poisoned> {
+        self.wait()?;
+        Ok(PoisonGuard { sem: self })
+    }
+
+    /// Like [`access`][PoisoningSemaphore::access], but doesn't care about the poisoning.
+    pub fn access_ignore_poison(&self) -> PoisonGuard<'_> {
+        self.sem.wait();
+        PoisonGuard { sem: self }
+    }
+
+    /// The number of tokens available right now.
+    pub fn value(&self) -> u32 {
+        self.sem.value().max(0) as u32
+    }
+}
+
+impl Drop for PoisoningSemaphore {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.sem.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+
+    use super::*;
+    use test_util::fork;
+
+    fn poison(sem: &PoisoningSemaphore) {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = sem.access().unwrap();
+            panic!("Holder failed");
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn poisoned_by_panic() {
+        let sem = PoisoningSemaphore::anonymous_shared(2).unwrap();
+        assert!(!sem.is_poisoned());
+        poison(&sem);
+        assert!(sem.is_poisoned());
+        // The token came back anyway
+        assert_eq!(2, sem.value());
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(Err(Poisoned), sem.access().map(|_| ()));
+                assert_eq!(Err(Poisoned), sem.wait());
+                drop(sem.access_ignore_poison());
+            });
+        });
+        assert_eq!(2, sem.value());
+        sem.clear_poison();
+        drop(sem.access().unwrap());
+        sem.wait().unwrap();
+        sem.post().unwrap();
+        assert_eq!(2, sem.value());
+    }
+
+    #[test]
+    fn no_poison_without_panic() {
+        let sem = PoisoningSemaphore::anonymous_shared(1).unwrap();
+        drop(sem.access().unwrap());
+        assert!(!sem.is_poisoned());
+    }
+
+    #[test]
+    fn across_processes() {
+        let sem = PoisoningSemaphore::anonymous_shared(1).unwrap();
+        fork(|| poison(&sem)).join();
+        assert!(sem.is_poisoned());
+        assert_eq!(Err(Poisoned), sem.wait());
+        sem.clear_poison();
+        sem.wait().unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//! A raw cross-process mutex with a const initializer.
+
+use std::time::{Duration, Instant};
+
+use libc;
+
+use mapped::{SharedRegion, INIT_TIMEOUT};
+use SemaphoreSlot;
+
+/// A mutex without data, initialized on first use.
+///
+/// This is the building block for generic lock wrappers that need a `const` initial value. With
+/// the `lock_api` feature, it implements `lock_api::RawMutex` and `RawMutexTimed`, so
+/// `lock_api::Mutex<RawSemMutex, T>` is a mutex with data (the inherent methods are the same).
+/// `sem_init` can't run in a const context, so the semaphore is initialized by whoever locks
+/// first, using the same state machine as [`SharedRegion`][::SharedRegion]. Concurrent first
+/// uses are fine, exactly one of them initializes it.
+///
+/// Zeroed memory is a valid unlocked mutex, so it can be placed into fresh shared memory and used
+/// by multiple processes right away, without any initialization step.
+#[repr(C)]
+pub struct RawSemMutex {
+    region: SharedRegion,
+}
+
+impl RawSemMutex {
+    /// An unlocked mutex.
+    #[allow(clippy::declare_interior_mutable_const)]
+    pub const INIT: RawSemMutex = RawSemMutex {
+        region: SharedRegion::uninit(),
+    };
+
+    fn sem(&self) -> &SemaphoreSlot {
+        match self.region.ready() {
+            Some(sem) => sem,
+            None => {
+                self.region
+                    .init_once(1, INIT_TIMEOUT)
+                    .expect("Failed to initialize the mutex semaphore")
+                    .0
+            },
+        }
+    }
+
+    /// Locks the mutex, waiting as long as needed.
+    pub fn lock(&self) {
+        self.sem().wait();
+    }
+
+    /// Locks the mutex if it's not locked already.
+    pub fn try_lock(&self) -> bool {
+        self.sem().trywait().is_ok()
+    }
+
+    /// Locks the mutex, waiting at most for the given time.
+    pub fn try_lock_for(&self, timeout: Duration) -> bool {
+        match Instant::now().checked_add(timeout) {
+            Some(deadline) => self.try_lock_until(deadline),
+            None => {
+                self.lock();
+                true
+            },
+        }
+    }
+
+    /// Locks the mutex, waiting at most until the given time.
+    pub fn try_lock_until(&self, deadline: Instant) -> bool {
+        self.sem().wait_deadline(deadline).is_ok()
+    }
+
+    /// Unlocks the mutex.
+    ///
+    /// # Safety
+    ///
+    /// The mutex must be locked, by the current holder of the lock (in whatever process).
+    pub unsafe fn unlock(&self) {
+        let _ = self.sem().post();
+    }
+
+    /// Is the mutex locked right now?
+    ///
+    /// Where the value can't be read, this tries to lock it for a moment.
+    pub fn is_locked(&self) -> bool {
+        let sem = match self.region.ready() {
+            Some(sem) => sem,
+            None => return false,
+        };
+        match sem.try_value() {
+            Ok(value) => value == 0,
+            Err(_) if sem.trywait().is_ok() => {
+                let _ = sem.post();
+                false
+            },
+            Err(_) => true,
+        }
+    }
+}
+
+impl Default for RawSemMutex {
+    fn default() -> Self {
+        Self::INIT
+    }
+}
+
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawMutex for RawSemMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawSemMutex = RawSemMutex::INIT;
+
+    // A semaphore can be posted by any thread
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        RawSemMutex::lock(self)
+    }
+
+    fn try_lock(&self) -> bool {
+        RawSemMutex::try_lock(self)
+    }
+
+    unsafe fn unlock(&self) {
+        RawSemMutex::unlock(self)
+    }
+
+    fn is_locked(&self) -> bool {
+        RawSemMutex::is_locked(self)
+    }
+}
+
+#[cfg(feature = "lock_api")]
+unsafe impl lock_api::RawMutexTimed for RawSemMutex {
+    type Duration = Duration;
+    type Instant = Instant;
+
+    fn try_lock_for(&self, timeout: Duration) -> bool {
+        RawSemMutex::try_lock_for(self, timeout)
+    }
+
+    fn try_lock_until(&self, deadline: Instant) -> bool {
+        RawSemMutex::try_lock_until(self, deadline)
+    }
+}
+
+impl Drop for RawSemMutex {
+    fn drop(&mut self) {
+        if let Some(sem) = self.region.ready() {
+            unsafe { libc::sem_destroy(sem.as_ptr()) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::UnsafeCell;
+    use std::thread;
+
+    use super::*;
+    use ipc::Shared;
+    use test_util::fork;
+
+    struct Counter {
+        raw: RawSemMutex,
+        value: UnsafeCell<u64>,
+    }
+
+    unsafe impl Sync for Counter {}
+
+    impl Counter {
+        fn increment(&self) {
+            self.raw.lock();
+            unsafe {
+                // Not atomic, lost updates if the lock doesn't work
+                let value = self.value.get().read_volatile();
+                thread::yield_now();
+                self.value.get().write_volatile(value + 1);
+                self.raw.unlock();
+            }
+        }
+    }
+
+    static COUNTER: Counter = Counter {
+        raw: RawSemMutex::INIT,
+        value: UnsafeCell::new(0),
+    };
+
+    #[test]
+    fn contended_static() {
+        assert!(!COUNTER.raw.is_locked());
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        COUNTER.increment();
+                    }
+                });
+            }
+        });
+        assert_eq!(4000, unsafe { *COUNTER.value.get() });
+    }
+
+    #[test]
+    fn timed() {
+        let raw = RawSemMutex::default();
+        assert!(raw.try_lock_for(Duration::from_millis(1)));
+        assert!(raw.is_locked());
+        assert!(!raw.try_lock());
+        assert!(!raw.try_lock_for(Duration::from_millis(10)));
+        unsafe { raw.unlock() };
+        assert!(raw.try_lock());
+        unsafe { raw.unlock() };
+    }
+
+    #[cfg(feature = "lock_api")]
+    static LOCKED: lock_api::Mutex<RawSemMutex, u64> = lock_api::Mutex::const_new(
+        <RawSemMutex as lock_api::RawMutex>::INIT,
+        0,
+    );
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn lock_api_static() {
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        *LOCKED.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(4000, *LOCKED.lock());
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn lock_api_timed() {
+        let mutex = lock_api::Mutex::<RawSemMutex, u64>::new(1);
+        let guard = mutex.try_lock_for(Duration::from_millis(1)).unwrap();
+        assert!(mutex.is_locked());
+        assert!(mutex.try_lock().is_none());
+        assert!(mutex.try_lock_for(Duration::from_millis(10)).is_none());
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert!(mutex.try_lock_until(deadline).is_none());
+        drop(guard);
+        *mutex.try_lock_until(Instant::now() + Duration::from_secs(1)).unwrap() += 1;
+        assert_eq!(2, mutex.into_inner());
+    }
+
+    #[test]
+    fn zeroed_shared() {
+        // Zeroed by mmap, nobody initializes it explicitly
+        let counter = unsafe { Shared::<Counter>::new(|_| Ok(())) }.unwrap();
+        let children = (0..3)
+            .map(|_| {
+                fork(|| {
+                    for _ in 0..200 {
+                        counter.increment();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for child in children {
+            child.join();
+        }
+        assert_eq!(600, unsafe { *counter.value.get() });
+    }
+}
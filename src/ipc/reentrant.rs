@@ -0,0 +1,289 @@
+//! The cross-process mutex that can be locked repeatedly by its owner.
+
+use std::cell::UnsafeCell;
+use std::io::Error;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::process;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use libc;
+
+use super::Shared;
+use {init, SemaphoreSlot, WaitError};
+
+/// Identification of the current thread, unique within the process.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn thread_id() -> u64 {
+    // Not libc::gettid, that one is too new in glibc
+    unsafe { libc::syscall(libc::SYS_gettid) as u64 }
+}
+
+/// Identification of the current thread, unique within the process.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn thread_id() -> u64 {
+    unsafe { libc::pthread_self() as usize as u64 }
+}
+
+const NO_PID: i32 = 0;
+
+/// A mutex usable by multiple processes, that its owner can lock again without deadlocking.
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes. Next to the
+/// semaphore, it keeps the owning process and thread and how many times it's locked. Only the
+/// first lock waits on the semaphore (if someone else holds it) and the semaphore is posted
+/// when the last guard goes away.
+///
+/// As the lock may be held multiple times at once, the guards give only shared access to the
+/// data. Use a `Cell` or similar inside for mutation.
+///
+/// If the owner dies while holding the lock, it stays locked. This can be detected by checking
+/// the [`owner`][ReentrantMutex::owner] and fixed by
+/// [`force_unlock`][ReentrantMutex::force_unlock].
+#[repr(C)]
+pub struct ReentrantMutex<T = ()> {
+    sem: SemaphoreSlot,
+    owner_pid: AtomicI32,
+    owner_tid: AtomicU64,
+    depth: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+impl<T> ReentrantMutex<T> {
+    /// Creates the mutex in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(value: T) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, value)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the mutex in the provided memory.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, value: T) -> Result<&'a Self, Error> {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).sem).cast(), true, 1)?;
+        ptr::addr_of_mut!((*place).owner_pid).write(AtomicI32::new(NO_PID));
+        ptr::addr_of_mut!((*place).owner_tid).write(AtomicU64::new(0));
+        ptr::addr_of_mut!((*place).depth).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).data).write(UnsafeCell::new(value));
+        Ok(&*place)
+    }
+
+    /// Views a mutex initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a mutex initialized by [`init_at`][ReentrantMutex::init_at]
+    /// that stays valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// The process ID and thread ID of the current owner, if locked.
+    ///
+    /// The thread ID is the kernel one on Linux and `pthread_self` elsewhere.
+    pub fn owner(&self) -> Option<(libc::pid_t, u64)> {
+        match self.owner_pid.load(Ordering::Relaxed) {
+            NO_PID => None,
+            pid => Some((pid, self.owner_tid.load(Ordering::Relaxed))),
+        }
+    }
+
+    fn is_mine(&self) -> bool {
+        // Only the owner writes its own identity there and it clears it before unlocking, so
+        // seeing ourselves can't be a leftover.
+        self.owner() == Some((process::id() as libc::pid_t, thread_id()))
+    }
+
+    /// Locks it again, if already held by this thread.
+    fn relock(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        if self.is_mine() {
+            let depth = self.depth.load(Ordering::Relaxed);
+            let depth = depth.checked_add(1).expect("Locked too many times");
+            self.depth.store(depth, Ordering::Relaxed);
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+
+    /// Takes over after getting the token.
+    fn acquired(&self) -> ReentrantMutexGuard<'_, T> {
+        self.owner_tid.store(thread_id(), Ordering::Relaxed);
+        self.owner_pid.store(process::id() as _, Ordering::Relaxed);
+        self.depth.store(1, Ordering::Relaxed);
+        self.guard()
+    }
+
+    fn guard(&self) -> ReentrantMutexGuard<'_, T> {
+        ReentrantMutexGuard {
+            mutex: self,
+            _data: PhantomData,
+        }
+    }
+
+    /// Locks the mutex, waiting if someone else holds it.
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        if let Some(guard) = self.relock() {
+            return guard;
+        }
+        self.sem.wait();
+        self.acquired()
+    }
+
+    /// Locks the mutex, unless someone else holds it.
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        if let Some(guard) = self.relock() {
+            return Some(guard);
+        }
+        self.sem.trywait().ok().map(|()| self.acquired())
+    }
+
+    /// Locks the mutex, waiting at most for the given time if someone else holds it.
+    pub fn lock_timeout(&self, timeout: Duration)
+        -> Result<ReentrantMutexGuard<'_, T>, WaitError>
+    {
+        if let Some(guard) = self.relock() {
+            return Ok(guard);
+        }
+        self.sem.wait_timeout(timeout)?;
+        Ok(self.acquired())
+    }
+
+    fn release(&self) {
+        self.depth.store(0, Ordering::Relaxed);
+        self.owner_pid.store(NO_PID, Ordering::Relaxed);
+        self.owner_tid.store(0, Ordering::Relaxed);
+        // We hold the only token, there's room for it
+        let _ = self.sem.post();
+    }
+
+    /// Unlocks the mutex, no matter who holds it.
+    ///
+    /// Returns `false` (and does nothing) if it wasn't locked.
+    ///
+    /// # Safety
+    ///
+    /// The owner must be gone (eg. crashed) and must not use its guards any more.
+    pub unsafe fn force_unlock(&self) -> bool {
+        if self.owner().is_none() {
+            return false;
+        }
+        self.release();
+        true
+    }
+}
+
+impl<T> Drop for ReentrantMutex<T> {
+    fn drop(&mut self) {
+        unsafe { libc::sem_destroy(self.sem.as_ptr()) };
+    }
+}
+
+unsafe impl<T: Send> Send for ReentrantMutex<T> {}
+unsafe impl<T: Send> Sync for ReentrantMutex<T> {}
+
+/// One lock of a [`ReentrantMutex`], released on drop.
+#[must_use = "The lock is released right away if the guard is dropped"]
+pub struct ReentrantMutexGuard<'a, T: 'a> {
+    mutex: &'a ReentrantMutex<T>,
+    // The guard must stay in the owning thread, as it is identified by the thread ID
+    _data: PhantomData<*const T>,
+}
+
+impl<'a, T> Deref for ReentrantMutexGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for ReentrantMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let depth = self.mutex.depth.load(Ordering::Relaxed) - 1;
+        if depth == 0 {
+            self.mutex.release();
+        } else {
+            self.mutex.depth.store(depth, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+
+    #[test]
+    fn nested() {
+        let mutex = ReentrantMutex::anonymous_shared(Cell::new(0)).unwrap();
+        let outer = mutex.lock();
+        let inner = mutex.lock();
+        inner.set(1);
+        assert_eq!(1, outer.get());
+        assert!(mutex.try_lock().is_some());
+        assert!(mutex.lock_timeout(Duration::from_millis(1)).is_ok());
+        drop(outer);
+        assert!(mutex.owner().is_some());
+        drop(inner);
+        assert!(mutex.owner().is_none());
+        assert_eq!(1, mutex.sem.value());
+    }
+
+    #[test]
+    fn others_block() {
+        let mutex = ReentrantMutex::anonymous_shared(()).unwrap();
+        let _guard = mutex.lock();
+        assert_eq!(Some((process::id() as _, thread_id())), mutex.owner());
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert!(mutex.try_lock().is_none());
+                let timeout = Duration::from_millis(10);
+                assert_eq!(WaitError::TimedOut, mutex.lock_timeout(timeout).err().unwrap());
+            });
+        });
+        fork(|| assert!(mutex.try_lock().is_none())).join();
+    }
+
+    #[test]
+    fn depth_survives_panic() {
+        let mutex = ReentrantMutex::anonymous_shared(()).unwrap();
+        let outer = mutex.lock();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _inner = mutex.lock();
+            panic!("Nested");
+        }));
+        assert!(result.is_err());
+        assert_eq!(1, mutex.depth.load(Ordering::Relaxed));
+        drop(outer);
+        assert!(mutex.owner().is_none());
+        assert!(mutex.try_lock().is_some());
+    }
+
+    #[test]
+    fn force_unlock_dead_owner() {
+        let mutex = ReentrantMutex::anonymous_shared(()).unwrap();
+        // The child dies with the lock held
+        fork(|| std::mem::forget(mutex.lock())).join();
+        let (pid, _) = mutex.owner().unwrap();
+        assert_ne!(process::id() as libc::pid_t, pid);
+        assert!(mutex.try_lock().is_none());
+        assert!(unsafe { mutex.force_unlock() });
+        assert!(!unsafe { mutex.force_unlock() });
+        assert!(mutex.try_lock().is_some());
+    }
+}
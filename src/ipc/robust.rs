@@ -0,0 +1,262 @@
+//! The cross-process semaphore that can get back tokens of dead processes.
+
+use std::io::{Error, ErrorKind};
+use std::process;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use libc::{self, pid_t};
+
+use super::Shared;
+use {init, NoToken, SemaphoreSlot};
+
+/// How many tokens a [`RobustSemaphore`] can have.
+pub const MAX_HOLDERS: usize = 64;
+
+/// A registry slot: the generation in the high half, the PID of the holder (or 0) in the low.
+fn pack(generation: u32, pid: pid_t) -> u64 {
+    (u64::from(generation) << 32) | u64::from(pid as u32)
+}
+
+fn unpack(slot: u64) -> (u32, pid_t) {
+    ((slot >> 32) as u32, slot as u32 as pid_t)
+}
+
+/// Is the process gone for good?
+///
+/// A zombie (a dead child its parent didn't reap yet) still counts as alive.
+fn is_dead(pid: pid_t) -> Result<bool, Error> {
+    if unsafe { libc::kill(pid, 0) } == 0 {
+        return Ok(false);
+    }
+    let e = Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ESRCH) => Ok(true),
+        // Exists, only belongs to someone else
+        Some(libc::EPERM) => Ok(false),
+        _ => Err(e),
+    }
+}
+
+/// A semaphore that remembers which processes hold its tokens.
+///
+/// A process killed while holding a token of a plain semaphore takes the token with it. This one
+/// records the PID of each holder in a registry next to the semaphore, and
+/// [`recover`][RobustSemaphore::recover] posts back the tokens of processes that no longer
+/// exist. Nothing calls it automatically, it's up to the users to do so from time to time (or
+/// when they suspect a token went missing).
+///
+/// To keep the registry complete, the tokens are only ever taken and returned through
+/// [`RobustGuard`]s and there can be at most [`MAX_HOLDERS`] of them. A PID reused by an
+/// unrelated process keeps the token of the dead one lost until that process exits too.
+///
+/// Like [`Mutex`][super::Mutex], it lives in memory shared between the processes.
+#[repr(C)]
+pub struct RobustSemaphore {
+    sem: SemaphoreSlot,
+    // Bumping the generation on each release makes sure a token is returned only once, even if
+    // the release and a recovery race for it
+    holders: [AtomicU64; MAX_HOLDERS],
+}
+
+/// A token taken from a [`RobustSemaphore`], posted back on drop.
+///
+/// Don't let it cross a `fork`, the copy in the child would return the token too.
+#[must_use = "The token is returned right away if the guard is dropped"]
+pub struct RobustGuard<'a> {
+    sem: &'a RobustSemaphore,
+    slot: usize,
+    held: u64,
+}
+
+impl<'a> Drop for RobustGuard<'a> {
+    fn drop(&mut self) {
+        self.sem.release(self.slot, self.held);
+    }
+}
+
+impl RobustSemaphore {
+    /// Creates the semaphore in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(value: u32) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, value)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the semaphore in the provided memory.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the value is over [`MAX_HOLDERS`].
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, value: u32) -> Result<&'a Self, Error> {
+        if value as usize > MAX_HOLDERS {
+            return Err(Error::new(ErrorKind::InvalidInput, "Too many tokens to track"));
+        }
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).sem).cast(), true, value)?;
+        let holders = ptr::addr_of_mut!((*place).holders).cast::<AtomicU64>();
+        for i in 0..MAX_HOLDERS {
+            holders.add(i).write(AtomicU64::new(0));
+        }
+        Ok(&*place)
+    }
+
+    /// Views a semaphore initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a semaphore initialized by
+    /// [`init_at`][RobustSemaphore::init_at] that stays valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    /// Records this process as the holder of a just taken token.
+    fn register(&self) -> RobustGuard<'_> {
+        let pid = process::id() as pid_t;
+        loop {
+            for (slot, holder) in self.holders.iter().enumerate() {
+                let current = holder.load(Ordering::Acquire);
+                let (generation, owner) = unpack(current);
+                if owner != 0 {
+                    continue;
+                }
+                let held = pack(generation, pid);
+                let claim =
+                    holder.compare_exchange(current, held, Ordering::AcqRel, Ordering::Relaxed);
+                if claim.is_ok() {
+                    return RobustGuard {
+                        sem: self,
+                        slot,
+                        held,
+                    };
+                }
+            }
+            // A slot is freed before its token is posted, so there's always one for each token.
+            // We may have lost it to a racing holder only to have another freed meanwhile.
+        }
+    }
+
+    /// Frees the slot and posts its token, unless someone else already did.
+    fn release(&self, slot: usize, held: u64) -> bool {
+        let (generation, _) = unpack(held);
+        let free = pack(generation.wrapping_add(1), 0);
+        let released = self.holders[slot]
+            .compare_exchange(held, free, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok();
+        if released {
+            // Each slot stands for a token taken, there's room for it
+            let _ = self.sem.post();
+        }
+        released
+    }
+
+    /// Waits for a token and returns a guard that posts it back when dropped.
+    pub fn acquire(&self) -> RobustGuard<'_> {
+        self.sem.wait();
+        self.register()
+    }
+
+    /// Takes a token if one is available right away.
+    pub fn try_acquire(&self) -> Result<RobustGuard<'_>, NoToken> {
+        self.sem.trywait()?;
+        Ok(self.register())
+    }
+
+    /// Posts back the tokens held by processes that no longer exist.
+    ///
+    /// Returns how many tokens came back. Safe to call concurrently with the holders and other
+    /// recoveries, each token is returned exactly once.
+    pub fn recover(&self) -> Result<u32, Error> {
+        let mut recovered = 0;
+        for (slot, holder) in self.holders.iter().enumerate() {
+            let held = holder.load(Ordering::Acquire);
+            let (_, pid) = unpack(held);
+            if pid != 0 && is_dead(pid)? && self.release(slot, held) {
+                recovered += 1;
+            }
+        }
+        Ok(recovered)
+    }
+
+    /// The number of tokens available right now.
+    pub fn value(&self) -> u32 {
+        self.sem.value().max(0) as u32
+    }
+}
+
+impl Drop for RobustSemaphore {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.sem.as_ptr());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn guards() {
+        let sem = RobustSemaphore::anonymous_shared(2).unwrap();
+        let a = sem.acquire();
+        let b = sem.try_acquire().unwrap();
+        sem.try_acquire().map(|_| ()).unwrap_err();
+        assert_eq!(0, sem.recover().unwrap());
+        drop(a);
+        drop(b);
+        assert_eq!(2, sem.value());
+    }
+
+    #[test]
+    fn too_many() {
+        let e = RobustSemaphore::anonymous_shared(MAX_HOLDERS as u32 + 1).map(|_| ()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn concurrent() {
+        let sem = RobustSemaphore::anonymous_shared(3).unwrap();
+        thread::scope(|s| {
+            for _ in 0..6 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let _guard = sem.acquire();
+                        assert_eq!(0, sem.recover().unwrap());
+                    }
+                });
+            }
+        });
+        assert_eq!(3, sem.value());
+    }
+
+    #[test]
+    fn recovers_from_abort() {
+        let sem = RobustSemaphore::anonymous_shared(1).unwrap();
+        let pid = unsafe { libc::fork() };
+        if pid == 0 {
+            mem::forget(sem.acquire());
+            process::abort();
+        }
+        assert!(pid > 0);
+        let mut status = 0;
+        assert_eq!(pid, unsafe { libc::waitpid(pid, &mut status, 0) });
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(0, sem.value());
+        assert_eq!(1, sem.recover().unwrap());
+        assert_eq!(0, sem.recover().unwrap());
+        assert_eq!(1, sem.value());
+        drop(sem.acquire());
+    }
+}
@@ -0,0 +1,329 @@
+//! The cross-process readers-writer lock.
+
+use std::cell::UnsafeCell;
+use std::io::Error;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use libc;
+
+use super::Shared;
+use {init, SemaphoreSlot, WaitError};
+
+/// Takes a token, waiting until the deadline (or forever without one).
+fn take(sem: &SemaphoreSlot, deadline: Option<Instant>) -> Result<(), WaitError> {
+    match deadline {
+        Some(deadline) => sem.wait_deadline(deadline),
+        None => {
+            sem.wait();
+            Ok(())
+        },
+    }
+}
+
+/// A readers-writer lock usable by multiple processes.
+///
+/// Any number of readers or a single writer may hold it at once. Like
+/// [`Mutex`][super::Mutex], it lives in memory shared between the processes, together with the
+/// reader count and the protected data.
+///
+/// It's built from three semaphores: one that guards the reader count, one that is held while
+/// anyone is inside and a turnstile everyone passes on the way in. A waiting writer holds the
+/// turnstile, so readers arriving after it wait for it to finish. Therefore a steady stream of
+/// readers can't starve the writers (and the writers can't starve the readers either, they go
+/// in the order they arrive at the turnstile).
+///
+/// The `try_` variants may fail spuriously when another thread is in the middle of locking or
+/// unlocking it.
+#[repr(C)]
+pub struct RwLock<T = ()> {
+    turnstile: SemaphoreSlot,
+    room: SemaphoreSlot,
+    readers_lock: SemaphoreSlot,
+    readers: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+impl<T> RwLock<T> {
+    /// Creates the lock in its own shared anonymous mapping.
+    ///
+    /// It is shared with child processes forked after this call.
+    pub fn anonymous_shared(value: T) -> Result<Shared<Self>, Error> {
+        unsafe {
+            Shared::new(|place| {
+                Self::init_at(place, value)?;
+                Ok(())
+            })
+        }
+    }
+
+    /// Initializes the lock in the provided memory.
+    ///
+    /// # Safety
+    ///
+    /// The same as [`Mutex::init_at`][super::Mutex::init_at].
+    pub unsafe fn init_at<'a>(place: NonNull<Self>, value: T) -> Result<&'a Self, Error> {
+        let place = place.as_ptr();
+        init(ptr::addr_of_mut!((*place).turnstile).cast(), true, 1)?;
+        if let Err(e) = init(ptr::addr_of_mut!((*place).room).cast(), true, 1) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).turnstile).cast());
+            return Err(e);
+        }
+        if let Err(e) = init(ptr::addr_of_mut!((*place).readers_lock).cast(), true, 1) {
+            libc::sem_destroy(ptr::addr_of_mut!((*place).turnstile).cast());
+            libc::sem_destroy(ptr::addr_of_mut!((*place).room).cast());
+            return Err(e);
+        }
+        ptr::addr_of_mut!((*place).readers).write(AtomicU32::new(0));
+        ptr::addr_of_mut!((*place).data).write(UnsafeCell::new(value));
+        Ok(&*place)
+    }
+
+    /// Views a lock initialized by someone else, possibly in another process.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to a lock initialized by [`init_at`][RwLock::init_at] that stays
+    /// valid for the lifetime `'a`.
+    pub unsafe fn from_ptr<'a>(place: NonNull<Self>) -> &'a Self {
+        &*place.as_ptr()
+    }
+
+    fn read_until(&self, deadline: Option<Instant>) -> Result<RwLockReadGuard<'_, T>, WaitError> {
+        // Just pass through, so we don't overtake a writer waiting inside
+        take(&self.turnstile, deadline)?;
+        let _ = self.turnstile.post();
+        take(&self.readers_lock, deadline)?;
+        let readers = self.readers.load(Ordering::Relaxed);
+        if readers == 0 {
+            // The first reader locks the writers out for everyone
+            if let Err(e) = take(&self.room, deadline) {
+                let _ = self.readers_lock.post();
+                return Err(e);
+            }
+        }
+        self.readers.store(readers + 1, Ordering::Relaxed);
+        let _ = self.readers_lock.post();
+        Ok(RwLockReadGuard {
+            lock: self,
+            _data: PhantomData,
+        })
+    }
+
+    fn read_unlock(&self) {
+        self.readers_lock.wait();
+        let readers = self.readers.load(Ordering::Relaxed) - 1;
+        self.readers.store(readers, Ordering::Relaxed);
+        if readers == 0 {
+            let _ = self.room.post();
+        }
+        let _ = self.readers_lock.post();
+    }
+
+    fn write_until(&self, deadline: Option<Instant>)
+        -> Result<RwLockWriteGuard<'_, T>, WaitError>
+    {
+        take(&self.turnstile, deadline)?;
+        if let Err(e) = take(&self.room, deadline) {
+            let _ = self.turnstile.post();
+            return Err(e);
+        }
+        Ok(RwLockWriteGuard {
+            lock: self,
+            _data: PhantomData,
+        })
+    }
+
+    fn write_unlock(&self) {
+        let _ = self.turnstile.post();
+        let _ = self.room.post();
+    }
+
+    /// Locks for reading, waiting for any writer to finish.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        self.read_until(None).expect("Wait without deadline failed")
+    }
+
+    /// Locks for reading if no writer holds it or waits for it.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        self.read_until(Some(Instant::now())).ok()
+    }
+
+    /// Locks for reading, waiting at most for the given time.
+    pub fn read_timeout(&self, timeout: Duration) -> Result<RwLockReadGuard<'_, T>, WaitError> {
+        self.read_until(Instant::now().checked_add(timeout))
+    }
+
+    /// Locks for writing, waiting for everyone else to leave.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        self.write_until(None).expect("Wait without deadline failed")
+    }
+
+    /// Locks for writing if nobody else holds it.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        self.write_until(Some(Instant::now())).ok()
+    }
+
+    /// Locks for writing, waiting at most for the given time.
+    pub fn write_timeout(&self, timeout: Duration)
+        -> Result<RwLockWriteGuard<'_, T>, WaitError>
+    {
+        self.write_until(Instant::now().checked_add(timeout))
+    }
+
+    /// The protected data, no locking needed thanks to the exclusive borrow.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Drop for RwLock<T> {
+    fn drop(&mut self) {
+        unsafe {
+            libc::sem_destroy(self.turnstile.as_ptr());
+            libc::sem_destroy(self.room.as_ptr());
+            libc::sem_destroy(self.readers_lock.as_ptr());
+        }
+    }
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+/// Shared access to an [`RwLock`], unlocking it on drop.
+#[must_use = "The lock is released right away if the guard is dropped"]
+pub struct RwLockReadGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+    _data: PhantomData<&'a T>,
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+/// Exclusive access to an [`RwLock`], unlocking it on drop.
+#[must_use = "The lock is released right away if the guard is dropped"]
+pub struct RwLockWriteGuard<'a, T: 'a> {
+    lock: &'a RwLock<T>,
+    _data: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::Barrier;
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+    use Semaphore;
+
+    #[test]
+    fn concurrent_readers() {
+        const READERS: usize = 8;
+        let lock = RwLock::anonymous_shared(42).unwrap();
+        let barrier = Barrier::new(READERS);
+        thread::scope(|s| {
+            for _ in 0..READERS {
+                s.spawn(|| {
+                    let guard = lock.read();
+                    // All of them get here, holding the lock at the same time
+                    barrier.wait();
+                    assert_eq!(42, *guard);
+                    assert!(lock.try_write().is_none());
+                });
+            }
+        });
+        *lock.try_write().unwrap() += 1;
+        assert_eq!(43, *lock.read());
+    }
+
+    #[test]
+    fn exclusion() {
+        let lock = RwLock::anonymous_shared(0).unwrap();
+        let reader = lock.read();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(WaitError::TimedOut, lock.write_timeout(timeout).err().unwrap());
+        assert!(lock.read_timeout(timeout).is_ok());
+        drop(reader);
+        let writer = lock.write();
+        assert!(lock.try_read().is_none());
+        assert_eq!(WaitError::TimedOut, lock.read_timeout(timeout).err().unwrap());
+        drop(writer);
+        assert!(lock.try_read().is_some());
+    }
+
+    #[test]
+    fn processes() {
+        let lock = RwLock::anonymous_shared(0).unwrap();
+        let ready = Semaphore::anonymous_shared(0).unwrap();
+        let go = Semaphore::anonymous_shared(0).unwrap();
+        let child = fork(|| {
+            let mut writer = lock.write();
+            *writer = 1;
+            ready.post().unwrap();
+            go.wait();
+        });
+        ready.wait();
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        go.post().unwrap();
+        assert_eq!(1, *lock.read());
+        child.join();
+
+        let reader = lock.read();
+        let child = fork(|| {
+            assert!(lock.try_read().is_some());
+            assert!(lock.try_write().is_none());
+        });
+        child.join();
+        drop(reader);
+    }
+
+    #[test]
+    fn unlocked_on_panic() {
+        let lock = RwLock::anonymous_shared(()).unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _writer = lock.write();
+            panic!("Writing");
+        }));
+        assert!(result.is_err());
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _reader = lock.read();
+            panic!("Reading");
+        }));
+        assert!(result.is_err());
+        assert!(lock.try_write().is_some());
+    }
+}
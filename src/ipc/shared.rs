@@ -0,0 +1,116 @@
+//! Values in shared anonymous mappings.
+
+use std::io::Error;
+use std::mem;
+use std::ops::Deref;
+use std::process;
+use std::ptr::{self, NonNull};
+
+use libc;
+
+/// A value living in its own shared anonymous mapping.
+///
+/// Child processes created by `fork` share the value with the parent (it's not copied, as would
+/// be the case with ordinary memory). Each process unmaps the memory when it drops its copy of
+/// the handle, but only the process that created the value drops it (eg. destroys the semaphores
+/// inside). The others may still be using it at that point, so the creator should be the last
+/// one to let go.
+pub struct Shared<T> {
+    value: NonNull<T>,
+    creator: u32,
+}
+
+impl<T> Shared<T> {
+    fn size() -> usize {
+        // mmap refuses empty mappings
+        mem::size_of::<T>().max(1)
+    }
+
+    /// Maps the memory and lets the closure initialize the value in it.
+    ///
+    /// # Safety
+    ///
+    /// On success, the closure must have initialized the value.
+    pub(crate) unsafe fn new<F>(init: F) -> Result<Self, Error>
+    where
+        F: FnOnce(NonNull<T>) -> Result<(), Error>,
+    {
+        // Mappings are page-aligned, anything bigger doesn't make sense for shared structures
+        assert!(mem::align_of::<T>() <= 4096, "Too large alignment");
+        let mem = libc::mmap(
+            ptr::null_mut(),
+            Self::size(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        if mem == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        let value = NonNull::new(mem as *mut T).expect("mmap returned NULL");
+        if let Err(e) = init(value) {
+            libc::munmap(mem, Self::size());
+            return Err(e);
+        }
+        Ok(Shared {
+            value,
+            creator: process::id(),
+        })
+    }
+}
+
+impl<T> Deref for Shared<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { self.value.as_ref() }
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        unsafe {
+            // A forked child must not destroy what the parent keeps using
+            if process::id() == self.creator {
+                ptr::drop_in_place(self.value.as_ptr());
+            }
+            libc::munmap(self.value.as_ptr() as *mut _, Self::size());
+        }
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for Shared<T> {}
+unsafe impl<T: Sync> Sync for Shared<T> {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use test_util::fork;
+
+    struct Flag(AtomicBool);
+
+    impl Drop for Flag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn dropped_by_creator_only() {
+        let shared = unsafe {
+            Shared::<Flag>::new(|place| {
+                place.as_ptr().write(Flag(AtomicBool::new(false)));
+                Ok(())
+            })
+        }
+        .unwrap();
+        fork(|| {
+            // The child's own copy of the handle, as if it returned from main
+            drop(unsafe { ptr::read(&shared) });
+        })
+        .join();
+        assert!(!shared.0.load(Ordering::SeqCst));
+    }
+}
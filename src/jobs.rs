@@ -0,0 +1,225 @@
+//! Limiting the number of running child processes across a process tree.
+
+use std::convert::TryInto;
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::process::{Child, Command, ExitStatus};
+
+use named::{NameError, NamedSemaphore, SemName};
+
+/// The environment variable through which the children learn about the gate.
+pub const JOB_GATE_ENV: &str = "UNIX_SEMAPHORE_JOB_GATE";
+
+/// Limits the number of child processes running at once, like the jobserver of GNU make.
+///
+/// It's a named semaphore with a token for each slot. Every process in the tree spawns its
+/// children through [`spawn_limited`][JobGate::spawn_limited], which takes a slot for each of
+/// them and returns it when the child is reaped. The name is passed to the children in the
+/// [`JOB_GATE_ENV`] environment variable, so they (and their children) can share the same limit
+/// through [`from_env`][JobGate::from_env].
+///
+/// A process holding a slot and waiting for a slot for its own child may deadlock if all the
+/// slots are taken the same way. Unlike make, there's no implicit slot for the process itself.
+pub struct JobGate {
+    sem: NamedSemaphore,
+}
+
+impl JobGate {
+    /// Creates a new gate with the given number of slots.
+    ///
+    /// The semaphore is removed from the system when this handle is dropped (the ones attached
+    /// elsewhere keep working).
+    pub fn create<N>(name: N, slots: u32) -> Result<Self, Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        let mut sem = NamedSemaphore::create(name, 0o600, slots)?;
+        sem.unlink_on_drop(true);
+        Ok(JobGate { sem })
+    }
+
+    /// Attaches to a gate created elsewhere.
+    pub fn attach<N>(name: N) -> Result<Self, Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        NamedSemaphore::open(name).map(|sem| JobGate { sem })
+    }
+
+    /// Attaches to the gate of the parent, passed through the [`JOB_GATE_ENV`] variable.
+    ///
+    /// Fails with [`ErrorKind::NotFound`] if the variable is not set.
+    pub fn from_env() -> Result<Self, Error> {
+        let name = env::var(JOB_GATE_ENV)
+            .map_err(|_| Error::new(ErrorKind::NotFound, "No job gate in the environment"))?;
+        Self::attach(name)
+    }
+
+    /// The name of the gate.
+    pub fn name(&self) -> &SemName {
+        self.sem.name()
+    }
+
+    /// The number of free slots right now.
+    pub fn free(&self) -> u32 {
+        self.sem.value().max(0) as u32
+    }
+
+    /// Waits for a free slot and spawns the command in it.
+    ///
+    /// The command gets the [`JOB_GATE_ENV`] variable set. If spawning fails, the slot is
+    /// returned right away.
+    pub fn spawn_limited(&self, cmd: &mut Command) -> Result<LimitedChild<'_>, Error> {
+        self.sem.wait();
+        match cmd.env(JOB_GATE_ENV, self.name().as_str()).spawn() {
+            Ok(child) => Ok(LimitedChild {
+                child,
+                gate: self,
+                reaped: false,
+            }),
+            Err(e) => {
+                self.release();
+                Err(e)
+            },
+        }
+    }
+
+    fn release(&self) {
+        // We took the slot, so there's room for it
+        let _ = self.sem.post();
+    }
+}
+
+/// A child process holding a slot of a [`JobGate`].
+///
+/// The slot is returned when the child is reaped by [`wait`][LimitedChild::wait] or
+/// [`try_wait`][LimitedChild::try_wait]. If dropped before that, the drop waits for the child
+/// to terminate (so the slot isn't returned too early).
+pub struct LimitedChild<'a> {
+    child: Child,
+    gate: &'a JobGate,
+    reaped: bool,
+}
+
+impl<'a> LimitedChild<'a> {
+    fn reaped(&mut self) {
+        if !self.reaped {
+            self.reaped = true;
+            self.gate.release();
+        }
+    }
+
+    /// Waits for the child to terminate and returns its slot.
+    pub fn wait(&mut self) -> Result<ExitStatus, Error> {
+        let status = self.child.wait()?;
+        self.reaped();
+        Ok(status)
+    }
+
+    /// Checks if the child has terminated, returning its slot if so.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+        let status = self.child.try_wait()?;
+        if status.is_some() {
+            self.reaped();
+        }
+        Ok(status)
+    }
+
+    /// Kills the child (it still needs to be waited for to return the slot).
+    pub fn kill(&mut self) -> Result<(), Error> {
+        self.child.kill()
+    }
+
+    /// The process ID of the child.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Access to the child, for example its standard streams.
+    ///
+    /// Waiting for it directly leaves the slot taken until this is dropped.
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+}
+
+impl<'a> Drop for LimitedChild<'a> {
+    fn drop(&mut self) {
+        if !self.reaped {
+            // Even if the wait fails, there's nothing better to do than returning the slot
+            let _ = self.child.wait();
+            self.reaped();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+
+    use super::*;
+    use test_util::{child, child_arg, unique_name};
+
+    #[test]
+    fn child_attach() {
+        if child_arg().is_some() {
+            let gate = JobGate::from_env().unwrap();
+            // The parent took a slot for us
+            assert_eq!(1, gate.free());
+        }
+    }
+
+    #[test]
+    fn from_env() {
+        let gate = JobGate::create(unique_name("jobs-env"), 2).unwrap();
+        let mut cmd = child("jobs::tests::child_attach", "");
+        let status = gate.spawn_limited(&mut cmd).unwrap().wait().unwrap();
+        assert!(status.success());
+        assert_eq!(2, gate.free());
+    }
+
+    #[test]
+    fn failed_spawn() {
+        let gate = JobGate::create(unique_name("jobs-fail"), 1).unwrap();
+        let mut cmd = Command::new("/nonexistent/command");
+        assert!(gate.spawn_limited(&mut cmd).is_err());
+        assert_eq!(1, gate.free());
+    }
+
+    #[test]
+    fn limited() {
+        const SLOTS: usize = 4;
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let gate = JobGate::create(unique_name("jobs"), SLOTS as u32).unwrap();
+        thread::scope(|s| {
+            for _ in 0..20 {
+                s.spawn(|| {
+                    let mut cmd = Command::new("sh");
+                    cmd.arg("-c")
+                        .arg("echo + >> \"$0\"; sleep 0.05; echo - >> \"$0\"")
+                        .arg(&log);
+                    let mut child = gate.spawn_limited(&mut cmd).unwrap();
+                    assert!(child.wait().unwrap().success());
+                });
+            }
+        });
+        let log = fs::read_to_string(log).unwrap();
+        let mut running = 0;
+        let mut most = 0;
+        for line in log.lines() {
+            match line {
+                "+" => running += 1,
+                "-" => running -= 1,
+                other => panic!("Unexpected line {}", other),
+            }
+            most = most.max(running);
+        }
+        assert_eq!(0, running);
+        assert!(most <= SLOTS, "{} children at once", most);
+        assert_eq!(SLOTS as u32, gate.free());
+    }
+}
@@ -0,0 +1,353 @@
+//! Tokens lent out only for a limited time.
+
+use std::error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use {NoToken, Semaphore};
+
+// The state of a lease: its deadline in nanoseconds since the epoch of the semaphore, or one of
+// these. The holder and the watchdog both move it out of the deadline with a CAS and only the
+// one that succeeds posts the token.
+const RELEASED: u64 = u64::MAX;
+const EXPIRED: u64 = u64::MAX - 1;
+const LAST_DEADLINE: u64 = u64::MAX - 2;
+
+/// The lease ran out before being released or renewed and the watchdog took the token back.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Expired;
+
+impl Display for Expired {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Lease expired")
+    }
+}
+
+impl error::Error for Expired {}
+
+impl From<Expired> for Error {
+    fn from(_: Expired) -> Error {
+        Error::new(ErrorKind::TimedOut, Expired)
+    }
+}
+
+struct Watch {
+    leases: Vec<Arc<AtomicU64>>,
+    stop: bool,
+}
+
+struct Inner {
+    sem: Semaphore,
+    epoch: Instant,
+    watch: Mutex<Watch>,
+    wakeup: Condvar,
+}
+
+impl Inner {
+    fn watch(&self) -> MutexGuard<'_, Watch> {
+        self.watch.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn now(&self) -> u64 {
+        self.deadline(Duration::from_secs(0))
+    }
+
+    fn deadline(&self, ttl: Duration) -> u64 {
+        let nanos = self.epoch.elapsed().saturating_add(ttl).as_nanos();
+        nanos.min(u128::from(LAST_DEADLINE)) as u64
+    }
+
+    /// Expires the lease if it's due, returns its deadline if it's still running.
+    fn check(&self, lease: &AtomicU64, now: u64) -> Option<u64> {
+        let mut current = lease.load(Ordering::Acquire);
+        loop {
+            match current {
+                RELEASED | EXPIRED => return None,
+                deadline if deadline > now => return Some(deadline),
+                deadline => {
+                    let expire = lease.compare_exchange(
+                        deadline,
+                        EXPIRED,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    );
+                    match expire {
+                        Ok(_) => {
+                            // The holder took the token, so there's room for it
+                            let _ = self.sem.post();
+                            return None;
+                        },
+                        // Renewed or released meanwhile
+                        Err(changed) => current = changed,
+                    }
+                },
+            }
+        }
+    }
+
+    fn run(&self) {
+        let mut watch = self.watch();
+        while !watch.stop {
+            let now = self.now();
+            let mut next = None::<u64>;
+            watch.leases.retain(|lease| match self.check(lease, now) {
+                Some(deadline) => {
+                    next = Some(next.map_or(deadline, |next| next.min(deadline)));
+                    true
+                },
+                None => false,
+            });
+            watch = match next {
+                Some(next) => {
+                    let sleep = Duration::from_nanos(next - now);
+                    self.wakeup.wait_timeout(watch, sleep).unwrap_or_else(|e| e.into_inner()).0
+                },
+                None => self.wakeup.wait(watch).unwrap_or_else(|e| e.into_inner()),
+            };
+        }
+    }
+}
+
+/// A semaphore whose tokens come back on their own if held for too long.
+///
+/// Each token is taken as a [`Lease`] with a time to live. If the holder doesn't
+/// [`renew`][Lease::renew] or return it in time, a background thread posts the token back. The
+/// holder finds out when it tries to renew or release the lease afterwards, by getting
+/// [`Expired`]. Either the holder or the thread returns each token, never both.
+///
+/// This guards against holders that hang (or forget the lease somewhere). The thread is stopped
+/// when the semaphore is dropped.
+pub struct LeasedSemaphore {
+    inner: Arc<Inner>,
+    watchdog: Option<JoinHandle<()>>,
+}
+
+/// A token lent by a [`LeasedSemaphore`] until a deadline.
+///
+/// Returned on drop, unless it expired already.
+#[must_use = "The token is returned right away if the lease is dropped"]
+pub struct Lease<'a> {
+    inner: &'a Inner,
+    state: Arc<AtomicU64>,
+}
+
+impl<'a> Lease<'a> {
+    /// Moves the deadline to `ttl` from now.
+    ///
+    /// Fails if the lease already expired, the token is no longer held then.
+    pub fn renew(&self, ttl: Duration) -> Result<(), Expired> {
+        let deadline = self.inner.deadline(ttl);
+        let mut current = self.state.load(Ordering::Acquire);
+        loop {
+            if current == EXPIRED {
+                return Err(Expired);
+            }
+            debug_assert_ne!(RELEASED, current, "Renewing a released lease");
+            let renew = self.state.compare_exchange(
+                current,
+                deadline,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+            match renew {
+                Ok(_) => break,
+                Err(changed) => current = changed,
+            }
+        }
+        // The deadline may have got closer. Taking the lock makes sure the watchdog is either
+        // before looking at the leases or already waiting for the notification.
+        let _watch = self.inner.watch();
+        self.inner.wakeup.notify_one();
+        Ok(())
+    }
+
+    /// Has the lease run out and the token gone back?
+    pub fn is_expired(&self) -> bool {
+        self.state.load(Ordering::Acquire) == EXPIRED
+    }
+
+    fn give_back(&self) -> Result<(), Expired> {
+        let mut current = self.state.load(Ordering::Acquire);
+        loop {
+            match current {
+                EXPIRED => return Err(Expired),
+                RELEASED => return Ok(()),
+                deadline => {
+                    let release = self.state.compare_exchange(
+                        deadline,
+                        RELEASED,
+                        Ordering::AcqRel,
+                        Ordering::Acquire,
+                    );
+                    match release {
+                        Ok(_) => {
+                            // We hold the token, so there's room for it
+                            let _ = self.inner.sem.post();
+                            return Ok(());
+                        },
+                        Err(changed) => current = changed,
+                    }
+                },
+            }
+        }
+    }
+
+    /// Returns the token.
+    ///
+    /// Fails if the lease expired before, in which case the token is already back.
+    pub fn release(self) -> Result<(), Expired> {
+        self.give_back()
+    }
+}
+
+impl<'a> Drop for Lease<'a> {
+    fn drop(&mut self) {
+        let _ = self.give_back();
+    }
+}
+
+impl LeasedSemaphore {
+    /// Creates the semaphore with `value` tokens to lend.
+    pub fn new(value: u32) -> Result<Self, Error> {
+        let inner = Arc::new(Inner {
+            sem: Semaphore::anonymous(value)?,
+            epoch: Instant::now(),
+            watch: Mutex::new(Watch {
+                leases: Vec::new(),
+                stop: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+        let watchdog = thread::Builder::new()
+            .name("lease-watchdog".to_owned())
+            .spawn({
+                let inner = Arc::clone(&inner);
+                move || inner.run()
+            })?;
+        Ok(LeasedSemaphore {
+            inner,
+            watchdog: Some(watchdog),
+        })
+    }
+
+    fn lease(&self, ttl: Duration) -> Lease<'_> {
+        let state = Arc::new(AtomicU64::new(self.inner.deadline(ttl)));
+        self.inner.watch().leases.push(Arc::clone(&state));
+        self.inner.wakeup.notify_one();
+        Lease {
+            inner: &self.inner,
+            state,
+        }
+    }
+
+    /// Waits for a token and lends it for `ttl`.
+    pub fn acquire_lease(&self, ttl: Duration) -> Lease<'_> {
+        self.inner.sem.wait();
+        self.lease(ttl)
+    }
+
+    /// Lends a token for `ttl`, if one is available right away.
+    pub fn try_acquire_lease(&self, ttl: Duration) -> Result<Lease<'_>, NoToken> {
+        self.inner.sem.trywait()?;
+        Ok(self.lease(ttl))
+    }
+
+    /// The number of tokens available right now.
+    pub fn available(&self) -> u32 {
+        self.inner.sem.value().max(0) as u32
+    }
+}
+
+impl Drop for LeasedSemaphore {
+    fn drop(&mut self) {
+        self.inner.watch().stop = true;
+        self.inner.wakeup.notify_one();
+        if let Some(watchdog) = self.watchdog.take() {
+            let _ = watchdog.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHORT: Duration = Duration::from_millis(20);
+
+    #[test]
+    fn expires() {
+        let sem = LeasedSemaphore::new(1).unwrap();
+        let lease = sem.acquire_lease(SHORT);
+        sem.try_acquire_lease(SHORT).map(|_| ()).unwrap_err();
+        // Blocks until the watchdog takes the first one back
+        let start = Instant::now();
+        let second = sem.acquire_lease(Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(lease.is_expired());
+        assert_eq!(Err(Expired), lease.renew(SHORT));
+        assert_eq!(Err(Expired), lease.release());
+        assert_eq!(0, sem.available());
+        second.release().unwrap();
+        assert_eq!(1, sem.available());
+    }
+
+    #[test]
+    fn renewed() {
+        let sem = LeasedSemaphore::new(1).unwrap();
+        let lease = sem.acquire_lease(SHORT * 2);
+        for _ in 0..10 {
+            thread::sleep(SHORT / 2);
+            lease.renew(SHORT * 2).unwrap();
+            sem.try_acquire_lease(SHORT).map(|_| ()).unwrap_err();
+        }
+        lease.release().unwrap();
+        assert_eq!(1, sem.available());
+        // Released leases don't come back a second time
+        thread::sleep(SHORT * 3);
+        assert_eq!(1, sem.available());
+    }
+
+    #[test]
+    fn forever() {
+        let sem = LeasedSemaphore::new(1).unwrap();
+        let lease = sem.acquire_lease(Duration::MAX);
+        lease.renew(Duration::MAX).unwrap();
+        thread::sleep(SHORT);
+        assert!(!lease.is_expired());
+        sem.try_acquire_lease(Duration::MAX).map(|_| ()).unwrap_err();
+        lease.release().unwrap();
+        assert_eq!(1, sem.available());
+    }
+
+    #[test]
+    fn exact_under_races() {
+        const TOKENS: u32 = 3;
+        let sem = LeasedSemaphore::new(TOKENS).unwrap();
+        thread::scope(|s| {
+            for t in 0..6 {
+                let sem = &sem;
+                s.spawn(move || {
+                    for i in 0..200 {
+                        let lease = sem.acquire_lease(Duration::from_micros((t * i) % 500));
+                        assert!(sem.available() <= TOKENS);
+                        if i % 3 == 0 {
+                            thread::yield_now();
+                        }
+                        let _ = lease.release();
+                    }
+                });
+            }
+        });
+        // A token expired just now may still be on the way back
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while sem.available() < TOKENS && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        thread::sleep(SHORT);
+        assert_eq!(TOKENS, sem.available());
+    }
+}
@@ -1,27 +1,396 @@
 extern crate libc;
+#[cfg(feature = "lock_api")]
+extern crate lock_api;
+#[cfg(feature = "shared-memory")]
+extern crate shared_memory;
+#[cfg(test)]
+extern crate tempfile;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 use std::error;
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::io::{Error, ErrorKind};
 use std::mem;
-use std::ptr::NonNull;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::ops::ControlFlow;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use libc::{c_int, sem_t};
+use libc::{c_int, c_uint, sem_t};
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
-pub struct NoToken;
+use backend::Backend;
+#[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+use dispatch::DispatchSemaphore;
+use many::value_max;
+use metrics::Metrics;
+use shadow::Shadow;
+use slow::SlowWaitHook;
+use trace::{traced, Op, Outcome};
+
+mod array;
+mod backend;
+mod binary;
+mod bounded;
+mod builder;
+mod cached;
+mod capabilities;
+mod clock;
+#[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+mod dispatch;
+mod file;
+mod fifo;
+#[cfg(all(target_os = "linux", feature = "futex"))]
+mod futex;
+mod gate;
+mod guard;
+#[cfg(feature = "histogram")]
+mod histogram;
+mod inline;
+mod jobs;
+mod lease;
+mod limiter;
+pub mod ipc;
+mod many;
+mod mapped;
+mod metrics;
+#[cfg(target_os = "linux")]
+mod memfd;
+mod multi;
+mod parker;
+pub mod named;
+mod placed;
+mod pool;
+mod priority;
+mod rate;
+mod reference;
+mod shadow;
+mod shm;
+#[cfg(feature = "shared-memory")]
+mod shmem;
+mod slot;
+mod slow;
+mod trace;
+mod transfer;
+mod weighted;
+mod spin;
+mod waiters;
+#[cfg(test)]
+mod test_util;
+
+pub use array::SemaphoreArray;
+pub use binary::{AlreadySignalled, BinarySemaphore};
+pub use cached::CachedSemaphore;
+pub use bounded::{BoundExceeded, BoundedSemaphore};
+pub use builder::Builder;
+pub use capabilities::{capabilities, Capabilities};
+pub use clock::{Clock, ClockId, SystemClock};
+#[cfg(feature = "test-util")]
+pub use clock::MockClock;
+#[cfg(all(target_os = "linux", feature = "futex"))]
+pub use futex::FutexSemaphore;
+pub use file::FileSemaphore;
+pub use fifo::FifoSemaphore;
+pub use gate::Gate;
+pub use guard::{SemaphoreGuard, Token};
+#[cfg(feature = "histogram")]
+pub use histogram::HistogramSnapshot;
+pub use inline::InlineSemaphore;
+pub use jobs::{JobGate, LimitedChild, JOB_GATE_ENV};
+pub use lease::{Expired, Lease, LeasedSemaphore};
+pub use limiter::ConcurrencyLimiter;
+pub use mapped::SharedRegion;
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsSnapshot;
+#[cfg(target_os = "linux")]
+pub use memfd::MemfdSemaphore;
+pub use multi::AllTokens;
+pub use parker::{parker, Parker, Unparker};
+pub use named::{NameError, NamedOptions, NamedSemaphore, SemName, TempSemaphore};
+pub use placed::BorrowedSemaphore;
+pub use pool::{Pool, PoolGuard};
+pub use priority::{Priority, PrioritySemaphore};
+pub use rate::RateLimiter;
+pub use reference::SemaphoreRef;
+pub use shm::ShmSemaphore;
+pub use slot::{Restart, SemaphoreSlot, CANCEL_GRANULARITY};
+pub use slow::SlowWait;
+pub use spin::{Relax, SpinConfig};
+pub use transfer::{PartialTransfer, TransferError};
+pub use weighted::WeightedSemaphore;
+
+/// No token was available right away.
+///
+/// If the semaphore itself said so, this carries its errno. Types keeping their own books (eg.
+/// [`WeightedSemaphore`]) refuse without asking the OS, so there's none.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct NoToken {
+    errno: Option<i32>,
+    lost: u32,
+}
+
+impl NoToken {
+    /// A refusal not coming from the OS.
+    pub const fn new() -> Self {
+        NoToken { errno: None, lost: 0 }
+    }
+
+    pub(crate) const fn from_errno(errno: i32) -> Self {
+        NoToken {
+            errno: Some(errno),
+            lost: 0,
+        }
+    }
+
+    pub(crate) const fn with_lost(self, lost: u32) -> Self {
+        NoToken { lost, ..self }
+    }
+
+    /// The errno of the failed call, if there was one.
+    pub fn os_error(&self) -> Option<i32> {
+        self.errno
+    }
+
+    /// How many tokens [`try_acquire_many`][SemaphoreSlot::try_acquire_many] took and couldn't
+    /// return, because others overflowed the semaphore meanwhile.
+    ///
+    /// These tokens are gone from the semaphore. It happens only if someone posts more than they
+    /// took.
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+}
 
 impl Display for NoToken {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
-        write!(fmt, "No token available")
+        write!(fmt, "No token available")?;
+        if self.lost > 0 {
+            write!(fmt, " ({} tokens lost returning the rest)", self.lost)?;
+        }
+        Ok(())
     }
 }
 
 impl error::Error for NoToken {}
 
+impl From<NoToken> for Error {
+    fn from(e: NoToken) -> Error {
+        Error::new(ErrorKind::WouldBlock, e)
+    }
+}
+
+/// A wait was interrupted by a signal before getting a token.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Interrupted;
+
+impl Display for Interrupted {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Interrupted by a signal")
+    }
+}
+
+impl error::Error for Interrupted {}
+
+impl From<Interrupted> for Error {
+    fn from(_: Interrupted) -> Error {
+        Error::new(ErrorKind::Interrupted, Interrupted)
+    }
+}
+
+/// A cancellable wait was cancelled before getting a token.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Cancelled;
+
+impl Display for Cancelled {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Wait cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+impl From<Cancelled> for Error {
+    fn from(_: Cancelled) -> Error {
+        Error::other(Cancelled)
+    }
+}
+
+/// A wait was aborted by its callback before getting a token.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct WaitAborted;
+
+impl Display for WaitAborted {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Wait aborted")
+    }
+}
+
+impl error::Error for WaitAborted {}
+
+impl From<WaitAborted> for Error {
+    fn from(_: WaitAborted) -> Error {
+        Error::other(WaitAborted)
+    }
+}
+
+/// Why a wait didn't get a token.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum WaitError {
+    /// No token was available right away.
+    ///
+    /// The timed waits don't return this, but it allows handling [`NoToken`] from
+    /// [`trywait`][Semaphore::trywait] together with them.
+    WouldBlock,
+    /// The deadline passed.
+    TimedOut,
+    /// Interrupted by a signal, only from the waits that don't retry.
+    Interrupted,
+    /// The requested clock can't be used for waiting on this platform, or the value the wait is
+    /// for can't be read.
+    Unsupported,
+}
+
+impl Display for WaitError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            WaitError::WouldBlock => write!(fmt, "No token available"),
+            WaitError::TimedOut => write!(fmt, "Timed out waiting for a token"),
+            WaitError::Interrupted => write!(fmt, "Interrupted by a signal"),
+            WaitError::Unsupported => write!(fmt, "Unsupported clock"),
+        }
+    }
+}
+
+impl error::Error for WaitError {}
+
+impl From<Interrupted> for WaitError {
+    fn from(_: Interrupted) -> WaitError {
+        WaitError::Interrupted
+    }
+}
+
+impl From<NoToken> for WaitError {
+    fn from(_: NoToken) -> WaitError {
+        WaitError::WouldBlock
+    }
+}
+
+impl From<WaitError> for Error {
+    fn from(e: WaitError) -> Error {
+        let kind = match e {
+            WaitError::WouldBlock => ErrorKind::WouldBlock,
+            WaitError::TimedOut => ErrorKind::TimedOut,
+            WaitError::Interrupted => ErrorKind::Interrupted,
+            WaitError::Unsupported => ErrorKind::Unsupported,
+        };
+        Error::new(kind, e)
+    }
+}
+
+/// A timed acquisition of several tokens didn't get all of them in time.
+///
+/// The tokens it got meanwhile were returned, except for the [`lost`][PartialTimeout::lost] ones.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct PartialTimeout {
+    collected: u32,
+    lost: u32,
+}
+
+impl PartialTimeout {
+    /// How many tokens it held before giving up.
+    pub fn collected(&self) -> u32 {
+        self.collected
+    }
+
+    /// How many of the collected tokens couldn't be returned, because others overflowed the
+    /// semaphore meanwhile.
+    pub fn lost(&self) -> u32 {
+        self.lost
+    }
+}
+
+impl Display for PartialTimeout {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Timed out after collecting {} tokens", self.collected)?;
+        if self.lost > 0 {
+            write!(fmt, " ({} lost returning them)", self.lost)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for PartialTimeout {}
+
+impl From<PartialTimeout> for WaitError {
+    fn from(_: PartialTimeout) -> WaitError {
+        WaitError::TimedOut
+    }
+}
+
+impl From<PartialTimeout> for Error {
+    fn from(e: PartialTimeout) -> Error {
+        Error::new(ErrorKind::TimedOut, e)
+    }
+}
+
+/// Posting several tokens at once overflowed the semaphore.
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
-pub struct Overflow;
+pub struct PartialPost {
+    posted: u32,
+}
+
+impl PartialPost {
+    /// How many of the tokens stayed posted.
+    pub fn posted(&self) -> u32 {
+        self.posted
+    }
+}
+
+impl Display for PartialPost {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Overflow of a semaphore after posting {} tokens", self.posted)
+    }
+}
+
+impl error::Error for PartialPost {}
+
+impl From<PartialPost> for Overflow {
+    fn from(_: PartialPost) -> Overflow {
+        Overflow::new()
+    }
+}
+
+impl From<PartialPost> for Error {
+    fn from(e: PartialPost) -> Error {
+        Error::other(e)
+    }
+}
+
+/// A post would take the semaphore over its maximum value.
+///
+/// Like with [`NoToken`], the errno is there if the OS refused the post.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Overflow {
+    errno: Option<i32>,
+}
+
+impl Overflow {
+    /// An overflow not coming from the OS.
+    pub const fn new() -> Self {
+        Overflow { errno: None }
+    }
+
+    pub(crate) const fn from_errno(errno: i32) -> Self {
+        Overflow { errno: Some(errno) }
+    }
+
+    /// The errno of the failed call, if there was one.
+    pub fn os_error(&self) -> Option<i32> {
+        self.errno
+    }
+}
 
 impl Display for Overflow {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
@@ -31,14 +400,191 @@ impl Display for Overflow {
 
 impl error::Error for Overflow {}
 
+impl From<Overflow> for Error {
+    fn from(e: Overflow) -> Error {
+        Error::other(e)
+    }
+}
+
+/// Any failure of a basic semaphore operation, including the ones that shouldn't happen.
+///
+/// Returned by the `_checked` variants of the operations (eg.
+/// [`wait_checked`][SemaphoreSlot::wait_checked]). Unlike the plain ones, they don't panic on an
+/// unexpected errno (for example from a semaphore corrupted by some unsafe code), but leave it up
+/// to the caller.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SemError {
+    /// No token was available right away.
+    WouldBlock,
+    /// The deadline passed.
+    TimedOut,
+    /// Interrupted by a signal.
+    ///
+    /// The checked waits restart after signals, so they don't return this.
+    Interrupted,
+    /// The semaphore would go over its maximum value.
+    Overflow,
+    /// The OS doesn't consider this a valid semaphore (or the arguments are invalid).
+    Invalid,
+    /// Some other error, with the raw errno.
+    Os(i32),
+}
+
+impl SemError {
+    /// Translates the errno of a failed semaphore call.
+    pub fn from_errno(errno: c_int) -> SemError {
+        match errno {
+            libc::EAGAIN => SemError::WouldBlock,
+            libc::ETIMEDOUT => SemError::TimedOut,
+            libc::EINTR => SemError::Interrupted,
+            libc::EOVERFLOW => SemError::Overflow,
+            libc::EINVAL => SemError::Invalid,
+            other => SemError::Os(other),
+        }
+    }
+}
+
+impl Display for SemError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            SemError::WouldBlock => write!(fmt, "No token available"),
+            SemError::TimedOut => write!(fmt, "Timed out waiting for a token"),
+            SemError::Interrupted => write!(fmt, "Interrupted by a signal"),
+            SemError::Overflow => write!(fmt, "Overflow of a semaphore"),
+            SemError::Invalid => write!(fmt, "Invalid semaphore"),
+            SemError::Os(errno) => write!(fmt, "{}", Error::from_raw_os_error(*errno)),
+        }
+    }
+}
+
+impl error::Error for SemError {}
+
+impl From<NoToken> for SemError {
+    fn from(_: NoToken) -> SemError {
+        SemError::WouldBlock
+    }
+}
+
+impl From<Interrupted> for SemError {
+    fn from(_: Interrupted) -> SemError {
+        SemError::Interrupted
+    }
+}
+
+impl From<Overflow> for SemError {
+    fn from(_: Overflow) -> SemError {
+        SemError::Overflow
+    }
+}
+
+impl From<WaitError> for SemError {
+    fn from(e: WaitError) -> SemError {
+        match e {
+            WaitError::WouldBlock => SemError::WouldBlock,
+            WaitError::TimedOut => SemError::TimedOut,
+            WaitError::Interrupted => SemError::Interrupted,
+            // That's what sem_clockwait says about the clock
+            WaitError::Unsupported => SemError::Invalid,
+        }
+    }
+}
+
+impl From<SemError> for Error {
+    fn from(e: SemError) -> Error {
+        let kind = match e {
+            SemError::WouldBlock => ErrorKind::WouldBlock,
+            SemError::TimedOut => ErrorKind::TimedOut,
+            SemError::Interrupted => ErrorKind::Interrupted,
+            SemError::Overflow => ErrorKind::Other,
+            SemError::Invalid => ErrorKind::InvalidInput,
+            SemError::Os(errno) => return Error::from_raw_os_error(errno),
+        };
+        Error::new(kind, e)
+    }
+}
+
 enum Mode {
     Uninitialized,
     Anonymous,
+    /// Process-shared, in its own shared anonymous mapping.
+    Shared,
+    /// Like Shared, but only unmap on drop.
+    SharedKept,
+    Named,
+    /// In a shared mapping of a file, see the mapped module.
+    Mapped,
+    /// Placed into memory owned by someone else, destroy but don't free.
+    Placed,
+    /// Like Placed, but left alone on drop.
+    PlacedKept,
+    /// Already torn down by other means, nothing to do on drop.
+    Released,
+    /// Backed by GCD, `inner` points to a boxed `DispatchSemaphore`, not a `sem_t`.
+    #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+    Dispatch,
+}
+
+/// What kind of a semaphore a handle is, see [`Semaphore::kind`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Kind {
+    /// Created by [`anonymous`][Semaphore::anonymous], private to this process.
+    Anonymous,
+    /// Created by [`anonymous_shared`][Semaphore::anonymous_shared], in its own shared mapping.
+    AnonymousShared,
+    /// Opened by name, see the [`named`] module.
+    Named,
+    /// In a shared mapping of a file or a shared memory object (eg. [`ShmSemaphore`]).
+    Mapped,
+    /// Initialized in memory provided by the caller, see [`init_at`][Semaphore::init_at].
+    Placed {
+        /// The semaphore was initialized as process-shared.
+        process_shared: bool,
+    },
+    /// Adopted by [`from_raw`][Semaphore::from_raw], nothing is known about it.
+    Foreign,
+}
+
+impl Kind {
+    /// Can the semaphore be used from other processes, if known?
+    ///
+    /// This tells what the semaphore is initialized as, not if any other process actually has
+    /// access to its memory (eg. a [`Placed`][Kind::Placed] one in private memory).
+    pub fn is_process_shared(&self) -> Option<bool> {
+        match self {
+            Kind::Anonymous => Some(false),
+            Kind::AnonymousShared | Kind::Named | Kind::Mapped => Some(true),
+            Kind::Placed { process_shared } => Some(*process_shared),
+            Kind::Foreign => None,
+        }
+    }
 }
 
 pub struct Semaphore {
     inner: NonNull<sem_t>,
     mode: Mode,
+    kind: Kind,
+    metrics: Metrics,
+    slow_wait: Option<SlowWaitHook>,
+    shadow: Shadow,
+}
+
+/// Refuses initial values the system can't hold, with a clearer error than the libc would give.
+fn check_value(value: u32) -> Result<(), Error> {
+    if value > value_max() {
+        Err(Error::new(ErrorKind::InvalidInput, "Initial value over SEM_VALUE_MAX"))
+    } else {
+        Ok(())
+    }
+}
+
+unsafe fn init(sem: *mut sem_t, pshared: bool, value: c_uint) -> Result<(), Error> {
+    check_value(value)?;
+    match libc::sem_init(sem, pshared as c_int, value) {
+        0 => Ok(()),
+        -1 => Err(Error::last_os_error()),
+        other => unreachable!("sem_init doesn't return value {}", other),
+    }
 }
 
 impl Semaphore {
@@ -49,95 +595,784 @@ impl Semaphore {
         Semaphore {
             inner,
             mode: Mode::Uninitialized,
+            kind: Kind::Anonymous,
+            metrics: Metrics::new(),
+            slow_wait: None,
+            shadow: Shadow::untracked(),
         }
     }
 
-    pub fn anonymous(value: c_int) -> Result<Self, Error> {
+    /// Creates a semaphore private to this process.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the value is over
+    /// [`max_value`][Semaphore::max_value].
+    ///
+    /// On macOS, which doesn't implement `sem_init`, this is a named semaphore unlinked right
+    /// after creation. It behaves the same, only [`into_raw`][Semaphore::into_raw] returns a
+    /// pointer for `sem_close` rather than `sem_destroy`. With the `dispatch` feature, it is a
+    /// GCD semaphore instead, which blocks in the timed waits rather than polling. That one has
+    /// no `sem_t` at all (so [`as_raw`][Semaphore::as_raw] and friends panic) and its waits are
+    /// not interrupted by signals.
+    pub fn anonymous(value: u32) -> Result<Self, Error> {
+        #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+        return Self::dispatch(value);
+        #[cfg(not(all(target_vendor = "apple", feature = "dispatch")))]
+        Self::anonymous_posix(value)
+    }
+
+    /// Like [`anonymous`][Semaphore::anonymous], but always with a `sem_t` behind it.
+    pub(crate) fn anonymous_posix(value: u32) -> Result<Self, Error> {
+        if cfg!(target_vendor = "apple") {
+            let mut sem = NamedSemaphore::temporary(value)?.into_unlinked(Kind::Anonymous)?;
+            sem.shadow = Shadow::automatic(value);
+            return Ok(sem);
+        }
         unsafe {
             let mut me = Self::uninitialized();
 
-            match libc::sem_init(me.inner.as_ptr(), 0, value as _) {
-                0 => {
-                    me.mode = Mode::Anonymous;
-                    Ok(me)
-                },
-                // Note: the destructor will take care of disposing of the memory, etc.
-                -1 => Err(Error::last_os_error()),
-                other => unreachable!("sem_init doesn't return value {}", other),
-            }
+            // Note: on error, the destructor will take care of disposing of the memory, etc.
+            init(me.inner.as_ptr(), false, value)?;
+            me.mode = Mode::Anonymous;
+            me.shadow = Shadow::automatic(value);
+            Ok(me)
         }
     }
 
-    pub fn wait(&self) {
+    /// Creates a process-shared anonymous semaphore.
+    ///
+    /// The semaphore lives in a shared memory mapping, so a child created by `fork` after this
+    /// call shares it with the parent. On macOS, it is an unlinked named semaphore instead, see
+    /// [`anonymous`][Semaphore::anonymous].
+    pub fn anonymous_shared(value: u32) -> Result<Self, Error> {
+        if cfg!(target_vendor = "apple") {
+            // The handle is inherited over fork just as well
+            return NamedSemaphore::temporary(value)?.into_unlinked(Kind::AnonymousShared);
+        }
         unsafe {
-            loop {
-                if libc::sem_wait(self.inner.as_ptr()) == 0 {
-                    return;
-                } else {
-                    let e = Error::last_os_error();
-                    assert!(e.kind() == ErrorKind::Interrupted);
-                }
+            let mem = libc::mmap(
+                ptr::null_mut(),
+                mem::size_of::<sem_t>(),
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if mem == libc::MAP_FAILED {
+                return Err(Error::last_os_error());
             }
+            let inner = NonNull::new(mem as *mut sem_t).expect("mmap returned NULL");
+
+            if let Err(e) = init(inner.as_ptr(), true, value) {
+                libc::munmap(mem, mem::size_of::<sem_t>());
+                return Err(e);
+            }
+            Ok(Semaphore {
+                inner,
+                mode: Mode::Shared,
+                kind: Kind::AnonymousShared,
+                metrics: Metrics::new(),
+                slow_wait: None,
+                shadow: Shadow::untracked(),
+            })
         }
     }
 
-    pub fn trywait(&self) -> Result<(), NoToken> {
-        unsafe {
-            loop {
-                if libc::sem_trywait(self.inner.as_ptr()) == 0 {
-                    return Ok(())
-                } else {
-                    let e = Error::last_os_error();
-                    match e.kind() {
-                        ErrorKind::Interrupted => continue,
-                        ErrorKind::WouldBlock => return Err(NoToken),
-                        _ => unreachable!("Impossible error {}", e),
-                    }
-                }
-            }
+    /// The largest value of a semaphore, as known at compile time.
+    ///
+    /// Taken from the platform's `SEM_VALUE_MAX` where the crate knows it, otherwise the POSIX
+    /// minimum of 32767. The running system may allow more, see
+    /// [`max_value`][Semaphore::max_value].
+    pub const MAX_VALUE: u32 = many::SEM_VALUE_MAX;
+
+    /// The largest value a semaphore can have on this system (`SEM_VALUE_MAX`).
+    ///
+    /// Asks `sysconf`, falling back to the POSIX minimum if the system doesn't say. This is the
+    /// limit the constructors, [`post_many_atomic`][SemaphoreSlot::post_many_atomic] and the
+    /// bounded types check against.
+    pub fn max_value() -> u32 {
+        value_max()
+    }
+
+    /// What kind of a semaphore this is, eg. to check it is process-shared before a `fork`.
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+
+    pub(crate) fn backend(&self) -> Backend<'_> {
+        match self.mode {
+            #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+            Mode::Dispatch => Backend::Dispatch(unsafe { self.dispatch_semaphore() }),
+            _ => Backend::Posix(unsafe { SemaphoreSlot::from_ptr(self.inner.as_ptr()) }),
+        }
+    }
+
+    /// The `sem_t`, which a semaphore backed by GCD doesn't have.
+    fn sem_t(&self) -> NonNull<sem_t> {
+        #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+        assert!(!matches!(self.mode, Mode::Dispatch), "A semaphore backed by GCD has no sem_t");
+        self.inner
+    }
+
+    pub fn wait(&self) {
+        let op = Op::start("wait", self.kind, 1, None);
+        op.run(|| self.wait_counted());
+        op.finish(Outcome::Acquired);
+    }
+
+    fn wait_counted(&self) {
+        let _ = self.metrics.wait(self.backend(), || match self.slow_wait {
+            Some(ref slow_wait) => slow_wait.wait(self, None),
+            None => {
+                self.backend().wait();
+                Ok(())
+            },
+        });
+        self.shadow.took(1);
+    }
+
+    /// Updates the shadow value after a successful wait for a token.
+    fn took<E>(&self, result: Result<(), E>) -> Result<(), E> {
+        if result.is_ok() {
+            self.shadow.took(1);
         }
+        result
+    }
+
+    /// Waits for a token, returning unexpected errors instead of panicking.
+    pub fn wait_checked(&self) -> Result<(), SemError> {
+        self.took(self.backend().wait_checked())
+    }
+
+    /// Waits for a token, spinning for a while before blocking.
+    pub fn wait_spin(&self, spin: SpinConfig) {
+        let op = Op::start("wait_spin", self.kind, 1, None);
+        op.run(|| {
+            let _ = self.metrics.wait(self.backend(), || {
+                self.backend().wait_spin(spin);
+                Ok(())
+            });
+            self.shadow.took(1);
+        });
+        op.finish(Outcome::Acquired);
     }
 
-    pub fn timedwait(&self, until: SystemTime) -> Result<(), NoToken> {
-        let dur = until.duration_since(UNIX_EPOCH).unwrap();
-        let timespec = libc::timespec {
-            tv_sec: dur.as_secs() as _,
-            tv_nsec: i64::from(dur.subsec_nanos()),
+    /// Waits for a token, but returns early if interrupted by a signal.
+    pub fn wait_interruptible(&self) -> Result<(), Interrupted> {
+        self.took(self.backend().wait_interruptible())
+    }
+
+    pub fn trywait(&self) -> Result<(), NoToken> {
+        self.took(self.backend().trywait())
+    }
+
+    /// Takes a token if available, returning unexpected errors instead of panicking.
+    pub fn trywait_checked(&self) -> Result<(), SemError> {
+        self.took(self.backend().trywait_checked())
+    }
+
+    pub fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+        let op = Op::start("timedwait", self.kind, 1, Some(until));
+        let wait = || self.took(self.metrics.wait(self.backend(), || self.block_until(until)));
+        traced(op, wait, WaitError::outcome)
+    }
+
+    /// Waits for a token until the time, returning unexpected errors instead of panicking.
+    pub fn timedwait_checked(&self, until: SystemTime) -> Result<(), SemError> {
+        self.took(self.backend().timedwait_checked(until))
+    }
+
+    /// Waits for a token, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        let op = Op::start("wait_timeout", self.kind, 1, SystemTime::now().checked_add(timeout));
+        traced(op, || self.wait_timeout_counted(timeout), WaitError::outcome)
+    }
+
+    fn wait_timeout_counted(&self, timeout: Duration) -> Result<(), WaitError> {
+        let block = || match self.slow_wait {
+            Some(ref slow_wait) => slow_wait.wait(self, SystemTime::now().checked_add(timeout)),
+            None => self.backend().wait_timeout(timeout),
         };
+        self.took(self.metrics.wait(self.backend(), block))
+    }
 
-        unsafe {
-            loop {
-                if libc::sem_timedwait(self.inner.as_ptr(), &timespec) == 0 {
-                    return Ok(())
-                } else {
-                    let e = Error::last_os_error();
-                    match e.kind() {
-                        ErrorKind::Interrupted => continue,
-                        ErrorKind::TimedOut => return Err(NoToken),
-                        _ => unreachable!("Impossible error {}", e),
-                    }
-                }
-            }
+    fn block_until(&self, until: SystemTime) -> Result<(), WaitError> {
+        match self.slow_wait {
+            Some(ref slow_wait) => slow_wait.wait(self, Some(until)),
+            None => self.backend().timedwait(until),
         }
     }
 
+    /// Calls the hook whenever a wait blocks for longer than `threshold`.
+    ///
+    /// This covers [`wait`][Semaphore::wait], [`timedwait`][Semaphore::timedwait],
+    /// [`wait_timeout`][Semaphore::wait_timeout] and the guards. The other waits are not
+    /// reported: the `_checked`, `_interruptible`, `_with` and `_on` variants, the
+    /// [`wait_deadline`][Semaphore::wait_deadline], [`wait_spin`][Semaphore::wait_spin],
+    /// cancellable and ticking ones, and the multi-token ones. The hook runs in the waiting
+    /// thread, which keeps waiting afterwards. For the same wait, it is called again every
+    /// `repeat`, or never again with `None`.
+    ///
+    /// With a hook installed, the waits are done in slices ending whenever the hook is due,
+    /// without one they are a single call. Replaces any hook set before.
+    pub fn set_slow_wait_hook(
+        &mut self,
+        threshold: Duration,
+        repeat: Option<Duration>,
+        hook: Arc<dyn Fn(SlowWait) + Send + Sync>,
+    ) {
+        self.slow_wait = Some(SlowWaitHook::new(threshold, repeat, hook));
+    }
+
+    /// Removes the hook set by [`set_slow_wait_hook`][Semaphore::set_slow_wait_hook].
+    pub fn clear_slow_wait_hook(&mut self) {
+        self.slow_wait = None;
+    }
+
+    /// Waits for a token at most for the given time, returning unexpected errors instead of
+    /// panicking.
+    pub fn wait_timeout_checked(&self, timeout: Duration) -> Result<(), SemError> {
+        self.took(self.backend().wait_timeout_checked(timeout))
+    }
+
+    /// Waits for a token at most for the given time, returning early on a signal.
+    pub fn wait_timeout_interruptible(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.took(self.backend().wait_timeout_interruptible(timeout))
+    }
+
+    /// Waits for a token at most for the given time, computing the deadline from the clock.
+    pub fn wait_timeout_on<C: Clock + ?Sized>(&self, clock: &C, timeout: Duration)
+        -> Result<(), WaitError>
+    {
+        self.took(self.backend().wait_timeout_on(clock, timeout))
+    }
+
+    /// Waits for a token until the deadline, computing the kernel deadline from the clock.
+    pub fn wait_deadline_on<C: Clock + ?Sized>(&self, clock: &C, deadline: Instant)
+        -> Result<(), WaitError>
+    {
+        self.took(self.backend().wait_deadline_on(clock, deadline))
+    }
+
+    /// Waits for a token until the deadline, unaffected by changes of the system time.
+    ///
+    /// Not covered by the [slow-wait hook][Semaphore::set_slow_wait_hook], which would slice the
+    /// wait by the system time.
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), WaitError> {
+        // Only for the span, the wait itself is against the monotonic clock
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let until = SystemTime::now().checked_add(remaining);
+        let op = Op::start("wait_deadline", self.kind, 1, until);
+        let block = || self.backend().wait_deadline(deadline);
+        traced(op, || self.took(self.metrics.wait(self.backend(), block)), WaitError::outcome)
+    }
+
+    /// Waits for a token until the absolute time of the given clock.
+    pub fn timedwait_with_clock(&self, clock: ClockId, abstime: libc::timespec)
+        -> Result<(), WaitError>
+    {
+        self.took(self.backend().timedwait_with_clock(clock, abstime))
+    }
+
+    /// Waits for a token until it's available or the flag gets set.
+    pub fn wait_cancellable(&self, cancel: &AtomicBool) -> Result<(), Cancelled> {
+        self.took(self.backend().wait_cancellable(cancel))
+    }
+
+    /// Waits for a token until it's available or the flag gets set, checking it with the given
+    /// granularity.
+    pub fn wait_cancellable_every(&self, cancel: &AtomicBool, granularity: Duration)
+        -> Result<(), Cancelled>
+    {
+        self.took(self.backend().wait_cancellable_every(cancel, granularity))
+    }
+
+    /// Polls the value until the predicate holds for it, see
+    /// [`SemaphoreSlot::wait_for_value`].
+    ///
+    /// The value is read by [`try_value`][Semaphore::try_value], so this works wherever that
+    /// does, and fails with [`WaitError::Unsupported`] elsewhere.
+    pub fn wait_for_value<P>(&self, pred: P, poll: Duration, timeout: Option<Duration>)
+        -> Result<u32, WaitError>
+    where
+        P: Fn(u32) -> bool,
+    {
+        slot::poll_value(|| self.try_value(), pred, poll, timeout)
+    }
+
+    /// Waits for a token, calling the callback every `interval` while blocked.
+    pub fn wait_with_tick<F>(&self, interval: Duration, on_tick: F) -> Result<(), WaitAborted>
+    where
+        F: FnMut() -> ControlFlow<()>,
+    {
+        self.took(self.backend().wait_with_tick(interval, on_tick))
+    }
+
+    /// Waits for a token, restarting after signals according to the policy.
+    pub fn wait_with(&self, restart: Restart) -> Result<(), WaitError> {
+        self.took(self.backend().wait_with(restart))
+    }
+
+    /// Takes a token if available, restarting after signals according to the policy.
+    pub fn trywait_with(&self, restart: Restart) -> Result<(), WaitError> {
+        self.took(self.backend().trywait_with(restart))
+    }
+
+    /// Waits for a token until the deadline, restarting after signals according to the policy.
+    pub fn timedwait_with(&self, until: SystemTime, restart: Restart) -> Result<(), WaitError> {
+        self.took(self.backend().timedwait_with(until, restart))
+    }
+
+    /// Waits for a token at most for the given time, restarting after signals according to the
+    /// policy.
+    pub fn wait_timeout_with(&self, timeout: Duration, restart: Restart)
+        -> Result<(), WaitError>
+    {
+        self.took(self.backend().wait_timeout_with(timeout, restart))
+    }
+
     pub fn post(&self) -> Result<(), Overflow> {
-        unsafe {
-            if libc::sem_post(self.inner.as_ptr()) == 0 {
+        let op = Op::start("post", self.kind, 1, None);
+        let result = self.backend().post();
+        if result.is_ok() {
+            self.shadow.posted(1);
+        }
+        self.metrics.post(result.is_ok());
+        op.finish(if result.is_ok() { Outcome::Posted } else { Outcome::Overflow });
+        result
+    }
+
+    /// Returns a token, reporting unexpected errors instead of panicking.
+    pub fn post_checked(&self) -> Result<(), SemError> {
+        self.backend().post_checked()?;
+        self.shadow.posted(1);
+        Ok(())
+    }
+
+    /// Waits for a token and returns a guard that posts it back when dropped.
+    pub fn access(&self) -> SemaphoreGuard<'_> {
+        let op = Op::start("access", self.kind, 1, None);
+        op.run(|| self.wait_counted());
+        op.finish(Outcome::Acquired);
+        SemaphoreGuard::held(self.backend(), &self.shadow)
+    }
+
+    /// Waits for a token, leaving the decision to keep or return it for later.
+    pub fn take(&self) -> Token<'_> {
+        let op = Op::start("take", self.kind, 1, None);
+        op.run(|| self.wait_counted());
+        op.finish(Outcome::Acquired);
+        Token::held(self.backend(), &self.shadow)
+    }
+
+    /// Takes a token if available, returning a guard that posts it back when dropped.
+    pub fn try_access(&self) -> Option<SemaphoreGuard<'_>> {
+        self.trywait().ok().map(|()| SemaphoreGuard::held(self.backend(), &self.shadow))
+    }
+
+    /// Waits for a token at most for the given time, returning a guard that posts it back.
+    pub fn access_timeout(&self, timeout: Duration) -> Result<SemaphoreGuard<'_>, WaitError> {
+        let op = Op::start("access_timeout", self.kind, 1, SystemTime::now().checked_add(timeout));
+        traced(op, || self.wait_timeout_counted(timeout), WaitError::outcome)?;
+        Ok(SemaphoreGuard::held(self.backend(), &self.shadow))
+    }
+
+    /// Runs the closure while holding a token.
+    pub fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        let _guard = self.access();
+        f()
+    }
+
+    /// Runs the closure while holding a token, if one is available right away.
+    pub fn try_with<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        let _guard = self.try_access()?;
+        Some(f())
+    }
+
+    /// Runs the closure while holding a token, waiting for it at most for the given time.
+    pub fn with_timeout<R, F: FnOnce() -> R>(&self, timeout: Duration, f: F)
+        -> Result<R, WaitError>
+    {
+        let _guard = self.access_timeout(timeout)?;
+        Ok(f())
+    }
+
+    /// Waits until it gets `n` tokens, returning them if interrupted by a panic.
+    pub fn acquire_many(&self, n: u32) {
+        let op = Op::start("acquire_many", self.kind, n, None);
+        op.run(|| self.backend().acquire_many(n));
+        self.shadow.took(n);
+        op.finish(Outcome::Acquired);
+    }
+
+    /// Takes `n` tokens if they are all available right away, or none.
+    pub fn try_acquire_many(&self, n: u32) -> Result<(), NoToken> {
+        self.backend().try_acquire_many(n)?;
+        self.shadow.took(n);
+        Ok(())
+    }
+
+    /// Waits for `n` tokens at most for the given time, or returns the ones it got.
+    pub fn acquire_many_timeout(&self, n: u32, timeout: Duration) -> Result<(), PartialTimeout> {
+        let deadline = SystemTime::now().checked_add(timeout);
+        let op = Op::start("acquire_many_timeout", self.kind, n, deadline);
+        traced(op, || self.backend().acquire_many_timeout(n, timeout), |_| Outcome::TimedOut)?;
+        self.shadow.took(n);
+        Ok(())
+    }
+
+    /// Takes all the tokens available right now, returning how many.
+    pub fn drain(&self) -> u32 {
+        let drained = self.backend().drain();
+        self.shadow.took(drained);
+        drained
+    }
+
+    /// Takes at most `max` of the tokens available right now, returning how many.
+    pub fn drain_up_to(&self, max: u32) -> u32 {
+        let drained = self.backend().drain_up_to(max);
+        self.shadow.took(drained);
+        drained
+    }
+
+    /// Forces the value to `target`; only safe when nobody else uses the semaphore meanwhile.
+    ///
+    /// See [`SemaphoreSlot::set_value`].
+    pub fn set_value(&self, target: u32) -> Result<(), Error> {
+        self.backend().set_value(target)?;
+        self.shadow.set(target);
+        Ok(())
+    }
+
+    /// Like [`set_value`][Semaphore::set_value], but the exclusive borrow makes sure no other
+    /// thread uses the semaphore meanwhile.
+    ///
+    /// Other processes sharing the semaphore are out of reach of the borrow checker, though.
+    pub fn reset(&mut self, target: u32) -> Result<(), Error> {
+        self.set_value(target)
+    }
+
+    /// Wakes all the threads currently blocked on the semaphore, returning how many.
+    pub fn post_all(&self) -> Result<u32, Error> {
+        let waiters = self.waiters();
+        self.post_many(waiters)?;
+        Ok(waiters)
+    }
+
+    /// Posts `n` tokens.
+    pub fn release_many(&self, n: u32) -> Result<(), Overflow> {
+        self.post_many(n)?;
+        Ok(())
+    }
+
+    /// Posts `n` tokens, telling how many got posted on overflow.
+    pub fn post_many(&self, n: u32) -> Result<(), PartialPost> {
+        let result = self.backend().post_many(n);
+        self.shadow.posted(result.map_or_else(|partial| partial.posted(), |()| n));
+        result
+    }
+
+    /// Posts `n` tokens or, on overflow, tries to take back the ones posted.
+    pub fn post_many_atomic(&self, n: u32) -> Result<(), PartialPost> {
+        let result = self.backend().post_many_atomic(n);
+        self.shadow.posted(result.map_or_else(|partial| partial.posted(), |()| n));
+        result
+    }
+
+    /// The number of tokens available right now, never negative.
+    ///
+    /// # Panics
+    ///
+    /// If the value can't be found out, see [`try_value`][Semaphore::try_value].
+    pub fn value(&self) -> c_int {
+        let value = self.try_value();
+        value.unwrap_or_else(|e| panic!("Failed to read semaphore value: {}", e)) as c_int
+    }
+
+    /// The number of tokens available, failing if the platform can't tell.
+    ///
+    /// Where `sem_getvalue` doesn't work (macOS), this is the
+    /// [`tracked_value`][Semaphore::tracked_value] instead. Anonymous semaphores private to the
+    /// process are tracked there automatically, the others fail.
+    pub fn try_value(&self) -> Result<u32, Error> {
+        if shadow::GETVALUE_BROKEN {
+            return self.tracked_value();
+        }
+        self.backend().try_value()
+    }
+
+    /// The value as counted by this handle, without asking the OS.
+    ///
+    /// The counting is turned on by [`Builder::track_value`] (or automatically, see
+    /// [`try_value`][Semaphore::try_value]). It sees only the tokens taken and posted through
+    /// this handle and its guards, so it's right only as long as nothing else uses the
+    /// semaphore. Posts made by other processes can't be seen at all, so this fails with
+    /// [`ErrorKind::Unsupported`] for semaphores that may be shared with them, as well as for
+    /// ones not counted.
+    pub fn tracked_value(&self) -> Result<u32, Error> {
+        if self.kind.is_process_shared() != Some(false) {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Can't track the value of a semaphore shared with other processes",
+            ));
+        }
+        self.shadow
+            .get()
+            .ok_or_else(|| Error::new(ErrorKind::Unsupported, "Value not tracked"))
+    }
+
+    pub(crate) fn track_value(&mut self, value: u32) {
+        self.shadow = Shadow::tracking(value);
+    }
+
+    /// The value as reported by `sem_getvalue`, possibly negative when there are waiters.
+    pub fn raw_value(&self) -> Result<c_int, Error> {
+        self.backend().raw_value()
+    }
+
+    /// The number of threads blocked on the semaphore, see [`SemaphoreSlot::waiters`].
+    pub fn waiters(&self) -> u32 {
+        self.backend().waiters()
+    }
+
+    /// The number of blocked threads as the platform reports it, see
+    /// [`SemaphoreSlot::reported_waiters`].
+    pub fn reported_waiters(&self) -> Result<Option<u32>, Error> {
+        self.backend().reported_waiters()
+    }
+
+    /// The counters of the waits and posts through this handle so far.
+    ///
+    /// The waits are [`wait`][Semaphore::wait], [`timedwait`][Semaphore::timedwait],
+    /// [`wait_timeout`][Semaphore::wait_timeout], [`wait_deadline`][Semaphore::wait_deadline],
+    /// [`wait_spin`][Semaphore::wait_spin] and the ones behind the guards. The other waits
+    /// (the `_checked`, `_interruptible`, `_with` and `_on` variants, the cancellable and ticking
+    /// ones and the multi-token ones) aren't counted. The posts are the calls to
+    /// [`post`][Semaphore::post], the guards returning their tokens aren't counted.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Zeroes the [`metrics`][Semaphore::metrics].
+    #[cfg(feature = "metrics")]
+    pub fn reset_metrics(&self) {
+        self.metrics.reset()
+    }
+
+    /// How long the waits through this handle that had to block took.
+    ///
+    /// Covers the same waits as the [`metrics`][Semaphore::metrics], see [`HistogramSnapshot`]
+    /// for which are recorded.
+    #[cfg(feature = "histogram")]
+    pub fn latency_histogram(&self) -> HistogramSnapshot {
+        self.metrics.latency()
+    }
+
+    /// Empties the [`latency_histogram`][Semaphore::latency_histogram].
+    #[cfg(feature = "histogram")]
+    pub fn reset_latency_histogram(&self) {
+        self.metrics.reset_latency()
+    }
+
+    /// The underlying `sem_t`, for passing to C code.
+    ///
+    /// The semaphore still belongs to this handle, the pointer is valid only as long as it
+    /// lives.
+    ///
+    /// # Panics
+    ///
+    /// If the semaphore is backed by GCD, see [`anonymous`][Semaphore::anonymous].
+    pub fn as_raw(&self) -> *mut sem_t {
+        self.sem_t().as_ptr()
+    }
+
+    /// Gives up the semaphore, returning the underlying `sem_t`.
+    ///
+    /// Nothing is destroyed or freed, that becomes the caller's responsibility. An anonymous
+    /// semaphore can be adopted back by [`from_raw`][Semaphore::from_raw].
+    ///
+    /// # Panics
+    ///
+    /// If the semaphore is backed by GCD, like [`as_raw`][Semaphore::as_raw].
+    pub fn into_raw(self) -> *mut sem_t {
+        let ptr = self.sem_t().as_ptr();
+        mem::forget(self);
+        ptr
+    }
+
+    /// Adopts a semaphore created elsewhere.
+    ///
+    /// If `owned` is set, the semaphore is destroyed and its memory freed on drop, as with
+    /// [`anonymous`][Semaphore::anonymous]. Otherwise, drop does nothing at all.
+    ///
+    /// On macOS, where the anonymous semaphores are unlinked named ones, an owned semaphore is
+    /// closed (`sem_close`) on drop instead.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to an initialized semaphore that stays valid for the lifetime of
+    /// the result. If `owned` is set, it must come from [`into_raw`][Semaphore::into_raw] of an
+    /// anonymous semaphore (or otherwise be allocated as `Box<sem_t>`, or on macOS be returned
+    /// by `sem_open`) and must not be used elsewhere afterwards.
+    ///
+    /// # Panics
+    ///
+    /// If the pointer is NULL.
+    pub unsafe fn from_raw(ptr: *mut sem_t, owned: bool) -> Semaphore {
+        Semaphore {
+            inner: NonNull::new(ptr).expect("NULL semaphore"),
+            mode: match owned {
+                false => Mode::Released,
+                // See anonymous, there's nothing else an owned pointer can come from
+                true if cfg!(target_vendor = "apple") => Mode::Named,
+                true => Mode::Anonymous,
+            },
+            kind: Kind::Foreign,
+            metrics: Metrics::new(),
+            slow_wait: None,
+            shadow: Shadow::untracked(),
+        }
+    }
+
+    /// Gives up the semaphore for good, leaving it initialized and its memory allocated.
+    ///
+    /// This is for semaphores that should outlive the handle, eg. ones other processes keep
+    /// using. The pointer can be adopted again by [`from_raw`][Semaphore::from_raw].
+    ///
+    /// # Panics
+    ///
+    /// If the semaphore is backed by GCD, like [`as_raw`][Semaphore::as_raw].
+    pub fn leak(self) -> NonNull<sem_t> {
+        let ptr = self.sem_t();
+        mem::forget(self);
+        ptr
+    }
+
+    /// Whether the semaphore gets destroyed (`sem_destroy`) when this handle is dropped.
+    ///
+    /// Unlike with [`leak`][Semaphore::leak], the memory is still released on drop. This makes a
+    /// difference only for semaphores this handle destroys in the first place (anonymous and
+    /// placed ones), named and mapped semaphores are never destroyed by the handle. Neither are
+    /// the ones adopted by [`from_raw`][Semaphore::from_raw] without ownership, turning it on
+    /// there does nothing.
+    pub fn set_destroy_on_drop(&mut self, destroy: bool) {
+        self.mode = match (&self.mode, destroy) {
+            // Uninitialized frees the memory without destroying, which is just what we want
+            (&Mode::Anonymous, false) => Mode::Uninitialized,
+            (&Mode::Uninitialized, true) => Mode::Anonymous,
+            (&Mode::Shared, false) => Mode::SharedKept,
+            (&Mode::SharedKept, true) => Mode::Shared,
+            (&Mode::Placed, false) => Mode::PlacedKept,
+            (&Mode::PlacedKept, true) => Mode::Placed,
+            _ => return,
+        };
+    }
+
+    /// Destroys the semaphore, reporting the failure.
+    ///
+    /// Dropping the semaphore destroys it too, but ignores any errors. This returns them (eg.
+    /// `EBUSY` on platforms that detect destroying a semaphore with waiters), together with the
+    /// semaphore itself, still alive, so it can be retried later. The memory is released only on
+    /// success.
+    ///
+    /// Semaphores that are not destroyed by the handle (named or mapped ones, see
+    /// [`set_destroy_on_drop`][Semaphore::set_destroy_on_drop]) are simply dropped.
+    pub fn destroy(mut self) -> Result<(), (Self, Error)> {
+        let destroyed = match self.mode {
+            Mode::Anonymous => Mode::Uninitialized,
+            Mode::Shared => Mode::SharedKept,
+            Mode::Placed => Mode::Released,
+            _ => return Ok(()),
+        };
+        match unsafe { Self::destroy_in_place(self.inner) } {
+            Ok(()) => {
+                // Only release the memory when dropped
+                self.mode = destroyed;
                 Ok(())
-            } else if Error::last_os_error().raw_os_error() == Some(libc::EOVERFLOW) {
-                Err(Overflow)
-            } else {
-                unreachable!("Semaphore corruption")
+            },
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    /// Destroys the semaphore and returns how many tokens it had left.
+    ///
+    /// Useful at the end of a test or at shutdown, to check that every token taken was returned.
+    /// If the value can't be read or the destruction fails (see [`destroy`][Semaphore::destroy]),
+    /// the semaphore is handed back together with the error.
+    pub fn into_inner(self) -> Result<u32, (Self, Error)> {
+        let value = match self.try_value() {
+            Ok(value) => value,
+            Err(e) => return Err((self, e)),
+        };
+        self.destroy()?;
+        Ok(value)
+    }
+
+    /// Like [`into_inner`][Semaphore::into_inner], but for a shared semaphore.
+    ///
+    /// Succeeds only if this is the last reference to the semaphore, otherwise (or if
+    /// `into_inner` fails) the semaphore is returned back, like with [`Arc::try_unwrap`].
+    pub fn try_into_inner(sem: Arc<Semaphore>) -> Result<u32, Arc<Semaphore>> {
+        Arc::try_unwrap(sem).and_then(|sem| sem.into_inner().map_err(|(sem, _)| Arc::new(sem)))
+    }
+
+    /// Wakes up all the waiters and destroys the semaphore once they are gone.
+    ///
+    /// The waiters (through other handles to the same semaphore, eg. ones from
+    /// [`from_raw`][Semaphore::from_raw]) are woken by posting a token for each of them, so their
+    /// waits succeed. It's up to them to notice the shutdown by other means. The semaphore is
+    /// destroyed only after [`waiters`][Semaphore::waiters] drops to zero, which needs the waits
+    /// to go through this crate (or the platform to report them).
+    ///
+    /// No new waiters must come meanwhile. If they are not all gone within the timeout (`None`
+    /// waits for as long as it takes) or the destruction fails, the semaphore is returned back,
+    /// possibly with some extra tokens.
+    pub fn destroy_when_idle(self, timeout: Option<Duration>) -> Result<(), Self> {
+        const POLL: Duration = Duration::from_millis(1);
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        loop {
+            let waiters = self.waiters();
+            if waiters == 0 {
+                return self.destroy().map_err(|(me, _)| me);
+            }
+            // Top up the tokens, the ones posted before may not have been picked up yet
+            let available = self.try_value().unwrap_or(0);
+            if waiters > available {
+                let _ = self.post_many(waiters - available);
             }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err(self);
+            }
+            thread::sleep(POLL);
         }
     }
 
-    pub fn value(&self) -> c_int {
-        unsafe {
-            let mut val = 0;
-            assert_eq!(0, libc::sem_getvalue(self.inner.as_ptr(), &mut val));
-            val
+    /// Starts the semaphore from scratch in a child process after `fork`.
+    ///
+    /// A semaphore that is not process-shared gets copied into the child together with whatever
+    /// state it was in at the time of the fork (eg. bookkeeping of the parent's waiting threads).
+    /// This throws the state away and initializes the semaphore again, in the same memory, with
+    /// the given value.
+    ///
+    /// Semaphores that are shared with other processes (named or in shared memory) are refused
+    /// with [`ErrorKind::InvalidInput`], reinitializing these would break everyone else.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else (no other thread) may be using the semaphore during or before the call. That
+    /// is naturally the case in a freshly forked child, which has only the one thread.
+    pub unsafe fn reinit_after_fork(&self, value: u32) -> Result<(), Error> {
+        match self.mode {
+            Mode::Anonymous => {
+                // Best effort, the old state may be nonsense. Glibc doesn't do anything here
+                // anyway.
+                libc::sem_destroy(self.inner.as_ptr());
+                init(self.inner.as_ptr(), false, value)
+            },
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Only private anonymous semaphores can be reinitialized",
+            )),
         }
     }
 }
@@ -145,18 +1380,54 @@ impl Semaphore {
 impl Drop for Semaphore {
     fn drop(&mut self) {
         unsafe {
+            // The destruction is best effort, there's nothing much to do about a failure here and
+            // panicking in drop is worse (it aborts during unwinding). Use destroy to get the
+            // error.
             match self.mode {
-                Mode::Uninitialized => (),
+                Mode::Uninitialized => drop(Box::from_raw(self.inner.as_ptr())),
                 Mode::Anonymous => {
-                    assert_eq!(0, libc::sem_destroy(self.inner.as_ptr()), "Corrupt semaphore");
-                }
+                    libc::sem_destroy(self.inner.as_ptr());
+                    drop(Box::from_raw(self.inner.as_ptr()));
+                },
+                Mode::Shared => {
+                    libc::sem_destroy(self.inner.as_ptr());
+                    libc::munmap(self.inner.as_ptr() as *mut _, mem::size_of::<sem_t>());
+                },
+                Mode::SharedKept => {
+                    libc::munmap(self.inner.as_ptr() as *mut _, mem::size_of::<sem_t>());
+                },
+                // Use NamedSemaphore::close to get the error.
+                Mode::Named => {
+                    libc::sem_close(self.inner.as_ptr());
+                },
+                // Other processes may still be using it, so only unmap our view.
+                Mode::Mapped => mapped::unmap(self.inner.cast()),
+                Mode::Placed => {
+                    libc::sem_destroy(self.inner.as_ptr());
+                },
+                Mode::PlacedKept | Mode::Released => (),
+                #[cfg(all(target_vendor = "apple", feature = "dispatch"))]
+                Mode::Dispatch => {
+                    drop(Box::from_raw(self.inner.as_ptr() as *mut DispatchSemaphore));
+                },
             }
-
-            drop(Box::from_raw(self.inner.as_ptr()));
         }
     }
 }
 
+impl Debug for Semaphore {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        // Neither panic nor block here, this may be printed from error handling paths
+        let mut out = fmt.debug_struct("Semaphore");
+        out.field("kind", &self.kind).field("address", &self.inner);
+        match self.try_value() {
+            Ok(value) => out.field("value", &value),
+            Err(_) => out.field("value", &format_args!("<unavailable>")),
+        };
+        out.field("process_shared", &self.kind.is_process_shared()).finish()
+    }
+}
+
 unsafe impl Send for Semaphore {}
 unsafe impl Sync for Semaphore {}
 
@@ -166,6 +1437,7 @@ mod tests {
     use std::thread;
 
     use super::*;
+    use test_util::fork;
 
     #[test]
     fn anon_create_destroy() {
@@ -204,4 +1476,293 @@ mod tests {
         sem.post().unwrap();
         sem.trywait().unwrap();
     }
+
+    #[test]
+    fn error_kinds() {
+        assert_eq!(ErrorKind::WouldBlock, Error::from(NoToken::new()).kind());
+        assert_eq!(WaitError::WouldBlock, WaitError::from(NoToken::new()));
+        assert_eq!(ErrorKind::TimedOut, Error::from(WaitError::TimedOut).kind());
+        assert_eq!(ErrorKind::Unsupported, Error::from(WaitError::Unsupported).kind());
+        assert_eq!(ErrorKind::Other, Error::from(Overflow::new()).kind());
+        assert_eq!(ErrorKind::Interrupted, Error::from(Interrupted).kind());
+        let partial = PartialTimeout {
+            collected: 2,
+            lost: 0,
+        };
+        assert_eq!(ErrorKind::TimedOut, Error::from(partial).kind());
+        assert_eq!(ErrorKind::Other, Error::from(PartialPost { posted: 1 }).kind());
+        let sem = Semaphore::anonymous(0).unwrap();
+        assert_eq!(Err(NoToken::from_errno(libc::EAGAIN)), sem.trywait());
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn errno_preserved() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let e = sem.trywait().unwrap_err();
+        assert_eq!(Some(libc::EAGAIN), e.os_error());
+        let e = Error::from(e);
+        assert_eq!(ErrorKind::WouldBlock, e.kind());
+        let inner = e.get_ref().unwrap().downcast_ref::<NoToken>().unwrap();
+        assert_eq!(Some(libc::EAGAIN), inner.os_error());
+
+        let sem = Semaphore::anonymous(Semaphore::max_value()).unwrap();
+        let e = sem.post().unwrap_err();
+        assert_eq!(Some(libc::EOVERFLOW), e.os_error());
+        let e = Error::from(e);
+        assert_eq!(ErrorKind::Other, e.kind());
+        let inner = e.get_ref().unwrap().downcast_ref::<Overflow>().unwrap();
+        assert_eq!(Some(libc::EOVERFLOW), inner.os_error());
+
+        assert_eq!(None, NoToken::new().os_error());
+        assert_eq!(None, Overflow::new().os_error());
+    }
+
+    #[test]
+    fn sem_errors() {
+        let mapping = [
+            (libc::EAGAIN, SemError::WouldBlock, ErrorKind::WouldBlock),
+            (libc::ETIMEDOUT, SemError::TimedOut, ErrorKind::TimedOut),
+            (libc::EINTR, SemError::Interrupted, ErrorKind::Interrupted),
+            (libc::EOVERFLOW, SemError::Overflow, ErrorKind::Other),
+            (libc::EINVAL, SemError::Invalid, ErrorKind::InvalidInput),
+            (libc::EPERM, SemError::Os(libc::EPERM), ErrorKind::PermissionDenied),
+        ];
+        for &(errno, err, kind) in &mapping {
+            assert_eq!(err, SemError::from_errno(errno));
+            assert_eq!(kind, Error::from(err).kind());
+        }
+        assert_eq!(Some(libc::ENOSYS), Error::from(SemError::Os(libc::ENOSYS)).raw_os_error());
+        assert_eq!(SemError::WouldBlock, SemError::from(NoToken::new()));
+        assert_eq!(SemError::Overflow, SemError::from(Overflow::new()));
+        assert_eq!(SemError::Invalid, SemError::from(WaitError::Unsupported));
+    }
+
+    #[test]
+    fn initial_value_limits() {
+        let max = Semaphore::max_value();
+        let sem = Semaphore::anonymous(max).unwrap();
+        assert_eq!(max, sem.try_value().unwrap());
+        let e = Semaphore::anonymous(max + 1).map(|_| ()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+        assert_eq!(None, e.raw_os_error());
+        let e = Semaphore::anonymous_shared(max + 1).map(|_| ()).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+    }
+
+    #[test]
+    fn shared_fork() {
+        let sem = Semaphore::anonymous_shared(0).unwrap();
+        let child = fork(|| {
+            sem.post().unwrap();
+            sem.post().unwrap();
+        });
+        sem.wait();
+        sem.wait();
+        child.join();
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        let sem = Semaphore::anonymous_posix(1).unwrap();
+        let ptr = sem.into_raw();
+        assert_eq!(0, unsafe { libc::sem_post(ptr) });
+        let sem = unsafe { Semaphore::from_raw(ptr, true) };
+        assert_eq!(ptr, sem.as_raw());
+        sem.wait();
+        sem.wait();
+        sem.trywait().unwrap_err();
+    }
+
+    #[test]
+    fn raw_borrowed() {
+        let sem = Semaphore::anonymous_posix(0).unwrap();
+        let view = unsafe { Semaphore::from_raw(sem.as_raw(), false) };
+        view.post().unwrap();
+        drop(view);
+        // Still alive and usable
+        sem.wait();
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn leak_adopt() {
+        let sem = Semaphore::anonymous_posix(0).unwrap();
+        let ptr = sem.leak();
+        let mut view = unsafe { Semaphore::from_raw(ptr.as_ptr(), false) };
+        view.post().unwrap();
+        // Not ours to destroy
+        view.set_destroy_on_drop(true);
+        drop(view);
+        unsafe {
+            assert_eq!(0, libc::sem_trywait(ptr.as_ptr()));
+            Semaphore::destroy_in_place(ptr).unwrap();
+            drop(Box::from_raw(ptr.as_ptr()));
+        }
+    }
+
+    #[test]
+    fn destroy_explicit() {
+        Semaphore::anonymous(1).unwrap().destroy().map_err(|(_, e)| e).unwrap();
+        Semaphore::anonymous_shared(1).unwrap().destroy().map_err(|(_, e)| e).unwrap();
+        let mut kept = Semaphore::anonymous(0).unwrap();
+        kept.set_destroy_on_drop(false);
+        kept.destroy().map_err(|(_, e)| e).unwrap();
+    }
+
+    #[test]
+    fn debug() {
+        let sem = Semaphore::anonymous(3).unwrap();
+        let out = format!("{:?}", sem);
+        assert!(out.starts_with("Semaphore { kind: Anonymous, address: 0x"), "{}", out);
+        assert!(out.ends_with("value: 3, process_shared: Some(false) }"), "{}", out);
+        let out = format!("{:?}", Semaphore::anonymous_shared(0).unwrap());
+        assert!(out.contains("kind: AnonymousShared"), "{}", out);
+        assert!(out.contains("process_shared: Some(true)"), "{}", out);
+    }
+
+    #[test]
+    fn into_inner_balance() {
+        const ITEMS: u32 = 100;
+        let sem = Arc::new(Semaphore::anonymous(4).unwrap());
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..ITEMS {
+                        sem.with(|| ());
+                    }
+                });
+            }
+        });
+        assert_eq!(Some(4), Semaphore::try_into_inner(sem).ok());
+
+        // One leaked token
+        let sem = Semaphore::anonymous(2).unwrap();
+        sem.wait();
+        assert_eq!(1, sem.into_inner().map_err(|(_, e)| e).unwrap());
+    }
+
+    #[test]
+    fn try_into_inner_shared() {
+        let sem = Arc::new(Semaphore::anonymous(1).unwrap());
+        let other = Arc::clone(&sem);
+        let sem = Semaphore::try_into_inner(sem).unwrap_err();
+        drop(other);
+        assert_eq!(Some(1), Semaphore::try_into_inner(sem).ok());
+    }
+
+    #[test]
+    fn drop_with_waiter() {
+        // The waiter is left in an undefined state, so keep it away from the other tests
+        fork(|| {
+            let sem = Semaphore::anonymous_posix(0).unwrap();
+            let raw = sem.as_raw() as usize;
+            thread::spawn(move || {
+                let view = unsafe { Semaphore::from_raw(raw as *mut sem_t, false) };
+                view.wait();
+            });
+            thread::sleep(Duration::from_millis(50));
+            drop(sem);
+        })
+        .join();
+    }
+
+    #[test]
+    fn waiters_counted() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        assert_eq!(0, sem.waiters());
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| sem.wait());
+            }
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while sem.waiters() != 3 {
+                assert!(Instant::now() < deadline, "Waiters never showed up");
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert_eq!(0, sem.value());
+            sem.post_many(3).unwrap();
+        });
+        assert_eq!(0, sem.waiters());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn reported_waiters() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        // Tokens for everyone, so nobody waits
+        assert_eq!(Some(0), sem.reported_waiters().unwrap());
+        sem.wait();
+        // Linux reports 0 either way, that tells nothing
+        assert_eq!(None, sem.reported_waiters().unwrap());
+    }
+
+    #[test]
+    fn destroy_idle() {
+        let sem = Semaphore::anonymous_posix(0).unwrap();
+        let raw = sem.as_raw() as usize;
+        let waiters = (0..3)
+            .map(|_| {
+                thread::spawn(move || {
+                    let view = unsafe { Semaphore::from_raw(raw as *mut sem_t, false) };
+                    view.wait();
+                })
+            })
+            .collect::<Vec<_>>();
+        while sem.waiters() < 3 {
+            thread::yield_now();
+        }
+        sem.destroy_when_idle(Some(Duration::from_secs(10))).map_err(|_| ()).unwrap();
+        for waiter in waiters {
+            waiter.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn destroy_idle_timeout() {
+        let sem = Semaphore::anonymous_posix(0).unwrap();
+        // Pretends to wait, but never takes the token
+        let stuck = waiters::Waiting::new(sem.as_raw());
+        let sem = sem.destroy_when_idle(Some(Duration::from_millis(20))).unwrap_err();
+        assert_eq!(1, sem.value());
+        drop(stuck);
+        sem.destroy_when_idle(None).map_err(|_| ()).unwrap();
+    }
+
+    #[test]
+    fn keep_shared() {
+        let mut sem = Semaphore::anonymous_shared(1).unwrap();
+        sem.set_destroy_on_drop(false);
+        let child = fork(|| sem.wait());
+        child.join();
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn reinit_fork() {
+        let sem = Arc::new(Semaphore::anonymous(0).unwrap());
+        let child = fork(|| {
+            unsafe { sem.reinit_after_fork(2).unwrap() };
+            let poster = thread::spawn({
+                let sem = Arc::clone(&sem);
+                move || sem.post().unwrap()
+            });
+            poster.join().unwrap();
+            assert_eq!(3, sem.value());
+            sem.wait();
+            assert_eq!(2, sem.value());
+        });
+        child.join();
+        // The parent's copy is not affected
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn reinit_shared_refused() {
+        let sem = Semaphore::anonymous_shared(1).unwrap();
+        let err = unsafe { sem.reinit_after_fork(0) }.unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        assert_eq!(1, sem.value());
+    }
 }
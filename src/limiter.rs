@@ -0,0 +1,208 @@
+//! Limiting the number of concurrent operations.
+
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use many::value_max;
+use {Overflow, Semaphore};
+
+/// Accounts for a running operation and returns its token, even on panic.
+struct Running<'a>(&'a ConcurrencyLimiter);
+
+impl<'a> Drop for Running<'a> {
+    fn drop(&mut self) {
+        let limiter = self.0;
+        limiter.in_flight.fetch_sub(1, Ordering::SeqCst);
+        let absorbed = limiter
+            .debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| d.checked_sub(1))
+            .is_ok();
+        if !absorbed {
+            // We took the token, so there's room for it
+            let _ = limiter.sem.post();
+        }
+    }
+}
+
+/// Lets at most a given number of operations run at once.
+///
+/// Apart from the semaphore doing the limiting, it keeps track of how many operations are
+/// running and waiting, for reporting the utilization.
+///
+/// The limit can be changed at runtime by [`set_max`][ConcurrencyLimiter::set_max]. If it goes
+/// down while more operations are running than the new limit allows, the surplus is absorbed
+/// as they finish, so for a while there may be more of them in flight than the limit.
+pub struct ConcurrencyLimiter {
+    sem: Semaphore,
+    max: AtomicU32,
+    in_flight: AtomicU32,
+    waiting: AtomicU32,
+    // Tokens to swallow as they come back, after lowering the limit
+    debt: AtomicU32,
+    resize: Mutex<()>,
+}
+
+impl ConcurrencyLimiter {
+    /// Creates the limiter for up to `max` operations at once.
+    pub fn new(max: u32) -> Result<Self, Error> {
+        if max > value_max() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Limit too large for a semaphore"));
+        }
+        Ok(ConcurrencyLimiter {
+            sem: Semaphore::anonymous(max)?,
+            max: AtomicU32::new(max),
+            in_flight: AtomicU32::new(0),
+            waiting: AtomicU32::new(0),
+            debt: AtomicU32::new(0),
+            resize: Mutex::new(()),
+        })
+    }
+
+    fn running(&self) -> Running<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        Running(self)
+    }
+
+    /// Waits for a free slot and runs the closure in it.
+    ///
+    /// The slot is released afterwards, even if the closure panics.
+    pub fn run<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        self.waiting.fetch_add(1, Ordering::SeqCst);
+        self.sem.wait();
+        self.waiting.fetch_sub(1, Ordering::SeqCst);
+        let _running = self.running();
+        f()
+    }
+
+    /// Runs the closure if there's a free slot right now.
+    pub fn try_run<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        self.sem.trywait().ok()?;
+        let _running = self.running();
+        Some(f())
+    }
+
+    /// The number of operations running right now.
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// The number of operations waiting for a slot.
+    pub fn waiting(&self) -> u32 {
+        self.waiting.load(Ordering::SeqCst)
+    }
+
+    /// The current limit.
+    pub fn max(&self) -> u32 {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    /// Changes the limit.
+    ///
+    /// Raising the limit lets waiting operations in right away. Lowering it doesn't wait for the
+    /// running operations, the surplus slots disappear as they finish.
+    pub fn set_max(&self, max: u32) -> Result<(), Overflow> {
+        if max > value_max() {
+            return Err(Overflow::new());
+        }
+        let _resize = self.resize.lock().unwrap_or_else(|e| e.into_inner());
+        let old = self.max.swap(max, Ordering::SeqCst);
+        if max > old {
+            let mut grow = max - old;
+            // First forget about the tokens we didn't absorb yet
+            while grow > 0 {
+                let paid = self.debt.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |d| {
+                    d.checked_sub(1)
+                });
+                if paid.is_err() {
+                    break;
+                }
+                grow -= 1;
+            }
+            self.sem.post_many(grow).expect("Overflow raising a checked limit");
+        } else {
+            let mut shrink = old - max;
+            // Take what's free right away, the rest when it comes back
+            while shrink > 0 && self.sem.trywait().is_ok() {
+                shrink -= 1;
+            }
+            self.debt.fetch_add(shrink, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    /// Runs a task, checking the limit holds.
+    fn task(limiter: &ConcurrencyLimiter, limit: &AtomicU32) {
+        limiter.run(|| {
+            // The limit may change under our hands, either of them holds for the moment we look
+            let before = limit.load(Ordering::SeqCst);
+            let in_flight = limiter.in_flight();
+            let after = limit.load(Ordering::SeqCst);
+            assert!(in_flight <= before.max(after), "{} in flight", in_flight);
+            thread::sleep(Duration::from_micros(200));
+        });
+    }
+
+    #[test]
+    fn limits_across_resizes() {
+        let limiter = ConcurrencyLimiter::new(4).unwrap();
+        // Raised before raising the limit, lowered only after the surplus is gone
+        let limit = AtomicU32::new(4);
+        thread::scope(|s| {
+            for _ in 0..16 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        task(&limiter, &limit);
+                    }
+                });
+            }
+            for &max in &[6, 2, 5, 1, 4] {
+                thread::sleep(Duration::from_millis(10));
+                limit.fetch_max(max, Ordering::SeqCst);
+                limiter.set_max(max).unwrap();
+                while limiter.debt.load(Ordering::SeqCst) > 0 {
+                    thread::yield_now();
+                }
+                limit.store(max, Ordering::SeqCst);
+            }
+        });
+        assert_eq!(0, limiter.in_flight());
+        assert_eq!(0, limiter.waiting());
+        assert_eq!(4, limiter.max());
+        assert_eq!(4, limiter.sem.value());
+    }
+
+    #[test]
+    fn released_on_panic() {
+        let limiter = ConcurrencyLimiter::new(1).unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| limiter.run(|| panic!("Oops"))));
+        assert!(result.is_err());
+        assert_eq!(0, limiter.in_flight());
+        assert_eq!(Some(42), limiter.try_run(|| 42));
+        limiter.run(|| assert_eq!(None, limiter.try_run(|| ())));
+    }
+
+    #[test]
+    fn shrink_absorbs_lazily() {
+        let limiter = ConcurrencyLimiter::new(2).unwrap();
+        limiter.run(|| {
+            limiter.set_max(0).unwrap();
+            // The free one is gone right away, ours when we finish
+            assert_eq!(1, limiter.debt.load(Ordering::SeqCst));
+            assert_eq!(None, limiter.try_run(|| ()));
+        });
+        assert_eq!(0, limiter.debt.load(Ordering::SeqCst));
+        assert_eq!(0, limiter.sem.value());
+        limiter.set_max(1).unwrap();
+        assert_eq!(Some(()), limiter.try_run(|| ()));
+    }
+}
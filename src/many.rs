@@ -0,0 +1,595 @@
+//! Taking and returning several tokens at once.
+//!
+//! POSIX semaphores deal in single tokens only, so these loop. Other threads may observe the
+//! value going down in steps (and, if the operation fails, up again).
+
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+use libc::{self, c_int};
+
+use backend::Backend;
+use trace;
+use {NoToken, Overflow, PartialPost, PartialTimeout, SemaphoreSlot};
+
+/// The smallest `SEM_VALUE_MAX` POSIX allows.
+const POSIX_SEM_VALUE_MAX: u32 = 32_767;
+
+/// `SEM_VALUE_MAX` from the system headers, where we know it.
+#[cfg(any(
+    all(target_os = "linux", any(target_env = "gnu", target_env = "musl")),
+    target_os = "freebsd",
+    target_os = "dragonfly",
+))]
+pub(crate) const SEM_VALUE_MAX: u32 = c_int::MAX as u32;
+#[cfg(target_os = "android")]
+pub(crate) const SEM_VALUE_MAX: u32 = 0x3fff_ffff;
+// Elsewhere, only the minimum is guaranteed.
+#[cfg(not(any(
+    all(target_os = "linux", any(target_env = "gnu", target_env = "musl")),
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "android",
+)))]
+pub(crate) const SEM_VALUE_MAX: u32 = POSIX_SEM_VALUE_MAX;
+
+/// The largest value of a semaphore the system supports.
+///
+/// Asks the system, as the header constant may be missing or lower than the real limit.
+pub(crate) fn value_max() -> u32 {
+    match unsafe { libc::sysconf(libc::_SC_SEM_VALUE_MAX) } {
+        // Even a 64-bit sysconf answer can't go over what sem_post counts in
+        max if max > 0 => max.min(c_int::MAX as _) as u32,
+        // Indeterminate, only the POSIX minimum is sure
+        _ => POSIX_SEM_VALUE_MAX,
+    }
+}
+
+/// Tokens taken so far, returned on drop unless kept.
+///
+/// This makes sure the tokens go back even if a wait panics.
+struct Held<'a> {
+    backend: Backend<'a>,
+    count: u32,
+}
+
+impl<'a> Held<'a> {
+    fn keep(mut self) {
+        self.count = 0;
+    }
+
+    /// Returns the tokens now, telling how many didn't fit back.
+    fn release(mut self) -> u32 {
+        self.post_back()
+    }
+
+    fn post_back(&mut self) -> u32 {
+        let mut lost = 0;
+        for _ in 0..self.count {
+            // We took the tokens, so there should be room for them. If someone else posted so
+            // much meanwhile there isn't, the token is lost, but don't give up on the rest.
+            if self.backend.post().is_err() {
+                lost += 1;
+            }
+        }
+        self.count = 0;
+        if lost > 0 {
+            trace::lost_tokens(lost);
+        }
+        lost
+    }
+}
+
+impl<'a> Drop for Held<'a> {
+    fn drop(&mut self) {
+        // Only when unwinding, the errors return the tokens through release and report the loss
+        self.post_back();
+    }
+}
+
+impl SemaphoreSlot {
+    /// Waits until it gets `n` tokens.
+    ///
+    /// The tokens are taken one by one. If the waiting panics midway, the tokens taken so far
+    /// are posted back. Zero is a no-op.
+    ///
+    /// Note that two threads acquiring multiple tokens from a semaphore that doesn't have enough
+    /// for both may deadlock, each holding part of what it needs.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is above the maximum value of a semaphore, as such a wait could never succeed.
+    pub fn acquire_many(&self, n: u32) {
+        self.backend().acquire_many(n)
+    }
+
+    /// Waits for `n` tokens, but at most for the given time.
+    ///
+    /// The timeout applies to the whole operation, not to each token. If it runs out, the tokens
+    /// taken so far are posted back and the error tells how many there were. Signals don't
+    /// extend the deadline.
+    pub fn acquire_many_timeout(&self, n: u32, timeout: Duration) -> Result<(), PartialTimeout> {
+        self.backend().acquire_many_timeout(n, timeout)
+    }
+
+    /// Takes `n` tokens if they are available right away, or none.
+    ///
+    /// The tokens are taken one by one and, if one is missing, the ones already taken are posted
+    /// back. So it's all-or-nothing in effect, but not atomic, other threads may see the value
+    /// dip for a moment, or fail their own attempt because of tokens this one is about to
+    /// return. Zero always succeeds.
+    ///
+    /// Returning the tokens keeps going even if one of the posts fails.
+    pub fn try_acquire_many(&self, n: u32) -> Result<(), NoToken> {
+        self.backend().try_acquire_many(n)
+    }
+
+    /// Takes all the tokens available right now and returns how many there were.
+    ///
+    /// The semaphore was empty at some point during the call, but someone may have posted right
+    /// after that. If someone keeps posting quickly, this may keep going for a long time, see
+    /// [`drain_up_to`][SemaphoreSlot::drain_up_to].
+    pub fn drain(&self) -> u32 {
+        self.backend().drain()
+    }
+
+    /// Takes the available tokens, but at most `max` of them.
+    ///
+    /// Returns how many it took.
+    pub fn drain_up_to(&self, max: u32) -> u32 {
+        self.backend().drain_up_to(max)
+    }
+
+    /// Forces the value to `target`, by draining the semaphore and posting `target` tokens.
+    ///
+    /// This is not atomic. It is meant for when nobody else uses the semaphore at the moment
+    /// (eg. between benchmark iterations); a concurrent wait or post may end up on either side of
+    /// the drain and the value is then off.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] if the `target` is over the maximum value. If a
+    /// post overflows midway (someone else posted meanwhile), the error wraps a [`PartialPost`]
+    /// telling how many of the tokens got posted.
+    pub fn set_value(&self, target: u32) -> Result<(), Error> {
+        self.backend().set_value(target)
+    }
+
+    /// Wakes all the threads currently blocked on the semaphore.
+    ///
+    /// Posts as many tokens as there are [`waiters`][SemaphoreSlot::waiters] and returns how many
+    /// that was. Threads starting to wait during the call may or may not be counted, and a thread
+    /// that just woke up may still be counted, in which case its token stays in the semaphore.
+    ///
+    /// Threads blocked outside of this crate (eg. in C code) are woken only where the platform
+    /// reports them.
+    pub fn post_all(&self) -> Result<u32, Error> {
+        self.backend().post_all()
+    }
+
+    /// How many threads the platform says are blocked on the semaphore.
+    ///
+    /// Some platforms report the waiters as a negative value of `sem_getvalue`. Elsewhere
+    /// (including Linux), this is `None` unless the semaphore has tokens (and therefore no
+    /// waiters). Unlike [`waiters`][SemaphoreSlot::waiters], the waits of this
+    /// crate are not counted on top.
+    ///
+    /// The number is stale by the time it is returned, threads come and go all the time. It's
+    /// good for monitoring, not for deciding anything.
+    pub fn reported_waiters(&self) -> Result<Option<u32>, Error> {
+        self.backend().reported_waiters()
+    }
+
+    /// Posts `n` tokens.
+    ///
+    /// On overflow, the tokens posted up to that point stay posted.
+    pub fn release_many(&self, n: u32) -> Result<(), Overflow> {
+        self.backend().release_many(n)
+    }
+
+    /// Posts `n` tokens.
+    ///
+    /// On overflow, the tokens posted up to that point stay posted and the error tells how many
+    /// there were. Zero is a no-op.
+    pub fn post_many(&self, n: u32) -> Result<(), PartialPost> {
+        self.backend().post_many(n)
+    }
+
+    /// Posts `n` tokens or, on overflow, tries to take the posted ones back.
+    ///
+    /// The tokens already posted may have been taken by someone else in the meantime, so they
+    /// can't always be all taken back. The error tells how many stayed posted. Other threads may
+    /// also see the value go up and down for a moment.
+    ///
+    /// More than the maximum value of a semaphore can never fit, so nothing is posted then.
+    pub fn post_many_atomic(&self, n: u32) -> Result<(), PartialPost> {
+        self.backend().post_many_atomic(n)
+    }
+}
+
+impl<'a> Backend<'a> {
+    pub(crate) fn acquire_many(self, n: u32) {
+        assert!(n <= value_max(), "Can't ever acquire {} tokens", n);
+        let mut held = Held {
+            backend: self,
+            count: 0,
+        };
+        while held.count < n {
+            self.wait();
+            held.count += 1;
+        }
+        held.keep();
+    }
+
+    pub(crate) fn acquire_many_timeout(self, n: u32, timeout: Duration)
+        -> Result<(), PartialTimeout>
+    {
+        // Too far in the future to represent is as good as never
+        let deadline = Instant::now().checked_add(timeout);
+        let mut held = Held {
+            backend: self,
+            count: 0,
+        };
+        while held.count < n {
+            let result = match deadline {
+                Some(deadline) => self.wait_deadline(deadline),
+                None => {
+                    self.wait();
+                    Ok(())
+                },
+            };
+            if result.is_err() {
+                return Err(PartialTimeout {
+                    collected: held.count,
+                    lost: held.release(),
+                });
+            }
+            held.count += 1;
+        }
+        held.keep();
+        Ok(())
+    }
+
+    pub(crate) fn try_acquire_many(self, n: u32) -> Result<(), NoToken> {
+        let mut held = Held {
+            backend: self,
+            count: 0,
+        };
+        while held.count < n {
+            if let Err(e) = self.trywait() {
+                return Err(e.with_lost(held.release()));
+            }
+            held.count += 1;
+        }
+        held.keep();
+        Ok(())
+    }
+
+    pub(crate) fn drain(self) -> u32 {
+        self.drain_up_to(u32::MAX)
+    }
+
+    pub(crate) fn drain_up_to(self, max: u32) -> u32 {
+        let mut drained = 0;
+        while drained < max && self.trywait().is_ok() {
+            drained += 1;
+        }
+        drained
+    }
+
+    pub(crate) fn set_value(self, target: u32) -> Result<(), Error> {
+        if target > value_max() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Value over SEM_VALUE_MAX"));
+        }
+        self.drain();
+        self.post_many(target)?;
+        Ok(())
+    }
+
+    pub(crate) fn post_all(self) -> Result<u32, Error> {
+        let waiters = self.waiters();
+        self.post_many(waiters)?;
+        Ok(waiters)
+    }
+
+    pub(crate) fn reported_waiters(self) -> Result<Option<u32>, Error> {
+        Ok(match self.raw_value()? {
+            // POSIX allows reporting the waiters as a negative value
+            waiters if waiters < 0 => Some(waiters.unsigned_abs()),
+            // There are tokens for everyone, so nobody waits (at least not for long)
+            value if value > 0 => Some(0),
+            // But most systems just report 0, which tells nothing
+            _ => None,
+        })
+    }
+
+    pub(crate) fn release_many(self, n: u32) -> Result<(), Overflow> {
+        self.post_many(n)?;
+        Ok(())
+    }
+
+    pub(crate) fn post_many(self, n: u32) -> Result<(), PartialPost> {
+        for posted in 0..n {
+            if self.post().is_err() {
+                return Err(PartialPost { posted });
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn post_many_atomic(self, n: u32) -> Result<(), PartialPost> {
+        if n > value_max() {
+            return Err(PartialPost { posted: 0 });
+        }
+        self.post_many(n).map_err(|mut partial| {
+            while partial.posted > 0 && self.trywait().is_ok() {
+                partial.posted -= 1;
+            }
+            partial
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    use std::panic;
+    use std::thread;
+
+    use super::*;
+    use test_util;
+    use Semaphore;
+
+    #[test]
+    fn concurrent_demand() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        // Total demand: 10 * (1 + 2 + 3 + 4) = 100
+        thread::scope(|s| {
+            for n in 1..=4 {
+                let sem = &sem;
+                s.spawn(move || {
+                    for _ in 0..10 {
+                        sem.acquire_many(n);
+                    }
+                });
+            }
+            for _ in 0..20 {
+                sem.release_many(5).unwrap();
+            }
+        });
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn held_returned_on_unwind() {
+        let sem = Semaphore::anonymous(2).unwrap();
+        let result = panic::catch_unwind(|| {
+            let mut held = Held {
+                backend: sem.backend(),
+                count: 0,
+            };
+            sem.wait();
+            sem.wait();
+            held.count = 2;
+            panic!("Midway");
+        });
+        assert!(result.is_err());
+        assert_eq!(2, sem.value());
+    }
+
+    #[test]
+    fn held_overflow_reported() {
+        let sem = Semaphore::anonymous(value_max()).unwrap();
+        let held = Held {
+            backend: sem.backend(),
+            count: 2,
+        };
+        assert_eq!(2, held.release());
+        assert_eq!(value_max(), sem.value() as u32);
+    }
+
+    /// A cheap source of pseudo-random numbers, different on each run.
+    fn random() -> impl FnMut() -> u64 {
+        let state = RandomState::new();
+        let mut cnt = 0u64;
+        move || {
+            cnt += 1;
+            let mut hasher = state.build_hasher();
+            hasher.write_u64(cnt);
+            hasher.finish()
+        }
+    }
+
+    #[test]
+    fn try_all_or_nothing() {
+        let sem = Semaphore::anonymous(3).unwrap();
+        sem.try_acquire_many(4).unwrap_err();
+        assert_eq!(3, sem.value());
+        sem.try_acquire_many(2).unwrap();
+        assert_eq!(1, sem.value());
+        sem.try_acquire_many(0).unwrap();
+        sem.try_acquire_many(2).unwrap_err();
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn try_conserves_tokens() {
+        const TOTAL: u32 = 10;
+        let sem = Semaphore::anonymous(TOTAL).unwrap();
+        thread::scope(|s| {
+            for _ in 0..4 {
+                let sem = &sem;
+                s.spawn(move || {
+                    let mut random = random();
+                    let mut held = 0;
+                    for _ in 0..2000 {
+                        let n = (random() % 5) as u32;
+                        if random().is_multiple_of(2) && sem.try_acquire_many(n).is_ok() {
+                            held += n;
+                        } else if held > 0 {
+                            let n = (random() % u64::from(held)) as u32 + 1;
+                            sem.release_many(n).unwrap();
+                            held -= n;
+                        }
+                        assert!(sem.try_value().unwrap() <= TOTAL);
+                    }
+                    sem.release_many(held).unwrap();
+                });
+            }
+        });
+        assert_eq!(TOTAL, sem.try_value().unwrap());
+    }
+
+    #[test]
+    fn timeout_returns_collected() {
+        let sem = Semaphore::anonymous(3).unwrap();
+        let err = sem.acquire_many_timeout(4, Duration::from_millis(50)).unwrap_err();
+        assert_eq!(3, err.collected());
+        assert_eq!(3, sem.value());
+    }
+
+    #[test]
+    fn timeout_last_token_in_time() {
+        let sem = Semaphore::anonymous(3).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                sem.post().unwrap();
+            });
+            sem.acquire_many_timeout(4, Duration::from_secs(1)).unwrap();
+        });
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn timeout_whole_operation_on_signals() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let start = Instant::now();
+        let timeout = Duration::from_millis(100);
+        let result = test_util::interrupt(|| sem.acquire_many_timeout(2, timeout));
+        assert_eq!(1, result.unwrap_err().collected());
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn post_many_overflow() {
+        let max = value_max();
+        let sem = Semaphore::anonymous(max - 3).unwrap();
+        sem.post_many(2).unwrap();
+        assert_eq!(PartialPost { posted: 1 }, sem.post_many(5).unwrap_err());
+        assert_eq!(max as c_int, sem.value());
+        assert_eq!(PartialPost { posted: 0 }, sem.post_many(1).unwrap_err());
+        sem.release_many(1).unwrap_err();
+        assert_eq!(max as c_int, sem.value());
+    }
+
+    #[test]
+    fn post_many_atomic_rollback() {
+        let max = value_max();
+        let sem = Semaphore::anonymous(max - 3).unwrap();
+        assert_eq!(PartialPost { posted: 0 }, sem.post_many_atomic(4).unwrap_err());
+        assert_eq!((max - 3) as c_int, sem.value());
+        sem.post_many_atomic(3).unwrap();
+        assert_eq!(max as c_int, sem.value());
+    }
+
+    #[test]
+    fn post_many_atomic_never_fits() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let e = sem.post_many_atomic(value_max() + 1).unwrap_err();
+        assert_eq!(PartialPost { posted: 0 }, e);
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", any(target_env = "gnu", target_env = "musl")))]
+    fn value_max_matches_headers() {
+        assert_eq!(SEM_VALUE_MAX, value_max());
+        assert_eq!(Semaphore::MAX_VALUE, Semaphore::max_value());
+    }
+
+    #[test]
+    fn set_value() {
+        for &(start, target) in &[(0, 0), (0, 5), (7, 2), (3, 3), (4, 0)] {
+            let sem = Semaphore::anonymous(start).unwrap();
+            sem.set_value(target).unwrap();
+            assert_eq!(target as c_int, sem.value());
+        }
+        let sem = Semaphore::anonymous(2).unwrap();
+        let e = sem.set_value(value_max() + 1).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, e.kind());
+        assert_eq!(2, sem.value());
+    }
+
+    #[test]
+    fn reset() {
+        let mut sem = Semaphore::anonymous(10).unwrap();
+        sem.reset(1).unwrap();
+        assert_eq!(1, sem.value());
+        sem.reset(3).unwrap();
+        assert_eq!(3, sem.value());
+    }
+
+    #[test]
+    fn drain() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        assert_eq!(0, sem.drain());
+        sem.post_many(7).unwrap();
+        assert_eq!(3, sem.drain_up_to(3));
+        assert_eq!(4, sem.drain());
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn drain_racing() {
+        const POSTS: u32 = 10_000;
+        let sem = Semaphore::anonymous(0).unwrap();
+        let mut drained = 0;
+        thread::scope(|s| {
+            let poster = s.spawn(|| {
+                for _ in 0..POSTS {
+                    sem.post().unwrap();
+                }
+            });
+            while !poster.is_finished() {
+                drained += sem.drain_up_to(100);
+            }
+        });
+        drained += sem.drain();
+        assert_eq!(POSTS, drained);
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn post_all() {
+        const THREADS: u32 = 4;
+        let sem = Semaphore::anonymous(0).unwrap();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| sem.wait());
+            }
+            while sem.waiters() != THREADS {
+                thread::yield_now();
+            }
+            assert_eq!(THREADS, sem.post_all().unwrap());
+        });
+        assert_eq!(0, sem.value());
+        assert_eq!(0, sem.post_all().unwrap());
+    }
+
+    #[test]
+    fn zero() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        sem.acquire_many(0);
+        sem.release_many(0).unwrap();
+        sem.post_many(0).unwrap();
+        sem.post_many_atomic(0).unwrap();
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    #[should_panic]
+    fn absurd() {
+        Semaphore::anonymous(0).unwrap().acquire_many(u32::MAX);
+    }
+}
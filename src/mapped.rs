@@ -0,0 +1,286 @@
+//! Semaphores living in a shared mapping, possibly initialized by another process.
+//!
+//! The mapping holds a [`SharedRegion`], the semaphore itself followed by a word tracking its
+//! initialization. Exactly one of the processes initializes the semaphore, the others wait for
+//! it to finish before touching the semaphore.
+
+use std::alloc::Layout;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+
+use metrics::Metrics;
+use shadow::Shadow;
+use {init, Kind, Mode, Semaphore, SemaphoreSlot};
+
+/// How long to wait for someone else to finish initialization.
+pub const INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const UNINIT: u32 = 0;
+const INITIALIZING: u32 = 1;
+const READY: u32 = 2;
+
+/// A process-shared semaphore together with the state of its initialization.
+///
+/// This is meant to be placed into shared memory mapped by multiple processes independently
+/// (for example, through `shm_open` or a memfd). Zeroed memory is a valid, not yet initialized
+/// region. Each process calls [`init_once`][SharedRegion::init_once] and exactly one of them
+/// runs `sem_init`, the others wait for it to finish.
+///
+/// If the initializing process dies in the middle, the others can't know what state the
+/// semaphore is in. They give up after a timeout and the region stays unusable.
+#[repr(C)]
+pub struct SharedRegion {
+    // The semaphore goes first, so the semaphore pointer is also the start of the region.
+    sem: SemaphoreSlot,
+    state: AtomicU32,
+}
+
+pub const REGION_SIZE: usize = SharedRegion::LAYOUT.size();
+
+impl SharedRegion {
+    /// The memory layout of the region.
+    pub const LAYOUT: Layout = Layout::new::<SharedRegion>();
+
+    /// A region that is not initialized yet, the same as zeroed memory.
+    pub(crate) const fn uninit() -> Self {
+        SharedRegion {
+            sem: SemaphoreSlot::zeroed(),
+            state: AtomicU32::new(UNINIT),
+        }
+    }
+
+    /// Views the memory as a region.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be aligned and valid for [`LAYOUT`][SharedRegion::LAYOUT] for the
+    /// lifetime `'a` and the memory must be either zeroed or hold a region (initialized or not)
+    /// and not be used in other ways.
+    pub unsafe fn from_ptr<'a>(ptr: NonNull<SharedRegion>) -> &'a SharedRegion {
+        &*ptr.as_ptr()
+    }
+
+    /// Initializes the semaphore, unless someone else does so.
+    ///
+    /// Returns the semaphore and `true` if this call initialized it. If someone else is in the
+    /// middle of initialization, this waits up to `timeout` for them to finish and then fails
+    /// with [`ErrorKind::TimedOut`].
+    pub fn init_once(&self, value: u32, timeout: Duration)
+        -> Result<(&SemaphoreSlot, bool), Error>
+    {
+        let claim = self
+            .state
+            .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Acquire);
+        match claim {
+            Ok(_) => {
+                if let Err(e) = unsafe { init(self.sem.as_ptr(), true, value) } {
+                    // Let someone else try
+                    self.state.store(UNINIT, Ordering::Release);
+                    return Err(e);
+                }
+                self.state.store(READY, Ordering::Release);
+                Ok((&self.sem, true))
+            },
+            Err(_) => self.wait_ready(timeout).map(|sem| (sem, false)),
+        }
+    }
+
+    /// Waits up to `timeout` for someone else to initialize the semaphore.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<&SemaphoreSlot, Error> {
+        // Too far to ever come counts as no deadline
+        let deadline = Instant::now().checked_add(timeout);
+        let mut attempt = 0;
+        while !self.is_ready() {
+            backoff(&mut attempt, deadline, "Shared semaphore was never initialized")?;
+        }
+        Ok(&self.sem)
+    }
+
+    /// Has the semaphore been initialized?
+    pub fn is_ready(&self) -> bool {
+        self.state.load(Ordering::Acquire) == READY
+    }
+
+    /// The semaphore, if it's already initialized.
+    pub(crate) fn ready(&self) -> Option<&SemaphoreSlot> {
+        if self.is_ready() {
+            Some(&self.sem)
+        } else {
+            None
+        }
+    }
+}
+
+/// Sleeps for a bit, longer each time, failing once the deadline (if any) passes.
+pub(crate) fn backoff(attempt: &mut u32, deadline: Option<Instant>, what: &str)
+    -> Result<(), Error>
+{
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(Error::new(ErrorKind::TimedOut, what));
+    }
+    if *attempt < 10 {
+        thread::yield_now();
+    } else {
+        thread::sleep(Duration::from_micros(1 << (*attempt - 10).min(10)));
+    }
+    *attempt += 1;
+    Ok(())
+}
+
+/// The current size of the file.
+pub(crate) fn size(fd: RawFd) -> Result<u64, Error> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(stat.st_size as u64)
+}
+
+/// Waits until the file has at least the given size (the creator might not have resized it yet).
+pub(crate) fn wait_size(fd: RawFd, size: usize, deadline: Instant) -> Result<(), Error> {
+    let mut attempt = 0;
+    while self::size(fd)? < size as u64 {
+        backoff(&mut attempt, Some(deadline), "Shared semaphore was never resized")?;
+    }
+    Ok(())
+}
+
+/// Maps the region from the file descriptor.
+pub(crate) fn map(fd: RawFd) -> Result<NonNull<SharedRegion>, Error> {
+    unsafe {
+        let mem = libc::mmap(
+            ptr::null_mut(),
+            REGION_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        );
+        if mem == libc::MAP_FAILED {
+            return Err(Error::last_os_error());
+        }
+        Ok(NonNull::new(mem as *mut SharedRegion).expect("mmap returned NULL"))
+    }
+}
+
+/// Unmaps a region previously mapped by [`map`].
+pub(crate) unsafe fn unmap(region: NonNull<SharedRegion>) {
+    libc::munmap(region.as_ptr() as *mut _, REGION_SIZE);
+}
+
+/// Sizes a freshly created object, maps it and initializes the semaphore.
+pub(crate) fn create(fd: RawFd, value: u32) -> Result<NonNull<SharedRegion>, Error> {
+    if unsafe { libc::ftruncate(fd, REGION_SIZE as _) } == -1 {
+        return Err(Error::last_os_error());
+    }
+    let region = map(fd)?;
+    if let Err(e) = unsafe { region.as_ref() }.init_once(value, INIT_TIMEOUT) {
+        unsafe { unmap(region) };
+        return Err(e);
+    }
+    Ok(region)
+}
+
+/// Maps an object created by someone else, waiting for it to be ready.
+pub(crate) fn attach(fd: RawFd, deadline: Instant) -> Result<NonNull<SharedRegion>, Error> {
+    wait_size(fd, REGION_SIZE, deadline)?;
+    let region = map(fd)?;
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if let Err(e) = unsafe { region.as_ref() }.wait_ready(remaining) {
+        unsafe { unmap(region) };
+        return Err(e);
+    }
+    Ok(region)
+}
+
+/// Turns a ready region into a semaphore that unmaps it on drop.
+pub(crate) unsafe fn semaphore(region: NonNull<SharedRegion>) -> Semaphore {
+    Semaphore {
+        inner: region.cast(),
+        mode: Mode::Mapped,
+        kind: Kind::Mapped,
+        metrics: Metrics::new(),
+        slow_wait: None,
+        shadow: Shadow::untracked(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use named::SemName;
+    use test_util::{fork, unique_name};
+
+    const CHILDREN: usize = 8;
+
+    #[test]
+    fn init_race() {
+        let name = SemName::new(unique_name("init-race")).unwrap();
+        let name = name.as_c_str();
+        let oflag = libc::O_RDWR | libc::O_CREAT | libc::O_EXCL;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), oflag, 0o600) };
+        assert!(fd >= 0);
+        assert_eq!(0, unsafe { libc::ftruncate(fd, REGION_SIZE as _) });
+        let go = Semaphore::anonymous_shared(0).unwrap();
+        let inits = Semaphore::anonymous_shared(0).unwrap();
+        let children = (0..CHILDREN)
+            .map(|_| {
+                fork(|| {
+                    let region = map(fd).unwrap();
+                    go.wait();
+                    let region = unsafe { SharedRegion::from_ptr(region) };
+                    let (sem, created) = region.init_once(0, INIT_TIMEOUT).unwrap();
+                    if created {
+                        inits.post().unwrap();
+                    }
+                    sem.post().unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..CHILDREN {
+            go.post().unwrap();
+        }
+        for child in children {
+            child.join();
+        }
+        assert_eq!(1, inits.value());
+        let region = attach(fd, Instant::now() + INIT_TIMEOUT).unwrap();
+        let sem = unsafe { semaphore(region) };
+        assert_eq!(CHILDREN as libc::c_int, sem.value());
+        unsafe {
+            libc::close(fd);
+            libc::shm_unlink(name.as_ptr());
+        }
+    }
+
+    #[test]
+    fn stuck_initializer() {
+        let mut mem = vec![0u64; REGION_SIZE.div_ceil(8)].into_boxed_slice();
+        let ptr = NonNull::new(mem.as_mut_ptr()).unwrap().cast();
+        let region = unsafe { SharedRegion::from_ptr(ptr) };
+        // Pretend someone started and died
+        region.state.store(INITIALIZING, Ordering::Relaxed);
+        let err = region.init_once(1, Duration::from_millis(20)).err().unwrap();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(!region.is_ready());
+    }
+
+    #[test]
+    fn huge_timeout() {
+        let mut mem = vec![0u64; REGION_SIZE.div_ceil(8)].into_boxed_slice();
+        let ptr = NonNull::new(mem.as_mut_ptr()).unwrap().cast();
+        let region = unsafe { SharedRegion::from_ptr(ptr) };
+        let (_, created) = region.init_once(1, Duration::MAX).unwrap();
+        assert!(created);
+        let sem = region.wait_ready(Duration::MAX).unwrap();
+        sem.wait();
+        assert_eq!(0, sem.value());
+    }
+}
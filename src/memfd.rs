@@ -0,0 +1,263 @@
+//! Nameless process-shared semaphores living in a memfd (Linux only).
+
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::ops::Deref;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::ptr;
+use std::time::Instant;
+
+use libc;
+
+use mapped::{self, INIT_TIMEOUT, REGION_SIZE};
+use Semaphore;
+
+const FD_SIZE: libc::c_uint = mem::size_of::<libc::c_int>() as _;
+const CMSG_SPACE: usize = unsafe { libc::CMSG_SPACE(FD_SIZE) } as usize;
+const CMSG_WORDS: usize = CMSG_SPACE.div_ceil(8);
+
+/// A process-shared semaphore inside an anonymous memory file created by `memfd_create`.
+///
+/// Unlike [`ShmSemaphore`][::ShmSemaphore], it has no name, so it can't collide with anything
+/// or leak after all processes are gone. Other processes get access by inheriting (or otherwise
+/// receiving) the file descriptor and passing it to [`from_fd`][MemfdSemaphore::from_fd]. The
+/// descriptor is created with close-on-exec.
+///
+/// The file is sealed against resizing, so a misbehaving peer can't truncate it from under
+/// the mapping.
+#[derive(Debug)]
+pub struct MemfdSemaphore {
+    sem: Semaphore,
+    fd: OwnedFd,
+}
+
+impl MemfdSemaphore {
+    /// Creates a new semaphore in a new memfd.
+    pub fn new(value: u32) -> Result<Self, Error> {
+        let name = b"unix-semaphore\0".as_ptr() as *const _;
+        let flags = libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING;
+        let fd = match unsafe { libc::memfd_create(name, flags) } {
+            -1 => return Err(Error::last_os_error()),
+            fd => unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+        let region = mapped::create(fd.as_raw_fd(), value)?;
+        let sem = unsafe { mapped::semaphore(region) };
+        let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+        if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_ADD_SEALS, seals) } == -1 {
+            return Err(Error::last_os_error());
+        }
+        Ok(MemfdSemaphore { sem, fd })
+    }
+
+    /// Attaches to a semaphore created by [`new`][MemfdSemaphore::new], through its file
+    /// descriptor.
+    ///
+    /// The semaphore is only mapped, not initialized again. Fails with
+    /// [`ErrorKind::InvalidInput`] if the file is too small to hold the semaphore.
+    pub fn from_fd(fd: OwnedFd) -> Result<Self, Error> {
+        if mapped::size(fd.as_raw_fd())? < REGION_SIZE as u64 {
+            return Err(Error::new(ErrorKind::InvalidInput, "File too small for a semaphore"));
+        }
+        let region = mapped::attach(fd.as_raw_fd(), Instant::now() + INIT_TIMEOUT)?;
+        let sem = unsafe { mapped::semaphore(region) };
+        Ok(MemfdSemaphore { sem, fd })
+    }
+}
+
+impl MemfdSemaphore {
+    /// Sends the semaphore to the process on the other end of the socket.
+    ///
+    /// The file descriptor is passed as `SCM_RIGHTS` along with a single byte of data, the
+    /// other side receives it with [`recv_from_socket`][MemfdSemaphore::recv_from_socket].
+    pub fn send_over_socket(&self, sock: &UnixStream) -> Result<(), Error> {
+        let mut byte = [0u8];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut _,
+            iov_len: byte.len(),
+        };
+        // Buffer for a control message with one file descriptor, aligned for cmsghdr
+        let mut buf = [0u64; CMSG_WORDS];
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = buf.as_mut_ptr() as *mut _;
+            msg.msg_controllen = CMSG_SPACE as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(FD_SIZE) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut libc::c_int, self.as_raw_fd());
+            loop {
+                // With a single byte of payload, it either goes whole (with the descriptor) or
+                // not at all.
+                match libc::sendmsg(sock.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) {
+                    -1 => {
+                        let e = Error::last_os_error();
+                        if e.kind() != ErrorKind::Interrupted {
+                            return Err(e);
+                        }
+                    },
+                    0 => return Err(Error::new(ErrorKind::WriteZero, "Socket accepted no data")),
+                    _ => return Ok(()),
+                }
+            }
+        }
+    }
+
+    /// Receives a semaphore sent by [`send_over_socket`][MemfdSemaphore::send_over_socket].
+    ///
+    /// The received descriptor is validated to be large enough to hold the semaphore.
+    pub fn recv_from_socket(sock: &UnixStream) -> Result<Self, Error> {
+        let mut byte = [0u8];
+        let mut iov = libc::iovec {
+            iov_base: byte.as_mut_ptr() as *mut _,
+            iov_len: byte.len(),
+        };
+        // Buffer for a control message with one file descriptor, aligned for cmsghdr
+        let mut buf = [0u64; CMSG_WORDS];
+        unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = buf.as_mut_ptr() as *mut _;
+            msg.msg_controllen = CMSG_SPACE as _;
+            loop {
+                match libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) {
+                    -1 => {
+                        let e = Error::last_os_error();
+                        if e.kind() != ErrorKind::Interrupted {
+                            return Err(e);
+                        }
+                    },
+                    0 => return Err(Error::new(ErrorKind::UnexpectedEof, "Socket closed")),
+                    _ => break,
+                }
+            }
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            let valid = !cmsg.is_null()
+                && (*cmsg).cmsg_level == libc::SOL_SOCKET
+                && (*cmsg).cmsg_type == libc::SCM_RIGHTS
+                && (*cmsg).cmsg_len as usize >= libc::CMSG_LEN(FD_SIZE) as usize;
+            if !valid {
+                return Err(Error::new(ErrorKind::InvalidData, "No file descriptor received"));
+            }
+            let fd = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+            let fd = OwnedFd::from_raw_fd(fd);
+            if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+                let msg = "Too many file descriptors received";
+                return Err(Error::new(ErrorKind::InvalidData, msg));
+            }
+            Self::from_fd(fd)
+        }
+    }
+}
+
+impl AsFd for MemfdSemaphore {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl AsRawFd for MemfdSemaphore {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl Deref for MemfdSemaphore {
+    type Target = Semaphore;
+    fn deref(&self) -> &Semaphore {
+        &self.sem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::CommandExt;
+
+    use super::*;
+    use test_util::{child, child_arg, fork};
+
+    const ROUNDS: usize = 10;
+
+    #[test]
+    fn child_socket() {
+        if let Some(fd) = child_arg() {
+            let sock = unsafe { UnixStream::from_raw_fd(fd.parse().unwrap()) };
+            let ping = MemfdSemaphore::recv_from_socket(&sock).unwrap();
+            let pong = MemfdSemaphore::recv_from_socket(&sock).unwrap();
+            for _ in 0..ROUNDS {
+                ping.wait();
+                pong.post().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn socket() {
+        let ping = MemfdSemaphore::new(0).unwrap();
+        let pong = MemfdSemaphore::new(0).unwrap();
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let theirs_fd = theirs.as_raw_fd();
+        let mut cmd = child("memfd::tests::child_socket", &theirs_fd.to_string());
+        unsafe {
+            cmd.pre_exec(move || {
+                // Let the child inherit its end of the socket
+                if libc::fcntl(theirs_fd, libc::F_SETFD, 0) == -1 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn().unwrap();
+        drop(theirs);
+        ping.send_over_socket(&ours).unwrap();
+        pong.send_over_socket(&ours).unwrap();
+        for _ in 0..ROUNDS {
+            ping.post().unwrap();
+            pong.wait();
+        }
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn recv_closed() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        drop(theirs);
+        let err = MemfdSemaphore::recv_from_socket(&ours).err().unwrap();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn fork_inherit() {
+        let sem = MemfdSemaphore::new(0).unwrap();
+        let fd = sem.as_fd().try_clone_to_owned().unwrap();
+        let child = fork(move || {
+            let sem = MemfdSemaphore::from_fd(fd).unwrap();
+            sem.post().unwrap();
+            sem.post().unwrap();
+        });
+        sem.wait();
+        sem.wait();
+        child.join();
+    }
+
+    #[test]
+    fn sealed() {
+        let sem = MemfdSemaphore::new(0).unwrap();
+        assert_eq!(-1, unsafe { libc::ftruncate(sem.as_raw_fd(), 0) });
+        assert_eq!(Some(libc::EPERM), Error::last_os_error().raw_os_error());
+    }
+
+    #[test]
+    fn too_small() {
+        let name = b"too-small\0".as_ptr() as *const _;
+        let fd = unsafe { libc::memfd_create(name, libc::MFD_CLOEXEC) };
+        assert!(fd >= 0);
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let err = MemfdSemaphore::from_fd(fd).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+    }
+}
@@ -0,0 +1,212 @@
+//! Optional counters of what happens to a semaphore.
+//!
+//! With the `metrics` feature off, [`Metrics`] is empty and the counting compiles to nothing.
+
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "histogram")]
+use std::time::Instant;
+
+#[cfg(feature = "histogram")]
+use histogram::{Histogram, HistogramSnapshot};
+
+use backend::Backend;
+use WaitError;
+
+/// The counters of a [`Semaphore`][::Semaphore] at one moment.
+///
+/// The counters are kept per handle, in this process. Other handles to the same semaphore (eg.
+/// in other processes) have their own.
+#[cfg(feature = "metrics")]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub struct MetricsSnapshot {
+    /// The waits for a token, blocking or timed ones. Trying without waiting isn't counted.
+    pub waits: u64,
+    /// The waits that didn't find a token right away and had to block.
+    pub blocked: u64,
+    /// The waits that ran out of time.
+    pub timed_out: u64,
+    /// The successful posts.
+    pub posts: u64,
+    /// The posts that failed, because the semaphore was at its maximum value.
+    pub overflows: u64,
+}
+
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct Counters {
+    waits: AtomicU64,
+    blocked: AtomicU64,
+    timed_out: AtomicU64,
+    posts: AtomicU64,
+    overflows: AtomicU64,
+    #[cfg(feature = "histogram")]
+    latency: Histogram,
+}
+
+// Boxed, so the handles moved around by value stay small
+#[cfg(feature = "metrics")]
+pub(crate) struct Metrics(Box<Counters>);
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Metrics(Box::default())
+    }
+
+    fn bump(counter: &AtomicU64) {
+        // Only the numbers matter, nothing is synchronized through them
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a wait for a token.
+    ///
+    /// To tell the waits that block apart, a token available right away is taken without
+    /// calling `block` at all.
+    #[inline]
+    pub(crate) fn wait<F>(&self, backend: Backend, block: F) -> Result<(), WaitError>
+    where
+        F: FnOnce() -> Result<(), WaitError>,
+    {
+        Self::bump(&self.0.waits);
+        if backend.trywait().is_ok() {
+            return Ok(());
+        }
+        Self::bump(&self.0.blocked);
+        #[cfg(feature = "histogram")]
+        let start = Instant::now();
+        let result = block();
+        #[cfg(feature = "histogram")]
+        if result.is_ok() {
+            self.0.latency.record(start.elapsed());
+        }
+        if result == Err(WaitError::TimedOut) {
+            Self::bump(&self.0.timed_out);
+        }
+        result
+    }
+
+    /// Counts a post, successful or not.
+    #[inline]
+    pub(crate) fn post(&self, posted: bool) {
+        Self::bump(if posted { &self.0.posts } else { &self.0.overflows });
+    }
+
+    pub(crate) fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            waits: self.0.waits.load(Ordering::Relaxed),
+            blocked: self.0.blocked.load(Ordering::Relaxed),
+            timed_out: self.0.timed_out.load(Ordering::Relaxed),
+            posts: self.0.posts.load(Ordering::Relaxed),
+            overflows: self.0.overflows.load(Ordering::Relaxed),
+        }
+    }
+
+    #[cfg(feature = "histogram")]
+    pub(crate) fn latency(&self) -> HistogramSnapshot {
+        self.0.latency.snapshot()
+    }
+
+    #[cfg(feature = "histogram")]
+    pub(crate) fn reset_latency(&self) {
+        self.0.latency.reset()
+    }
+
+    /// Zeroes the counters.
+    ///
+    /// Each counter is zeroed on its own, operations running meanwhile may get counted in some
+    /// and not others.
+    pub(crate) fn reset(&self) {
+        let c = &self.0;
+        for counter in &[&c.waits, &c.blocked, &c.timed_out, &c.posts, &c.overflows] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) struct Metrics;
+
+#[cfg(not(feature = "metrics"))]
+impl Metrics {
+    #[inline(always)]
+    pub(crate) fn new() -> Self {
+        Metrics
+    }
+
+    #[inline(always)]
+    pub(crate) fn wait<F>(&self, _: Backend, block: F) -> Result<(), WaitError>
+    where
+        F: FnOnce() -> Result<(), WaitError>,
+    {
+        block()
+    }
+
+    #[inline(always)]
+    pub(crate) fn post(&self, _: bool) {}
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use {Semaphore, SpinConfig};
+
+    #[test]
+    fn scripted() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        assert_eq!(MetricsSnapshot::default(), sem.metrics());
+        // Fast path
+        sem.wait();
+        // Nothing there, times out
+        let timeout = Duration::from_millis(5);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(timeout));
+        // Blocks until the other thread posts
+        thread::scope(|s| {
+            s.spawn(|| {
+                while sem.waiters() == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                sem.post().unwrap();
+            });
+            drop(sem.access());
+        });
+        sem.trywait().unwrap();
+        sem.post().unwrap();
+        let full = Semaphore::anonymous(Semaphore::max_value()).unwrap();
+        full.post().unwrap_err();
+        assert_eq!(1, full.metrics().overflows);
+        let expected = MetricsSnapshot {
+            waits: 3,
+            blocked: 2,
+            timed_out: 1,
+            posts: 2,
+            overflows: 0,
+        };
+        assert_eq!(expected, sem.metrics());
+        sem.reset_metrics();
+        assert_eq!(MetricsSnapshot::default(), sem.metrics());
+    }
+
+    #[test]
+    fn deadline_and_spin() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        sem.wait_spin(SpinConfig::new());
+        let deadline = Instant::now() + Duration::from_millis(5);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_deadline(deadline));
+        // Not counted
+        sem.post().unwrap();
+        sem.wait_checked().unwrap();
+        let expected = MetricsSnapshot {
+            waits: 2,
+            blocked: 1,
+            timed_out: 1,
+            posts: 1,
+            overflows: 0,
+        };
+        assert_eq!(expected, sem.metrics());
+    }
+}
@@ -0,0 +1,225 @@
+//! Waiting on more than one semaphore at once.
+//!
+//! POSIX semaphores can't be waited on together natively. For waiting on any of them, this
+//! sweeps through them with `trywait` and blocks on one of them for a short while in between,
+//! rotating which one. For waiting on all of them, the tokens are taken one by one in a fixed
+//! order.
+
+use std::cmp;
+use std::mem;
+use std::time::{Duration, Instant};
+
+use {Semaphore, WaitError};
+
+const MIN_SLICE: Duration = Duration::from_micros(100);
+const MAX_SLICE: Duration = Duration::from_millis(10);
+
+fn wait_any_until(sems: &[&Semaphore], deadline: Option<Instant>) -> Result<usize, WaitError> {
+    assert!(!sems.is_empty(), "Waiting on no semaphores");
+    let mut slice = MIN_SLICE;
+    let mut blocking_on = 0;
+    loop {
+        for i in 0..sems.len() {
+            let idx = (blocking_on + i) % sems.len();
+            if sems[idx].trywait().is_ok() {
+                return Ok(idx);
+            }
+        }
+        let now = Instant::now();
+        let mut until = now + slice;
+        if let Some(deadline) = deadline {
+            if now >= deadline {
+                return Err(WaitError::TimedOut);
+            }
+            until = cmp::min(until, deadline);
+        }
+        match sems[blocking_on].wait_deadline(until) {
+            Ok(()) => return Ok(blocking_on),
+            Err(WaitError::TimedOut) => (),
+            Err(e) => return Err(e),
+        }
+        blocking_on = (blocking_on + 1) % sems.len();
+        slice = cmp::min(slice * 2, MAX_SLICE);
+    }
+}
+
+/// Tokens taken from several semaphores by [`wait_all`][Semaphore::wait_all].
+///
+/// The tokens are returned (posted back) on drop, unless [`forget`][AllTokens::forget] is
+/// called.
+#[must_use = "The tokens are returned right away if the value is dropped"]
+pub struct AllTokens<'a> {
+    sems: Vec<&'a Semaphore>,
+}
+
+impl<'a> AllTokens<'a> {
+    /// Keeps the tokens taken, without posting them back.
+    pub fn forget(mut self) {
+        self.sems.clear();
+    }
+}
+
+impl<'a> Drop for AllTokens<'a> {
+    fn drop(&mut self) {
+        for sem in mem::take(&mut self.sems) {
+            // We took a token from it, so there's room for putting it back
+            sem.post().expect("Overflow returning a token");
+        }
+    }
+}
+
+impl Semaphore {
+    /// Waits until any of the semaphores has a token, taking exactly one token.
+    ///
+    /// Returns the index of the semaphore the token was taken from. If several have tokens, any
+    /// one of them is picked, the others are left alone.
+    ///
+    /// There's no native support for this, so it polls. The waiter notices a token with some
+    /// latency (up to a few milliseconds) unless it arrives at the semaphore it currently
+    /// blocks on.
+    ///
+    /// # Panics
+    ///
+    /// If there are no semaphores.
+    pub fn wait_any(sems: &[&Semaphore]) -> Result<usize, WaitError> {
+        wait_any_until(sems, None)
+    }
+
+    /// Like [`wait_any`][Semaphore::wait_any], but gives up after the timeout.
+    pub fn wait_any_timeout(sems: &[&Semaphore], timeout: Duration) -> Result<usize, WaitError> {
+        wait_any_until(sems, Instant::now().checked_add(timeout))
+    }
+
+    /// Takes one token from each of the semaphores.
+    ///
+    /// The tokens are taken in a fixed order (by the address of the semaphores), no matter the
+    /// order in the slice. Therefore two threads waiting for the same semaphores can't deadlock
+    /// each holding some of them. If the timeout passes, the tokens already taken are posted
+    /// back. A semaphore listed twice provides two tokens.
+    pub fn wait_all<'a>(sems: &[&'a Semaphore], timeout: Option<Duration>)
+        -> Result<AllTokens<'a>, WaitError>
+    {
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        let mut ordered = sems.to_vec();
+        ordered.sort_by_key(|sem| sem.backend().address() as usize);
+        let mut tokens = AllTokens {
+            sems: Vec::with_capacity(ordered.len()),
+        };
+        for sem in ordered {
+            match deadline {
+                // Dropping the tokens on error puts back what we have so far
+                Some(deadline) => sem.wait_deadline(deadline)?,
+                None => sem.wait(),
+            }
+            tokens.sems.push(sem);
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const ROUNDS: usize = 1000;
+
+    #[test]
+    fn balanced() {
+        let work = Semaphore::anonymous(0).unwrap();
+        let shutdown = Semaphore::anonymous(0).unwrap();
+        let sems = [&work, &shutdown];
+        let mut counts = [0; 2];
+        thread::scope(|s| {
+            for sem in &sems {
+                s.spawn(move || {
+                    for _ in 0..ROUNDS {
+                        sem.post().unwrap();
+                    }
+                });
+            }
+            for _ in 0..2 * ROUNDS {
+                counts[Semaphore::wait_any(&sems).unwrap()] += 1;
+            }
+        });
+        assert_eq!([ROUNDS, ROUNDS], counts);
+        assert_eq!(0, work.value());
+        assert_eq!(0, shutdown.value());
+    }
+
+    #[test]
+    fn one_token() {
+        let a = Semaphore::anonymous(1).unwrap();
+        let b = Semaphore::anonymous(1).unwrap();
+        let idx = Semaphore::wait_any(&[&a, &b]).unwrap();
+        assert_eq!(1, a.value() + b.value());
+        let other = Semaphore::wait_any_timeout(&[&a, &b], Duration::from_secs(1)).unwrap();
+        assert_ne!(idx, other);
+        let result = Semaphore::wait_any_timeout(&[&a, &b], Duration::from_millis(20));
+        assert_eq!(Err(WaitError::TimedOut), result);
+    }
+
+    #[test]
+    fn any_huge_timeout() {
+        let a = Semaphore::anonymous(0).unwrap();
+        let b = Semaphore::anonymous(1).unwrap();
+        assert_eq!(1, Semaphore::wait_any_timeout(&[&a, &b], Duration::MAX).unwrap());
+    }
+
+    #[test]
+    fn all_opposite_orders() {
+        let a = Semaphore::anonymous(1).unwrap();
+        let b = Semaphore::anonymous(1).unwrap();
+        let orders = [[&a, &b], [&b, &a]];
+        thread::scope(|s| {
+            for order in &orders {
+                s.spawn(move || {
+                    for _ in 0..ROUNDS {
+                        let tokens = Semaphore::wait_all(order, None).unwrap();
+                        thread::yield_now();
+                        drop(tokens);
+                    }
+                });
+            }
+        });
+        assert_eq!(1, a.value());
+        assert_eq!(1, b.value());
+    }
+
+    #[test]
+    fn all_timeout() {
+        let a = Semaphore::anonymous(1).unwrap();
+        let b = Semaphore::anonymous(0).unwrap();
+        let timeout = Some(Duration::from_millis(20));
+        let result = Semaphore::wait_all(&[&a, &b], timeout);
+        assert_eq!(WaitError::TimedOut, result.err().unwrap());
+        assert_eq!(1, a.value());
+        b.post().unwrap();
+        Semaphore::wait_all(&[&b, &a], timeout).unwrap().forget();
+        assert_eq!(0, a.value());
+        assert_eq!(0, b.value());
+    }
+
+    #[test]
+    fn all_huge_timeout() {
+        let a = Semaphore::anonymous(1).unwrap();
+        let b = Semaphore::anonymous(1).unwrap();
+        Semaphore::wait_all(&[&a, &b], Some(Duration::MAX)).unwrap().forget();
+        assert_eq!(0, a.value());
+        assert_eq!(0, b.value());
+    }
+
+    #[test]
+    fn wakes_up() {
+        let a = Semaphore::anonymous(0).unwrap();
+        let b = Semaphore::anonymous(0).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(30));
+                b.post().unwrap();
+            });
+            assert_eq!(Ok(1), Semaphore::wait_any(&[&a, &b]));
+        });
+    }
+}
@@ -0,0 +1,1085 @@
+//! Named semaphores, usable to coordinate unrelated processes.
+
+use std::collections::hash_map::RandomState;
+use std::convert::{Infallible, TryFrom, TryInto};
+use std::error;
+use std::ffi::{CStr, CString};
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Error, ErrorKind};
+use std::ops::Deref;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+use std::process;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use libc::{self, c_uint, gid_t, mode_t, uid_t};
+
+use metrics::Metrics;
+use shadow::Shadow;
+use {check_value, Kind, Mode, Semaphore};
+
+/// The longest accepted semaphore name, including the leading slash.
+#[cfg(target_vendor = "apple")]
+pub const MAX_NAME_LEN: usize = 31;
+
+/// The longest accepted semaphore name, including the leading slash.
+///
+/// This is `NAME_MAX` minus the `sem.` prefix glibc puts in front of the backing file.
+#[cfg(not(target_vendor = "apple"))]
+pub const MAX_NAME_LEN: usize = 252;
+
+/// Reasons why a string isn't a valid semaphore name.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum NameError {
+    /// The name doesn't start with a slash.
+    NoLeadingSlash,
+    /// There's nothing after the leading slash.
+    Empty,
+    /// There's another slash after the leading one.
+    Slash,
+    /// The name contains a NUL byte.
+    Nul,
+    /// The name is longer than [`MAX_NAME_LEN`].
+    TooLong,
+}
+
+impl Display for NameError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let msg = match *self {
+            NameError::NoLeadingSlash => "Semaphore name doesn't start with a slash",
+            NameError::Empty => "Empty semaphore name",
+            NameError::Slash => "Semaphore name contains a slash after the leading one",
+            NameError::Nul => "Semaphore name contains a NUL byte",
+            NameError::TooLong => "Semaphore name is too long",
+        };
+        write!(fmt, "{}", msg)
+    }
+}
+
+impl error::Error for NameError {}
+
+impl From<Infallible> for NameError {
+    fn from(e: Infallible) -> Self {
+        match e {}
+    }
+}
+
+impl From<NameError> for Error {
+    fn from(e: NameError) -> Self {
+        Error::new(ErrorKind::InvalidInput, e)
+    }
+}
+
+/// A validated name of a named semaphore.
+///
+/// The name is of the form `/something`, with no further slashes or NUL bytes and at most
+/// [`MAX_NAME_LEN`] bytes long.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct SemName(CString);
+
+impl SemName {
+    /// Validates the name.
+    pub fn new<N: Into<String>>(name: N) -> Result<Self, NameError> {
+        let name = name.into();
+        if !name.starts_with('/') {
+            Err(NameError::NoLeadingSlash)
+        } else if name.len() == 1 {
+            Err(NameError::Empty)
+        } else if name[1..].contains('/') {
+            Err(NameError::Slash)
+        } else if name.len() > MAX_NAME_LEN {
+            Err(NameError::TooLong)
+        } else {
+            CString::new(name).map(SemName).map_err(|_| NameError::Nul)
+        }
+    }
+
+    /// The name, including the leading slash.
+    pub fn as_str(&self) -> &str {
+        self.0.to_str().expect("SemName is created from a str")
+    }
+
+    /// The name as a C string, suitable for passing to `sem_open` and friends.
+    pub fn as_c_str(&self) -> &CStr {
+        &self.0
+    }
+
+    /// The file backing the semaphore.
+    ///
+    /// This is where glibc and musl keep it, intended for diagnostics.
+    #[cfg(target_os = "linux")]
+    pub fn shm_path(&self) -> PathBuf {
+        PathBuf::from(format!("/dev/shm/sem.{}", &self.as_str()[1..]))
+    }
+}
+
+impl Display for SemName {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}", self.as_str())
+    }
+}
+
+impl<'a> TryFrom<&'a str> for SemName {
+    type Error = NameError;
+    fn try_from(name: &'a str) -> Result<Self, NameError> {
+        Self::new(name)
+    }
+}
+
+impl<'a> TryFrom<&'a String> for SemName {
+    type Error = NameError;
+    fn try_from(name: &'a String) -> Result<Self, NameError> {
+        Self::new(name.as_str())
+    }
+}
+
+impl TryFrom<String> for SemName {
+    type Error = NameError;
+    fn try_from(name: String) -> Result<Self, NameError> {
+        Self::new(name)
+    }
+}
+
+impl<'a> From<&'a SemName> for SemName {
+    fn from(name: &'a SemName) -> Self {
+        name.clone()
+    }
+}
+
+pub(crate) fn sem_name<N>(name: N) -> Result<SemName, Error>
+where
+    N: TryInto<SemName>,
+    N::Error: Into<NameError>,
+{
+    name.try_into().map_err(|e| e.into().into())
+}
+
+/// A semaphore opened by name through `sem_open`.
+///
+/// All the waiting and posting is available through dereferencing to [`Semaphore`]. Dropping
+/// the handle closes it, but the semaphore itself stays in the system.
+#[derive(Debug)]
+pub struct NamedSemaphore {
+    sem: Semaphore,
+    name: SemName,
+    unlink_on_drop: bool,
+}
+
+/// Options for opening or creating a [`NamedSemaphore`], in the style of
+/// [`OpenOptions`][std::fs::OpenOptions].
+///
+/// By default, only existing semaphores are opened. The `mode` and `initial_value` make sense
+/// only when creating one, so setting them without [`create`][NamedOptions::create] is an error.
+#[derive(Clone, Debug, Default)]
+pub struct NamedOptions {
+    create: bool,
+    exclusive: bool,
+    mode: Option<mode_t>,
+    initial_value: Option<u32>,
+}
+
+impl NamedOptions {
+    /// Creates the default options.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the semaphore if it doesn't exist.
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Fail if the semaphore already exists.
+    ///
+    /// Requires [`create`][NamedOptions::create].
+    pub fn exclusive(&mut self, exclusive: bool) -> &mut Self {
+        self.exclusive = exclusive;
+        self
+    }
+
+    /// Permissions of a newly created semaphore (subject to umask).
+    ///
+    /// Defaults to `0o600`.
+    pub fn mode(&mut self, mode: mode_t) -> &mut Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// The value of a newly created semaphore.
+    ///
+    /// Defaults to 0. An already existing semaphore keeps its value.
+    pub fn initial_value(&mut self, value: u32) -> &mut Self {
+        self.initial_value = Some(value);
+        self
+    }
+
+    /// Opens the semaphore with these options.
+    pub fn open<N>(&self, name: N) -> Result<NamedSemaphore, Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        let name = sem_name(name)?;
+        let mut oflag = 0;
+        if self.create {
+            oflag |= libc::O_CREAT;
+        } else if self.exclusive || self.mode.is_some() || self.initial_value.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "exclusive, mode and initial_value require create",
+            ));
+        }
+        if self.exclusive {
+            oflag |= libc::O_EXCL;
+        }
+        let mode = self.mode.unwrap_or(0o600);
+        let value = self.initial_value.unwrap_or(0);
+        check_value(value)?;
+        unsafe {
+            let name_ptr = name.as_c_str().as_ptr();
+            let ptr = libc::sem_open(name_ptr, oflag, mode as c_uint, value as c_uint);
+            if ptr == libc::SEM_FAILED {
+                return Err(Error::last_os_error());
+            }
+            let sem = Semaphore {
+                inner: NonNull::new(ptr).expect("sem_open returned NULL"),
+                mode: Mode::Named,
+                kind: Kind::Named,
+                metrics: Metrics::new(),
+                slow_wait: None,
+                shadow: Shadow::untracked(),
+            };
+            Ok(NamedSemaphore {
+                sem,
+                name,
+                unlink_on_drop: false,
+            })
+        }
+    }
+}
+
+/// The name of a temporary semaphore, short enough even for macOS.
+fn temporary_name(hash: u64) -> String {
+    format!("/us-{:016x}", hash)
+}
+
+impl NamedSemaphore {
+    /// Creates a new named semaphore.
+    ///
+    /// Note that the semaphore stays in the system until it is [unlinked][NamedSemaphore::unlink].
+    ///
+    /// Fails with [`ErrorKind::AlreadyExists`] if a semaphore of the same name already exists.
+    pub fn create<N>(name: N, mode: mode_t, value: u32) -> Result<Self, Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        NamedOptions::new()
+            .create(true)
+            .exclusive(true)
+            .mode(mode)
+            .initial_value(value)
+            .open(name)
+    }
+
+    /// Opens an existing named semaphore.
+    ///
+    /// Fails with [`ErrorKind::NotFound`] if there's no semaphore of that name.
+    pub fn open<N>(name: N) -> Result<Self, Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        NamedOptions::new().open(name)
+    }
+
+    /// Removes the named semaphore from the system.
+    ///
+    /// Already opened handles stay valid, but further attempts to open it fail (or create a new
+    /// one). Fails with [`ErrorKind::NotFound`] if there's no semaphore of that name.
+    pub fn unlink<N>(name: N) -> Result<(), Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        let name = sem_name(name)?;
+        match unsafe { libc::sem_unlink(name.as_c_str().as_ptr()) } {
+            0 => Ok(()),
+            -1 => Err(Error::last_os_error()),
+            other => unreachable!("sem_unlink doesn't return value {}", other),
+        }
+    }
+
+    /// The name this semaphore was opened with.
+    pub fn name(&self) -> &SemName {
+        &self.name
+    }
+
+    /// Opens another, independent handle to the same semaphore.
+    ///
+    /// This opens the semaphore by name again, therefore it fails with [`ErrorKind::NotFound`]
+    /// if it has been unlinked in the meantime. The new handle doesn't unlink on drop.
+    pub fn try_clone(&self) -> Result<NamedSemaphore, Error> {
+        Self::open(&self.name)
+    }
+
+    /// Reads the metadata of the semaphore.
+    ///
+    /// This goes through the name, so it fails if the semaphore has been unlinked. See
+    /// [`metadata`].
+    pub fn metadata(&self) -> Result<SemMetadata, Error> {
+        metadata(&self.name)
+    }
+
+    /// Changes the permission bits of the semaphore.
+    ///
+    /// See [`set_permissions`].
+    pub fn set_permissions(&self, mode: u32) -> Result<(), Error> {
+        set_permissions(&self.name, mode)
+    }
+
+    /// Changes the owner and/or group of the semaphore.
+    ///
+    /// See [`set_owner`].
+    pub fn set_owner(&self, uid: Option<uid_t>, gid: Option<gid_t>) -> Result<(), Error> {
+        set_owner(&self.name, uid, gid)
+    }
+
+    /// Closes the handle, reporting any errors.
+    ///
+    /// Dropping the handle closes it too, but ignores errors.
+    pub fn close(mut self) -> Result<(), Error> {
+        let result = unsafe { libc::sem_close(self.sem.inner.as_ptr()) };
+        self.sem.mode = Mode::Released;
+        match result {
+            0 => Ok(()),
+            -1 => Err(Error::last_os_error()),
+            other => unreachable!("sem_close doesn't return value {}", other),
+        }
+    }
+
+    /// Unlink the semaphore when this handle is dropped (including during a panic).
+    ///
+    /// This is off by default, even for handles that created the semaphore.
+    pub fn unlink_on_drop(&mut self, unlink: bool) {
+        self.unlink_on_drop = unlink;
+    }
+
+    /// Creates a new semaphore with a random name that is unlinked when dropped.
+    ///
+    /// The name can be passed to other processes through [`TempSemaphore::name`].
+    pub fn temporary(value: u32) -> Result<TempSemaphore, Error> {
+        const ATTEMPTS: usize = 64;
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        for _ in 0..ATTEMPTS {
+            // RandomState is seeded randomly, which is all we need to avoid collisions.
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(process::id());
+            hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+            let name = temporary_name(hasher.finish());
+            match Self::create(name.as_str(), 0o600, value) {
+                Ok(mut sem) => {
+                    sem.unlink_on_drop(true);
+                    return Ok(TempSemaphore { sem });
+                },
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::new(ErrorKind::AlreadyExists, "No free temporary name found"))
+    }
+
+    /// Opens the named semaphore, creating it first if it doesn't exist.
+    ///
+    /// Unlike trying [`open`][NamedSemaphore::open] and [`create`][NamedSemaphore::create] in
+    /// sequence, this doesn't race with other processes doing the same. The returned flag is
+    /// `true` if this call created the semaphore.
+    pub fn open_or_create<N>(name: N, mode: mode_t, value: u32) -> Result<(Self, bool), Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        let name = sem_name(name)?;
+        loop {
+            match Self::create(&name, mode, value) {
+                Ok(sem) => return Ok((sem, true)),
+                Err(ref e) if e.kind() == ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+            match Self::open(&name) {
+                Ok(sem) => return Ok((sem, false)),
+                // Someone unlinked it in between, try creating it again.
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Deref for NamedSemaphore {
+    type Target = Semaphore;
+    fn deref(&self) -> &Semaphore {
+        &self.sem
+    }
+}
+
+impl Drop for NamedSemaphore {
+    fn drop(&mut self) {
+        if self.unlink_on_drop {
+            // Best effort, someone else might have already unlinked it.
+            unsafe {
+                libc::sem_unlink(self.name.as_c_str().as_ptr());
+            }
+        }
+    }
+}
+
+/// A named semaphore with a random name, unlinked on drop.
+///
+/// Created by [`NamedSemaphore::temporary`].
+#[derive(Debug)]
+pub struct TempSemaphore {
+    sem: NamedSemaphore,
+}
+
+impl TempSemaphore {
+    /// The generated name.
+    pub fn name(&self) -> &SemName {
+        self.sem.name()
+    }
+
+    /// Keeps the semaphore in the system after the handle is dropped.
+    ///
+    /// Useful if some other process needs to outlive the current one.
+    pub fn keep(mut self) -> NamedSemaphore {
+        self.sem.unlink_on_drop(false);
+        self.sem
+    }
+
+    /// Unlinks the name right away, keeping only the handle.
+    ///
+    /// Nothing can open the semaphore afterwards, but children forked later inherit the handle.
+    /// Stands in for `sem_init` on the platforms that don't implement it.
+    pub(crate) fn into_unlinked(self, kind: Kind) -> Result<Semaphore, Error> {
+        // On failure, the handle still unlinks on drop
+        NamedSemaphore::unlink(self.name())?;
+        let mut named = self.sem;
+        named.unlink_on_drop(false);
+        // The new handle does the closing instead
+        named.sem.mode = Mode::Released;
+        Ok(Semaphore {
+            inner: named.sem.inner,
+            mode: Mode::Named,
+            kind,
+            metrics: Metrics::new(),
+            slow_wait: None,
+            shadow: Shadow::untracked(),
+        })
+    }
+}
+
+impl Deref for TempSemaphore {
+    type Target = NamedSemaphore;
+    fn deref(&self) -> &NamedSemaphore {
+        &self.sem
+    }
+}
+
+/// Ownership and permission details of a named semaphore.
+///
+/// Returned by [`metadata`] and [`NamedSemaphore::metadata`].
+#[derive(Clone, Debug)]
+pub struct SemMetadata {
+    uid: uid_t,
+    gid: gid_t,
+    mode: u32,
+    created: Option<SystemTime>,
+    modified: SystemTime,
+    can_open: bool,
+}
+
+impl SemMetadata {
+    /// The owning user.
+    pub fn uid(&self) -> uid_t {
+        self.uid
+    }
+
+    /// The owning group.
+    pub fn gid(&self) -> gid_t {
+        self.gid
+    }
+
+    /// The permission bits.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// When the semaphore was created, if the platform tracks it.
+    pub fn created(&self) -> Option<SystemTime> {
+        self.created
+    }
+
+    /// The last modification time of the backing object.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    /// Whether the current process is allowed to open the semaphore (for reading and writing).
+    pub fn can_open(&self) -> bool {
+        self.can_open
+    }
+}
+
+/// Reads the metadata of a named semaphore.
+///
+/// This is implemented by looking at the backing file in `/dev/shm` on Linux, elsewhere it
+/// fails with [`ErrorKind::Unsupported`].
+#[cfg(target_os = "linux")]
+pub fn metadata<N>(name: N) -> Result<SemMetadata, Error>
+where
+    N: TryInto<SemName>,
+    N::Error: Into<NameError>,
+{
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+
+    let path = sem_name(name)?.shm_path();
+    let meta = fs::metadata(&path)?;
+    let c_path = CString::new(path.as_os_str().as_bytes()).expect("SemName has no NUL bytes");
+    let can_open = unsafe {
+        let flags = libc::R_OK | libc::W_OK;
+        libc::faccessat(libc::AT_FDCWD, c_path.as_ptr(), flags, libc::AT_EACCESS) == 0
+    };
+    Ok(SemMetadata {
+        uid: meta.uid(),
+        gid: meta.gid(),
+        mode: meta.mode() & 0o7777,
+        created: meta.created().ok(),
+        modified: meta.modified()?,
+        can_open,
+    })
+}
+
+/// Reads the metadata of a named semaphore.
+///
+/// This is implemented by looking at the backing file in `/dev/shm` on Linux, elsewhere it
+/// fails with [`ErrorKind::Unsupported`].
+#[cfg(not(target_os = "linux"))]
+pub fn metadata<N>(name: N) -> Result<SemMetadata, Error>
+where
+    N: TryInto<SemName>,
+    N::Error: Into<NameError>,
+{
+    sem_name(name)?;
+    Err(Error::new(ErrorKind::Unsupported, "Can't read semaphore metadata on this platform"))
+}
+
+/// Changes the permission bits of a named semaphore.
+///
+/// Works on Linux by changing the backing file in `/dev/shm`, elsewhere it fails with
+/// [`ErrorKind::Unsupported`].
+#[cfg(target_os = "linux")]
+pub fn set_permissions<N>(name: N, mode: u32) -> Result<(), Error>
+where
+    N: TryInto<SemName>,
+    N::Error: Into<NameError>,
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(sem_name(name)?.shm_path(), fs::Permissions::from_mode(mode))
+}
+
+/// Changes the permission bits of a named semaphore.
+///
+/// Works on Linux by changing the backing file in `/dev/shm`, elsewhere it fails with
+/// [`ErrorKind::Unsupported`].
+#[cfg(not(target_os = "linux"))]
+pub fn set_permissions<N>(name: N, _mode: u32) -> Result<(), Error>
+where
+    N: TryInto<SemName>,
+    N::Error: Into<NameError>,
+{
+    sem_name(name)?;
+    Err(Error::new(ErrorKind::Unsupported, "Can't change semaphore permissions on this platform"))
+}
+
+/// Changes the owner and/or group of a named semaphore.
+///
+/// `None` leaves the corresponding one unchanged. Works on Linux by changing the backing file
+/// in `/dev/shm`, elsewhere it fails with [`ErrorKind::Unsupported`].
+#[cfg(target_os = "linux")]
+pub fn set_owner<N>(name: N, uid: Option<uid_t>, gid: Option<gid_t>) -> Result<(), Error>
+where
+    N: TryInto<SemName>,
+    N::Error: Into<NameError>,
+{
+    ::std::os::unix::fs::chown(sem_name(name)?.shm_path(), uid, gid)
+}
+
+/// Changes the owner and/or group of a named semaphore.
+///
+/// `None` leaves the corresponding one unchanged. Works on Linux by changing the backing file
+/// in `/dev/shm`, elsewhere it fails with [`ErrorKind::Unsupported`].
+#[cfg(not(target_os = "linux"))]
+pub fn set_owner<N>(name: N, _uid: Option<uid_t>, _gid: Option<gid_t>) -> Result<(), Error>
+where
+    N: TryInto<SemName>,
+    N::Error: Into<NameError>,
+{
+    sem_name(name)?;
+    Err(Error::new(ErrorKind::Unsupported, "Can't change semaphore owner on this platform"))
+}
+
+/// Information about an existing named semaphore, as returned by [`list_info`].
+#[derive(Clone, Debug)]
+pub struct NamedSemInfo {
+    name: SemName,
+    size: u64,
+    mode: u32,
+    modified: SystemTime,
+}
+
+impl NamedSemInfo {
+    /// The name of the semaphore.
+    pub fn name(&self) -> &SemName {
+        &self.name
+    }
+
+    /// The size of the backing object.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The permission bits.
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// The last modification time of the backing object.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+/// Lists the named semaphores currently existing in the system, with some details.
+///
+/// This works only where the semaphores live in an enumerable place (`/dev/shm` on Linux), it
+/// fails with [`ErrorKind::Unsupported`] elsewhere. Entries that don't form a valid [`SemName`]
+/// are skipped.
+#[cfg(target_os = "linux")]
+pub fn list_info() -> Result<Vec<NamedSemInfo>, Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut result = Vec::new();
+    for entry in fs::read_dir("/dev/shm")? {
+        let entry = entry?;
+        let name = match entry.file_name().to_str() {
+            Some(file) if file.starts_with("sem.") => SemName::new(format!("/{}", &file[4..])),
+            _ => continue,
+        };
+        let name = match name {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let meta = match entry.metadata() {
+            Ok(meta) => meta,
+            // Unlinked while we were looking at it
+            Err(ref e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        result.push(NamedSemInfo {
+            name,
+            size: meta.len(),
+            mode: meta.permissions().mode() & 0o7777,
+            modified: meta.modified()?,
+        });
+    }
+    Ok(result)
+}
+
+/// Lists the named semaphores currently existing in the system, with some details.
+///
+/// This works only where the semaphores live in an enumerable place (`/dev/shm` on Linux), it
+/// fails with [`ErrorKind::Unsupported`] elsewhere.
+#[cfg(not(target_os = "linux"))]
+pub fn list_info() -> Result<Vec<NamedSemInfo>, Error> {
+    Err(Error::new(ErrorKind::Unsupported, "Can't list named semaphores on this platform"))
+}
+
+/// Lists the names of named semaphores currently existing in the system.
+///
+/// See [`list_info`] for details.
+pub fn list() -> Result<Vec<SemName>, Error> {
+    list_info().map(|infos| infos.into_iter().map(|info| info.name).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    use super::*;
+    use test_util::{child_arg, run_child, unique_name};
+
+    #[test]
+    fn temporary_name_fits() {
+        for &hash in &[0, 1, u64::MAX] {
+            let name = temporary_name(hash);
+            // Checked against the macOS limit on every platform
+            assert!(name.len() <= 31, "{}", name);
+            SemName::new(name).unwrap();
+        }
+    }
+
+    #[test]
+    fn child_post() {
+        if let Some(name) = child_arg() {
+            let sem = NamedSemaphore::open(&name).unwrap();
+            sem.post().unwrap();
+            sem.post().unwrap();
+        }
+    }
+
+    #[test]
+    fn unlinked() {
+        let temp = NamedSemaphore::temporary(2).unwrap();
+        let name = temp.name().clone();
+        let sem = temp.into_unlinked(Kind::Anonymous).unwrap();
+        assert_eq!(ErrorKind::NotFound, NamedSemaphore::open(&name).unwrap_err().kind());
+        assert_eq!(Kind::Anonymous, sem.kind());
+        sem.wait();
+        sem.post().unwrap();
+        assert_eq!(2, sem.value());
+    }
+
+    #[test]
+    fn debug() {
+        let sem = NamedSemaphore::temporary(2).unwrap();
+        let out = format!("{:?}", sem);
+        assert!(out.contains(&format!("name: SemName({:?})", sem.name().as_str())), "{}", out);
+        assert!(out.contains("kind: Named"), "{}", out);
+        assert!(out.contains("value: 2"), "{}", out);
+    }
+
+    #[test]
+    fn cross_process() {
+        let name = unique_name("cross");
+        let sem = NamedSemaphore::create(&name, 0o600, 1).unwrap();
+        run_child("named::tests::child_post", &name);
+        assert_eq!(3, sem.value());
+        sem.wait();
+        sem.wait();
+        sem.wait();
+        sem.trywait().unwrap_err();
+        NamedSemaphore::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn create_exclusive() {
+        let name = unique_name("excl");
+        let _sem = NamedSemaphore::create(&name, 0o600, 0).unwrap();
+        let err = NamedSemaphore::create(&name, 0o600, 0).err().unwrap();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+        NamedSemaphore::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn open_or_create_race() {
+        const THREADS: usize = 8;
+        for _ in 0..20 {
+            let name = Arc::new(SemName::new(unique_name("race")).unwrap());
+            let barrier = Arc::new(Barrier::new(THREADS));
+            let threads = (0..THREADS)
+                .map(|_| {
+                    let name = Arc::clone(&name);
+                    let barrier = Arc::clone(&barrier);
+                    thread::spawn(move || {
+                        barrier.wait();
+                        let (sem, created) =
+                            NamedSemaphore::open_or_create(&*name, 0o600, 0).unwrap();
+                        sem.post().unwrap();
+                        created
+                    })
+                })
+                .collect::<Vec<_>>();
+            let created = threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .filter(|&created| created)
+                .count();
+            assert_eq!(1, created);
+            let (sem, created) = NamedSemaphore::open_or_create(&*name, 0o600, 0).unwrap();
+            assert!(!created);
+            assert_eq!(THREADS as libc::c_int, sem.value());
+            NamedSemaphore::unlink(&*name).unwrap();
+        }
+    }
+
+    #[test]
+    fn options_exclusive() {
+        let name = unique_name("opts-excl");
+        let mut opts = NamedOptions::new();
+        opts.create(true).exclusive(true).initial_value(2);
+        let sem = opts.open(name.as_str()).unwrap();
+        assert_eq!(2, sem.value());
+        let err = opts.open(name.as_str()).err().unwrap();
+        assert_eq!(Some(libc::EEXIST), err.raw_os_error());
+        // Without exclusive, it simply opens the existing one and keeps the value.
+        let sem2 = opts.exclusive(false).initial_value(5).open(name.clone()).unwrap();
+        assert_eq!(2, sem2.value());
+        NamedSemaphore::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn options_require_create() {
+        let name = unique_name("opts-invalid");
+        for opts in &[
+            NamedOptions::new().mode(0o600).clone(),
+            NamedOptions::new().initial_value(1).clone(),
+            NamedOptions::new().exclusive(true).clone(),
+        ] {
+            let err = opts.open(name.as_str()).err().unwrap();
+            assert_eq!(ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn options_mode() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let name = unique_name("opts-mode");
+        let _sem = NamedOptions::new()
+            .create(true)
+            .mode(0o640)
+            .open(name.as_str())
+            .unwrap();
+        let meta = fs::metadata(SemName::new(name.as_str()).unwrap().shm_path()).unwrap();
+        let mode = meta.permissions().mode() & 0o777;
+        // Modulo umask, which can only take bits away
+        assert_eq!(0, mode & !0o640);
+        assert_eq!(0o600, mode & 0o600);
+        NamedSemaphore::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn unlink() {
+        let name = unique_name("unlink");
+        let sem = NamedSemaphore::create(&name, 0o600, 0).unwrap();
+        NamedSemaphore::unlink(&name).unwrap();
+        let err = NamedSemaphore::open(&name).err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        let err = NamedSemaphore::unlink(&name).unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+        // The handle still works
+        sem.post().unwrap();
+        sem.wait();
+    }
+
+    #[test]
+    fn unlink_on_drop() {
+        let name = unique_name("unlink-drop");
+        let mut sem = NamedSemaphore::create(&name, 0o600, 0).unwrap();
+        sem.unlink_on_drop(true);
+        // Opened handles don't unlink unless asked to
+        drop(NamedSemaphore::open(&name).unwrap());
+        drop(NamedSemaphore::open(&name).unwrap());
+        drop(sem);
+        let err = NamedSemaphore::open(&name).err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn name_validation() {
+        assert_eq!("/foo", SemName::new("/foo").unwrap().as_str());
+        assert_eq!(NameError::NoLeadingSlash, SemName::new("foo").unwrap_err());
+        assert_eq!(NameError::Empty, SemName::new("/").unwrap_err());
+        assert_eq!(NameError::Slash, SemName::new("//foo").unwrap_err());
+        assert_eq!(NameError::Slash, SemName::new("/foo/bar").unwrap_err());
+        assert_eq!(NameError::Nul, SemName::new("/foo\0bar").unwrap_err());
+        let longest = format!("/{}", "x".repeat(MAX_NAME_LEN - 1));
+        SemName::new(longest.as_str()).unwrap();
+        assert_eq!(NameError::TooLong, SemName::new(longest + "x").unwrap_err());
+
+        let err = NamedSemaphore::open("no-slash").err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn name_path() {
+        let name = SemName::new("/foo").unwrap();
+        assert_eq!(PathBuf::from("/dev/shm/sem.foo"), name.shm_path());
+    }
+
+    #[test]
+    fn open_by_sem_name() {
+        let name = SemName::new(unique_name("sem-name")).unwrap();
+        let sem = NamedSemaphore::create(&name, 0o600, 0).unwrap();
+        assert_eq!(&name, sem.name());
+        drop(NamedSemaphore::open(name.as_str()).unwrap());
+        NamedSemaphore::unlink(name).unwrap();
+    }
+
+    #[test]
+    fn temporary_unique() {
+        let threads = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    (0..16)
+                        .map(|_| NamedSemaphore::temporary(0).unwrap())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let sems = threads
+            .into_iter()
+            .flat_map(|t| t.join().unwrap())
+            .collect::<Vec<_>>();
+        let mut names = sems.iter().map(|s| s.name().clone()).collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        assert_eq!(sems.len(), names.len());
+    }
+
+    #[test]
+    fn temporary_unlinked() {
+        let sem = NamedSemaphore::temporary(1).unwrap();
+        let name = sem.name().clone();
+        NamedSemaphore::open(&name).unwrap().wait();
+        #[cfg(target_os = "linux")]
+        assert!(name.shm_path().exists());
+        drop(sem);
+        #[cfg(target_os = "linux")]
+        assert!(!name.shm_path().exists());
+        let err = NamedSemaphore::open(&name).err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn temporary_keep() {
+        let sem = NamedSemaphore::temporary(0).unwrap().keep();
+        let name = sem.name().clone();
+        drop(sem);
+        NamedSemaphore::open(&name).unwrap();
+        NamedSemaphore::unlink(&name).unwrap();
+    }
+
+    #[test]
+    fn try_clone() {
+        let sem = NamedSemaphore::temporary(0).unwrap();
+        let clone = sem.try_clone().unwrap();
+        let t = thread::spawn(move || {
+            clone.wait();
+            clone.post().unwrap();
+            clone
+        });
+        sem.post().unwrap();
+        let clone = t.join().unwrap();
+        sem.wait();
+        // Dropping one handle keeps the other one alive
+        drop(clone);
+        sem.post().unwrap();
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn try_clone_unlinked() {
+        let sem = NamedSemaphore::temporary(0).unwrap();
+        NamedSemaphore::unlink(sem.name()).unwrap();
+        let err = sem.try_clone().err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    fn close() {
+        let sem = NamedSemaphore::temporary(0).unwrap();
+        let other = NamedSemaphore::open(sem.name()).unwrap();
+        other.close().unwrap();
+        sem.post().unwrap();
+        sem.wait();
+    }
+
+    #[test]
+    fn close_unlinked() {
+        let name = unique_name("close-unlinked");
+        let sem = NamedSemaphore::create(&name, 0o600, 0).unwrap();
+        NamedSemaphore::unlink(&name).unwrap();
+        sem.close().unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn list_existing() {
+        let sem = NamedSemaphore::temporary(0).unwrap();
+        let name = sem.name().clone();
+        let info = list_info()
+            .unwrap()
+            .into_iter()
+            .find(|info| info.name() == &name)
+            .unwrap();
+        assert_eq!(0, info.mode() & !0o600);
+        assert!(info.size() > 0);
+        drop(sem);
+        assert!(!list().unwrap().contains(&name));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sem_metadata() {
+        let sem = NamedSemaphore::temporary(0).unwrap();
+        let meta = sem.metadata().unwrap();
+        assert_eq!(unsafe { libc::geteuid() }, meta.uid());
+        assert_eq!(0, meta.mode() & !0o600);
+        assert_eq!(0o600, meta.mode() & 0o600);
+        assert!(meta.can_open());
+        NamedSemaphore::unlink(sem.name()).unwrap();
+        let err = sem.metadata().unwrap_err();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn chmod() {
+        let sem = NamedSemaphore::temporary(0).unwrap();
+        sem.set_permissions(0o640).unwrap();
+        assert_eq!(0o640, sem.metadata().unwrap().mode());
+        set_permissions(sem.name(), 0o000).unwrap();
+        assert_eq!(0, sem.metadata().unwrap().mode());
+        // Root can open it anyway
+        if unsafe { libc::geteuid() } != 0 {
+            assert!(!sem.metadata().unwrap().can_open());
+            let err = NamedSemaphore::open(sem.name()).err().unwrap();
+            assert_eq!(ErrorKind::PermissionDenied, err.kind());
+        }
+        // The already opened handle still works
+        sem.post().unwrap();
+        sem.wait();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn chown() {
+        let sem = NamedSemaphore::temporary(0).unwrap();
+        sem.set_owner(None, None).unwrap();
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        sem.set_owner(Some(uid), Some(gid)).unwrap();
+        let meta = sem.metadata().unwrap();
+        assert_eq!(uid, meta.uid());
+        assert_eq!(gid, meta.gid());
+    }
+
+    #[test]
+    fn open_missing() {
+        let err = NamedSemaphore::open(unique_name("missing")).err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+    }
+}
@@ -0,0 +1,168 @@
+//! Thread parking on top of a semaphore.
+
+use std::cell::Cell;
+use std::io::Error;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use libc;
+
+use {Semaphore, WaitError};
+
+struct Inner {
+    sem: Semaphore,
+    // Is there a token in the semaphore (or about to be)?
+    notified: AtomicBool,
+}
+
+/// The waiting side of a [`parker`].
+///
+/// There can be only one thread parking at a time, so this is not `Sync` (but can be sent to
+/// another thread).
+pub struct Parker {
+    inner: Arc<Inner>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl Parker {
+    fn consumed(&self) {
+        self.inner.notified.store(false, Ordering::Release);
+    }
+
+    /// Blocks until the token is available and consumes it.
+    ///
+    /// Returns right away if [`unpark`][Unparker::unpark] was called since the last park.
+    pub fn park(&self) {
+        self.inner.sem.wait();
+        self.consumed();
+    }
+
+    /// Like [`park`][Parker::park], but waits at most for the given time.
+    pub fn park_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.inner.sem.wait_timeout(timeout)?;
+        self.consumed();
+        Ok(())
+    }
+
+    /// Like [`park`][Parker::park], but waits at most until the given time.
+    pub fn park_until(&self, until: SystemTime) -> Result<(), WaitError> {
+        self.inner.sem.timedwait(until)?;
+        self.consumed();
+        Ok(())
+    }
+
+    /// Creates another unparker for this parker.
+    pub fn unparker(&self) -> Unparker {
+        Unparker {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+/// The waking side of a [`parker`].
+#[derive(Clone)]
+pub struct Unparker {
+    inner: Arc<Inner>,
+}
+
+impl Unparker {
+    /// Makes the token available, waking the parked thread.
+    ///
+    /// If the token is already available, this does nothing, the calls don't accumulate.
+    ///
+    /// This is async-signal-safe, it's only an atomic operation and a `sem_post`, so it may be
+    /// called from a signal handler.
+    pub fn unpark(&self) {
+        if !self.inner.notified.swap(true, Ordering::AcqRel) {
+            // The bare call, the wrapper does more on failure. But this can't overflow, there's at
+            // most one token.
+            unsafe { libc::sem_post(self.inner.sem.as_raw()) };
+        }
+    }
+}
+
+/// Creates a pair for parking and unparking a thread, like [`std::thread::park`].
+///
+/// Unlike the standard parking, the unparker doesn't need to know the thread and can be used
+/// from a signal handler.
+///
+/// At most one token is kept. Unparking before parking lets the next park through right away,
+/// but unparking several times lets through only one. And like with the standard parking, an
+/// unpark racing with a park that is already returning may be absorbed by it.
+pub fn parker() -> Result<(Parker, Unparker), Error> {
+    let inner = Arc::new(Inner {
+        sem: Semaphore::anonymous_posix(0)?,
+        notified: AtomicBool::new(false),
+    });
+    let parker = Parker {
+        inner,
+        _not_sync: PhantomData,
+    };
+    let unparker = parker.unparker();
+    Ok((parker, unparker))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::OnceLock;
+    use std::thread;
+
+    use super::*;
+    use test_util::fork;
+
+    const TIMEOUT: Duration = Duration::from_millis(10);
+
+    #[test]
+    fn coalesces() {
+        let (parker, unparker) = parker().unwrap();
+        unparker.unpark();
+        unparker.clone().unpark();
+        parker.park();
+        assert_eq!(Err(WaitError::TimedOut), parker.park_timeout(TIMEOUT));
+        assert_eq!(0, parker.inner.sem.value());
+    }
+
+    #[test]
+    fn wakes_up() {
+        let (parker, unparker) = parker().unwrap();
+        assert_eq!(Err(WaitError::TimedOut), parker.park_timeout(TIMEOUT));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(TIMEOUT);
+                unparker.unpark();
+            });
+            parker.park_timeout(Duration::from_secs(10)).unwrap();
+        });
+    }
+
+    static UNPARKER: OnceLock<Unparker> = OnceLock::new();
+
+    extern "C" fn unpark_handler(_: libc::c_int) {
+        if let Some(unparker) = UNPARKER.get() {
+            unparker.unpark();
+        }
+    }
+
+    #[test]
+    fn from_signal_handler() {
+        // In a child process, so the handler doesn't meddle with other tests using the signal
+        fork(|| {
+            let (parker, unparker) = parker().unwrap();
+            UNPARKER.set(unparker).ok().unwrap();
+            unsafe {
+                let mut action: libc::sigaction = ::std::mem::zeroed();
+                action.sa_sigaction = unpark_handler as *const () as libc::sighandler_t;
+                assert_eq!(0, libc::sigaction(libc::SIGUSR1, &action, ::std::ptr::null_mut()));
+            }
+            let signaller = thread::spawn(|| {
+                thread::sleep(TIMEOUT);
+                unsafe { libc::kill(libc::getpid(), libc::SIGUSR1) };
+            });
+            parker.park_timeout(Duration::from_secs(10)).unwrap();
+            signaller.join().unwrap();
+        })
+        .join();
+    }
+}
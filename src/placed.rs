@@ -0,0 +1,165 @@
+//! Semaphores placed into memory provided by the caller.
+
+use std::alloc::Layout;
+use std::io::Error;
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+use libc::{self, sem_t};
+
+use metrics::Metrics;
+use shadow::Shadow;
+use {init, Kind, Mode, Semaphore};
+
+/// A semaphore initialized in place, in memory it doesn't own.
+///
+/// Created by [`Semaphore::init_at`] or [`Semaphore::init_in`]. It dereferences to
+/// [`Semaphore`] for all the waiting and posting. Dropping it destroys the semaphore (unless
+/// turned off by [`destroy_on_drop`][BorrowedSemaphore::destroy_on_drop]), but it never frees
+/// the memory.
+#[derive(Debug)]
+pub struct BorrowedSemaphore<'a> {
+    sem: Semaphore,
+    _memory: PhantomData<&'a mut sem_t>,
+}
+
+impl Semaphore {
+    /// The memory layout of the platform's `sem_t`.
+    ///
+    /// Use it to reserve space for semaphores placed by [`init_at`][Semaphore::init_at]. This
+    /// is the very `sem_t` C code uses, so a placed semaphore is layout-compatible with C and C
+    /// code mapping the same memory can use it directly.
+    pub const LAYOUT: Layout = Layout::new::<sem_t>();
+
+    /// Initializes a semaphore in the provided memory, without allocating.
+    ///
+    /// If `pshared` is set, the semaphore can be used by other processes mapping the same memory.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be valid for writes of [`LAYOUT`][Semaphore::LAYOUT] and aligned
+    /// accordingly. The memory must stay valid, must not move and must not be used in other ways
+    /// for the lifetime `'a`.
+    pub unsafe fn init_at<'a>(ptr: NonNull<sem_t>, pshared: bool, value: u32)
+        -> Result<BorrowedSemaphore<'a>, Error>
+    {
+        init(ptr.as_ptr(), pshared, value)?;
+        Ok(BorrowedSemaphore {
+            sem: Semaphore {
+                inner: ptr,
+                mode: Mode::Placed,
+                kind: Kind::Placed {
+                    process_shared: pshared,
+                },
+                metrics: Metrics::new(),
+                slow_wait: None,
+                shadow: Shadow::untracked(),
+            },
+            _memory: PhantomData,
+        })
+    }
+
+    /// Initializes a semaphore in the provided slot.
+    ///
+    /// This is the safe counterpart of [`init_at`][Semaphore::init_at], the borrow ensures the
+    /// memory outlives the semaphore.
+    pub fn init_in(slot: &mut MaybeUninit<sem_t>, pshared: bool, value: u32)
+        -> Result<BorrowedSemaphore<'_>, Error>
+    {
+        unsafe { Self::init_at(NonNull::from(slot).cast(), pshared, value) }
+    }
+
+    /// Destroys a semaphore initialized in place, without freeing the memory.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to an initialized semaphore that is no longer in use by anything
+    /// (including a [`BorrowedSemaphore`] that would destroy it on drop).
+    pub unsafe fn destroy_in_place(ptr: NonNull<sem_t>) -> Result<(), Error> {
+        match libc::sem_destroy(ptr.as_ptr()) {
+            0 => Ok(()),
+            -1 => Err(Error::last_os_error()),
+            other => unreachable!("sem_destroy doesn't return value {}", other),
+        }
+    }
+}
+
+impl<'a> BorrowedSemaphore<'a> {
+    /// Wraps a semaphore initialized by someone else, without destroying it on drop.
+    #[cfg(feature = "shared-memory")]
+    pub(crate) unsafe fn foreign(ptr: NonNull<sem_t>) -> Self {
+        BorrowedSemaphore {
+            sem: Semaphore {
+                inner: ptr,
+                mode: Mode::Released,
+                // Only the shared memory segments are wrapped this way
+                kind: Kind::Placed {
+                    process_shared: true,
+                },
+                metrics: Metrics::new(),
+                slow_wait: None,
+                shadow: Shadow::untracked(),
+            },
+            _memory: PhantomData,
+        }
+    }
+
+    /// Whether the semaphore gets destroyed when this handle is dropped.
+    ///
+    /// On by default. Turn it off if the semaphore is to be used further through the memory
+    /// (eg. by other processes).
+    pub fn destroy_on_drop(&mut self, destroy: bool) {
+        self.sem.set_destroy_on_drop(destroy);
+    }
+}
+
+impl<'a> Deref for BorrowedSemaphore<'a> {
+    type Target = Semaphore;
+    fn deref(&self) -> &Semaphore {
+        &self.sem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn placed_in_buffer() {
+        assert!(Semaphore::LAYOUT.align() <= mem::align_of::<u64>());
+        let mut buffer = vec![0u64; 2 + Semaphore::LAYOUT.size() / 8].into_boxed_slice();
+        let sem = unsafe {
+            let ptr = buffer.as_mut_ptr().add(1) as *mut sem_t;
+            Semaphore::init_at(NonNull::new(ptr).unwrap(), false, 0).unwrap()
+        };
+        thread::scope(|s| {
+            s.spawn(|| {
+                sem.post().unwrap();
+                sem.post().unwrap();
+            });
+            sem.wait();
+            sem.wait();
+        });
+        assert_eq!(0, sem.value());
+        drop(sem);
+        assert_eq!(0, buffer[0]);
+    }
+
+    #[test]
+    fn placed_keep() {
+        let mut slot = MaybeUninit::uninit();
+        let mut sem = Semaphore::init_in(&mut slot, false, 1).unwrap();
+        sem.destroy_on_drop(false);
+        drop(sem);
+        unsafe {
+            let ptr = NonNull::from(&mut slot).cast();
+            assert_eq!(0, libc::sem_trywait(ptr.as_ptr()));
+            Semaphore::destroy_in_place(ptr).unwrap();
+        }
+    }
+}
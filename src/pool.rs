@@ -0,0 +1,176 @@
+//! A pool of reusable objects.
+
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use {NoToken, Semaphore, WaitError};
+
+/// A fixed set of objects handed out one at a time.
+///
+/// The semaphore counts the idle objects, so [`get`][Pool::get] waits until one is returned by
+/// dropping its [`PoolGuard`].
+pub struct Pool<T> {
+    sem: Semaphore,
+    items: Mutex<Vec<T>>,
+    capacity: AtomicUsize,
+}
+
+impl<T> Pool<T> {
+    /// Creates a pool of the given objects, all of them idle.
+    pub fn new(items: Vec<T>) -> Result<Self, Error> {
+        let count = u32::try_from(items.len())
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Too many items for a pool"))?;
+        Ok(Pool {
+            sem: Semaphore::anonymous(count)?,
+            capacity: AtomicUsize::new(items.len()),
+            items: Mutex::new(items),
+        })
+    }
+
+    /// The number of objects in the pool, both idle and handed out.
+    ///
+    /// It goes down when an object is removed by [`PoolGuard::take`].
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// The number of idle objects.
+    pub fn idle(&self) -> usize {
+        self.sem.value().max(0) as usize
+    }
+
+    fn items(&self) -> MutexGuard<'_, Vec<T>> {
+        self.items.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Picks one of the idle objects, after getting a token for it.
+    fn checkout(&self) -> PoolGuard<'_, T> {
+        let item = self.items().pop().expect("Pool token without an item");
+        PoolGuard {
+            pool: self,
+            item: Some(item),
+        }
+    }
+
+    /// Waits for an idle object and takes it.
+    pub fn get(&self) -> PoolGuard<'_, T> {
+        self.sem.wait();
+        self.checkout()
+    }
+
+    /// Takes an idle object, if there's one.
+    pub fn try_get(&self) -> Result<PoolGuard<'_, T>, NoToken> {
+        self.sem.trywait()?;
+        Ok(self.checkout())
+    }
+
+    /// Waits for an idle object, but at most for the given time.
+    pub fn get_timeout(&self, timeout: Duration) -> Result<PoolGuard<'_, T>, WaitError> {
+        self.sem.wait_timeout(timeout)?;
+        Ok(self.checkout())
+    }
+}
+
+/// An object borrowed from a [`Pool`].
+///
+/// Dropping it returns the object to the pool.
+#[must_use = "The object goes back to the pool right away if the guard is not used"]
+pub struct PoolGuard<'a, T: 'a> {
+    pool: &'a Pool<T>,
+    // Always Some, until taken out for good
+    item: Option<T>,
+}
+
+impl<'a, T> PoolGuard<'a, T> {
+    /// Removes the object from the pool permanently.
+    ///
+    /// The capacity of the pool goes down by one.
+    pub fn take(mut self) -> T {
+        self.pool.capacity.fetch_sub(1, Ordering::Relaxed);
+        self.item.take().expect("Pool guard without an item")
+    }
+}
+
+impl<'a, T> Deref for PoolGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("Pool guard without an item")
+    }
+}
+
+impl<'a, T> DerefMut for PoolGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().expect("Pool guard without an item")
+    }
+}
+
+impl<'a, T> Drop for PoolGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            self.pool.items().push(item);
+            // Can't overflow, there are at most as many tokens as there were items at the start
+            let _ = self.pool.sem.post();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use super::*;
+
+    const ITEMS: usize = 3;
+
+    #[test]
+    fn exclusive() {
+        let pool = Pool::new((0..ITEMS).collect()).unwrap();
+        let in_use = (0..ITEMS).map(|_| AtomicBool::new(false)).collect::<Vec<_>>();
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let item = pool.get();
+                        assert!(!in_use[*item].swap(true, Ordering::Relaxed));
+                        thread::yield_now();
+                        in_use[*item].store(false, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(ITEMS, pool.idle());
+        assert_eq!(ITEMS, pool.capacity());
+    }
+
+    #[test]
+    fn exhausted() {
+        let pool = Pool::new(vec![String::new(); ITEMS]).unwrap();
+        let mut guards = (0..ITEMS).map(|_| pool.try_get().unwrap()).collect::<Vec<_>>();
+        assert!(pool.try_get().is_err());
+        let timeout = Duration::from_millis(10);
+        assert_eq!(WaitError::TimedOut, pool.get_timeout(timeout).err().unwrap());
+        guards[0].push_str("used");
+        guards.clear();
+        assert_eq!(ITEMS, pool.idle());
+        let used = (0..ITEMS).filter(|_| !pool.get().take().is_empty()).count();
+        assert_eq!(1, used);
+    }
+
+    #[test]
+    fn take() {
+        let pool = Pool::new((0..ITEMS).collect()).unwrap();
+        let taken = pool.get().take();
+        assert_eq!(ITEMS - 1, pool.capacity());
+        assert_eq!(ITEMS - 1, pool.idle());
+        let guards = (1..ITEMS).map(|_| pool.get()).collect::<Vec<_>>();
+        assert!(guards.iter().all(|g| **g != taken));
+        assert!(pool.try_get().is_err());
+        drop(guards);
+        assert_eq!(ITEMS - 1, pool.idle());
+    }
+}
@@ -0,0 +1,238 @@
+//! A semaphore preferring one class of waiters over another.
+
+use std::io::Error;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use {NoToken, Overflow, Semaphore, WaitError};
+
+/// The class of a waiter of a [`PrioritySemaphore`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Priority {
+    /// Served first.
+    High,
+    /// Served only when no high priority waiter waits.
+    Low,
+}
+
+#[derive(Default)]
+struct Dispatch {
+    permits: u32,
+    high: u32,
+    low: u32,
+    // High priority grants since the last low one, while low ones were waiting
+    highs_in_row: u32,
+    budget: Option<u32>,
+}
+
+impl Dispatch {
+    fn waiting(&mut self, priority: Priority) -> &mut u32 {
+        match priority {
+            Priority::High => &mut self.high,
+            Priority::Low => &mut self.low,
+        }
+    }
+}
+
+/// A semaphore handing its permits to high priority waiters first.
+///
+/// A released permit goes to one of the [`Priority::High`] waiters if there are any and to a
+/// [`Priority::Low`] one only if not. Permits are handed directly to the chosen waiter, so a
+/// newcomer can't steal them. Only when nobody waits, the permit stays in the semaphore for
+/// whoever comes first.
+///
+/// If high priority waiters keep coming, the low ones may wait forever. Setting a
+/// [starvation budget][PrioritySemaphore::set_starvation_budget] lets one low priority waiter
+/// through after the given number of high priority grants in a row.
+pub struct PrioritySemaphore {
+    dispatch: Mutex<Dispatch>,
+    high: Semaphore,
+    low: Semaphore,
+}
+
+impl PrioritySemaphore {
+    /// Creates the semaphore with the given number of permits, with no starvation budget.
+    pub fn new(permits: u32) -> Result<Self, Error> {
+        Ok(PrioritySemaphore {
+            dispatch: Mutex::new(Dispatch {
+                permits,
+                ..Dispatch::default()
+            }),
+            high: Semaphore::anonymous(0)?,
+            low: Semaphore::anonymous(0)?,
+        })
+    }
+
+    fn dispatch(&self) -> MutexGuard<'_, Dispatch> {
+        self.dispatch.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sem(&self, priority: Priority) -> &Semaphore {
+        match priority {
+            Priority::High => &self.high,
+            Priority::Low => &self.low,
+        }
+    }
+
+    /// Lets a low priority waiter through after `budget` high priority grants in a row.
+    ///
+    /// `None` (the default) means strict priority. A zero budget is the same as one.
+    pub fn set_starvation_budget(&self, budget: Option<u32>) {
+        self.dispatch().budget = budget;
+    }
+
+    /// Waits for a permit and takes it.
+    pub fn acquire(&self, priority: Priority) {
+        self.acquire_until(priority, None).expect("Wait without a deadline failed");
+    }
+
+    /// Takes a permit if one is available.
+    pub fn try_acquire(&self, _priority: Priority) -> Result<(), NoToken> {
+        let mut dispatch = self.dispatch();
+        // Available permits mean nobody is waiting, so the priority doesn't matter
+        if dispatch.permits == 0 {
+            return Err(NoToken::new());
+        }
+        dispatch.permits -= 1;
+        Ok(())
+    }
+
+    /// Waits for a permit, but at most for the given time.
+    pub fn acquire_timeout(&self, priority: Priority, timeout: Duration) -> Result<(), WaitError> {
+        self.acquire_until(priority, Instant::now().checked_add(timeout))
+    }
+
+    fn acquire_until(&self, priority: Priority, deadline: Option<Instant>)
+        -> Result<(), WaitError>
+    {
+        {
+            let mut dispatch = self.dispatch();
+            if dispatch.permits > 0 {
+                dispatch.permits -= 1;
+                return Ok(());
+            }
+            *dispatch.waiting(priority) += 1;
+        }
+        let sem = self.sem(priority);
+        let result = match deadline {
+            Some(deadline) => sem.wait_deadline(deadline),
+            None => {
+                sem.wait();
+                Ok(())
+            },
+        };
+        if let Err(e) = result {
+            let mut dispatch = self.dispatch();
+            let waiting = dispatch.waiting(priority);
+            if *waiting > 0 {
+                *waiting -= 1;
+                return Err(e);
+            }
+            // Granted to us just now, pick it up
+            drop(dispatch);
+            sem.wait();
+        }
+        Ok(())
+    }
+
+    /// Returns a permit, to a waiter of the highest priority if there's any.
+    pub fn release(&self) -> Result<(), Overflow> {
+        let mut dispatch = self.dispatch();
+        let budget_spent = match dispatch.budget {
+            Some(budget) => dispatch.highs_in_row >= budget.max(1),
+            None => false,
+        };
+        let to = if dispatch.high > 0 && !(budget_spent && dispatch.low > 0) {
+            dispatch.highs_in_row = if dispatch.low > 0 {
+                dispatch.highs_in_row + 1
+            } else {
+                0
+            };
+            Priority::High
+        } else if dispatch.low > 0 {
+            dispatch.highs_in_row = 0;
+            Priority::Low
+        } else {
+            dispatch.permits = dispatch.permits.checked_add(1).ok_or(Overflow::new())?;
+            return Ok(());
+        };
+        *dispatch.waiting(to) -= 1;
+        // Each waiter gets at most one token, so there are never too many
+        let _ = self.sem(to).post();
+        Ok(())
+    }
+
+    /// The number of permits nobody holds.
+    pub fn available(&self) -> u32 {
+        self.dispatch().permits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    const WAITERS: u32 = 3;
+
+    fn waiting(sem: &PrioritySemaphore) -> (u32, u32) {
+        let dispatch = sem.dispatch();
+        (dispatch.high, dispatch.low)
+    }
+
+    fn wait_for(sem: &PrioritySemaphore, high: u32, low: u32) {
+        while waiting(sem) != (high, low) {
+            thread::yield_now();
+        }
+    }
+
+    /// Starts the waiters and releases the permits one by one, checking who got each of them.
+    fn grants(sem: &PrioritySemaphore, expected: &[Priority]) {
+        thread::scope(|s| {
+            for _ in 0..WAITERS {
+                s.spawn(|| sem.acquire(Priority::Low));
+                s.spawn(|| sem.acquire(Priority::High));
+            }
+            wait_for(sem, WAITERS, WAITERS);
+            for &to in expected {
+                let (high, low) = waiting(sem);
+                sem.release().unwrap();
+                match to {
+                    Priority::High => assert_eq!((high - 1, low), waiting(sem)),
+                    Priority::Low => assert_eq!((high, low - 1), waiting(sem)),
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn strict() {
+        use self::Priority::*;
+        let sem = PrioritySemaphore::new(0).unwrap();
+        grants(&sem, &[High, High, High, Low, Low, Low]);
+        sem.release().unwrap();
+        assert_eq!(1, sem.available());
+        sem.try_acquire(Low).unwrap();
+        assert_eq!(Err(NoToken::new()), sem.try_acquire(High));
+    }
+
+    #[test]
+    fn starvation_budget() {
+        use self::Priority::*;
+        let sem = PrioritySemaphore::new(0).unwrap();
+        sem.set_starvation_budget(Some(2));
+        grants(&sem, &[High, High, Low, High, Low, Low]);
+    }
+
+    #[test]
+    fn timeout() {
+        let sem = PrioritySemaphore::new(0).unwrap();
+        let timeout = Duration::from_millis(10);
+        assert_eq!(Err(WaitError::TimedOut), sem.acquire_timeout(Priority::High, timeout));
+        assert_eq!((0, 0), waiting(&sem));
+        sem.release().unwrap();
+        sem.acquire_timeout(Priority::Low, timeout).unwrap();
+        assert_eq!(0, sem.available());
+    }
+}
@@ -0,0 +1,205 @@
+//! Limiting the rate of operations.
+
+use std::io::Error;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use {BoundedSemaphore, NoToken, WaitError};
+
+struct Refill {
+    rate: f64,
+    // Tokens are due since this time at the rate
+    epoch: Instant,
+    credited: u64,
+    stop: bool,
+}
+
+struct Inner {
+    sem: BoundedSemaphore,
+    refill: Mutex<Refill>,
+    wakeup: Condvar,
+}
+
+impl Inner {
+    fn refill(&self) -> MutexGuard<'_, Refill> {
+        self.refill.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn run(&self) {
+        let mut refill = self.refill();
+        while !refill.stop {
+            if refill.rate == 0.0 {
+                refill = self.wakeup.wait(refill).unwrap_or_else(|e| e.into_inner());
+                continue;
+            }
+            // Computed from the time since the epoch, not by adding up the sleeps, so it doesn't
+            // drift by the time spent waking up.
+            let due = (refill.epoch.elapsed().as_secs_f64() * refill.rate) as u64;
+            for _ in refill.credited..due {
+                if self.sem.post().is_err() {
+                    // The bucket is full, the rest is lost
+                    break;
+                }
+            }
+            refill.credited = due;
+            let next = refill.epoch + Duration::from_secs_f64((due + 1) as f64 / refill.rate);
+            let sleep = next.saturating_duration_since(Instant::now());
+            refill = self
+                .wakeup
+                .wait_timeout(refill, sleep)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+}
+
+/// A token bucket limiting how often something happens.
+///
+/// The bucket holds up to `burst` tokens and starts full. A background thread adds tokens at the
+/// configured rate, as long as they fit, and [`acquire`][RateLimiter::acquire] takes them out,
+/// waiting if there are none. The thread is stopped when the limiter is dropped.
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+    refiller: Option<JoinHandle<()>>,
+}
+
+fn check_rate(rate_per_sec: f64) {
+    assert!(rate_per_sec.is_finite() && rate_per_sec >= 0.0, "Invalid rate {}", rate_per_sec);
+}
+
+impl RateLimiter {
+    /// Creates the limiter, letting through `rate_per_sec` operations a second on average and up
+    /// to `burst` at once.
+    ///
+    /// A zero rate adds no tokens, until changed by [`set_rate`][RateLimiter::set_rate].
+    ///
+    /// # Panics
+    ///
+    /// If the rate is negative or not finite.
+    pub fn new(rate_per_sec: f64, burst: u32) -> Result<Self, Error> {
+        check_rate(rate_per_sec);
+        let inner = Arc::new(Inner {
+            sem: BoundedSemaphore::new(burst, burst)?,
+            refill: Mutex::new(Refill {
+                rate: rate_per_sec,
+                epoch: Instant::now(),
+                credited: 0,
+                stop: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+        let refiller = thread::Builder::new()
+            .name("rate-limiter".to_owned())
+            .spawn({
+                let inner = Arc::clone(&inner);
+                move || inner.run()
+            })?;
+        Ok(RateLimiter {
+            inner,
+            refiller: Some(refiller),
+        })
+    }
+
+    /// Changes the rate.
+    ///
+    /// The tokens already in the bucket stay there. New ones come at the new rate from now on.
+    ///
+    /// # Panics
+    ///
+    /// If the rate is negative or not finite.
+    pub fn set_rate(&self, rate_per_sec: f64) {
+        check_rate(rate_per_sec);
+        let mut refill = self.inner.refill();
+        refill.rate = rate_per_sec;
+        refill.epoch = Instant::now();
+        refill.credited = 0;
+        self.inner.wakeup.notify_one();
+    }
+
+    /// The current rate.
+    pub fn rate(&self) -> f64 {
+        self.inner.refill().rate
+    }
+
+    /// The number of tokens in the bucket right now.
+    pub fn available(&self) -> u32 {
+        self.inner.sem.available()
+    }
+
+    /// Waits for a token and takes it.
+    pub fn acquire(&self) {
+        self.inner.sem.wait()
+    }
+
+    /// Takes a token, if one is available.
+    pub fn try_acquire(&self) -> Result<(), NoToken> {
+        self.inner.sem.trywait()
+    }
+
+    /// Waits for a token, but at most for the given time.
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.inner.sem.wait_timeout(timeout)
+    }
+}
+
+impl Drop for RateLimiter {
+    fn drop(&mut self) {
+        self.inner.refill().stop = true;
+        self.inner.wakeup.notify_one();
+        if let Some(refiller) = self.refiller.take() {
+            let _ = refiller.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_rate() {
+        let limiter = RateLimiter::new(100.0, 10).unwrap();
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        let start = Instant::now();
+        for _ in 0..50 {
+            limiter.acquire();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed > Duration::from_millis(350), "Too fast: {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "Too slow: {:?}", elapsed);
+    }
+
+    #[test]
+    fn clamped_at_burst() {
+        let limiter = RateLimiter::new(1000.0, 3).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(3, limiter.available());
+        for _ in 0..3 {
+            limiter.try_acquire().unwrap();
+        }
+        limiter.set_rate(0.0);
+        // Something might have come in before the rate change
+        while limiter.try_acquire().is_ok() {}
+        limiter.try_acquire().unwrap_err();
+        let timeout = Duration::from_millis(20);
+        assert_eq!(Err(WaitError::TimedOut), limiter.acquire_timeout(timeout));
+        limiter.set_rate(1000.0);
+        limiter.acquire_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(1000.0, limiter.rate());
+    }
+
+    #[test]
+    fn drop_stops_refiller() {
+        let limiter = RateLimiter::new(0.0, 1).unwrap();
+        let refiller = limiter.refiller.as_ref().unwrap().thread().clone();
+        assert_eq!(Some("rate-limiter"), refiller.name());
+        let start = Instant::now();
+        drop(limiter);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,272 @@
+//! Borrowed, non-owning views of semaphores.
+
+use std::io::Error;
+use std::ops::ControlFlow;
+use std::ptr::NonNull;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant, SystemTime};
+
+use libc::{self, c_int, sem_t};
+
+use backend::Backend;
+use {
+    Cancelled, Clock, ClockId, Interrupted, NoToken, Overflow, PartialPost, PartialTimeout, Restart,
+    Semaphore, SemaphoreGuard, SemaphoreSlot, SpinConfig, Token, WaitAborted, WaitError,
+};
+
+/// A borrowed view of a semaphore owned by someone else.
+///
+/// It provides the same operations as [`Semaphore`], but never destroys or frees anything. It is
+/// useful for semaphores managed by C code and for code generic over where the semaphore lives.
+#[derive(Copy, Clone)]
+pub struct SemaphoreRef<'a> {
+    backend: Backend<'a>,
+}
+
+impl<'a> SemaphoreRef<'a> {
+    /// Views a semaphore initialized elsewhere.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must point to an initialized semaphore that stays valid (and isn't destroyed)
+    /// for the lifetime `'a`.
+    pub unsafe fn from_ptr(ptr: NonNull<sem_t>) -> SemaphoreRef<'a> {
+        SemaphoreRef {
+            backend: Backend::Posix(SemaphoreSlot::from_ptr(ptr.as_ptr())),
+        }
+    }
+
+    pub fn wait(&self) {
+        self.backend.wait()
+    }
+
+    /// Waits for a token, spinning for a while before blocking.
+    pub fn wait_spin(&self, spin: SpinConfig) {
+        self.backend.wait_spin(spin)
+    }
+
+    /// Waits for a token, but returns early if interrupted by a signal.
+    pub fn wait_interruptible(&self) -> Result<(), Interrupted> {
+        self.backend.wait_interruptible()
+    }
+
+    pub fn trywait(&self) -> Result<(), NoToken> {
+        self.backend.trywait()
+    }
+
+    pub fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+        self.backend.timedwait(until)
+    }
+
+    /// Waits for a token, but at most for the given time.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.backend.wait_timeout(timeout)
+    }
+
+    /// Waits for a token at most for the given time, returning early on a signal.
+    pub fn wait_timeout_interruptible(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.backend.wait_timeout_interruptible(timeout)
+    }
+
+    /// Waits for a token at most for the given time, computing the deadline from the clock.
+    pub fn wait_timeout_on<C: Clock + ?Sized>(&self, clock: &C, timeout: Duration)
+        -> Result<(), WaitError>
+    {
+        self.backend.wait_timeout_on(clock, timeout)
+    }
+
+    /// Waits for a token until the deadline, computing the kernel deadline from the clock.
+    pub fn wait_deadline_on<C: Clock + ?Sized>(&self, clock: &C, deadline: Instant)
+        -> Result<(), WaitError>
+    {
+        self.backend.wait_deadline_on(clock, deadline)
+    }
+
+    /// Waits for a token until the deadline, unaffected by changes of the system time.
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), WaitError> {
+        self.backend.wait_deadline(deadline)
+    }
+
+    /// Waits for a token until the absolute time of the given clock.
+    pub fn timedwait_with_clock(&self, clock: ClockId, abstime: libc::timespec)
+        -> Result<(), WaitError>
+    {
+        self.backend.timedwait_with_clock(clock, abstime)
+    }
+
+    /// Waits for a token until it's available or the flag gets set.
+    pub fn wait_cancellable(&self, cancel: &AtomicBool) -> Result<(), Cancelled> {
+        self.backend.wait_cancellable(cancel)
+    }
+
+    /// Waits for a token until it's available or the flag gets set, checking it with the given
+    /// granularity.
+    pub fn wait_cancellable_every(&self, cancel: &AtomicBool, granularity: Duration)
+        -> Result<(), Cancelled>
+    {
+        self.backend.wait_cancellable_every(cancel, granularity)
+    }
+
+    /// Waits for a token, calling the callback every `interval` while blocked.
+    pub fn wait_with_tick<F>(&self, interval: Duration, on_tick: F) -> Result<(), WaitAborted>
+    where
+        F: FnMut() -> ControlFlow<()>,
+    {
+        self.backend.wait_with_tick(interval, on_tick)
+    }
+
+    /// Waits for a token, restarting after signals according to the policy.
+    pub fn wait_with(&self, restart: Restart) -> Result<(), WaitError> {
+        self.backend.wait_with(restart)
+    }
+
+    /// Takes a token if available, restarting after signals according to the policy.
+    pub fn trywait_with(&self, restart: Restart) -> Result<(), WaitError> {
+        self.backend.trywait_with(restart)
+    }
+
+    /// Waits for a token until the deadline, restarting after signals according to the policy.
+    pub fn timedwait_with(&self, until: SystemTime, restart: Restart) -> Result<(), WaitError> {
+        self.backend.timedwait_with(until, restart)
+    }
+
+    /// Waits for a token at most for the given time, restarting after signals according to the
+    /// policy.
+    pub fn wait_timeout_with(&self, timeout: Duration, restart: Restart)
+        -> Result<(), WaitError>
+    {
+        self.backend.wait_timeout_with(timeout, restart)
+    }
+
+    pub fn post(&self) -> Result<(), Overflow> {
+        self.backend.post()
+    }
+
+    /// Waits for a token and returns a guard that posts it back when dropped.
+    pub fn access(&self) -> SemaphoreGuard<'a> {
+        self.backend.access()
+    }
+
+    /// Waits for a token, leaving the decision to keep or return it for later.
+    pub fn take(&self) -> Token<'a> {
+        self.backend.take()
+    }
+
+    /// Takes a token if available, returning a guard that posts it back when dropped.
+    pub fn try_access(&self) -> Option<SemaphoreGuard<'a>> {
+        self.backend.try_access()
+    }
+
+    /// Waits for a token at most for the given time, returning a guard that posts it back.
+    pub fn access_timeout(&self, timeout: Duration) -> Result<SemaphoreGuard<'a>, WaitError> {
+        self.backend.access_timeout(timeout)
+    }
+
+    /// Runs the closure while holding a token.
+    pub fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        self.backend.with(f)
+    }
+
+    /// Runs the closure while holding a token, if one is available right away.
+    pub fn try_with<R, F: FnOnce() -> R>(&self, f: F) -> Option<R> {
+        self.backend.try_with(f)
+    }
+
+    /// Runs the closure while holding a token, waiting for it at most for the given time.
+    pub fn with_timeout<R, F: FnOnce() -> R>(&self, timeout: Duration, f: F)
+        -> Result<R, WaitError>
+    {
+        self.backend.with_timeout(timeout, f)
+    }
+
+    /// Waits until it gets `n` tokens, returning them if interrupted by a panic.
+    pub fn acquire_many(&self, n: u32) {
+        self.backend.acquire_many(n)
+    }
+
+    /// Takes `n` tokens if they are all available right away, or none.
+    pub fn try_acquire_many(&self, n: u32) -> Result<(), NoToken> {
+        self.backend.try_acquire_many(n)
+    }
+
+    /// Waits for `n` tokens at most for the given time, or returns the ones it got.
+    pub fn acquire_many_timeout(&self, n: u32, timeout: Duration) -> Result<(), PartialTimeout> {
+        self.backend.acquire_many_timeout(n, timeout)
+    }
+
+    /// Takes all the tokens available right now, returning how many.
+    pub fn drain(&self) -> u32 {
+        self.backend.drain()
+    }
+
+    /// Takes at most `max` of the tokens available right now, returning how many.
+    pub fn drain_up_to(&self, max: u32) -> u32 {
+        self.backend.drain_up_to(max)
+    }
+
+    /// Forces the value to `target`, see [`SemaphoreSlot::set_value`].
+    pub fn set_value(&self, target: u32) -> Result<(), Error> {
+        self.backend.set_value(target)
+    }
+
+    /// Wakes all the threads currently blocked on the semaphore, returning how many.
+    pub fn post_all(&self) -> Result<u32, Error> {
+        self.backend.post_all()
+    }
+
+    /// Posts `n` tokens.
+    pub fn release_many(&self, n: u32) -> Result<(), Overflow> {
+        self.backend.release_many(n)
+    }
+
+    /// Posts `n` tokens, telling how many got posted on overflow.
+    pub fn post_many(&self, n: u32) -> Result<(), PartialPost> {
+        self.backend.post_many(n)
+    }
+
+    /// Posts `n` tokens or, on overflow, tries to take back the ones posted.
+    pub fn post_many_atomic(&self, n: u32) -> Result<(), PartialPost> {
+        self.backend.post_many_atomic(n)
+    }
+
+    pub fn value(&self) -> c_int {
+        self.backend.value()
+    }
+}
+
+impl Semaphore {
+    /// A borrowed view of the semaphore.
+    #[allow(clippy::should_implement_trait)]
+    pub fn as_ref(&self) -> SemaphoreRef<'_> {
+        SemaphoreRef {
+            backend: self.backend(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn post_twice(sem: SemaphoreRef) {
+        sem.post().unwrap();
+        sem.post().unwrap();
+    }
+
+    #[test]
+    fn interop() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let view = sem.as_ref();
+        thread::scope(|s| {
+            s.spawn(move || post_twice(view));
+            sem.wait();
+            view.wait();
+        });
+        assert_eq!(0, view.value());
+        view.post().unwrap();
+        sem.trywait().unwrap();
+        view.trywait().unwrap_err();
+    }
+}
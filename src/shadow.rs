@@ -0,0 +1,58 @@
+//! Counting the value ourselves, where the OS can't tell it.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// The platforms where `sem_getvalue` doesn't work (macOS fails with `ENOSYS`).
+pub(crate) const GETVALUE_BROKEN: bool = cfg!(target_vendor = "apple");
+
+/// A count of the tokens taken and posted through one handle.
+///
+/// It sees only what goes through that handle, so it's right only as long as nothing else
+/// touches the semaphore: no other handles, no raw pointers and no other processes.
+pub(crate) struct Shadow(Option<Box<AtomicI64>>);
+
+/// For the guards of semaphores without a shadow.
+pub(crate) static UNTRACKED: Shadow = Shadow(None);
+
+impl Shadow {
+    pub(crate) const fn untracked() -> Self {
+        Shadow(None)
+    }
+
+    /// Starts counting from the value.
+    pub(crate) fn tracking(value: u32) -> Self {
+        Shadow(Some(Box::new(AtomicI64::new(i64::from(value)))))
+    }
+
+    /// Counts only on the platforms that need it.
+    pub(crate) fn automatic(value: u32) -> Self {
+        if GETVALUE_BROKEN {
+            Self::tracking(value)
+        } else {
+            Self::untracked()
+        }
+    }
+
+    pub(crate) fn took(&self, n: u32) {
+        if let Some(ref value) = self.0 {
+            value.fetch_sub(i64::from(n), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn posted(&self, n: u32) {
+        if let Some(ref value) = self.0 {
+            value.fetch_add(i64::from(n), Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn set(&self, n: u32) {
+        if let Some(ref value) = self.0 {
+            value.store(i64::from(n), Ordering::Relaxed);
+        }
+    }
+
+    /// The counted value, if counting.
+    pub(crate) fn get(&self) -> Option<u32> {
+        self.0.as_ref().map(|value| value.load(Ordering::Relaxed).max(0) as u32)
+    }
+}
@@ -0,0 +1,162 @@
+//! Process-shared semaphores living in POSIX shared memory objects.
+
+use std::convert::TryInto;
+use std::io::Error;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Instant;
+
+use libc::{self, mode_t};
+
+use mapped::{self, INIT_TIMEOUT};
+use named::{sem_name, NameError, SemName};
+use Semaphore;
+
+/// A process-shared semaphore inside a shared memory object created by `shm_open`.
+///
+/// This is an alternative to [`NamedSemaphore`][::NamedSemaphore] for unrelated processes. The
+/// names follow the same rules as with named semaphores (though they live in a different
+/// namespace). It dereferences to [`Semaphore`] for all the waiting and posting.
+///
+/// Dropping it only unmaps the semaphore, the object stays in the system until
+/// [unlinked][ShmSemaphore::unlink].
+#[derive(Debug)]
+pub struct ShmSemaphore {
+    sem: Semaphore,
+    name: SemName,
+    _fd: OwnedFd,
+}
+
+fn shm_open(name: &SemName, oflag: libc::c_int, mode: mode_t) -> Result<OwnedFd, Error> {
+    match unsafe { libc::shm_open(name.as_c_str().as_ptr(), oflag, mode) } {
+        -1 => Err(Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+impl ShmSemaphore {
+    /// Creates the shared memory object and a semaphore inside it.
+    ///
+    /// Fails with [`ErrorKind::AlreadyExists`][std::io::ErrorKind::AlreadyExists] if the object
+    /// already exists.
+    pub fn create<N>(name: N, mode: mode_t, value: u32) -> Result<Self, Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        let name = sem_name(name)?;
+        let fd = shm_open(&name, libc::O_RDWR | libc::O_CREAT | libc::O_EXCL, mode)?;
+        match mapped::create(fd.as_raw_fd(), value) {
+            Ok(region) => Ok(ShmSemaphore {
+                sem: unsafe { mapped::semaphore(region) },
+                name,
+                _fd: fd,
+            }),
+            Err(e) => {
+                unsafe { libc::shm_unlink(name.as_c_str().as_ptr()) };
+                Err(e)
+            },
+        }
+    }
+
+    /// Opens a semaphore created by [`create`][ShmSemaphore::create], possibly in another process.
+    ///
+    /// If the creator hasn't finished initializing the semaphore yet, this waits for it (but
+    /// gives up with [`ErrorKind::TimedOut`][std::io::ErrorKind::TimedOut] after a few
+    /// seconds).
+    pub fn open<N>(name: N) -> Result<Self, Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        let name = sem_name(name)?;
+        let fd = shm_open(&name, libc::O_RDWR, 0)?;
+        let region = mapped::attach(fd.as_raw_fd(), Instant::now() + INIT_TIMEOUT)?;
+        Ok(ShmSemaphore {
+            sem: unsafe { mapped::semaphore(region) },
+            name,
+            _fd: fd,
+        })
+    }
+
+    /// Removes the shared memory object from the system.
+    ///
+    /// Semaphores already opened stay valid.
+    pub fn unlink<N>(name: N) -> Result<(), Error>
+    where
+        N: TryInto<SemName>,
+        N::Error: Into<NameError>,
+    {
+        let name = sem_name(name)?;
+        match unsafe { libc::shm_unlink(name.as_c_str().as_ptr()) } {
+            0 => Ok(()),
+            -1 => Err(Error::last_os_error()),
+            other => unreachable!("shm_unlink doesn't return value {}", other),
+        }
+    }
+
+    /// The name of the shared memory object.
+    pub fn name(&self) -> &SemName {
+        &self.name
+    }
+}
+
+impl Deref for ShmSemaphore {
+    type Target = Semaphore;
+    fn deref(&self) -> &Semaphore {
+        &self.sem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+
+    use super::*;
+    use test_util::{child, child_arg, unique_name};
+
+    const ROUNDS: usize = 10;
+
+    #[test]
+    fn child_ping_pong() {
+        if let Some(name) = child_arg() {
+            let ping = ShmSemaphore::open(format!("{}-ping", name)).unwrap();
+            let pong = ShmSemaphore::open(format!("{}-pong", name)).unwrap();
+            for _ in 0..ROUNDS {
+                ping.wait();
+                pong.post().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn ping_pong() {
+        let name = unique_name("shm");
+        let ping_name = format!("{}-ping", name);
+        let pong_name = format!("{}-pong", name);
+        let ping = ShmSemaphore::create(ping_name.as_str(), 0o600, 0).unwrap();
+        let pong = ShmSemaphore::create(pong_name.as_str(), 0o600, 0).unwrap();
+        let mut child = child("shm::tests::child_ping_pong", &name).spawn().unwrap();
+        for _ in 0..ROUNDS {
+            ping.post().unwrap();
+            pong.wait();
+        }
+        assert!(child.wait().unwrap().success());
+        ShmSemaphore::unlink(ping.name()).unwrap();
+        ShmSemaphore::unlink(pong.name()).unwrap();
+    }
+
+    #[test]
+    fn create_open() {
+        let name = unique_name("shm-open");
+        let sem = ShmSemaphore::create(name.as_str(), 0o600, 1).unwrap();
+        let err = ShmSemaphore::create(name.as_str(), 0o600, 1).err().unwrap();
+        assert_eq!(ErrorKind::AlreadyExists, err.kind());
+        let other = ShmSemaphore::open(name.as_str()).unwrap();
+        other.wait();
+        assert_eq!(0, sem.value());
+        ShmSemaphore::unlink(name.as_str()).unwrap();
+        let err = ShmSemaphore::open(name.as_str()).err().unwrap();
+        assert_eq!(ErrorKind::NotFound, err.kind());
+    }
+}
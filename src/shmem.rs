@@ -0,0 +1,73 @@
+//! Integration with segments of the `shared_memory` crate.
+
+use std::io::{Error, ErrorKind};
+use std::ptr::NonNull;
+
+use shared_memory::Shmem;
+
+use mapped::{SharedRegion, INIT_TIMEOUT};
+use {BorrowedSemaphore, Semaphore};
+
+fn region(shmem: &Shmem, offset: usize) -> Result<&SharedRegion, Error> {
+    let layout = SharedRegion::LAYOUT;
+    if !offset.is_multiple_of(layout.align()) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Misaligned semaphore offset"));
+    }
+    match offset.checked_add(layout.size()) {
+        Some(end) if end <= shmem.len() => (),
+        _ => return Err(Error::new(ErrorKind::InvalidInput, "Semaphore doesn't fit the segment")),
+    }
+    unsafe {
+        let ptr = NonNull::new(shmem.as_ptr().add(offset)).expect("Shmem at NULL");
+        Ok(SharedRegion::from_ptr(ptr.cast()))
+    }
+}
+
+impl Semaphore {
+    /// Places a process-shared semaphore into a `shared_memory` segment.
+    ///
+    /// The semaphore takes [`SharedRegion::LAYOUT`] bytes at `offset`, which must be aligned
+    /// accordingly. The memory is expected to be zeroed, as a fresh segment is. Other processes
+    /// use [`attach_in_shmem`][Semaphore::attach_in_shmem] with the same offset.
+    ///
+    /// The semaphore is not destroyed on drop, as other processes may still use it.
+    ///
+    /// Requires the `shared-memory` feature.
+    pub fn create_in_shmem(shmem: &Shmem, offset: usize, value: u32)
+        -> Result<BorrowedSemaphore<'_>, Error>
+    {
+        let (sem, _) = region(shmem, offset)?.init_once(value, INIT_TIMEOUT)?;
+        Ok(unsafe { BorrowedSemaphore::foreign(NonNull::new_unchecked(sem.as_ptr())) })
+    }
+
+    /// Attaches to a semaphore placed by [`create_in_shmem`][Semaphore::create_in_shmem].
+    ///
+    /// If the creator hasn't finished initializing the semaphore yet, this waits for it (but
+    /// gives up with [`ErrorKind::TimedOut`] after a few seconds).
+    ///
+    /// Requires the `shared-memory` feature.
+    pub fn attach_in_shmem(shmem: &Shmem, offset: usize) -> Result<BorrowedSemaphore<'_>, Error> {
+        let sem = region(shmem, offset)?.wait_ready(INIT_TIMEOUT)?;
+        Ok(unsafe { BorrowedSemaphore::foreign(NonNull::new_unchecked(sem.as_ptr())) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared_memory::ShmemConf;
+
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let shmem = ShmemConf::new().size(128).create().unwrap();
+        let err = Semaphore::create_in_shmem(&shmem, 1, 0).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        let err = Semaphore::create_in_shmem(&shmem, 128, 0).err().unwrap();
+        assert_eq!(ErrorKind::InvalidInput, err.kind());
+        let sem = Semaphore::create_in_shmem(&shmem, 64, 1).unwrap();
+        let other = Semaphore::attach_in_shmem(&shmem, 64).unwrap();
+        other.wait();
+        assert_eq!(0, sem.value());
+    }
+}
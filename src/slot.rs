@@ -0,0 +1,1037 @@
+//! The in-memory semaphore cell and the basic operations on it.
+
+use std::cell::UnsafeCell;
+use std::io::{Error, ErrorKind};
+use std::mem;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use libc::{self, c_int, sem_t};
+
+use backend::Backend;
+use clock::{self, Clock, ClockId, SystemClock};
+use waiters::{self, Waiting};
+use {Cancelled, Interrupted, NoToken, Overflow, SemError, WaitAborted, WaitError};
+
+/// How often [`wait_cancellable`][SemaphoreSlot::wait_cancellable] checks the flag.
+pub const CANCEL_GRANULARITY: Duration = Duration::from_millis(20);
+
+/// What to do when a wait is interrupted by a signal.
+///
+/// The plain waits always restart, the `_with` variants take the policy as a parameter.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub enum Restart {
+    /// Restart every time. This is the default.
+    #[default]
+    Always,
+    /// Return [`WaitError::Interrupted`] on the first signal.
+    Never,
+    /// Restart at most this many times, then return [`WaitError::Interrupted`].
+    MaxRetries(u32),
+    /// Restart as long as the deadline didn't pass.
+    ///
+    /// Restarted waits keep the original deadline, so for the timed waits this is the same as
+    /// `Always`. Waits without a deadline restart every time.
+    UntilDeadline,
+}
+
+/// An initialized `sem_t`, living wherever the memory is.
+///
+/// This is what [`Semaphore`][::Semaphore] and friends manage the memory and lifetime of. It
+/// provides the basic operations, but is only ever handed out by reference (eg. by
+/// [`SemaphoreArray::get`][::SemaphoreArray::get]).
+#[repr(transparent)]
+pub struct SemaphoreSlot(UnsafeCell<sem_t>);
+
+impl SemaphoreSlot {
+    /// Views an initialized semaphore as a slot.
+    pub(crate) unsafe fn from_ptr<'a>(ptr: *mut sem_t) -> &'a SemaphoreSlot {
+        &*(ptr as *const SemaphoreSlot)
+    }
+
+    /// A slot not initialized yet, to be set up by `sem_init` later.
+    pub(crate) const fn zeroed() -> Self {
+        SemaphoreSlot(UnsafeCell::new(unsafe { mem::zeroed() }))
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut sem_t {
+        self.0.get()
+    }
+
+    pub(crate) fn backend(&self) -> Backend<'_> {
+        Backend::Posix(self)
+    }
+
+    /// Waits for a token.
+    ///
+    /// # Panics
+    ///
+    /// On errors other than being interrupted, which would mean a corrupt semaphore. See
+    /// [`wait_checked`][SemaphoreSlot::wait_checked] for a variant that returns them.
+    pub fn wait(&self) {
+        self.backend().wait()
+    }
+
+    /// Like [`wait`][SemaphoreSlot::wait], but returns unexpected errors instead of panicking.
+    pub fn wait_checked(&self) -> Result<(), SemError> {
+        self.backend().wait_checked()
+    }
+
+    /// Waits for a token, but returns early if interrupted by a signal.
+    ///
+    /// This lets the caller check its own state (eg. a shutdown flag set by the signal handler)
+    /// before waiting again. If the token arrives first, it is taken like with
+    /// [`wait`][SemaphoreSlot::wait].
+    pub fn wait_interruptible(&self) -> Result<(), Interrupted> {
+        self.backend().wait_interruptible()
+    }
+
+    /// Takes a token if one is available right away.
+    ///
+    /// # Panics
+    ///
+    /// On unexpected errors, see [`trywait_checked`][SemaphoreSlot::trywait_checked].
+    pub fn trywait(&self) -> Result<(), NoToken> {
+        self.backend().trywait()
+    }
+
+    /// Like [`trywait`][SemaphoreSlot::trywait], but returns unexpected errors instead of
+    /// panicking.
+    ///
+    /// No token available is [`SemError::WouldBlock`].
+    pub fn trywait_checked(&self) -> Result<(), SemError> {
+        self.backend().trywait_checked()
+    }
+
+    pub fn timedwait(&self, until: SystemTime) -> Result<(), WaitError> {
+        self.backend().timedwait(until)
+    }
+
+    /// Like [`timedwait`][SemaphoreSlot::timedwait], but returns unexpected errors instead of
+    /// panicking.
+    pub fn timedwait_checked(&self, until: SystemTime) -> Result<(), SemError> {
+        self.backend().timedwait_checked(until)
+    }
+
+    /// Waits for a token until the absolute time, measured by the given clock.
+    ///
+    /// The realtime clock is always supported. Others need `sem_clockwait` and for the libc to
+    /// support waiting on that clock, [`WaitError::Unsupported`] is returned if it doesn't.
+    ///
+    /// # Panics
+    ///
+    /// If the nanoseconds of `abstime` are out of range.
+    pub fn timedwait_with_clock(&self, clock: ClockId, abstime: libc::timespec)
+        -> Result<(), WaitError>
+    {
+        self.backend().timedwait_with_clock(clock, abstime)
+    }
+
+    /// Waits for a token, but at most for the given time.
+    ///
+    /// A zero duration only tries, like [`trywait`][SemaphoreSlot::trywait]. The deadline is
+    /// computed once up front, so being interrupted by signals doesn't prolong the wait.
+    ///
+    /// On macOS, which has no `sem_timedwait`, this and the other timed waits poll for the
+    /// token. A token posted meanwhile or the deadline passing is then noticed up to about 5 ms
+    /// late.
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.backend().wait_timeout(timeout)
+    }
+
+    /// Like [`wait_timeout`][SemaphoreSlot::wait_timeout], but returns unexpected errors instead
+    /// of panicking.
+    pub fn wait_timeout_checked(&self, timeout: Duration) -> Result<(), SemError> {
+        self.backend().wait_timeout_checked(timeout)
+    }
+
+    /// Like [`wait_timeout`][SemaphoreSlot::wait_timeout], but computes the deadline from the
+    /// given clock.
+    ///
+    /// The waiting itself is still done by the OS against the real time, so a
+    /// `MockClock` lagging behind makes this time out right away, moving the clock forward by the
+    /// timeout.
+    pub fn wait_timeout_on<C: Clock + ?Sized>(&self, clock: &C, timeout: Duration)
+        -> Result<(), WaitError>
+    {
+        self.backend().wait_timeout_on(clock, timeout)
+    }
+
+    /// Like [`wait_timeout`][SemaphoreSlot::wait_timeout], but returns early with
+    /// [`WaitError::Interrupted`] if interrupted by a signal.
+    pub fn wait_timeout_interruptible(&self, timeout: Duration) -> Result<(), WaitError> {
+        self.backend().wait_timeout_interruptible(timeout)
+    }
+
+    /// Waits for a token until the deadline.
+    ///
+    /// Unlike [`timedwait`][SemaphoreSlot::timedwait], this is not affected by changes of the
+    /// system time. It uses `sem_clockwait` with the monotonic clock if the libc has it and falls
+    /// back to waiting in short slices otherwise.
+    pub fn wait_deadline(&self, deadline: Instant) -> Result<(), WaitError> {
+        self.backend().wait_deadline(deadline)
+    }
+
+    /// Like [`wait_deadline`][SemaphoreSlot::wait_deadline], but measures the deadline against
+    /// the given clock and computes the kernel deadline from it.
+    pub fn wait_deadline_on<C: Clock + ?Sized>(&self, clock: &C, deadline: Instant)
+        -> Result<(), WaitError>
+    {
+        self.backend().wait_deadline_on(clock, deadline)
+    }
+
+    /// The number of threads blocked on the semaphore right now.
+    ///
+    /// This counts the threads of this process waiting through this crate, and where the
+    /// platform reports them (see [`reported_waiters`][SemaphoreSlot::reported_waiters]), any
+    /// others. It may be higher than the real number (rarely, when other semaphores share the
+    /// counter).
+    pub fn waiters(&self) -> u32 {
+        self.backend().waiters()
+    }
+
+    /// Waits for a token until it's available or the flag gets set.
+    ///
+    /// The flag is checked every [`CANCEL_GRANULARITY`], so setting it wakes the waiter up
+    /// within that time. No token is consumed once the flag is set.
+    pub fn wait_cancellable(&self, cancel: &AtomicBool) -> Result<(), Cancelled> {
+        self.backend().wait_cancellable(cancel)
+    }
+
+    /// Like [`wait_cancellable`][SemaphoreSlot::wait_cancellable], but checks the flag with the
+    /// given granularity.
+    ///
+    /// # Panics
+    ///
+    /// If the granularity is zero.
+    pub fn wait_cancellable_every(&self, cancel: &AtomicBool, granularity: Duration)
+        -> Result<(), Cancelled>
+    {
+        self.backend().wait_cancellable_every(cancel, granularity)
+    }
+
+    /// Waits for a token, calling the callback every `interval` while blocked.
+    ///
+    /// If the callback returns [`ControlFlow::Break`], the wait is aborted without taking a
+    /// token. The callback is never called once the token is taken.
+    ///
+    /// # Panics
+    ///
+    /// If the interval is zero.
+    pub fn wait_with_tick<F>(&self, interval: Duration, on_tick: F) -> Result<(), WaitAborted>
+    where
+        F: FnMut() -> ControlFlow<()>,
+    {
+        self.backend().wait_with_tick(interval, on_tick)
+    }
+
+    /// Polls the value until the predicate holds for it, without taking any tokens.
+    ///
+    /// Returns the value the predicate accepted. This is inherently racy: the value may change
+    /// right after it is read (even before the predicate is called). It fits cases where the
+    /// value only moves one way, like counting check-ins nobody consumes. Changes shorter than
+    /// the `poll` interval may be missed.
+    ///
+    /// With a timeout (`None` waits forever), gives up with [`WaitError::TimedOut`] once it
+    /// passes. Fails with [`WaitError::Unsupported`] if the value can't be read on this platform.
+    ///
+    /// # Panics
+    ///
+    /// If the poll interval is zero.
+    pub fn wait_for_value<P>(&self, pred: P, poll: Duration, timeout: Option<Duration>)
+        -> Result<u32, WaitError>
+    where
+        P: Fn(u32) -> bool,
+    {
+        poll_value(|| self.try_value(), pred, poll, timeout)
+    }
+
+    /// Like [`wait`][SemaphoreSlot::wait], with a policy for restarting after signals.
+    ///
+    /// Returns [`WaitError::Interrupted`] once the policy gives up.
+    pub fn wait_with(&self, restart: Restart) -> Result<(), WaitError> {
+        self.backend().wait_with(restart)
+    }
+
+    /// Like [`trywait`][SemaphoreSlot::trywait], with a policy for restarting after signals.
+    ///
+    /// Returns [`WaitError::WouldBlock`] if there's no token.
+    pub fn trywait_with(&self, restart: Restart) -> Result<(), WaitError> {
+        self.backend().trywait_with(restart)
+    }
+
+    /// Like [`timedwait`][SemaphoreSlot::timedwait], with a policy for restarting after signals.
+    ///
+    /// The deadline is absolute, so restarts don't prolong the wait.
+    pub fn timedwait_with(&self, until: SystemTime, restart: Restart) -> Result<(), WaitError> {
+        self.backend().timedwait_with(until, restart)
+    }
+
+    /// Like [`wait_timeout`][SemaphoreSlot::wait_timeout], with a policy for restarting after
+    /// signals.
+    ///
+    /// The deadline is computed once up front, so restarts don't prolong the wait.
+    pub fn wait_timeout_with(&self, timeout: Duration, restart: Restart)
+        -> Result<(), WaitError>
+    {
+        self.backend().wait_timeout_with(timeout, restart)
+    }
+
+    /// Returns a token to the semaphore.
+    ///
+    /// # Panics
+    ///
+    /// On errors other than overflow, see [`post_checked`][SemaphoreSlot::post_checked].
+    pub fn post(&self) -> Result<(), Overflow> {
+        self.backend().post()
+    }
+
+    /// Like [`post`][SemaphoreSlot::post], but returns unexpected errors instead of panicking.
+    pub fn post_checked(&self) -> Result<(), SemError> {
+        self.backend().post_checked()
+    }
+
+    /// The current value of the semaphore, the number of tokens available.
+    ///
+    /// Never negative, even on platforms where `sem_getvalue` reports the waiters that way (see
+    /// [`raw_value`][SemaphoreSlot::raw_value] and
+    /// [`reported_waiters`][SemaphoreSlot::reported_waiters]).
+    ///
+    /// # Panics
+    ///
+    /// If the value can't be read, see [`try_value`][SemaphoreSlot::try_value].
+    pub fn value(&self) -> c_int {
+        self.backend().value()
+    }
+
+    /// The number of tokens available right now.
+    ///
+    /// Fails if the platform can't read the value (`sem_getvalue` gives `ENOSYS` on macOS) or
+    /// the semaphore is broken.
+    pub fn try_value(&self) -> Result<u32, Error> {
+        self.backend().try_value()
+    }
+
+    /// The value as reported by `sem_getvalue`.
+    ///
+    /// POSIX allows a negative value when there are threads waiting, its magnitude being the
+    /// number of the waiters. Linux always reports 0 instead.
+    pub fn raw_value(&self) -> Result<c_int, Error> {
+        self.backend().raw_value()
+    }
+}
+
+impl<'a> Backend<'a> {
+    pub(crate) fn wait(self) {
+        if let Err(e) = self.wait_checked() {
+            panic!("Semaphore wait failed: {}", e);
+        }
+    }
+
+    pub(crate) fn wait_checked(self) -> Result<(), SemError> {
+        // Not counted as a waiter if there's a token right away
+        match self.trywait_checked() {
+            Err(SemError::WouldBlock) => self.blocking(|| checked(|| self.sem_wait())),
+            result => result,
+        }
+    }
+
+    pub(crate) fn wait_interruptible(self) -> Result<(), Interrupted> {
+        match self.blocking(|| wait_once(|| self.sem_wait())) {
+            Ok(()) => Ok(()),
+            Err(WaitError::Interrupted) => Err(Interrupted),
+            Err(e) => unreachable!("Impossible error {}", e),
+        }
+    }
+
+    pub(crate) fn trywait(self) -> Result<(), NoToken> {
+        match self.trywait_checked() {
+            Ok(()) => Ok(()),
+            Err(SemError::WouldBlock) => Err(NoToken::from_errno(libc::EAGAIN)),
+            Err(e) => panic!("Semaphore trywait failed: {}", e),
+        }
+    }
+
+    pub(crate) fn trywait_checked(self) -> Result<(), SemError> {
+        checked(|| self.sem_trywait())
+    }
+
+    pub(crate) fn timedwait(self, until: SystemTime) -> Result<(), WaitError> {
+        match until.duration_since(UNIX_EPOCH) {
+            Ok(dur) => self.timedwait_with_clock(ClockId::Realtime, clock::from_epoch(dur)),
+            // Long past, so only try
+            Err(_) => self.try_expired(),
+        }
+    }
+
+    pub(crate) fn timedwait_checked(self, until: SystemTime) -> Result<(), SemError> {
+        match until.duration_since(UNIX_EPOCH) {
+            Ok(dur) => {
+                let timespec = clock::from_epoch(dur);
+                self.blocking(|| checked(|| self.sem_timedwait(&timespec)))
+            },
+            Err(_) => self.trywait_checked().map_err(expired),
+        }
+    }
+
+    pub(crate) fn timedwait_with_clock(self, clock: ClockId, abstime: libc::timespec)
+        -> Result<(), WaitError>
+    {
+        assert!(abstime.tv_nsec >= 0 && abstime.tv_nsec < 1_000_000_000, "Invalid timespec");
+        match (clock::sem_clockwait(), clock) {
+            (Some(clockwait), clock) => {
+                self.wait_loop(|| self.sem_clockwait(clockwait, clock.raw(), &abstime))
+            },
+            (None, ClockId::Realtime) => self.timedwait_raw(&abstime),
+            (None, _) => Err(WaitError::Unsupported),
+        }
+    }
+
+    pub(crate) fn wait_timeout(self, timeout: Duration) -> Result<(), WaitError> {
+        self.wait_timeout_on(&SystemClock, timeout)
+    }
+
+    pub(crate) fn wait_timeout_checked(self, timeout: Duration) -> Result<(), SemError> {
+        if timeout == Duration::from_secs(0) {
+            return self.trywait_checked().map_err(expired);
+        }
+        let timespec = SystemClock.realtime_after(timeout);
+        self.blocking(|| checked(|| self.sem_timedwait(&timespec)))
+    }
+
+    pub(crate) fn wait_timeout_on<C: Clock + ?Sized>(self, clock: &C, timeout: Duration)
+        -> Result<(), WaitError>
+    {
+        if timeout == Duration::from_secs(0) {
+            return self.try_expired();
+        }
+        let result = self.timedwait_raw(&clock.realtime_after(timeout));
+        if result == Err(WaitError::TimedOut) {
+            clock.timed_out(timeout);
+        }
+        result
+    }
+
+    pub(crate) fn wait_timeout_interruptible(self, timeout: Duration) -> Result<(), WaitError> {
+        if timeout == Duration::from_secs(0) {
+            return self.try_expired();
+        }
+        let timespec = SystemClock.realtime_after(timeout);
+        self.blocking(|| wait_once(|| self.sem_timedwait(&timespec)))
+    }
+
+    pub(crate) fn wait_deadline(self, deadline: Instant) -> Result<(), WaitError> {
+        self.wait_deadline_on(&SystemClock, deadline)
+    }
+
+    pub(crate) fn wait_deadline_on<C: Clock + ?Sized>(self, clock: &C, deadline: Instant)
+        -> Result<(), WaitError>
+    {
+        let remaining = deadline.saturating_duration_since(clock.now());
+        if let Some(clockwait) = clock::sem_clockwait() {
+            let timespec = clock.monotonic_after(remaining);
+            let id = libc::CLOCK_MONOTONIC;
+            match self.wait_loop(|| self.sem_clockwait(clockwait, id, &timespec)) {
+                // The backend can't wait on the monotonic clock, slice it below
+                Err(WaitError::Unsupported) => (),
+                Err(WaitError::TimedOut) => {
+                    clock.timed_out(remaining);
+                    return Err(WaitError::TimedOut);
+                },
+                result => return result,
+            }
+        }
+        let mut remaining = remaining;
+        loop {
+            let slice = remaining.min(clock::FALLBACK_SLICE);
+            match self.wait_timeout_on(clock, slice) {
+                Err(WaitError::TimedOut) if slice < remaining => (),
+                result => return result,
+            }
+            remaining = deadline.saturating_duration_since(clock.now());
+        }
+    }
+
+    /// The deadline already passed, but a token may still be available right away.
+    fn try_expired(self) -> Result<(), WaitError> {
+        self.trywait().map_err(|_| WaitError::TimedOut)
+    }
+
+    fn timedwait_raw(self, timespec: &libc::timespec) -> Result<(), WaitError> {
+        self.wait_loop(|| self.sem_timedwait(timespec))
+    }
+
+    /// Runs one of the timed waits, retrying on interruption.
+    fn wait_loop<F: Fn() -> Result<(), c_int>>(self, wait: F) -> Result<(), WaitError> {
+        self.blocking(|| restarting(Restart::Always, wait))
+    }
+
+    /// Runs a blocking wait, counting the thread among the waiters meanwhile.
+    fn blocking<R, F: FnOnce() -> R>(self, wait: F) -> R {
+        let _waiting = Waiting::new(self.address());
+        wait()
+    }
+
+    pub(crate) fn waiters(self) -> u32 {
+        let reported = self.reported_waiters().ok().flatten().unwrap_or(0);
+        waiters::count(self.address()).max(reported)
+    }
+
+    pub(crate) fn wait_cancellable(self, cancel: &AtomicBool) -> Result<(), Cancelled> {
+        self.wait_cancellable_every(cancel, CANCEL_GRANULARITY)
+    }
+
+    pub(crate) fn wait_cancellable_every(self, cancel: &AtomicBool, granularity: Duration)
+        -> Result<(), Cancelled>
+    {
+        assert!(granularity > Duration::from_secs(0), "Zero cancellation granularity");
+        loop {
+            if cancel.load(Ordering::Acquire) {
+                return Err(Cancelled);
+            }
+            let deadline = match Instant::now().checked_add(granularity) {
+                Some(deadline) => deadline,
+                // The flag would never be checked again anyway
+                None => {
+                    self.wait();
+                    return Ok(());
+                },
+            };
+            match self.wait_deadline(deadline) {
+                Ok(()) => return Ok(()),
+                Err(WaitError::TimedOut) => (),
+                Err(e) => unreachable!("Impossible error {}", e),
+            }
+        }
+    }
+
+    pub(crate) fn wait_with_tick<F>(self, interval: Duration, mut on_tick: F)
+        -> Result<(), WaitAborted>
+    where
+        F: FnMut() -> ControlFlow<()>,
+    {
+        assert!(interval > Duration::from_secs(0), "Zero tick interval");
+        loop {
+            let deadline = match Instant::now().checked_add(interval) {
+                Some(deadline) => deadline,
+                // The first tick would never come
+                None => {
+                    self.wait();
+                    return Ok(());
+                },
+            };
+            match self.wait_deadline(deadline) {
+                Ok(()) => return Ok(()),
+                Err(WaitError::TimedOut) => (),
+                Err(e) => unreachable!("Impossible error {}", e),
+            }
+            if on_tick().is_break() {
+                return Err(WaitAborted);
+            }
+        }
+    }
+
+    pub(crate) fn wait_with(self, restart: Restart) -> Result<(), WaitError> {
+        self.blocking(|| restarting(restart, || self.sem_wait()))
+    }
+
+    pub(crate) fn trywait_with(self, restart: Restart) -> Result<(), WaitError> {
+        restarting(restart, || self.sem_trywait())
+    }
+
+    pub(crate) fn timedwait_with(self, until: SystemTime, restart: Restart)
+        -> Result<(), WaitError>
+    {
+        let dur = match until.duration_since(UNIX_EPOCH) {
+            Ok(dur) => dur,
+            Err(_) => return self.try_expired_with(restart),
+        };
+        let timespec = clock::from_epoch(dur);
+        self.blocking(|| restarting(restart, || self.sem_timedwait(&timespec)))
+    }
+
+    pub(crate) fn wait_timeout_with(self, timeout: Duration, restart: Restart)
+        -> Result<(), WaitError>
+    {
+        if timeout == Duration::from_secs(0) {
+            return self.try_expired_with(restart);
+        }
+        let timespec = SystemClock.realtime_after(timeout);
+        self.blocking(|| restarting(restart, || self.sem_timedwait(&timespec)))
+    }
+
+    fn try_expired_with(self, restart: Restart) -> Result<(), WaitError> {
+        match self.trywait_with(restart) {
+            Err(WaitError::WouldBlock) => Err(WaitError::TimedOut),
+            result => result,
+        }
+    }
+
+    pub(crate) fn post(self) -> Result<(), Overflow> {
+        match self.post_checked() {
+            Ok(()) => Ok(()),
+            Err(SemError::Overflow) => Err(Overflow::from_errno(libc::EOVERFLOW)),
+            Err(e) => panic!("Semaphore post failed: {}", e),
+        }
+    }
+
+    pub(crate) fn post_checked(self) -> Result<(), SemError> {
+        checked(|| self.sem_post())
+    }
+
+    pub(crate) fn value(self) -> c_int {
+        let value = self.raw_value();
+        value.unwrap_or_else(|e| panic!("Failed to read semaphore value: {}", e)).max(0)
+    }
+
+    pub(crate) fn try_value(self) -> Result<u32, Error> {
+        self.raw_value().map(|val| val.max(0) as u32)
+    }
+
+    pub(crate) fn raw_value(self) -> Result<c_int, Error> {
+        self.sem_getvalue().map_err(Error::from_raw_os_error)
+    }
+}
+
+/// Polls the value until the predicate holds for it, see
+/// [`wait_for_value`][SemaphoreSlot::wait_for_value].
+pub(crate) fn poll_value<V, P>(value: V, pred: P, poll: Duration, timeout: Option<Duration>)
+    -> Result<u32, WaitError>
+where
+    V: Fn() -> Result<u32, Error>,
+    P: Fn(u32) -> bool,
+{
+    assert!(poll > Duration::from_secs(0), "Zero poll interval");
+    let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+    loop {
+        let value = value().map_err(|_| WaitError::Unsupported)?;
+        if pred(value) {
+            return Ok(value);
+        }
+        let mut sleep = poll;
+        if let Some(deadline) = deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(WaitError::TimedOut);
+            }
+            sleep = sleep.min(remaining);
+        }
+        thread::sleep(sleep);
+    }
+}
+
+/// Runs the operation, restarting it after signals and translating the errors.
+fn checked<F: Fn() -> Result<(), c_int>>(op: F) -> Result<(), SemError> {
+    loop {
+        match op() {
+            Ok(()) => return Ok(()),
+            Err(libc::EINTR) => (),
+            Err(errno) => return Err(SemError::from_errno(errno)),
+        }
+    }
+}
+
+/// The deadline passed, so not getting a token right away means timing out.
+fn expired(e: SemError) -> SemError {
+    match e {
+        SemError::WouldBlock => SemError::TimedOut,
+        e => e,
+    }
+}
+
+/// Runs the wait, restarting it after signals as the policy says.
+fn restarting<F: Fn() -> Result<(), c_int>>(restart: Restart, wait: F) -> Result<(), WaitError> {
+    let mut retries = 0;
+    loop {
+        match wait_once(&wait) {
+            Err(WaitError::Interrupted) => match restart {
+                Restart::Always | Restart::UntilDeadline => (),
+                Restart::MaxRetries(max) if retries < max => retries += 1,
+                Restart::Never | Restart::MaxRetries(_) => return Err(WaitError::Interrupted),
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Runs one of the waits, translating the errors.
+fn wait_once<F: Fn() -> Result<(), c_int>>(wait: F) -> Result<(), WaitError> {
+    let e = match wait() {
+        Ok(()) => return Ok(()),
+        Err(errno) => Error::from_raw_os_error(errno),
+    };
+    match e.kind() {
+        ErrorKind::Interrupted => Err(WaitError::Interrupted),
+        ErrorKind::WouldBlock => Err(WaitError::WouldBlock),
+        ErrorKind::TimedOut => Err(WaitError::TimedOut),
+        // The clock is not supported by sem_clockwait
+        ErrorKind::InvalidInput => Err(WaitError::Unsupported),
+        _ => unreachable!("Impossible error {}", e),
+    }
+}
+
+unsafe impl Send for SemaphoreSlot {}
+unsafe impl Sync for SemaphoreSlot {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use super::*;
+    use clock::MockClock;
+    use test_util::interrupt;
+    use {Builder, Semaphore};
+
+    #[test]
+    fn interruptible_token() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        sem.wait_interruptible().unwrap();
+        sem.post().unwrap();
+        sem.wait_timeout_interruptible(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn interruptible_signal() {
+        // GCD waits are never interrupted, this needs a sem_t
+        let sem = Semaphore::anonymous_posix(0).unwrap();
+        let result = interrupt(|| sem.wait_interruptible());
+        assert_eq!(Err(Interrupted), result);
+        let result = interrupt(|| sem.wait_timeout_interruptible(Duration::from_secs(60)));
+        assert_eq!(Err(WaitError::Interrupted), result);
+    }
+
+    #[test]
+    fn restart_policies() {
+        let sem = Semaphore::anonymous_posix(0).unwrap();
+        let result = interrupt(|| sem.wait_with(Restart::Never));
+        assert_eq!(Err(WaitError::Interrupted), result);
+        let result = interrupt(|| sem.wait_with(Restart::MaxRetries(2)));
+        assert_eq!(Err(WaitError::Interrupted), result);
+        assert_eq!(Err(WaitError::WouldBlock), sem.trywait_with(Restart::Never));
+        sem.post().unwrap();
+        sem.trywait_with(Restart::Never).unwrap();
+        sem.post().unwrap();
+        sem.wait_with(Restart::default()).unwrap();
+    }
+
+    #[test]
+    fn restart_timed() {
+        let sem = Semaphore::anonymous_posix(0).unwrap();
+        let start = Instant::now();
+        let result = interrupt(|| {
+            sem.wait_timeout_with(Duration::from_millis(100), Restart::UntilDeadline)
+        });
+        assert_eq!(Err(WaitError::TimedOut), result);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_secs(2), "Took too long: {:?}", elapsed);
+        let far = SystemTime::now() + Duration::from_secs(60);
+        let result = interrupt(|| sem.timedwait_with(far, Restart::MaxRetries(1)));
+        assert_eq!(Err(WaitError::Interrupted), result);
+        let past = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait_with(past, Restart::Never));
+    }
+
+    #[test]
+    fn cancel_blocked() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let cancel = AtomicBool::new(false);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| sem.wait_cancellable(&cancel));
+            thread::sleep(Duration::from_millis(50));
+            let start = Instant::now();
+            cancel.store(true, Ordering::Release);
+            assert_eq!(Err(Cancelled), waiter.join().unwrap());
+            assert!(start.elapsed() < Duration::from_secs(1));
+        });
+    }
+
+    #[test]
+    fn cancel_after_token() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let cancel = AtomicBool::new(false);
+        let granularity = Duration::from_millis(1);
+        sem.wait_cancellable_every(&cancel, granularity).unwrap();
+        sem.post().unwrap();
+        cancel.store(true, Ordering::Release);
+        assert_eq!(Err(Cancelled), sem.wait_cancellable_every(&cancel, granularity));
+        // Still there
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn cancel_huge_granularity() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let cancel = AtomicBool::new(false);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                sem.post().unwrap();
+            });
+            sem.wait_cancellable_every(&cancel, Duration::MAX).unwrap();
+        });
+    }
+
+    #[test]
+    fn tick_counts() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let interval = Duration::from_millis(10);
+        let mut ticks = 0;
+        let start = Instant::now();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                sem.post().unwrap();
+            });
+            sem.wait_with_tick(interval, || {
+                ticks += 1;
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        });
+        let expected = (start.elapsed().as_millis() / interval.as_millis()) as i32;
+        assert!((ticks - expected).abs() <= 3, "{} ticks, expected {}", ticks, expected);
+    }
+
+    #[test]
+    fn tick_abort() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let mut ticks = 0;
+        let result = sem.wait_with_tick(Duration::from_millis(1), || {
+            ticks += 1;
+            if ticks == 3 {
+                sem.post().unwrap();
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(Err(WaitAborted), result);
+        assert_eq!(3, ticks);
+        // The token posted meanwhile is left alone
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    #[should_panic]
+    fn tick_zero() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let _ = sem.wait_with_tick(Duration::from_secs(0), || ControlFlow::Continue(()));
+    }
+
+    #[test]
+    fn tick_huge() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        sem.wait_with_tick(Duration::MAX, || panic!("Ticked")).unwrap();
+    }
+
+    #[test]
+    fn wait_for_value() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let poll = Duration::from_millis(1);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..5 {
+                    thread::sleep(Duration::from_millis(5));
+                    sem.post().unwrap();
+                }
+            });
+            let timeout = Some(Duration::from_secs(10));
+            assert_eq!(Ok(5), sem.wait_for_value(|v| v >= 5, poll, timeout));
+        });
+        // Nothing taken
+        assert_eq!(5, sem.value());
+        let timeout = Some(Duration::from_millis(20));
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_for_value(|v| v > 5, poll, timeout));
+    }
+
+    #[test]
+    fn wait_for_value_unreadable() {
+        let poll = Duration::from_millis(1);
+        let unreadable = || Err(Error::new(ErrorKind::Unsupported, "No sem_getvalue"));
+        assert_eq!(Err(WaitError::Unsupported), poll_value(unreadable, |_| true, poll, None));
+        // A tracked value is good enough, even where the OS can't tell
+        let sem = Builder::new().value(2).track_value(true).build().unwrap();
+        let timeout = Some(Duration::from_secs(1));
+        assert_eq!(Ok(2), sem.wait_for_value(|v| v == 2, poll, timeout));
+    }
+
+    #[test]
+    #[should_panic]
+    fn wait_for_value_zero_poll() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let _ = sem.wait_for_value(|_| true, Duration::from_secs(0), None);
+    }
+
+    #[test]
+    fn mock_clock_behind() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let clock = MockClock::new();
+        clock.set(Duration::from_secs(3600), Duration::from_secs(0));
+        let origin = clock.now();
+        let start = Instant::now();
+        // The deadline is an hour and a second after the epoch, long past
+        let result = sem.wait_timeout_on(&clock, Duration::from_secs(1));
+        assert_eq!(Err(WaitError::TimedOut), result);
+        assert_eq!(Duration::from_secs(1), clock.now() - origin);
+        // Two seconds after boot, also past
+        let deadline = clock.now() + Duration::from_secs(1);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_deadline_on(&clock, deadline));
+        assert_eq!(deadline, clock.now());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        sem.post().unwrap();
+        sem.wait_timeout_on(&clock, Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn timedwait_before_epoch() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let past = UNIX_EPOCH - Duration::from_secs(10);
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait(past));
+        sem.post().unwrap();
+        sem.timedwait(past).unwrap();
+    }
+
+    #[test]
+    fn timedwait_far_future() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let far = SystemTime::now() + Duration::from_secs(u64::MAX / 4);
+        sem.timedwait(far).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                sem.post().unwrap();
+            });
+            // Would return right away if the deadline wrapped into the past
+            sem.timedwait(far).unwrap();
+        });
+    }
+
+    #[test]
+    fn checked_ops() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        assert_eq!(Err(SemError::WouldBlock), sem.trywait_checked());
+        assert_eq!(Err(SemError::TimedOut), sem.wait_timeout_checked(Duration::from_secs(0)));
+        let timeout = Duration::from_millis(10);
+        assert_eq!(Err(SemError::TimedOut), sem.wait_timeout_checked(timeout));
+        let past = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(Err(SemError::TimedOut), sem.timedwait_checked(past));
+        sem.post_checked().unwrap();
+        sem.post_checked().unwrap();
+        sem.post_checked().unwrap();
+        sem.wait_checked().unwrap();
+        sem.trywait_checked().unwrap();
+        sem.timedwait_checked(SystemTime::now() + timeout).unwrap();
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn values() {
+        let sem = Semaphore::anonymous(2).unwrap();
+        assert_eq!(2, sem.try_value().unwrap());
+        assert_eq!(2, sem.raw_value().unwrap());
+        sem.wait();
+        sem.wait();
+        assert_eq!(0, sem.try_value().unwrap());
+        assert_eq!(0, sem.value());
+    }
+
+    #[test]
+    fn timeout_elapses() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let clock = MockClock::new();
+        let start = clock.now();
+        let timeout = Duration::from_millis(100);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout_on(&clock, timeout));
+        assert_eq!(timeout, clock.now() - start);
+        let ts = clock.now_realtime();
+        assert_eq!((0, 100_000_000), (ts.tv_sec, ts.tv_nsec));
+    }
+
+    #[test]
+    fn timeout_acquires() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let clock = MockClock::new();
+        let zero = Duration::from_secs(0);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout_on(&clock, zero));
+        sem.post().unwrap();
+        sem.wait_timeout_on(&clock, zero).unwrap();
+        // A token available right away is taken even when the deadline is long past
+        sem.post().unwrap();
+        sem.wait_timeout_on(&clock, Duration::from_secs(1)).unwrap();
+        assert_eq!(0, clock.now_realtime().tv_sec);
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                sem.post().unwrap();
+            });
+            sem.wait_timeout(Duration::from_secs(10)).unwrap();
+        });
+    }
+
+    #[test]
+    fn timeout_huge() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                sem.post().unwrap();
+            });
+            sem.wait_timeout(Duration::MAX).unwrap();
+        });
+    }
+
+    #[test]
+    fn with_clock_monotonic() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let deadline = SystemClock.monotonic_after(Duration::from_millis(20));
+        sem.timedwait_with_clock(ClockId::Monotonic, deadline).unwrap();
+        let result = sem.timedwait_with_clock(ClockId::Monotonic, deadline);
+        assert_eq!(Err(WaitError::TimedOut), result);
+        let now = ClockId::Realtime.now();
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait_with_clock(ClockId::Realtime, now));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn with_clock_unsupported() {
+        let sem = Semaphore::anonymous(1).unwrap();
+        let deadline = ClockId::Boottime.now();
+        let result = sem.timedwait_with_clock(ClockId::Boottime, deadline);
+        assert_eq!(Err(WaitError::Unsupported), result);
+        // Not consumed
+        assert_eq!(1, sem.value());
+    }
+
+    #[test]
+    fn deadline_past() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let clock = MockClock::new();
+        let past = clock.now();
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_deadline_on(&clock, past));
+        // Nothing was left to wait for
+        assert_eq!(Duration::from_millis(1), clock.now() - past);
+        sem.post().unwrap();
+        sem.wait_deadline_on(&clock, past).unwrap();
+    }
+
+    #[test]
+    fn deadline_acquires() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(20));
+                sem.post().unwrap();
+            });
+            sem.wait_deadline(Instant::now() + Duration::from_secs(10)).unwrap();
+        });
+        let clock = MockClock::new();
+        let deadline = clock.now() + Duration::from_millis(50);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_deadline_on(&clock, deadline));
+        assert_eq!(deadline, clock.now());
+    }
+}
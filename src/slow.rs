@@ -0,0 +1,193 @@
+//! Reporting waits that take too long.
+
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::sync::Arc;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant, SystemTime};
+
+use {Kind, Semaphore, WaitError};
+
+/// A wait blocked for longer than the threshold of the slow-wait hook.
+///
+/// See [`set_slow_wait_hook`][Semaphore::set_slow_wait_hook].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SlowWait {
+    elapsed: Duration,
+    thread: ThreadId,
+    address: usize,
+    kind: Kind,
+}
+
+impl SlowWait {
+    /// How long the wait has been blocked so far.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// The waiting thread, which is also the one running the hook.
+    pub fn thread(&self) -> ThreadId {
+        self.thread
+    }
+
+    /// The address of the semaphore, telling it apart from others.
+    ///
+    /// The same as [`as_raw`][Semaphore::as_raw], for the semaphores that have a `sem_t`.
+    pub fn address(&self) -> usize {
+        self.address
+    }
+
+    /// What kind of semaphore it is.
+    pub fn kind(&self) -> Kind {
+        self.kind
+    }
+}
+
+pub(crate) struct SlowWaitHook {
+    threshold: Duration,
+    repeat: Option<Duration>,
+    hook: Arc<dyn Fn(SlowWait) + Send + Sync>,
+}
+
+impl SlowWaitHook {
+    pub(crate) fn new(
+        threshold: Duration,
+        repeat: Option<Duration>,
+        hook: Arc<dyn Fn(SlowWait) + Send + Sync>,
+    ) -> Self {
+        SlowWaitHook {
+            threshold,
+            repeat,
+            hook,
+        }
+    }
+
+    /// Waits until the deadline (or forever), in slices ending whenever the hook is due.
+    pub(crate) fn wait(&self, sem: &Semaphore, until: Option<SystemTime>)
+        -> Result<(), WaitError>
+    {
+        let backend = sem.backend();
+        let start = Instant::now();
+        let mut report = Some(self.threshold);
+        while let Some(due) = report {
+            let slice = match SystemTime::now().checked_add(due.saturating_sub(start.elapsed())) {
+                Some(slice) => slice,
+                // Never due, so just wait
+                None => break,
+            };
+            if until.is_some_and(|until| until <= slice) {
+                break;
+            }
+            match backend.timedwait(slice) {
+                Err(WaitError::TimedOut) => (),
+                result => return result,
+            }
+            let elapsed = start.elapsed();
+            (self.hook)(SlowWait {
+                elapsed,
+                thread: thread::current().id(),
+                address: backend.address() as usize,
+                kind: sem.kind(),
+            });
+            report = self.repeat.and_then(|repeat| elapsed.checked_add(repeat));
+        }
+        match until {
+            Some(until) => backend.timedwait(until),
+            None => {
+                backend.wait();
+                Ok(())
+            },
+        }
+    }
+}
+
+// The semaphore only ever calls the hook, a panic in it can't leave anything here broken. What
+// the hook itself touches is its business, like with any other callback.
+impl UnwindSafe for SlowWaitHook {}
+impl RefUnwindSafe for SlowWaitHook {}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    fn hooked(repeat: Option<Duration>) -> (Semaphore, Arc<Mutex<Vec<SlowWait>>>) {
+        let mut sem = Semaphore::anonymous(0).unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&reports);
+        let hook = move |slow| sink.lock().unwrap().push(slow);
+        sem.set_slow_wait_hook(Duration::from_millis(50), repeat, Arc::new(hook));
+        (sem, reports)
+    }
+
+    #[test]
+    fn stalled() {
+        let (sem, reports) = hooked(Some(Duration::from_millis(50)));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(200));
+                sem.post().unwrap();
+            });
+            sem.wait();
+        });
+        let reports = reports.lock().unwrap();
+        assert!(!reports.is_empty());
+        // Fires at 50, 100, 150, maybe 200 ms, but surely not more often than every 50 ms
+        assert!(reports.len() <= 4, "{:?}", reports);
+        assert!(reports[0].elapsed() >= Duration::from_millis(50));
+        for pair in reports.windows(2) {
+            // Some slack for the wall clock the slices use
+            assert!(pair[1].elapsed() - pair[0].elapsed() >= Duration::from_millis(40));
+        }
+        assert_eq!(thread::current().id(), reports[0].thread());
+        assert_eq!(sem.backend().address() as usize, reports[0].address());
+        assert_eq!(Kind::Anonymous, reports[0].kind());
+    }
+
+    #[test]
+    fn timed_out_once() {
+        let (sem, reports) = hooked(None);
+        let timeout = Duration::from_millis(200);
+        assert_eq!(Err(WaitError::TimedOut), sem.wait_timeout(timeout));
+        assert_eq!(1, reports.lock().unwrap().len());
+        let until = SystemTime::now() + Duration::from_millis(10);
+        assert_eq!(Err(WaitError::TimedOut), sem.timedwait(until));
+        assert_eq!(1, reports.lock().unwrap().len());
+    }
+
+    #[test]
+    fn huge_repeat() {
+        let (sem, reports) = hooked(Some(Duration::MAX));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(100));
+                sem.post().unwrap();
+            });
+            sem.wait();
+        });
+        assert_eq!(1, reports.lock().unwrap().len());
+    }
+
+    #[test]
+    fn huge_threshold() {
+        let mut sem = Semaphore::anonymous(0).unwrap();
+        sem.set_slow_wait_hook(Duration::MAX, None, Arc::new(|_| panic!("Reported")));
+        thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(Duration::from_millis(10));
+                sem.post().unwrap();
+            });
+            sem.wait();
+        });
+    }
+
+    #[test]
+    fn immediate() {
+        let (sem, reports) = hooked(Some(Duration::from_millis(50)));
+        sem.post().unwrap();
+        sem.wait();
+        sem.post().unwrap();
+        drop(sem.access_timeout(Duration::from_secs(1)).unwrap());
+        assert!(reports.lock().unwrap().is_empty());
+    }
+}
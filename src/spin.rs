@@ -0,0 +1,151 @@
+//! Spinning for a while before blocking.
+
+use std::hint;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::Backend;
+use SemaphoreSlot;
+
+/// What to do between the attempts when spinning.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Relax {
+    /// Busy-loop with a CPU hint ([`hint::spin_loop`]).
+    Spin,
+    /// Give up the CPU to other threads (`sched_yield`).
+    Yield,
+}
+
+/// How [`wait_spin`][SemaphoreSlot::wait_spin] spins before blocking.
+///
+/// The default is 100 attempts with the CPU hint in between and no time limit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SpinConfig {
+    attempts: u32,
+    relax: Relax,
+    max_duration: Option<Duration>,
+}
+
+impl Default for SpinConfig {
+    fn default() -> Self {
+        SpinConfig {
+            attempts: 100,
+            relax: Relax::Spin,
+            max_duration: None,
+        }
+    }
+}
+
+impl SpinConfig {
+    /// Creates the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many times to try taking a token before blocking.
+    pub fn attempts(mut self, attempts: u32) -> Self {
+        self.attempts = attempts;
+        self
+    }
+
+    /// What to do between the attempts.
+    pub fn relax(mut self, relax: Relax) -> Self {
+        self.relax = relax;
+        self
+    }
+
+    /// Stop spinning after this time, even if not all the attempts were used.
+    pub fn max_duration(mut self, max_duration: Duration) -> Self {
+        self.max_duration = Some(max_duration);
+        self
+    }
+}
+
+const UNKNOWN: usize = 0;
+
+static CPUS: AtomicUsize = AtomicUsize::new(UNKNOWN);
+
+/// Is there any point in spinning?
+///
+/// With a single CPU, whoever is to post the token can't run while we spin.
+fn multi_cpu() -> bool {
+    let mut cpus = CPUS.load(Ordering::Relaxed);
+    if cpus == UNKNOWN {
+        cpus = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        CPUS.store(cpus, Ordering::Relaxed);
+    }
+    cpus > 1
+}
+
+impl SemaphoreSlot {
+    /// Waits for a token, trying to get it without blocking for a while first.
+    ///
+    /// For very short waits, this saves the cost of going to sleep and being woken up. Once the
+    /// spinning runs out, this blocks like [`wait`][SemaphoreSlot::wait]. On single-CPU systems,
+    /// spinning is skipped altogether.
+    pub fn wait_spin(&self, spin: SpinConfig) {
+        self.backend().wait_spin(spin)
+    }
+}
+
+impl<'a> Backend<'a> {
+    pub(crate) fn wait_spin(self, spin: SpinConfig) {
+        if multi_cpu() {
+            let start = Instant::now();
+            for _ in 0..spin.attempts {
+                if self.trywait().is_ok() {
+                    return;
+                }
+                match spin.relax {
+                    Relax::Spin => hint::spin_loop(),
+                    Relax::Yield => thread::yield_now(),
+                }
+                if spin.max_duration.is_some_and(|max| start.elapsed() >= max) {
+                    break;
+                }
+            }
+        }
+        self.wait()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use Semaphore;
+
+    const CONSUMERS: usize = 4;
+    const TOKENS: usize = 10_000;
+
+    #[test]
+    fn contention() {
+        let sem = Semaphore::anonymous(0).unwrap();
+        let consumed = AtomicUsize::new(0);
+        let configs = [
+            SpinConfig::new(),
+            SpinConfig::new().relax(Relax::Yield),
+            SpinConfig::new().attempts(10_000).max_duration(Duration::from_micros(50)),
+            SpinConfig::new().attempts(0),
+        ];
+        thread::scope(|s| {
+            for config in &configs {
+                let sem = &sem;
+                let consumed = &consumed;
+                s.spawn(move || {
+                    for _ in 0..TOKENS {
+                        sem.wait_spin(*config);
+                        consumed.fetch_add(1, Ordering::Relaxed);
+                    }
+                });
+            }
+            for _ in 0..CONSUMERS * TOKENS {
+                sem.post().unwrap();
+            }
+        });
+        assert_eq!(CONSUMERS * TOKENS, consumed.load(Ordering::Relaxed));
+        assert_eq!(0, sem.value());
+    }
+}
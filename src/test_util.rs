@@ -0,0 +1,104 @@
+//! Helpers for tests that need more than one process, or signals.
+
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::{self, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Once;
+use std::thread;
+use std::time::Duration;
+
+const CHILD_ENV: &str = "UNIX_SEMAPHORE_TEST_CHILD";
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A name no other test (or concurrently running test binary) uses.
+pub fn unique_name(tag: &str) -> String {
+    let cnt = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("/unix-semaphore-{}-{}-{}", tag, process::id(), cnt)
+}
+
+/// The argument passed by the parent, if this process is a child started by [`child`].
+///
+/// Child tests return early when this is `None`, so they are no-ops in the normal test run.
+pub fn child_arg() -> Option<String> {
+    env::var(CHILD_ENV).ok()
+}
+
+/// Prepares a command that runs a single test of this binary in a child process.
+pub fn child(test: &str, arg: &str) -> Command {
+    let mut cmd = Command::new(env::current_exe().unwrap());
+    cmd.args(["--exact", test, "--test-threads=1", "--quiet"])
+        .env(CHILD_ENV, arg)
+        .stdout(Stdio::null());
+    cmd
+}
+
+/// Runs a single test of this binary in a child process and checks it passed.
+pub fn run_child(test: &str, arg: &str) {
+    let status = child(test, arg).status().unwrap();
+    assert!(status.success(), "Child test {} failed", test);
+}
+
+/// A child process created by [`fork`].
+pub struct Forked(libc::pid_t);
+
+impl Forked {
+    /// Waits for the child to terminate and checks it succeeded.
+    pub fn join(self) {
+        let mut status = 0;
+        assert_eq!(self.0, unsafe { libc::waitpid(self.0, &mut status, 0) });
+        assert!(libc::WIFEXITED(status), "Child didn't exit normally");
+        assert_eq!(0, libc::WEXITSTATUS(status), "Child failed");
+    }
+}
+
+/// Runs the closure in a forked child process.
+///
+/// The child exits right after the closure, with non-zero exit code if it panicked. Keep it
+/// simple, the child is a copy of a multi-threaded process.
+pub fn fork<F: FnOnce()>(f: F) -> Forked {
+    match unsafe { libc::fork() } {
+        -1 => panic!("Fork failed: {}", ::std::io::Error::last_os_error()),
+        0 => {
+            let code = match panic::catch_unwind(AssertUnwindSafe(f)) {
+                Ok(()) => 0,
+                Err(_) => 1,
+            };
+            unsafe { libc::_exit(code) }
+        },
+        pid => Forked(pid),
+    }
+}
+
+extern "C" fn noop_handler(_: libc::c_int) {}
+
+/// Runs the closure in a thread, sending it SIGUSR1 until it returns.
+///
+/// The signal has a handler that does nothing, so blocking calls in the thread fail with EINTR.
+/// The signal is sent repeatedly, as the first one may come before the thread blocks.
+pub fn interrupt<R: Send, F: FnOnce() -> R + Send>(f: F) -> R {
+    static HANDLER: Once = Once::new();
+    HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = ::std::mem::zeroed();
+        action.sa_sigaction = noop_handler as *const () as libc::sighandler_t;
+        assert_eq!(0, libc::sigaction(libc::SIGUSR1, &action, ::std::ptr::null_mut()));
+    });
+    let done = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|s| {
+        let handle = s.spawn(|| {
+            sender.send(unsafe { libc::pthread_self() }).unwrap();
+            let result = f();
+            done.store(true, Ordering::Release);
+            result
+        });
+        let thread = receiver.recv().unwrap();
+        while !done.load(Ordering::Acquire) {
+            unsafe { libc::pthread_kill(thread, libc::SIGUSR1) };
+            thread::sleep(Duration::from_millis(10));
+        }
+        handle.join().unwrap()
+    })
+}
@@ -0,0 +1,230 @@
+//! Optional instrumentation of the blocking operations with `tracing`.
+//!
+//! The spans are around [`wait`][::Semaphore::wait], [`timedwait`][::Semaphore::timedwait],
+//! [`wait_timeout`][::Semaphore::wait_timeout], [`wait_deadline`][::Semaphore::wait_deadline],
+//! [`wait_spin`][::Semaphore::wait_spin], [`post`][::Semaphore::post], the guards and the
+//! blocking multi-token acquires. The other operations of [`Semaphore`][::Semaphore] aren't
+//! traced: the `_checked`, `_interruptible`, `_with` and `_on` variants, the cancellable and
+//! ticking waits and the ones that never block (`trywait` and the like). Neither is anything
+//! done directly on a [`SemaphoreSlot`][::SemaphoreSlot] or a [`SemaphoreRef`][::SemaphoreRef].
+//!
+//! With the `tracing` feature off, everything here is empty and inlined away. With it on but no
+//! subscriber interested, a disabled span is just a handle with nothing allocated and the clock
+//! isn't even read.
+
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+use std::time::SystemTime;
+
+use {Kind, WaitError};
+
+/// How a traced operation ended.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Outcome {
+    Acquired,
+    Posted,
+    TimedOut,
+    Interrupted,
+    Overflow,
+}
+
+impl Outcome {
+    #[cfg(feature = "tracing")]
+    fn name(self) -> &'static str {
+        match self {
+            Outcome::Acquired => "acquired",
+            Outcome::Posted => "posted",
+            Outcome::TimedOut => "timed out",
+            Outcome::Interrupted => "interrupted",
+            Outcome::Overflow => "overflow",
+        }
+    }
+}
+
+impl WaitError {
+    pub(crate) fn outcome(&self) -> Outcome {
+        match self {
+            WaitError::Interrupted => Outcome::Interrupted,
+            _ => Outcome::TimedOut,
+        }
+    }
+}
+
+/// A span around one operation on a semaphore.
+#[cfg(feature = "tracing")]
+pub(crate) struct Op {
+    span: tracing::Span,
+    start: Option<Instant>,
+}
+
+#[cfg(feature = "tracing")]
+impl Op {
+    #[inline]
+    pub(crate) fn start(op: &'static str, kind: Kind, tokens: u32, deadline: Option<SystemTime>)
+        -> Op
+    {
+        let span = tracing::trace_span!(
+            "semaphore",
+            op,
+            kind = ?kind,
+            tokens,
+            deadline = ?deadline,
+        );
+        let start = if span.is_disabled() {
+            None
+        } else {
+            Some(Instant::now())
+        };
+        Op { span, start }
+    }
+
+    /// Runs the operation inside the span.
+    #[inline]
+    pub(crate) fn run<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        self.span.in_scope(f)
+    }
+
+    /// Emits the event with the outcome and the time it took.
+    #[inline]
+    pub(crate) fn finish(&self, outcome: Outcome) {
+        if let Some(start) = self.start {
+            let elapsed_us = start.elapsed().as_micros() as u64;
+            // The unhappy outcomes are the interesting ones
+            match outcome {
+                Outcome::Acquired | Outcome::Posted => {
+                    tracing::trace!(parent: &self.span, outcome = outcome.name(), elapsed_us)
+                },
+                _ => tracing::debug!(parent: &self.span, outcome = outcome.name(), elapsed_us),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Op;
+
+#[cfg(not(feature = "tracing"))]
+impl Op {
+    #[inline(always)]
+    pub(crate) fn start(_: &'static str, _: Kind, _: u32, _: Option<SystemTime>) -> Op {
+        Op
+    }
+
+    #[inline(always)]
+    pub(crate) fn run<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        f()
+    }
+
+    #[inline(always)]
+    pub(crate) fn finish(&self, _: Outcome) {}
+}
+
+/// Tokens taken by a multi-token operation that didn't fit back when rolling it back.
+#[cfg(feature = "tracing")]
+pub(crate) fn lost_tokens(lost: u32) {
+    tracing::warn!(lost, "Semaphore overflowed while returning tokens");
+}
+
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn lost_tokens(_: u32) {}
+
+/// Runs a fallible operation in a span, mapping its result to the outcome.
+#[inline]
+pub(crate) fn traced<T, E, F, O>(op: Op, f: F, outcome: O) -> Result<T, E>
+where
+    F: FnOnce() -> Result<T, E>,
+    O: FnOnce(&E) -> Outcome,
+{
+    let result = op.run(f);
+    op.finish(match result {
+        Ok(_) => Outcome::Acquired,
+        Err(ref e) => outcome(e),
+    });
+    result
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use std::fmt::Debug;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant, SystemTime};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use {Semaphore, SpinConfig, WaitError};
+
+    /// Collects the outcomes of all the events.
+    #[derive(Default)]
+    struct Collect {
+        next: AtomicU64,
+        outcomes: Arc<Mutex<Vec<String>>>,
+    }
+
+    struct Outcomes<'a>(&'a mut Vec<String>);
+
+    impl<'a> Visit for Outcomes<'a> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "outcome" {
+                self.0.push(value.to_owned());
+            }
+        }
+
+        fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
+    }
+
+    impl Subscriber for Collect {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &Attributes) -> Id {
+            Id::from_u64(self.next.fetch_add(1, Ordering::Relaxed) + 1)
+        }
+
+        fn record(&self, _: &Id, _: &Record) {}
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event) {
+            event.record(&mut Outcomes(&mut self.outcomes.lock().unwrap()));
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn timed_out_wait() {
+        let collect = Collect::default();
+        let outcomes = Arc::clone(&collect.outcomes);
+        let sem = Semaphore::anonymous(1).unwrap();
+        tracing::subscriber::with_default(collect, || {
+            sem.wait();
+            let until = SystemTime::now() + Duration::from_millis(10);
+            assert_eq!(Err(WaitError::TimedOut), sem.timedwait(until));
+            sem.post().unwrap();
+            drop(sem.access());
+        });
+        let outcomes = outcomes.lock().unwrap();
+        assert_eq!(&["acquired", "timed out", "posted", "acquired"][..], &outcomes[..]);
+    }
+
+    #[test]
+    fn deadline_and_spin() {
+        let collect = Collect::default();
+        let outcomes = Arc::clone(&collect.outcomes);
+        let sem = Semaphore::anonymous(1).unwrap();
+        tracing::subscriber::with_default(collect, || {
+            sem.wait_spin(SpinConfig::new());
+            let deadline = Instant::now() + Duration::from_millis(10);
+            assert_eq!(Err(WaitError::TimedOut), sem.wait_deadline(deadline));
+        });
+        let outcomes = outcomes.lock().unwrap();
+        assert_eq!(&["acquired", "timed out"][..], &outcomes[..]);
+    }
+}
@@ -0,0 +1,195 @@
+//! Moving tokens from one semaphore to another.
+//!
+//! A token is taken from the source first and then posted to the destination. If the
+//! destination overflows, the token goes back to the source, so it's never lost. But it is in
+//! neither semaphore for a moment, other threads may observe that.
+
+use std::error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+use {Semaphore, WaitError};
+
+/// Why a token couldn't be moved.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum TransferError {
+    /// The source had no token right away.
+    Empty,
+    /// The source had no token before the timeout.
+    TimedOut,
+    /// The destination is at its maximum value. The token stayed in the source.
+    Overflow,
+}
+
+impl Display for TransferError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            TransferError::Empty => write!(fmt, "No token to transfer"),
+            TransferError::TimedOut => write!(fmt, "Timed out waiting for a token to transfer"),
+            TransferError::Overflow => write!(fmt, "Overflow of the transfer destination"),
+        }
+    }
+}
+
+impl error::Error for TransferError {}
+
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Error {
+        let kind = match e {
+            TransferError::Empty => ErrorKind::WouldBlock,
+            TransferError::TimedOut => ErrorKind::TimedOut,
+            TransferError::Overflow => ErrorKind::Other,
+        };
+        Error::new(kind, e)
+    }
+}
+
+/// A transfer of several tokens stopped midway.
+///
+/// The tokens moved up to that point stay moved.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct PartialTransfer {
+    transferred: u32,
+    error: TransferError,
+}
+
+impl PartialTransfer {
+    /// How many tokens were moved before it stopped.
+    pub fn transferred(&self) -> u32 {
+        self.transferred
+    }
+
+    /// Why the next token couldn't be moved.
+    pub fn error(&self) -> TransferError {
+        self.error
+    }
+}
+
+impl Display for PartialTransfer {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{} after transferring {} tokens", self.error, self.transferred)
+    }
+}
+
+impl error::Error for PartialTransfer {}
+
+impl From<PartialTransfer> for TransferError {
+    fn from(e: PartialTransfer) -> TransferError {
+        e.error
+    }
+}
+
+impl From<PartialTransfer> for Error {
+    fn from(e: PartialTransfer) -> Error {
+        Error::new(Error::from(e.error).kind(), e)
+    }
+}
+
+impl Semaphore {
+    /// Posts the token just taken from this semaphore to `dst`, or back here on overflow.
+    fn pass_to(&self, dst: &Semaphore) -> Result<(), TransferError> {
+        dst.post().map_err(|_| {
+            // We took the token, so there should be room for it. If someone else posted so much
+            // meanwhile there isn't, there's nowhere else to keep it.
+            let _ = self.post();
+            TransferError::Overflow
+        })
+    }
+
+    /// Moves one token from this semaphore to `dst`, if one is available right away.
+    pub fn transfer_to(&self, dst: &Semaphore) -> Result<(), TransferError> {
+        self.trywait().map_err(|_| TransferError::Empty)?;
+        self.pass_to(dst)
+    }
+
+    /// Like [`transfer_to`][Semaphore::transfer_to], but waits for the token at most for the
+    /// given time.
+    ///
+    /// An overflow of the destination is reported right away, without waiting for room.
+    pub fn transfer_timeout(&self, dst: &Semaphore, timeout: Duration)
+        -> Result<(), TransferError>
+    {
+        match self.wait_timeout(timeout) {
+            Ok(()) => self.pass_to(dst),
+            Err(WaitError::TimedOut) => Err(TransferError::TimedOut),
+            Err(e) => unreachable!("Impossible error {}", e),
+        }
+    }
+
+    /// Moves `n` tokens from this semaphore to `dst`, one by one, without waiting.
+    ///
+    /// Stops at the first token that can't be moved, the ones before stay moved. Zero is a
+    /// no-op.
+    pub fn transfer_many(&self, dst: &Semaphore, n: u32) -> Result<(), PartialTransfer> {
+        for transferred in 0..n {
+            self.transfer_to(dst)
+                .map_err(|error| PartialTransfer { transferred, error })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn moves_token() {
+        let idle = Semaphore::anonymous(1).unwrap();
+        let active = Semaphore::anonymous(0).unwrap();
+        idle.transfer_to(&active).unwrap();
+        assert_eq!(0, idle.value());
+        assert_eq!(1, active.value());
+        assert_eq!(Err(TransferError::Empty), idle.transfer_to(&active));
+        let timeout = Duration::from_millis(1);
+        assert_eq!(Err(TransferError::TimedOut), idle.transfer_timeout(&active, timeout));
+        active.transfer_timeout(&idle, timeout).unwrap();
+        assert_eq!(1, idle.value());
+    }
+
+    #[test]
+    fn overflow_keeps_token() {
+        let src = Semaphore::anonymous(2).unwrap();
+        let dst = Semaphore::anonymous(Semaphore::max_value()).unwrap();
+        assert_eq!(Err(TransferError::Overflow), src.transfer_to(&dst));
+        assert_eq!(2, src.value());
+        dst.trywait().unwrap();
+        let e = src.transfer_many(&dst, 2).unwrap_err();
+        assert_eq!(1, e.transferred());
+        assert_eq!(TransferError::Overflow, e.error());
+        assert_eq!(1, src.value());
+    }
+
+    #[test]
+    fn transfer_many_partial() {
+        let src = Semaphore::anonymous(3).unwrap();
+        let dst = Semaphore::anonymous(0).unwrap();
+        src.transfer_many(&dst, 0).unwrap();
+        let e = src.transfer_many(&dst, 5).unwrap_err();
+        assert_eq!(3, e.transferred());
+        assert_eq!(TransferError::Empty, e.error());
+        assert_eq!(3, dst.value());
+    }
+
+    #[test]
+    fn concurrent_conserves_tokens() {
+        const TOTAL: u32 = 50;
+        let a = Semaphore::anonymous(TOTAL).unwrap();
+        let b = Semaphore::anonymous(0).unwrap();
+        thread::scope(|s| {
+            for i in 0..4 {
+                let (src, dst) = if i % 2 == 0 { (&a, &b) } else { (&b, &a) };
+                s.spawn(move || {
+                    for _ in 0..1000 {
+                        let _ = src.transfer_to(dst);
+                        let _ = src.transfer_many(dst, 3);
+                    }
+                });
+            }
+        });
+        assert_eq!(TOTAL, a.try_value().unwrap() + b.try_value().unwrap());
+    }
+}
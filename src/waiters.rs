@@ -0,0 +1,49 @@
+//! Counting the threads blocked on semaphores.
+//!
+//! Linux doesn't report the waiters through `sem_getvalue` and peeking into the glibc internals
+//! works only there, so the blocking waits of the crate count themselves. The counters are in a
+//! fixed table indexed by the address of the semaphore, so two semaphores may share a counter.
+//! That only ever makes the count higher, never lower.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use libc::sem_t;
+
+const BUCKETS: usize = 64;
+
+// Each on its own cache line, so unrelated semaphores don't fight over it
+#[repr(align(64))]
+struct Bucket(AtomicU32);
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Bucket = Bucket(AtomicU32::new(0));
+
+static TABLE: [Bucket; BUCKETS] = [EMPTY; BUCKETS];
+
+fn bucket(sem: *const sem_t) -> &'static AtomicU32 {
+    // The low bits are the same for all the semaphores because of the alignment
+    let addr = sem as usize / 8;
+    &TABLE[(addr ^ (addr >> 6) ^ (addr >> 12)) % BUCKETS].0
+}
+
+/// Counts a thread as blocked on the semaphore for as long as it lives.
+pub(crate) struct Waiting(&'static AtomicU32);
+
+impl Waiting {
+    pub(crate) fn new(sem: *const sem_t) -> Self {
+        let counter = bucket(sem);
+        counter.fetch_add(1, Ordering::SeqCst);
+        Waiting(counter)
+    }
+}
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The number of threads of this process blocked on the semaphore, possibly more.
+pub(crate) fn count(sem: *const sem_t) -> u32 {
+    bucket(sem).load(Ordering::SeqCst)
+}
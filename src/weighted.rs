@@ -0,0 +1,233 @@
+//! A semaphore with a large number of permits, taken many at a time.
+
+use std::io::Error;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use {NoToken, Overflow, Semaphore, WaitError};
+
+struct Permits {
+    available: u64,
+    // Does the waiter holding the turnstile need a wakeup?
+    wanting: bool,
+}
+
+/// A semaphore counting up to `u64::MAX` permits, acquired and released in arbitrary amounts.
+///
+/// The kernel semaphore is limited to `SEM_VALUE_MAX` and moves one token at a time, which
+/// doesn't work for accounting something like bytes. This keeps the count in a plain counter and
+/// uses semaphores only for waiting.
+///
+/// Waiters line up on a turnstile and only the one at the front waits for the permits, the others
+/// wait for it to finish. Therefore a large request is not overtaken by a stream of smaller ones
+/// (which could otherwise fit in even when it doesn't), but the smaller ones are blocked behind
+/// it. The order of the waiters in the line is the order in which the semaphore wakes them up,
+/// which is not guaranteed to be the order of arrival.
+pub struct WeightedSemaphore {
+    permits: Mutex<Permits>,
+    total: u64,
+    turnstile: Semaphore,
+    wakeup: Semaphore,
+}
+
+impl WeightedSemaphore {
+    /// Creates the semaphore with all the `permits` available.
+    pub fn new(permits: u64) -> Result<Self, Error> {
+        Ok(WeightedSemaphore {
+            permits: Mutex::new(Permits {
+                available: permits,
+                wanting: false,
+            }),
+            total: permits,
+            turnstile: Semaphore::anonymous(1)?,
+            wakeup: Semaphore::anonymous(0)?,
+        })
+    }
+
+    fn permits(&self) -> MutexGuard<'_, Permits> {
+        self.permits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn check(&self, n: u64) {
+        assert!(n <= self.total, "Acquiring {} permits out of {}", n, self.total);
+    }
+
+    /// Takes the permits if they are available.
+    ///
+    /// Must be called with the turnstile held. If not, it asks for a wakeup.
+    fn take(&self, n: u64) -> bool {
+        let mut permits = self.permits();
+        let enough = permits.available >= n;
+        if enough {
+            permits.available -= n;
+        } else {
+            permits.wanting = true;
+        }
+        enough
+    }
+
+    fn acquire_until(&self, n: u64, deadline: Option<Instant>) -> Result<(), WaitError> {
+        let wait = |sem: &Semaphore| match deadline {
+            Some(deadline) => sem.wait_deadline(deadline),
+            None => {
+                sem.wait();
+                Ok(())
+            },
+        };
+        wait(&self.turnstile)?;
+        let mut result = Ok(());
+        while !self.take(n) {
+            if let Err(e) = wait(&self.wakeup) {
+                let mut permits = self.permits();
+                if permits.wanting {
+                    permits.wanting = false;
+                    result = Err(e);
+                    break;
+                }
+                // Released just now, the wakeup is on its way
+                drop(permits);
+                self.wakeup.wait();
+                if !self.take(n) {
+                    self.permits().wanting = false;
+                    result = Err(e);
+                }
+                break;
+            }
+        }
+        let _ = self.turnstile.post();
+        result
+    }
+
+    /// Waits for `n` permits and takes them.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is more than the semaphore was created with, as it would never succeed.
+    pub fn acquire(&self, n: u64) {
+        self.check(n);
+        self.acquire_until(n, None).expect("Wait without a deadline failed");
+    }
+
+    /// Takes `n` permits if they are available and nobody else waits.
+    pub fn try_acquire(&self, n: u64) -> Result<(), NoToken> {
+        self.turnstile.trywait()?;
+        let mut permits = self.permits();
+        let enough = permits.available >= n;
+        if enough {
+            permits.available -= n;
+        }
+        drop(permits);
+        let _ = self.turnstile.post();
+        if enough {
+            Ok(())
+        } else {
+            Err(NoToken::new())
+        }
+    }
+
+    /// Waits for `n` permits, but at most for the given time.
+    ///
+    /// # Panics
+    ///
+    /// If `n` is more than the semaphore was created with.
+    pub fn acquire_timeout(&self, n: u64, timeout: Duration) -> Result<(), WaitError> {
+        self.check(n);
+        self.acquire_until(n, Instant::now().checked_add(timeout))
+    }
+
+    /// Returns `n` permits.
+    ///
+    /// Fails without returning anything if that would make more permits available than the
+    /// semaphore was created with.
+    pub fn release(&self, n: u64) -> Result<(), Overflow> {
+        let mut permits = self.permits();
+        if self.total - permits.available < n {
+            return Err(Overflow::new());
+        }
+        permits.available += n;
+        if permits.wanting {
+            permits.wanting = false;
+            let _ = self.wakeup.post();
+        }
+        Ok(())
+    }
+
+    /// The number of permits available right now.
+    pub fn available(&self) -> u64 {
+        self.permits().available
+    }
+
+    /// The number of permits the semaphore was created with.
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::thread;
+
+    use super::*;
+
+    const BUDGET: u64 = 1 << 40;
+
+    #[test]
+    fn large_amounts() {
+        let sem = WeightedSemaphore::new(BUDGET).unwrap();
+        sem.acquire(BUDGET - 1);
+        assert_eq!(Err(NoToken::new()), sem.try_acquire(2));
+        let timeout = Duration::from_millis(10);
+        assert_eq!(Err(WaitError::TimedOut), sem.acquire_timeout(2, timeout));
+        sem.try_acquire(1).unwrap();
+        assert_eq!(0, sem.available());
+        sem.release(BUDGET).unwrap();
+        assert_eq!(Err(Overflow::new()), sem.release(1));
+        assert_eq!(BUDGET, sem.available());
+        assert!(!sem.permits().wanting);
+        assert_eq!(0, sem.wakeup.value());
+    }
+
+    #[test]
+    fn mixed_sizes() {
+        let sem = WeightedSemaphore::new(BUDGET).unwrap();
+        let in_use = AtomicU64::new(0);
+        thread::scope(|s| {
+            for i in 1..=8 {
+                let (sem, in_use) = (&sem, &in_use);
+                s.spawn(move || {
+                    // Together, some of them need the whole budget
+                    let n = BUDGET / 8 * i;
+                    for _ in 0..200 {
+                        sem.acquire(n);
+                        let total = in_use.fetch_add(n, Ordering::SeqCst) + n;
+                        assert!(total <= BUDGET);
+                        in_use.fetch_sub(n, Ordering::SeqCst);
+                        sem.release(n).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(BUDGET, sem.available());
+    }
+
+    #[test]
+    fn large_not_starved() {
+        let sem = WeightedSemaphore::new(10).unwrap();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        sem.acquire(3);
+                        thread::yield_now();
+                        sem.release(3).unwrap();
+                    }
+                });
+            }
+            sem.acquire_timeout(10, Duration::from_secs(10)).unwrap();
+            done.store(true, Ordering::Relaxed);
+            sem.release(10).unwrap();
+        });
+    }
+}
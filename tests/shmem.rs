@@ -0,0 +1,44 @@
+#![cfg(feature = "shared-memory")]
+
+extern crate shared_memory;
+extern crate unix_semaphore;
+
+use std::env;
+use std::process::Command;
+
+use shared_memory::ShmemConf;
+use unix_semaphore::Semaphore;
+
+const CHILD_ENV: &str = "UNIX_SEMAPHORE_SHMEM_ID";
+const OFFSET: usize = 64;
+const ROUNDS: usize = 10;
+
+#[test]
+fn child() {
+    if let Ok(os_id) = env::var(CHILD_ENV) {
+        let shmem = ShmemConf::new().os_id(os_id).open().unwrap();
+        let ping = Semaphore::attach_in_shmem(&shmem, OFFSET).unwrap();
+        let pong = Semaphore::attach_in_shmem(&shmem, 2 * OFFSET).unwrap();
+        for _ in 0..ROUNDS {
+            ping.wait();
+            pong.post().unwrap();
+        }
+    }
+}
+
+#[test]
+fn two_processes() {
+    let shmem = ShmemConf::new().size(4096).create().unwrap();
+    let ping = Semaphore::create_in_shmem(&shmem, OFFSET, 0).unwrap();
+    let pong = Semaphore::create_in_shmem(&shmem, 2 * OFFSET, 0).unwrap();
+    let mut child = Command::new(env::current_exe().unwrap())
+        .args(["--exact", "child", "--quiet"])
+        .env(CHILD_ENV, shmem.get_os_id())
+        .spawn()
+        .unwrap();
+    for _ in 0..ROUNDS {
+        ping.post().unwrap();
+        pong.wait();
+    }
+    assert!(child.wait().unwrap().success());
+}